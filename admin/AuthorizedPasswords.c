@@ -61,6 +61,12 @@ static void add(Dict* args, void* vcontext, String* txid, struct Allocator* allo
         case Ca_addUser_DUPLICATE:
             sendResponse(String_CONST("Password already added."), context->admin, txid, alloc);
             break;
+        case Ca_addUser_WEAK_PASSWORD:
+            sendResponse(String_CONST("Password is too weak."), context->admin, txid, alloc);
+            break;
+        case Ca_addUser_TOO_MANY_USERS:
+            sendResponse(String_CONST("Too many passwords."), context->admin, txid, alloc);
+            break;
         default:
             sendResponse(String_CONST("Unknown error."), context->admin, txid, alloc);
     }
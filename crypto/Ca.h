@@ -45,6 +45,8 @@ typedef struct CryptoAuth_Session Ca_Session_t;
 
 enum Ca_addUser_Res {
     Ca_addUser_DUPLICATE = -3,
+    Ca_addUser_WEAK_PASSWORD = -4,
+    Ca_addUser_TOO_MANY_USERS = -5,
 };
 static inline int Ca_addUser_ipv6(String* password,
                             String* login,
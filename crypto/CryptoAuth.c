@@ -766,8 +766,8 @@ static enum CryptoAuth_DecryptErr decryptHandshake(struct CryptoAuth_Session_pvt
             // fresh new hello packet, we should reset the session.
             switch (session->nextNonce) {
                 case CryptoAuth_State_SENT_HELLO: {
-                    if (Bits_memcmp(session->herPublicKey,
-                                    session->context->pubKey, 32) < 0)
+                    if (CryptoAuth_compareKeys(session->herPublicKey,
+                                               session->context->pubKey) < 0)
                     {
                         // It's a hello and we are the initiator but their permant public key is
                         // numerically lower than ours, this is so that in the event of two hello
@@ -1235,3 +1235,24 @@ int CryptoAuth_decryptRndNonce(const uint8_t nonce[24], struct Message* msg, con
     return decryptRndNonce(nonce, msg, secret);
 }
 
+int CryptoAuth_compareKeys(const uint8_t a[32], const uint8_t b[32])
+{
+    return Bits_memcmp(a, b, 32);
+}
+
+void CryptoAuth_readHeader(struct Message* msg, struct CryptoHeader* out)
+{
+    Assert_true(Message_getLength(msg) >= CryptoHeader_SIZE);
+    struct CryptoHeader* header = (struct CryptoHeader*) msg->msgbytes;
+    out->nonce = Endian_bigEndianToHost32(header->nonce);
+    out->auth.type = header->auth.type;
+    Bits_memcpy(out->auth.lookup, header->auth.lookup, 7);
+    out->auth.requirePacketAuthAndDerivationCount =
+        header->auth.requirePacketAuthAndDerivationCount;
+    out->auth.additional = header->auth.additional;
+    Bits_memcpy(out->handshakeNonce, header->handshakeNonce, 24);
+    Bits_memcpy(out->publicKey, header->publicKey, 32);
+    Bits_memcpy(out->authenticator, header->authenticator, 16);
+    Bits_memcpy(out->encryptedTempKey, header->encryptedTempKey, 32);
+}
+
@@ -62,9 +62,13 @@ struct CryptoAuth_Session
  * @param context The CryptoAuth context.
  * @return 0 if all goes well,
  *         CryptoAuth_addUser_DUPLICATE if the same *password* already exists.
+ *         CryptoAuth_addUser_WEAK_PASSWORD if the password is rejected by the password policy.
+ *         CryptoAuth_addUser_TOO_MANY_USERS if the maximum number of users is reached.
  */
 enum CryptoAuth_addUser_Res {
     CryptoAuth_addUser_DUPLICATE = -3,
+    CryptoAuth_addUser_WEAK_PASSWORD = -4,
+    CryptoAuth_addUser_TOO_MANY_USERS = -5,
 };
 int CryptoAuth_addUser_ipv6(String* password,
                             String* login,
@@ -179,7 +183,16 @@ enum CryptoAuth_DecryptErr {
     CryptoAuth_DecryptErr_REPLAY = 14,
 
     // Authenticated decryption failed
-    CryptoAuth_DecryptErr_DECRYPT = 15
+    CryptoAuth_DecryptErr_DECRYPT = 15,
+
+    // Unexpected failure inside the Rust implementation
+    CryptoAuth_DecryptErr_INTERNAL = 16,
+
+    // Too many sessions are in the middle of a handshake to start another one
+    CryptoAuth_DecryptErr_TOO_MANY_HANDSHAKES = 17,
+
+    // The session expects a blinded hello and this one could not be unblinded
+    CryptoAuth_DecryptErr_BLINDING = 18
 };
 
 // returns 0 if everything if ok, otherwise an encryption error.
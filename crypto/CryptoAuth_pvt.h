@@ -145,4 +145,17 @@ int CryptoAuth_decryptRndNonce(const uint8_t nonce[24], struct Message* msg, con
 
 void CryptoAuth_encryptRndNonce(const uint8_t nonce[24], struct Message* msg, const uint8_t secret[32]);
 
+/**
+ * Compare two public keys the way crossed hellos are resolved: the node whose key is higher
+ * yields and the node with the lower key stays the initiator.
+ * Returns less than, equal to or greater than zero like memcmp().
+ */
+int CryptoAuth_compareKeys(const uint8_t a[32], const uint8_t b[32]);
+
+/**
+ * Copy the CryptoHeader at the beginning of msg into out field by field, with the nonce
+ * in host byte order. For checking the layout of the Rust CryptoHeader against this one.
+ */
+void CryptoAuth_readHeader(struct Message* msg, struct CryptoHeader* out);
+
 #endif
@@ -0,0 +1,16 @@
+//! Feed arbitrary packets to `CryptoAuth` session decryption.
+//!
+//! Run with `cargo fuzz run decrypt` from `rust/cjdns_sys`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 4 {
+        return;
+    }
+    let mut now = [0; 4];
+    now.copy_from_slice(&data[0..4]);
+    cjdns_sys::fuzz::fuzz_decrypt(&data[4..], u32::from_le_bytes(now));
+});
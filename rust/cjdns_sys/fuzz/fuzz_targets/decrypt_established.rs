@@ -0,0 +1,11 @@
+//! Feed arbitrary packets to an established `CryptoAuth` session.
+//!
+//! Run with `cargo fuzz run decrypt_established` from `rust/cjdns_sys`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    cjdns_sys::fuzz::fuzz_decrypt_established(data);
+});
@@ -510,6 +510,8 @@ pub struct CryptoAuth_Session {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum CryptoAuth_addUser_Res {
     CryptoAuth_addUser_DUPLICATE = -3,
+    CryptoAuth_addUser_WEAK_PASSWORD = -4,
+    CryptoAuth_addUser_TOO_MANY_USERS = -5,
 }
 extern "C" {
     pub fn CryptoAuth_addUser_ipv6(
@@ -575,6 +577,9 @@ pub enum CryptoAuth_DecryptErr {
     CryptoAuth_DecryptErr_INVALID_PACKET = 13,
     CryptoAuth_DecryptErr_REPLAY = 14,
     CryptoAuth_DecryptErr_DECRYPT = 15,
+    CryptoAuth_DecryptErr_INTERNAL = 16,
+    CryptoAuth_DecryptErr_TOO_MANY_HANDSHAKES = 17,
+    CryptoAuth_DecryptErr_BLINDING = 18,
 }
 extern "C" {
     pub fn CryptoAuth_decrypt(
@@ -619,9 +624,27 @@ extern "C" {
         statsOut: *mut RTypes_CryptoStats_t,
     );
 }
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CryptoHeader_Challenge {
+    pub type_: u8,
+    pub lookup: [u8; 7usize],
+    pub requirePacketAuthAndDerivationCount: u16,
+    pub additional: u16,
+}
 extern "C" {
     pub fn Assert_compileTime(x: *mut ::std::os::raw::c_char);
 }
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct CryptoHeader {
+    pub nonce: u32,
+    pub auth: CryptoHeader_Challenge,
+    pub handshakeNonce: [u8; 24usize],
+    pub publicKey: [u8; 32usize],
+    pub authenticator: [u8; 16usize],
+    pub encryptedTempKey: [u8; 32usize],
+}
 extern "C" {
     pub fn CryptoAuth_decryptRndNonce(
         nonce: *const u8,
@@ -632,6 +655,12 @@ extern "C" {
 extern "C" {
     pub fn CryptoAuth_encryptRndNonce(nonce: *const u8, msg: *mut Message, secret: *const u8);
 }
+extern "C" {
+    pub fn CryptoAuth_compareKeys(a: *const u8, b: *const u8) -> ::std::os::raw::c_int;
+}
+extern "C" {
+    pub fn CryptoAuth_readHeader(msg: *mut Message, out: *mut CryptoHeader);
+}
 extern "C" {
     pub fn DeterminentRandomSeed_new(alloc: *mut Allocator, buff: *mut u8) -> *mut RandomSeed_t;
 }
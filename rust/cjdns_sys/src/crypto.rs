@@ -7,6 +7,7 @@ pub mod crypto_auth;
 pub mod crypto_header;
 pub mod crypto_noise;
 pub mod keys;
+pub mod metrics;
 pub mod random;
 pub mod replay_protector;
 pub mod session;
@@ -31,6 +32,32 @@ mod utils {
         let sha256::Digest(digest) = sha256::hash(data);
         digest
     }
+
+    /// Compare two byte strings in a time which doesn't depend on where they differ.
+    /// Only the lengths are compared in variable time.
+    #[inline(always)]
+    pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+        sodiumoxide::utils::memcmp(a, b)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::ct_eq;
+
+        #[test]
+        fn test_ct_eq() {
+            let a = [0x55_u8; 12];
+            assert!(ct_eq(&a, &a.clone()));
+            let mut b = a;
+            b[0] ^= 1;
+            assert!(!ct_eq(&a, &b));
+            let mut b = a;
+            b[11] ^= 0x80;
+            assert!(!ct_eq(&a, &b));
+            assert!(!ct_eq(&a, &a[..11]));
+            assert!(ct_eq(&[], &[]));
+        }
+    }
 }
 
 mod wipe {
@@ -39,7 +66,9 @@ mod wipe {
 
     use sodiumoxide::crypto::scalarmult::curve25519::Scalar;
 
+    use crate::crypto::crypto_auth::HandshakeHeader;
     use crate::crypto::crypto_header::CryptoHeader;
+    use crate::crypto::keys::PrivateKey;
 
     /// Trait that defines `wipe` operation which securely overwrites sensitive
     /// data such as cryptographic keys with zeroes.
@@ -87,6 +116,53 @@ mod wipe {
         }
     }
 
+    impl Wipe for &mut [u8; 32] {
+        #[inline(always)]
+        fn wipe(self) {
+            // Prevent this write from being optimized away
+            volatile_write(self, [0; 32]);
+            // Prevent reordering
+            atomic_fence();
+        }
+    }
+
+    impl Wipe for Vec<u8> {
+        #[inline(always)]
+        fn wipe(mut self) {
+            // Prevent this write from being optimized away
+            volatile_set(self.as_mut_slice(), 0_u8);
+            // Prevent reordering
+            atomic_fence();
+        }
+    }
+
+    impl Wipe for PrivateKey {
+        #[inline(always)]
+        fn wipe(mut self) {
+            // There is no mutable access to the bytes of a `PrivateKey`, it's a plain array
+            let size = std::mem::size_of::<PrivateKey>();
+            let ptr = &mut self as *mut PrivateKey as *mut u8;
+            let bytes = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+            // Prevent this write from being optimized away
+            volatile_set(bytes, 0_u8);
+            // Prevent reordering
+            atomic_fence();
+        }
+    }
+
+    impl Wipe for &mut HandshakeHeader {
+        #[inline(always)]
+        fn wipe(self) {
+            let size = std::mem::size_of::<HandshakeHeader>();
+            // All zeroes is a valid `HandshakeHeader`, the auth type becomes `AuthType::Zero`
+            let bytes = unsafe { std::slice::from_raw_parts_mut(self as *mut _ as *mut u8, size) };
+            // Prevent this write from being optimized away
+            volatile_set(bytes, 0_u8);
+            // Prevent reordering
+            atomic_fence();
+        }
+    }
+
     impl Wipe for CryptoHeader {
         #[inline(always)]
         fn wipe(mut self) {
@@ -112,3 +188,86 @@ mod zero {
         }
     }
 }
+
+mod secret {
+    use super::wipe::Wipe;
+    use super::zero::IsZero;
+
+    /// 32 bytes of secret key material, which are wiped when dropped.
+    ///
+    /// There is no `Debug`, and no `Clone` so that copies are only made on purpose
+    /// with `clone_secret()`.
+    #[derive(Default)]
+    pub struct SecretBuf32([u8; 32]);
+
+    impl SecretBuf32 {
+        #[inline(always)]
+        pub fn as_bytes(&self) -> &[u8; 32] {
+            &self.0
+        }
+
+        #[inline(always)]
+        pub fn as_mut_bytes(&mut self) -> &mut [u8; 32] {
+            &mut self.0
+        }
+
+        /// An explicit copy of the secret, which is wiped separately.
+        #[inline(always)]
+        pub fn clone_secret(&self) -> Self {
+            SecretBuf32(self.0)
+        }
+    }
+
+    impl From<[u8; 32]> for SecretBuf32 {
+        #[inline(always)]
+        fn from(bytes: [u8; 32]) -> Self {
+            SecretBuf32(bytes)
+        }
+    }
+
+    impl IsZero for SecretBuf32 {
+        #[inline(always)]
+        fn is_zero(&self) -> bool {
+            self.0.is_zero()
+        }
+    }
+
+    impl Drop for SecretBuf32 {
+        fn drop(&mut self) {
+            (&mut self.0).wipe();
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::mem::MaybeUninit;
+        use std::ptr;
+
+        use super::SecretBuf32;
+        use crate::crypto::zero::IsZero;
+
+        #[test]
+        fn test_secret_buf() {
+            let mut secret = SecretBuf32::default();
+            assert!(secret.is_zero());
+            secret.as_mut_bytes()[31] = 1;
+            assert!(!secret.is_zero());
+
+            let copy = secret.clone_secret();
+            secret.as_mut_bytes()[0] = 2;
+            assert_eq!(copy.as_bytes()[..2], [0, 0]);
+            assert_eq!(copy.as_bytes()[31], 1);
+            assert_eq!(secret.as_bytes()[0], 2);
+        }
+
+        #[test]
+        fn test_secret_buf_wiped_on_drop() {
+            let mut secret = MaybeUninit::new(SecretBuf32::from([0x55; 32]));
+            unsafe {
+                ptr::drop_in_place(secret.as_mut_ptr());
+                // Still allocated, only dropped
+                assert_eq!((*secret.as_ptr()).0, [0; 32]);
+            }
+        }
+    }
+}
@@ -1,25 +1,37 @@
 //! CryptoAuth
 
-use std::sync::Arc;
+use std::cmp::{self, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::IoSlice;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::net::Ipv6Addr;
 
 use anyhow::Result;
 use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard};
+use sodiumoxide::crypto::hash::sha256;
 use thiserror::Error;
 
 use crate::bytestring::ByteString;
 use crate::crypto::crypto_noise;
-use crate::crypto::crypto_header::{AuthType, Challenge, CryptoHeader};
-use crate::crypto::keys::{PrivateKey, PublicKey};
+use crate::crypto::crypto_header::{AuthType, Challenge, CryptoHeader, HeaderError};
+use crate::crypto::keys::{Ip6Restriction, IpV6, IpV6Ext, KeyParseError, Keypair};
+use crate::crypto::keys::{KeyCtEq, NodeIdentity, PublicKey, PublicKeyExt};
+use crate::crypto::keys::{PrivateKey, PrivateKeyExt};
 use crate::crypto::random::Random;
-use crate::crypto::replay_protector::ReplayProtector;
-use crate::crypto::utils::{crypto_hash_sha256, crypto_scalarmult_curve25519_base};
+use crate::crypto::replay_protector::{ReplayKind, ReplayProtector};
+use crate::crypto::secret::SecretBuf32;
+use crate::crypto::session::{CryptoSession, SessionTrait};
+#[cfg(any(test, feature = "fuzz"))]
+use crate::crypto::utils::crypto_scalarmult_curve25519_base;
+use crate::crypto::utils::{crypto_hash_sha256, ct_eq};
 use crate::crypto::wipe::Wipe;
 use crate::crypto::zero::IsZero;
 use crate::external::interface::iface::{self, IfRecv, Iface, IfacePvt};
 use crate::interface::wire::message::Message;
-use crate::util::events::EventBase;
-use crate::crypto::session::SessionTrait;
+use crate::util::events::Clock;
 use crate::crypto::cnoise;
 use crate::external::memory::allocator::Allocator;
 
@@ -34,24 +46,601 @@ mod types {
 }
 
 pub struct CryptoAuth {
+    /// Our public key, the same as `keypair.public()`.
     pub public_key: PublicKey,
 
-    private_key: PrivateKey,
-    users: RwLock<Vec<User>>,
-    event_base: EventBase,
+    /// Live sessions created with this CryptoAuth.
+    pub sessions: SessionManager,
+
+    keypair: Keypair,
+    users: Arc<RwLock<Users>>,
+    anon_login: Mutex<AnonLogin>,
+    hello_collision_handler: RwLock<Option<Box<HelloCollisionHandler>>>,
+    handshake_timeout_handler: RwLock<Option<Box<HandshakeTimeoutHandler>>>,
+    replay_reset_handler: RwLock<Option<Box<ReplayResetHandler>>>,
+    decrypt_error_handler: RwLock<Option<Box<DecryptErrorHandler>>>,
+    auth_failure_handler: RwLock<Option<Box<AuthFailureHandler>>>,
+    max_handshakes: AtomicUsize,
+    max_sessions_per_ip6: AtomicUsize,
+    min_password_length: AtomicUsize,
+    max_users: AtomicUsize,
+    /// Accept users with the same login and different passwords, see `set_shared_logins()`.
+    shared_logins: AtomicBool,
+    metrics_max_sessions: AtomicUsize,
+    /// Handshakes with a password based auth which matched no user.
+    unmatched_challenges: AtomicU64,
+    /// Dropped packets of all the sessions, indexed by `DecryptErr`.
+    decrypt_error_counts: [AtomicU64; DecryptErr::COUNT],
+    /// Packets dropped as `DecryptErr::Replay`, indexed by `ReplayKind`.
+    replay_counts: [AtomicU64; ReplayKind::COUNT],
+    /// Log secret key material, see `set_log_secret_keys()`.
+    log_keys: AtomicBool,
+    clock: Arc<dyn Clock + Send + Sync>,
     rand: Random,
     noise: Arc<crypto_noise::CryptoNoise>,
 }
 
-#[derive(Default, Clone)]
-struct User {
+/// An authorized password, see `CryptoAuth::add_user_ipv6()`.
+#[derive(Default)]
+pub struct User {
     /// Double-hash of password for AuthType 1
     password_hash: [u8; Challenge::KEYSIZE],
     /// Hash of username for AuthType 2
     user_name_hash: [u8; Challenge::KEYSIZE],
-    secret: [u8; 32],
+    /// sha256 of the password, mixed into the shared secret of every handshake with this user.
+    /// It can't be stored with a slow KDF instead: the peer derives the same value from the
+    /// password, and it is needed in the clear to answer each handshake.
+    secret: SecretBuf32,
     login: ByteString,
-    restricted_to_ip6: Option<[u8; 16]>,
+    restricted_to_ip6: Option<Ip6Restriction>,
+    /// Clock time in seconds from which this user is no longer accepted.
+    expires_at: Option<u64>,
+    counters: Arc<UserCounters>,
+}
+
+/// Authentication counters of a user, shared with its `AuthMatch`es so that handshakes
+/// can update them without the users lock.
+#[derive(Default)]
+struct UserCounters {
+    successes: AtomicU64,
+    failures: AtomicU64,
+}
+
+/// Authentication counters of a user, see `CryptoAuth::get_user_stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserStats {
+    /// Handshake packets accepted with this user's password.
+    pub auth_successes: u64,
+    /// Handshake packets which matched this user but were rejected, because the password
+    /// was wrong or the peer was not the one the user is restricted to.
+    pub auth_failures: u64,
+}
+
+/// Authentication counters of a CryptoAuth, see `CryptoAuth::get_auth_stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AuthStats {
+    /// Sum of the successes of all the users.
+    pub auth_successes: u64,
+    /// Sum of the failures of all the users.
+    pub auth_failures: u64,
+    /// Handshake packets with a password based auth which matched no user.
+    pub unmatched_challenges: u64,
+}
+
+impl User {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at)
+    }
+}
+
+/// The users of a CryptoAuth, indexed by the hashes peers authenticate with.
+///
+/// Several users may have the same hash (e.g. a login shared by several passwords), so each
+/// hash maps to the positions of all the matching users.
+#[derive(Default)]
+pub struct Users {
+    list: Vec<User>,
+    /// Positions in `list` by the lookup bytes of `password_hash`, for AuthType 1
+    by_password_lookup: HashMap<[u8; 7], Vec<usize>>,
+    /// Positions in `list` by the lookup bytes of `user_name_hash`, for AuthType 2
+    by_user_name_lookup: HashMap<[u8; 7], Vec<usize>>,
+}
+
+/// The `Challenge::lookup` bytes of a challenge's key bytes, which follow the auth type.
+fn lookup_of(key: &[u8; Challenge::KEYSIZE]) -> [u8; 7] {
+    let mut lookup = [0; 7];
+    lookup.copy_from_slice(&key[1..]);
+    lookup
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, user: User) {
+        let pos = self.list.len();
+        self.index(pos, &user);
+        self.list.push(user);
+    }
+
+    fn index(&mut self, pos: usize, user: &User) {
+        let password_lookup = lookup_of(&user.password_hash);
+        self.by_password_lookup
+            .entry(password_lookup)
+            .or_default()
+            .push(pos);
+        let user_name_lookup = lookup_of(&user.user_name_hash);
+        self.by_user_name_lookup
+            .entry(user_name_lookup)
+            .or_default()
+            .push(pos);
+    }
+
+    fn retain<F: FnMut(&User) -> bool>(&mut self, f: F) {
+        let before = self.list.len();
+        self.list.retain(f);
+        if self.list.len() != before {
+            self.reindex();
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.by_password_lookup.clear();
+        self.by_user_name_lookup.clear();
+        let list = std::mem::take(&mut self.list);
+        for (pos, user) in list.iter().enumerate() {
+            self.index(pos, user);
+        }
+        self.list = list;
+    }
+
+    /// The users matching `auth`, in the order they were added.
+    ///
+    /// The lookup bytes of the challenge pick the candidates, there is more than one only
+    /// when their lookups collide (or for users sharing a login). Each candidate is then
+    /// confirmed against all the key bytes of the challenge, in constant time.
+    /// The indexes are hashed with a random key, so a lookup tells nothing about how much of
+    /// a challenge matches a user.
+    fn find<'a>(&'a self, auth: &'a Challenge) -> impl Iterator<Item = &'a User> {
+        let challenge = auth.as_key_bytes();
+        let positions = match auth.auth_type {
+            AuthType::One => self.by_password_lookup.get(&auth.lookup),
+            AuthType::Two => self.by_user_name_lookup.get(&auth.lookup),
+            // Not a password based auth type, nothing can match it.
+            _ => None,
+        };
+        positions
+            .into_iter()
+            .flatten()
+            .map(move |&pos| &self.list[pos])
+            .filter(move |u| match auth.auth_type {
+                AuthType::One => ct_eq(&u.password_hash, challenge),
+                _ => ct_eq(&u.user_name_hash, challenge),
+            })
+    }
+}
+
+impl std::ops::Deref for Users {
+    type Target = [User];
+
+    fn deref(&self) -> &[User] {
+        &self.list
+    }
+}
+
+/// The parts of a matched `User` which are needed to accept a handshake.
+/// The secret is wiped when this is dropped.
+struct AuthMatch {
+    secret: SecretBuf32,
+    restricted_to_ip6: Option<Ip6Restriction>,
+    counters: Arc<UserCounters>,
+}
+
+impl From<&User> for AuthMatch {
+    fn from(user: &User) -> Self {
+        AuthMatch {
+            secret: user.secret.clone_secret(),
+            restricted_to_ip6: user.restricted_to_ip6.clone(),
+            counters: Arc::clone(&user.counters),
+        }
+    }
+}
+
+/// Non-secret details of a user, as reported by `CryptoAuth::get_user_info()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub login: ByteString,
+    pub restricted_to_ip6: Option<Ip6Restriction>,
+    /// Position of the user in the order the current users were added.
+    pub index: usize,
+}
+
+impl UserInfo {
+    fn new(index: usize, user: &User) -> Self {
+        UserInfo {
+            login: user.login.clone(),
+            restricted_to_ip6: user.restricted_to_ip6.clone(),
+            index,
+        }
+    }
+}
+
+/// Registry of the live sessions of a CryptoAuth, by peer ip6.
+///
+/// Sessions are held weakly, dropped sessions are pruned when new ones are registered.
+#[derive(Default)]
+pub struct SessionManager {
+    sessions: RwLock<HashMap<[u8; 16], Vec<Weak<SessionInner>>>>,
+
+    /// Number of live sessions whose last reported state is a handshake state.
+    handshakes: AtomicUsize,
+
+    /// Number of peers at which `register()` next prunes the dropped sessions of every peer.
+    prune_at: AtomicUsize,
+}
+
+/// State of a single session, as reported by `SessionManager::snapshot()`.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+    pub her_ip6: Ipv6Addr,
+    pub state: State,
+    pub display_name: Option<String>,
+    pub stats: CryptoStats,
+    /// Number of times the clock was seen going backwards by this session.
+    pub clock_regressions: u32,
+    /// Plaintext bytes encrypted by this session, see `Session::byte_stats()`.
+    pub bytes_encrypted: u64,
+    /// Plaintext bytes decrypted by this session.
+    pub bytes_decrypted: u64,
+}
+
+/// Everything about a session an admin tool may show, see `Session::dump()`.
+///
+/// With the `serde` feature this can be serialized, the state and the error as their names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SessionDump {
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_state"))]
+    pub state: State,
+    pub her_ip6: String,
+    /// Her permanent public key in the base32 ".k" form, `None` until it is known.
+    pub her_public_key: Option<String>,
+    pub display_name: Option<String>,
+    pub is_initiator: bool,
+    pub next_nonce: u32,
+    /// Seconds since a packet was last received, or since the session was created or reset.
+    pub idle_seconds: u32,
+    /// Plaintext bytes encrypted since the session was created, see `Session::byte_stats()`.
+    pub bytes_encrypted: u64,
+    /// Plaintext bytes decrypted since the session was created.
+    pub bytes_decrypted: u64,
+    pub received_packets: u64,
+    pub duplicate_packets: u64,
+    pub lost_packets: u64,
+    pub received_unexpected: u64,
+    /// True if the four replay counters above are those of an earlier dump,
+    /// because the replay protector was in use.
+    pub stale_stats: bool,
+    /// Why the last dropped packet was dropped, `None` if no packet was.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_decrypt_err"))]
+    pub last_error: Option<DecryptErr>,
+}
+
+#[cfg(feature = "serde")]
+fn serialize_state<S: serde::Serializer>(state: &State, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_str(&format_args!("{:?}", state))
+}
+
+#[cfg(feature = "serde")]
+fn serialize_decrypt_err<S>(err: &Option<DecryptErr>, s: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match err {
+        Some(err) => s.serialize_some(err.as_str()),
+        None => s.serialize_none(),
+    }
+}
+
+/// The replay protector counters as of the last `Session::dump()` which could lock it.
+#[derive(Default)]
+struct StatsCache {
+    received_packets: AtomicU64,
+    duplicate_packets: AtomicU64,
+    lost_packets: AtomicU64,
+    received_unexpected: AtomicU64,
+}
+
+impl SessionManager {
+    /// Below this many peers, `register()` doesn't bother pruning the other peers.
+    const MIN_PRUNE_AT: usize = 64;
+
+    fn register(
+        &self,
+        session: &Arc<SessionInner>,
+        max_per_ip6: usize,
+    ) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.write();
+        // Other peers are only pruned once the map has doubled since the last time,
+        // so registering a session doesn't walk every session of every peer.
+        if sessions.len() >= self.prune_at.load(Ordering::Relaxed) {
+            sessions.retain(|_, list| {
+                list.retain(|s| s.strong_count() > 0);
+                !list.is_empty()
+            });
+            let prune_at = (sessions.len() * 2).max(Self::MIN_PRUNE_AT);
+            self.prune_at.store(prune_at, Ordering::Relaxed);
+        }
+        let list = sessions.entry(session.her_ip6).or_default();
+        list.retain(|s| s.strong_count() > 0);
+        if list.len() >= max_per_ip6 {
+            return Err(SessionError::TooManySessions { max: max_per_ip6 });
+        }
+        list.push(Arc::downgrade(session));
+        Ok(())
+    }
+
+    /// The live sessions, all of them or only those with the peer `her_ip6`.
+    fn live_sessions(&self, her_ip6: Option<&[u8; 16]>) -> Vec<Arc<SessionInner>> {
+        let sessions = self.sessions.read();
+        match her_ip6 {
+            Some(her_ip6) => sessions
+                .get(her_ip6)
+                .into_iter()
+                .flatten()
+                .filter_map(Weak::upgrade)
+                .collect(),
+            None => sessions
+                .values()
+                .flatten()
+                .filter_map(Weak::upgrade)
+                .collect(),
+        }
+    }
+
+    /// Number of live sessions in the middle of a handshake,
+    /// that is neither in `State::Init` nor `State::Established`.
+    ///
+    /// This is the state each session last reported, see `Session::on_state_change()`.
+    pub fn handshakes_in_progress(&self) -> usize {
+        self.handshakes.load(Ordering::Relaxed)
+    }
+
+    /// Keep `handshakes_in_progress()` up to date as a session moves from `prev` to `state`.
+    fn state_changed(&self, prev: State, state: State) {
+        let is_handshake = |s: State| s != State::Init && s != State::Established;
+        match (is_handshake(prev), is_handshake(state)) {
+            (false, true) => {
+                self.handshakes.fetch_add(1, Ordering::Relaxed);
+            }
+            (true, false) => {
+                self.handshakes.fetch_sub(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Summarize every live session which is not in `State::Init`,
+    /// ordered by peer ip6 and then by creation time.
+    pub fn snapshot(&self) -> Vec<SessionSummary> {
+        let sessions = self.sessions.read();
+        let mut res = Vec::with_capacity(sessions.len());
+        for list in sessions.values() {
+            for session in list.iter().filter_map(Weak::upgrade) {
+                let summary = session.summary(session.stats());
+                if summary.state != State::Init {
+                    res.push(summary);
+                }
+            }
+        }
+        res.sort_by_key(|summary| summary.her_ip6);
+        res
+    }
+
+    /// Summarize the `max` live sessions not in `State::Init` with the most traffic, the
+    /// busiest first and then by peer ip6 and creation time, with the number of the others.
+    ///
+    /// Unlike `snapshot()` this never waits for the replay protector of a session, whose
+    /// stats are then those it had the last time they could be read, see `Session::dump()`.
+    pub fn busiest(&self, max: usize) -> (Vec<SessionSummary>, usize) {
+        let sessions = self.sessions.read();
+        // The greatest is the least busy, so it is the one dropped when there are too many
+        let mut busiest = BinaryHeap::new();
+        let mut count = 0;
+        for (her_ip6, list) in sessions.iter() {
+            for (i, session) in list.iter().filter_map(Weak::upgrade).enumerate() {
+                if session.session_mut.read().get_state() == State::Init {
+                    continue;
+                }
+                count += 1;
+                let traffic = session.bytes_encrypted.load(Ordering::Relaxed)
+                    + session.bytes_decrypted.load(Ordering::Relaxed);
+                busiest.push(Ranked {
+                    key: (Reverse(traffic), *her_ip6, i),
+                    session,
+                });
+                if busiest.len() > max {
+                    busiest.pop();
+                }
+            }
+        }
+        let res: Vec<_> = busiest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| {
+                let (stats, _) = ranked.session.cached_stats();
+                ranked.session.summary(stats)
+            })
+            .collect();
+        let omitted = count - res.len();
+        (res, omitted)
+    }
+}
+
+/// A session ordered by `key` alone, see `SessionManager::busiest()`.
+struct Ranked {
+    key: (Reverse<u64>, [u8; 16], usize),
+    session: Arc<SessionInner>,
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// How this node resolved two hello packets crossing on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloCollision {
+    /// The peer has the lower key, this node reset its session and became the responder.
+    Yielded,
+    /// This node has the lower key and remains the initiator.
+    StoodFirm,
+}
+
+/// Called with the resolution of a hello collision and the peer's ip6.
+pub type HelloCollisionHandler = dyn Fn(HelloCollision, [u8; 16]) + Send + Sync;
+
+/// Why the replay protector of a session was reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayReset {
+    /// A hello with a new temp key restarted the handshake.
+    NewHello,
+    /// This node yielded in a hello collision and restarted the handshake as the responder.
+    HelloCollision,
+    /// A handshake packet was accepted and advanced the session.
+    HandshakeAdvanced,
+    /// The session was reset explicitly.
+    SessionReset,
+    /// This node started a new handshake with `Session::rekey()`.
+    Rekey,
+}
+
+/// Called with the reason and the peer's ip6 whenever a session's replay protector is reset.
+pub type ReplayResetHandler = dyn Fn(ReplayReset, [u8; 16]) + Send + Sync;
+
+/// Why a handshake authenticated with a login and password (AuthType 2) was rejected.
+///
+/// The peer only ever gets the usual `DecryptErr`, which doesn't tell these apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// No user has this login.
+    UnknownLogin,
+    /// The login is known but the handshake didn't decrypt with its password.
+    WrongPassword,
+}
+
+/// Called with the reason and the peer's ip6 when a login and password handshake is rejected.
+pub type AuthFailureHandler = dyn Fn(AuthFailure, [u8; 16]) + Send + Sync;
+
+/// Called with the reason and the peer's ip6 whenever an incoming packet is dropped.
+pub type DecryptErrorHandler = dyn Fn(DecryptErr, [u8; 16]) + Send + Sync;
+
+/// Called with the peer's ip6 and the session state when a handshake stalls.
+pub type HandshakeTimeoutHandler = dyn Fn([u8; 16], State) + Send + Sync;
+
+/// Called with the new state of a session after it moved to another state.
+pub type StateChangeHandler = dyn Fn(State) + Send + Sync;
+
+/// Generator of logins for users added without one.
+struct AnonLogin {
+    /// Number passed to the generator next, never reused.
+    next: u64,
+    generator: Box<dyn Fn(u64) -> ByteString + Send + Sync>,
+}
+
+impl AnonLogin {
+    fn default_generator(n: u64) -> ByteString {
+        ByteString::from(format!("Anon #{}", n))
+    }
+}
+
+/// The key of a session which was replaced by a rekey or a new hello from the peer.
+struct PrevSessionKey {
+    secret: SecretBuf32,
+    is_initiator: bool,
+    /// Nonces received with this key, apart from those of the new key which start over.
+    replay_protector: Mutex<ReplayProtector>,
+    /// When this key stops being accepted, `None` until the new handshake completes.
+    expires_at: Option<u32>,
+}
+
+/// Limits the DROP lines logged for a session, so a peer sending garbage can't flood the log.
+/// Only the lines are limited, the drops are still counted by `CryptoAuth::on_decrypt_error()`.
+///
+/// Up to `BURST` drops with the same reason are logged per second. The rest are suppressed
+/// and summed up in one line when a drop in a later second or with another reason comes.
+#[derive(Default)]
+struct DropLog {
+    /// The reason and the second of the current window, as `code << 32 | seconds`.
+    window: AtomicU64,
+    /// Drops in the current window.
+    count: AtomicU64,
+    /// Set while the current window is past `BURST`, to also silence the detailed lines
+    /// logged while the packet is decrypted.
+    quiet: AtomicBool,
+}
+
+impl DropLog {
+    const BURST: u64 = 10;
+
+    /// Called before decrypting a packet, ends a quiet window once its second is over.
+    fn update(&self, clock: &dyn Clock) {
+        if self.is_quiet() && self.window.load(Ordering::Relaxed) as u32 != clock.now_seconds() {
+            self.quiet.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn is_quiet(&self) -> bool {
+        self.quiet.load(Ordering::Relaxed)
+    }
+
+    /// Log the drop of a packet from `her_ip6` because of `code` at time `now`,
+    /// or count it as suppressed.
+    fn log(&self, code: DecryptErr, now: u32, her_ip6: &IpV6, line: &dyn fmt::Display) {
+        let window = u64::from(u32::from(code)) << 32 | u64::from(now);
+        let prev = self.window.swap(window, Ordering::Relaxed);
+        let count = if prev == window {
+            self.count.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            let suppressed = self
+                .count
+                .swap(1, Ordering::Relaxed)
+                .saturating_sub(Self::BURST);
+            if suppressed > 0 {
+                let prev_code = DecryptErr::try_from((prev >> 32) as u32)
+                    .map_or("UNKNOWN", |prev_code| prev_code.as_str());
+                log::debug!(
+                    "DROP suppressed {} further {} drops from [{}] in the last {}s",
+                    suppressed,
+                    prev_code,
+                    her_ip6,
+                    now.wrapping_sub(prev as u32).max(1),
+                );
+            }
+            1
+        };
+        self.quiet.store(count >= Self::BURST, Ordering::Relaxed);
+        if count <= Self::BURST {
+            log::debug!("DROP {}", line);
+        }
+    }
 }
 
 pub struct SessionMut {
@@ -66,15 +655,51 @@ pub struct SessionMut {
     /// If a session is not completely setup, reset it after this many seconds of inactivity.
     pub setup_reset_after_inactivity_seconds: u32,
 
+    /// Once the nonce reaches this value, a rekey is recommended.
+    pub rekey_threshold: u32,
+
+    /// True if the handshake timeout handler was called since the last packet or reset.
+    handshake_timeout_reported: bool,
+
     /// The shared secret.
-    shared_secret: [u8; 32],
+    shared_secret: SecretBuf32,
+
+    /// The key of the session which was established before a rekey. Traffic encrypted with it
+    /// is accepted until the new handshake completes, and for `rekey_overlap_seconds` after.
+    prev_session_key: Option<PrevSessionKey>,
+
+    /// Her temp keys which her rekeys replaced since our temp key was made, see `accept_rekey()`.
+    retired_her_temp_keys: Vec<[u8; 32]>,
+
+    /// How long the key from before a rekey is still accepted once the new one is established.
+    pub rekey_overlap_seconds: u32,
+
+    /// The responder may rekey, see `Session::set_responder_rekey()`.
+    pub responder_rekey: bool,
+
+    /// Grow the padding of messages to encrypt instead of failing, see `Session::set_auto_grow()`.
+    pub auto_grow: bool,
+
+    /// Realign misaligned messages instead of failing, see `Session::set_auto_realign()`.
+    pub auto_realign: bool,
+
+    /// Hide the permanent keys in handshake packets, see `Session::set_key_blinding()`.
+    pub blind_keys: bool,
+
+    /// Rate limit for the DROP lines of this session.
+    drop_log: DropLog,
 
     her_temp_pub_key: [u8; 32],
 
-    our_temp_priv_key: [u8; 32],
+    our_temp_priv_key: SecretBuf32,
 
     our_temp_pub_key: [u8; 32],
 
+    /// Used instead of a random temp private key, so tests and fuzzers can reproduce
+    /// a session exactly.
+    #[cfg(any(test, feature = "fuzz"))]
+    fixed_temp_priv_key: Option<[u8; 32]>,
+
     /// A password to use for authing with the other party.
     password: Option<ByteString>,
 
@@ -87,9 +712,18 @@ pub struct SessionMut {
     /// Used to reset the connection if it's in a bad state (no traffic coming in).
     time_of_last_packet: u32,
 
+    /// When the session was created, used to age out sessions which never got set up.
+    created_at: u32,
+
+    /// Number of times the clock was seen going backwards, see `check_clock()`.
+    clock_regressions: u32,
+
     /// The method to use for trying to auth with the server.
     auth_type: AuthType,
 
+    /// The method the other party used in the last accepted handshake packet.
+    peer_auth_type: Option<AuthType>,
+
     /// True if this node began the conversation.
     is_initiator: bool,
 
@@ -104,6 +738,8 @@ pub struct SessionInner {
 
     // This has to be briefly locked every packet, it should not contaminate the write lock
     // of the SessionMut so that multiple threads can decrypt at the same time...
+    //
+    // Lock order: when both are needed, `session_mut` is locked first.
     replay_protector: Mutex<ReplayProtector>,
 
     /// A pointer back to the main CryptoAuth context.
@@ -115,6 +751,130 @@ pub struct SessionInner {
 
     plain_pvt: IfacePvt,
     cipher_pvt: IfacePvt,
+
+    state_change_handler: RwLock<Option<Box<StateChangeHandler>>>,
+
+    /// The state last reported to the state change handler.
+    reported_state: Mutex<State>,
+
+    /// Parent of the spans of the operations of this session, with the `tracing` feature.
+    span: trace::SessionSpan,
+
+    /// Plaintext bytes successfully encrypted and decrypted since the session was created,
+    /// not cleared by resets.
+    bytes_encrypted: AtomicU64,
+    bytes_decrypted: AtomicU64,
+
+    /// Messages which had to be realigned, see `Session::set_auto_realign()`.
+    realigned_messages: AtomicU64,
+
+    /// The `DecryptErr` of the last dropped packet, `DecryptErr::None` before any.
+    last_error: AtomicU32,
+
+    /// Used by `Session::dump()` when the replay protector is locked.
+    stats_cache: StatsCache,
+}
+
+impl Drop for SessionInner {
+    fn drop(&mut self) {
+        let state = *self.reported_state.get_mut();
+        self.context.sessions.state_changed(state, State::Init);
+    }
+}
+
+impl SessionInner {
+    /// Reset the session back to `State::Init`, including the replay protector.
+    fn reset(&self) {
+        {
+            // Make sure we're write() session_mut when we do the replay because
+            // decrypt threads will read() session_mut
+            let mut session_mut = self.session_mut.write();
+            self.reset_replay_protector(&mut session_mut, ReplayReset::SessionReset);
+            session_mut.reset();
+        }
+        self.notify_state_change();
+    }
+
+    /// Reset the replay protector and report it to the replay reset handler.
+    ///
+    /// Takes the write-locked `session_mut` so the lock order can't be violated: decrypting
+    /// threads only hold `session_mut` for reading while they check the replay protector.
+    fn reset_replay_protector(&self, _session: &mut SessionMut, reason: ReplayReset) {
+        self.replay_protector.lock().reset();
+        self.context.on_replay_reset(reason, self.her_ip6);
+    }
+
+    /// Call the state change handler if the state differs from the one last reported.
+    /// Must be called without holding the `session_mut` lock.
+    fn notify_state_change(&self) {
+        let state = self.session_mut.read().get_state();
+        let prev_state = {
+            let mut reported = self.reported_state.lock();
+            if *reported == state {
+                return;
+            }
+            let prev_state = std::mem::replace(&mut *reported, state);
+            self.context.sessions.state_changed(prev_state, state);
+            prev_state
+        };
+        self.span.state_change(prev_state, state);
+        if let Some(handler) = &*self.state_change_handler.read() {
+            handler(state);
+        }
+    }
+
+    /// The replay protector stats if it can be locked at once, otherwise those of the last
+    /// time it could be, with `true` for stale.
+    fn cached_stats(&self) -> (CryptoStats, bool) {
+        let cache = &self.stats_cache;
+        let stale = match self.replay_protector.try_lock() {
+            Some(replay_protector) => {
+                let stats = replay_protector.stats();
+                drop(replay_protector);
+                let store = |counter: &AtomicU64, value| counter.store(value, Ordering::Relaxed);
+                store(&cache.received_packets, stats.received_packets as u64);
+                store(&cache.duplicate_packets, stats.duplicate_packets as u64);
+                store(&cache.lost_packets, stats.lost_packets as u64);
+                store(&cache.received_unexpected, stats.received_unexpected as u64);
+                false
+            }
+            None => true,
+        };
+        let stats = CryptoStats {
+            lost_packets: cache.lost_packets.load(Ordering::Relaxed),
+            received_unexpected: cache.received_unexpected.load(Ordering::Relaxed),
+            received_packets: cache.received_packets.load(Ordering::Relaxed),
+            duplicate_packets: cache.duplicate_packets.load(Ordering::Relaxed),
+            noise_proto: false,
+        };
+        (stats, stale)
+    }
+
+    fn summary(&self, stats: CryptoStats) -> SessionSummary {
+        let session_mut = self.session_mut.read();
+        SessionSummary {
+            her_ip6: Ipv6Addr::from(self.her_ip6),
+            state: session_mut.get_state(),
+            display_name: session_mut.display_name.clone(),
+            stats,
+            clock_regressions: session_mut.clock_regressions,
+            bytes_encrypted: self.bytes_encrypted.load(Ordering::Relaxed),
+            bytes_decrypted: self.bytes_decrypted.load(Ordering::Relaxed),
+        }
+    }
+
+    fn stats(&self) -> CryptoStats {
+        // Stats come from the replay protector
+        let rp = self.replay_protector.lock();
+        let stats = rp.stats();
+        CryptoStats {
+            lost_packets: stats.lost_packets as u64,
+            received_unexpected: stats.received_unexpected as u64,
+            received_packets: stats.received_packets as u64,
+            duplicate_packets: stats.duplicate_packets as u64,
+            noise_proto: false,
+        }
+    }
 }
 
 enum Nonce {
@@ -129,6 +889,21 @@ enum Nonce {
 pub enum AddUserError {
     #[error("Duplicate user '{login:?}'")]
     Duplicate { login: ByteString },
+
+    #[error("Empty password")]
+    EmptyPassword,
+
+    #[error("Password shorter than {min_length} bytes")]
+    WeakPassword { min_length: usize },
+
+    #[error("Too many users, at most {max} are allowed")]
+    TooManyUsers { max: usize },
+
+    #[error("Bad IPv6 address: {0}")]
+    BadIp6(KeyParseError),
+
+    #[error("Prefix of {0} bits is longer than an IPv6 address")]
+    BadPrefixLength(u8),
 }
 
 /// Keep these numbers same as `cffi::CryptoAuth_DecryptErr`
@@ -147,7 +922,7 @@ pub enum DecryptErr {
     #[error("NO_SESSION")]
     NoSession = 2,
 
-    #[error("FINAL_HANDSHAKE_FAIL")]
+    #[error("FINAL_SHAKE_FAIL")]
     FinalShakeFail = 3,
 
     #[error("FAILED_DECRYPT_RUN_MSG")]
@@ -199,6 +974,92 @@ pub enum DecryptErr {
 
     #[error("INTERNAL")]
     Internal = 16,
+
+    /// Too many sessions are in the middle of a handshake to start another one.
+    #[error("TOO_MANY_HANDSHAKES")]
+    TooManyHandshakes = 17,
+
+    /// The session expects blinded hellos and this one could not be unblinded,
+    /// e.g. it is from a node which doesn't blind its key, see `Session::set_key_blinding()`.
+    #[error("BLINDING")]
+    Blinding = 18,
+}
+
+impl DecryptErr {
+    /// The number of variants.
+    pub const COUNT: usize = 19;
+
+    /// Every variant, in numeric order: the value of each is its index.
+    pub const ALL: [DecryptErr; Self::COUNT] = [
+        DecryptErr::None,
+        DecryptErr::Runt,
+        DecryptErr::NoSession,
+        DecryptErr::FinalShakeFail,
+        DecryptErr::FailedDecryptionRunMsg,
+        DecryptErr::KeyPktEstablishedSession,
+        DecryptErr::WrongPermPubkey,
+        DecryptErr::IpRestricted,
+        DecryptErr::AuthRequired,
+        DecryptErr::UnrecognizedAuth,
+        DecryptErr::StrayKey,
+        DecryptErr::HandshakeDecryptFailed,
+        DecryptErr::Wiseguy,
+        DecryptErr::InvalidPacket,
+        DecryptErr::Replay,
+        DecryptErr::Decrypt,
+        DecryptErr::Internal,
+        DecryptErr::TooManyHandshakes,
+        DecryptErr::Blinding,
+    ];
+
+    /// The name of the error in `CryptoAuth_DecryptErr` without the prefix, e.g. `RUNT`,
+    /// so logs read the same as the C ones.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DecryptErr::None => "NONE",
+            DecryptErr::Runt => "RUNT",
+            DecryptErr::NoSession => "NO_SESSION",
+            DecryptErr::FinalShakeFail => "FINAL_SHAKE_FAIL",
+            DecryptErr::FailedDecryptionRunMsg => "FAILED_DECRYPT_RUN_MSG",
+            DecryptErr::KeyPktEstablishedSession => "KEY_PKT_ESTABLISHED_SESSION",
+            DecryptErr::WrongPermPubkey => "WRONG_PERM_PUBKEY",
+            DecryptErr::IpRestricted => "IP_RESTRICTED",
+            DecryptErr::AuthRequired => "AUTH_REQUIRED",
+            DecryptErr::UnrecognizedAuth => "UNRECOGNIZED_AUTH",
+            DecryptErr::StrayKey => "STRAY_KEY",
+            DecryptErr::HandshakeDecryptFailed => "HANDSHAKE_DECRYPT_FAILED",
+            DecryptErr::Wiseguy => "WISEGUY",
+            DecryptErr::InvalidPacket => "INVALID_PACKET",
+            DecryptErr::Replay => "REPLAY",
+            DecryptErr::Decrypt => "DECRYPT",
+            DecryptErr::Internal => "INTERNAL",
+            DecryptErr::TooManyHandshakes => "TOO_MANY_HANDSHAKES",
+            DecryptErr::Blinding => "BLINDING",
+        }
+    }
+}
+
+/// A number which is not the value of any `DecryptErr`.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Unknown DecryptErr value {0}")]
+pub struct UnknownDecryptErr(pub u32);
+
+impl TryFrom<u32> for DecryptErr {
+    type Error = UnknownDecryptErr;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        let index = usize::try_from(value).map_err(|_| UnknownDecryptErr(value))?;
+        DecryptErr::ALL
+            .get(index)
+            .cloned()
+            .ok_or(UnknownDecryptErr(value))
+    }
+}
+
+impl From<DecryptErr> for u32 {
+    fn from(err: DecryptErr) -> Self {
+        err as u32
+    }
 }
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
@@ -206,76 +1067,299 @@ pub enum DecryptError {
     #[error("DecryptErr: {0}")]
     DecryptErr(DecryptErr),
 
+    /// The message buffer does not fit the requirements of `Session::decrypt_msg()`.
+    #[error("Bad message buffer: {0}")]
+    BadBuffer(BufferProblem),
+
     #[error("Internal error: {0}")]
     Internal(&'static str),
 }
 
+/// What is wrong with a message buffer handed to `Session::decrypt_msg()`,
+/// or with the output buffer handed to `Session::decrypt_into()`.
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
-pub enum EncryptError {
-    #[error("Internal error: {0}")]
-    Internal(&'static str),
+pub enum BufferProblem {
+    #[error("data at {addr:#x} is not aligned to {align} bytes")]
+    Alignment { addr: usize, align: usize },
+
+    #[error("capacity {cap} is not a multiple of {multiple}")]
+    Capacity { cap: usize, multiple: usize },
+
+    #[error("output buffer of {len} bytes, the plaintext may need {required}")]
+    OutputTooSmall { len: usize, required: usize },
 }
 
-#[derive(Error, Debug, Clone, PartialEq, Eq)]
-pub enum KeyError {
-    #[error("PublicKey is all zeroes")]
-    ZeroPublicKey,
+/// Which step of the protocol a dropped packet failed in, see `DecryptFailure::phase()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecryptPhase {
+    /// Before the packet could be handled: it is malformed or its buffer is unusable.
+    Parse,
+    /// A hello or key packet, or the first traffic packet, which completes the handshake.
+    Handshake,
+    /// A traffic packet for an established session or with the key from before a rekey.
+    Traffic,
+}
 
-    #[error("Either PublicKey or PrivateKey cannot be used by WireGuard: {0}")]
-    BadWireGuardKey(&'static str),
+/// What is known about a packet a session dropped, captured when it was dropped.
+///
+/// Errors from decrypting with a `Session` carry this as context: `downcast_ref()` gives
+/// the `DecryptFailure` as well as the `DecryptError` itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecryptFailure {
+    code: DecryptErr,
+    nonce: Option<u32>,
+    kind: Option<PacketKind>,
+    phase: DecryptPhase,
+    replay: Option<ReplayKind>,
+    state: State,
+    her_ip6: IpV6,
 }
 
-/// Works like `assert!()` but returns Internal error instead of panicking.
-macro_rules! ensure {
-    ($cond:expr, $err_type:tt $(,)?) => {
-        if !$cond {
-            return Err($err_type::Internal(concat!(
-                "Condition failed: `",
-                stringify!($cond),
-                "`"
-            ))
-            .into());
-        }
-    };
+impl DecryptFailure {
+    /// The error as reported to C, `DecryptErr::Internal` for errors which aren't a `DecryptErr`.
+    pub fn code(&self) -> DecryptErr {
+        self.code.clone()
+    }
+
+    /// The nonce at the beginning of the packet, `None` if the packet is too short to have one.
+    pub fn nonce(&self) -> Option<u32> {
+        self.nonce
+    }
+
+    /// What `classify_packet()` took the packet for, `None` if it is too short for its kind.
+    pub fn packet_kind(&self) -> Option<PacketKind> {
+        self.kind
+    }
+
+    /// Whether the packet failed in the handshake or as traffic.
+    ///
+    /// The same error may come from either: a failed final handshake step may be fixed
+    /// by sending the key packet again, a failed traffic packet never is.
+    pub fn phase(&self) -> DecryptPhase {
+        self.phase
+    }
+
+    /// For `DecryptErr::Replay`, whether the nonce was seen before or is too old to tell.
+    pub fn replay_kind(&self) -> Option<ReplayKind> {
+        self.replay
+    }
+
+    /// The state of the session once the packet was dropped.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The address the session is bound to.
+    pub fn her_ip6(&self) -> &IpV6 {
+        &self.her_ip6
+    }
+}
+
+impl fmt::Display for DecryptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.kind, self.nonce) {
+            (Some(kind), Some(nonce)) => write!(f, "{:?} packet with nonce [{}]", kind, nonce)?,
+            (None, Some(nonce)) => write!(f, "runt packet with nonce [{}]", nonce)?,
+            (_, None) => write!(f, "packet without a nonce")?,
+        }
+        write!(f, " from [{}] in state [{:?}]", self.her_ip6, self.state)?;
+        write!(f, " during {:?}: {}", self.phase, self.code)?;
+        if let Some(kind) = self.replay {
+            write!(f, " ({})", kind)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `DecryptErr` a decryption error is reported as, `Internal` unless it is a `DecryptErr`.
+pub(crate) fn decrypt_err_of(err: &anyhow::Error) -> DecryptErr {
+    if let Some(failure) = err.downcast_ref::<DecryptFailure>() {
+        return failure.code();
+    }
+    match err.downcast_ref::<DecryptError>() {
+        Some(DecryptError::DecryptErr(err)) => err.clone(),
+        _ => DecryptErr::Internal,
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum EncryptError {
+    /// Her permanent public key is not known yet, the packet can be sent once it is set.
+    #[error("Her public key is not known")]
+    HerKeyUnknown,
+
+    /// The message has less padding than `Session::required_send_padding()`.
+    #[error("Not enough padding: {required} bytes required, {available} available")]
+    InsufficientPadding { required: usize, available: usize },
+
+    /// The message is not 4 byte aligned, and could not be realigned if that is enabled.
+    #[error("Message is not 4 byte aligned")]
+    Misaligned,
+
+    /// An empty message once the handshake is done, only handshake packets can be empty.
+    #[error("Empty message after the handshake")]
+    EmptyHandshakePayload,
+
+    /// The nonces of the key are used up. The session resets before this can happen,
+    /// so it is a bug if it is seen.
+    #[error("Nonce exhausted")]
+    NonceExhausted,
+
+    #[error("Internal error: {0}")]
+    Internal(&'static str),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    #[error("PublicKey is all zeroes")]
+    ZeroPublicKey,
+
+    #[error("IPv6 address is all zeroes")]
+    ZeroIp6,
+
+    #[error("PublicKey does not hash to the session's IPv6 address")]
+    Ip6Mismatch,
+
+    #[error("A different PublicKey is already set")]
+    KeyAlreadySet,
+
+    #[error("PublicKey does not map to a valid cjdns IPv6 address")]
+    BadPublicKey,
+
+    #[error("Either PublicKey or PrivateKey cannot be used by WireGuard: {0}")]
+    BadWireGuardKey(&'static str),
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    #[error("Too many sessions with the same IPv6 address (max {max})")]
+    TooManySessions { max: usize },
+
+    #[error("Malformed public key string")]
+    BadKeyString,
+
+    #[error("Bad public key: {0}")]
+    Key(KeyError),
+}
+
+/// Works like `assert!()` but returns Internal error instead of panicking.
+///
+/// Given an error value instead of a type, e.g. `ensure!(cond, EncryptError::Misaligned)`,
+/// that error is returned.
+macro_rules! ensure {
+    ($cond:expr, $err_type:tt $(,)?) => {
+        if !$cond {
+            return Err($err_type::Internal(concat!(
+                "Condition failed: `",
+                stringify!($cond),
+                "`"
+            ))
+            .into());
+        }
+    };
     ($cond:expr, $err_type:tt, $msg:literal $(,)?) => {
         if !$cond {
             return Err($err_type::Internal($msg).into());
         }
     };
+    ($cond:expr, $err:expr $(,)?) => {
+        if !$cond {
+            return Err($err.into());
+        }
+    };
 }
 
 impl CryptoAuth {
-    const LOG_KEYS: bool = false;
+    const DEFAULT_MAX_HANDSHAKES: usize = 10_000;
+    const DEFAULT_MAX_SESSIONS_PER_IP6: usize = 256;
+    /// Any non-empty password, as the C CryptoAuth and existing configs expect.
+    const DEFAULT_MIN_PASSWORD_LENGTH: usize = 1;
+    const DEFAULT_MAX_USERS: usize = 4096;
+    const DEFAULT_METRICS_MAX_SESSIONS: usize = 100;
 
     /// Create a new crypto authenticator.
     ///
     /// If `private_key` is `None` one should be randomly generated.
-    pub fn new(private_key: Option<PrivateKey>, event_base: EventBase, rand: Random) -> Self {
+    ///
+    /// Time is read from `clock`, which is normally the `EventBase`.
+    pub fn new<C>(private_key: Option<PrivateKey>, clock: C, rand: Random) -> Self
+    where
+        C: Clock + Send + Sync + 'static,
+    {
+        Self::new_with_shared_users(private_key, clock, rand, Arc::default())
+    }
+
+    /// Create a new crypto authenticator from a hex private key, e.g. the `privateKey`
+    /// of `cjdroute.conf`, see `PrivateKeyExt::from_hex()`.
+    pub fn new_from_hex_key<C>(
+        private_key: &str,
+        clock: C,
+        rand: Random,
+    ) -> Result<Self, KeyParseError>
+    where
+        C: Clock + Send + Sync + 'static,
+    {
+        let private_key = PrivateKey::from_hex(private_key)?;
+        Ok(Self::new(Some(private_key), clock, rand))
+    }
+
+    /// Create a new crypto authenticator with the keys of a node, e.g. from its
+    /// `cjdroute.conf`, see `NodeIdentity::load()`.
+    pub fn new_from_identity<C>(identity: &NodeIdentity, clock: C, rand: Random) -> Self
+    where
+        C: Clock + Send + Sync + 'static,
+    {
+        Self::new(Some(identity.keypair().private().clone()), clock, rand)
+    }
+
+    /// Create a new crypto authenticator whose users are kept in `users`, which can be
+    /// shared with other instances: users added to or removed from any of them apply to all.
+    ///
+    /// Only the users of the legacy handshake are shared, not those of the Noise protocol.
+    pub fn new_with_shared_users<C>(
+        private_key: Option<PrivateKey>,
+        clock: C,
+        rand: Random,
+        users: Arc<RwLock<Users>>,
+    ) -> Self
+    where
+        C: Clock + Send + Sync + 'static,
+    {
         let private_key = private_key.unwrap_or_else(|| PrivateKey::new_random(&rand));
 
-        let noise = crypto_noise::CryptoNoise::new(&private_key);
+        let clock: Arc<dyn Clock + Send + Sync> = Arc::new(clock);
 
-        let public_key = crypto_scalarmult_curve25519_base(&private_key);
+        let noise = crypto_noise::CryptoNoise::new(&private_key, Arc::clone(&clock));
 
-        if Self::LOG_KEYS {
-            log::debug!(
-                concat!(
-                    "Initialized CryptoAuth:\n",
-                    "    myPrivateKey={}\n",
-                    "     myPublicKey={}\n",
-                ),
-                debug::hex_key(private_key.raw()),
-                debug::hex_key(public_key.raw()),
-            );
-        }
+        let keypair = Keypair::from_private(private_key);
 
-        let users = RwLock::new(vec![]);
+        let anon_login = Mutex::new(AnonLogin {
+            next: 0,
+            generator: Box::new(AnonLogin::default_generator),
+        });
 
         CryptoAuth {
-            public_key,
-            private_key,
+            public_key: keypair.public().clone(),
+            sessions: SessionManager::default(),
+            keypair,
             users,
-            event_base,
+            anon_login,
+            hello_collision_handler: RwLock::new(None),
+            handshake_timeout_handler: RwLock::new(None),
+            replay_reset_handler: RwLock::new(None),
+            decrypt_error_handler: RwLock::new(None),
+            auth_failure_handler: RwLock::new(None),
+            max_handshakes: AtomicUsize::new(Self::DEFAULT_MAX_HANDSHAKES),
+            max_sessions_per_ip6: AtomicUsize::new(Self::DEFAULT_MAX_SESSIONS_PER_IP6),
+            min_password_length: AtomicUsize::new(Self::DEFAULT_MIN_PASSWORD_LENGTH),
+            max_users: AtomicUsize::new(Self::DEFAULT_MAX_USERS),
+            shared_logins: AtomicBool::new(false),
+            metrics_max_sessions: AtomicUsize::new(Self::DEFAULT_METRICS_MAX_SESSIONS),
+            unmatched_challenges: AtomicU64::new(0),
+            decrypt_error_counts: Default::default(),
+            replay_counts: Default::default(),
+            log_keys: AtomicBool::new(false),
+            clock,
             rand,
             noise,
         }
@@ -285,19 +1369,60 @@ impl CryptoAuth {
     ///
     /// If `ipv6` is not `None`, only allow connections to this CryptoAuth from
     /// the key which hashes to the given IPv6 address.
+    ///
+    /// Passwords shorter than the minimum password length are rejected, as are new users
+    /// once there are `max_users()` of them.
+    ///
+    /// A login which is already used with another password is rejected as a duplicate,
+    /// unless shared logins are allowed, see `set_shared_logins()`.
+    ///
+    /// If `expires_at` is not `None`, the user is no longer accepted from that time on
+    /// (in seconds, as given by the clock of this CryptoAuth) and is removed later.
     pub fn add_user_ipv6(
         &self,
         password: ByteString,
         login: Option<ByteString>,
         ipv6: Option<[u8; 16]>,
+        expires_at: Option<u64>,
+    ) -> Result<(), AddUserError> {
+        let restriction = ipv6.map(|ip6| Ip6Restriction::Exact(IpV6::from(ip6)));
+        self.add_user_restricted(password, login, restriction, expires_at)
+    }
+
+    /// Same as `add_user_ipv6()`, but the user may also be restricted to a prefix, e.g. the
+    /// addresses of a family of keys sharing their first 64 bits.
+    ///
+    /// Prefixes longer than 128 bits are rejected.
+    pub fn add_user_restricted(
+        &self,
+        password: ByteString,
+        login: Option<ByteString>,
+        restriction: Option<Ip6Restriction>,
+        expires_at: Option<u64>,
     ) -> Result<(), AddUserError> {
-        self.noise.add_user_ipv6(password.clone(), login.clone(), ipv6);
+        if let Some(Ip6Restriction::Prefix { bits, .. }) = restriction {
+            if bits > 128 {
+                return Err(AddUserError::BadPrefixLength(bits));
+            }
+        }
+        if password.is_empty() {
+            return Err(AddUserError::EmptyPassword);
+        }
+        let min_length = self.min_password_length.load(Ordering::Relaxed);
+        if password.len() < min_length {
+            return Err(AddUserError::WeakPassword { min_length });
+        }
         let mut users = self.users.write();
+        self.purge_expired_users(&mut users);
+        let max = self.max_users();
+        if users.len() >= max {
+            return Err(AddUserError::TooManyUsers { max });
+        }
         let mut user = User::default();
         if let Some(login) = login.clone() {
             user.login = login;
         } else {
-            user.login = ByteString::from(format!("Anon #{}", users.len()));
+            user.login = self.next_anon_login(&users)?;
         }
 
         // Users specified with a login field might want to use authType 1 still.
@@ -309,11 +1434,12 @@ impl CryptoAuth {
         user.secret = secret;
         user.password_hash.copy_from_slice(ac.as_key_bytes());
 
-        for u in &*users {
-            if user.secret == u.secret {
-                // Do nothing
-            } else if let Some(login) = login.as_ref() {
-                if *login == u.login {
+        if !self.shared_logins.load(Ordering::Relaxed) {
+            if let Some(login) = login.as_ref() {
+                let taken = users.iter().any(|u| {
+                    u.login == *login && !ct_eq(u.secret.as_bytes(), user.secret.as_bytes())
+                });
+                if taken {
                     return Err(AddUserError::Duplicate {
                         login: login.clone(),
                     });
@@ -321,13 +1447,267 @@ impl CryptoAuth {
             }
         }
 
-        user.restricted_to_ip6 = ipv6;
+        user.restricted_to_ip6 = restriction.clone();
+        user.expires_at = expires_at;
 
+        let name = user.login.clone();
         users.push(user);
+        // Last, so that a user which is refused is not registered for Noise either
+        self.noise
+            .add_user_ipv6(password, login, name, restriction, expires_at);
 
         Ok(())
     }
 
+    /// Same as `add_user_ipv6()` with the address in text form, e.g. from `cjdroute.conf`.
+    ///
+    /// The address must be a cjdns address, see `IpV6Ext::parse()`.
+    pub fn add_user_ipv6_str(
+        &self,
+        password: ByteString,
+        login: Option<ByteString>,
+        ipv6: Option<&str>,
+        expires_at: Option<u64>,
+    ) -> Result<(), AddUserError> {
+        let ipv6 = match ipv6 {
+            Some(ipv6) => Some(*IpV6::parse(ipv6).map_err(AddUserError::BadIp6)?.raw()),
+            None => None,
+        };
+        self.add_user_ipv6(password, login, ipv6, expires_at)
+    }
+
+    /// Set the function used to generate logins for users added without one.
+    ///
+    /// The function is given a counter which is incremented on every call and never reused.
+    pub fn set_anon_login_generator<F>(&self, generator: F)
+    where
+        F: Fn(u64) -> ByteString + Send + Sync + 'static,
+    {
+        self.anon_login.lock().generator = Box::new(generator);
+    }
+
+    /// Generate a login which is unique among `users`.
+    fn next_anon_login(&self, users: &[User]) -> Result<ByteString, AddUserError> {
+        let mut anon_login = self.anon_login.lock();
+        let mut login = ByteString::empty();
+        // At most `users.len()` generated logins can collide with existing ones.
+        for _ in 0..=users.len() {
+            login = (anon_login.generator)(anon_login.next);
+            anon_login.next += 1;
+            if !users.iter().any(|u| u.login == login) {
+                return Ok(login);
+            }
+        }
+        Err(AddUserError::Duplicate { login })
+    }
+
+    /// Set the function called whenever two hello packets cross on the wire.
+    ///
+    /// The handler is called with the session locked, so it must not call into the session.
+    pub fn set_hello_collision_handler<F>(&self, handler: F)
+    where
+        F: Fn(HelloCollision, [u8; 16]) + Send + Sync + 'static,
+    {
+        *self.hello_collision_handler.write() = Some(Box::new(handler));
+    }
+
+    fn on_hello_collision(&self, collision: HelloCollision, her_ip6: [u8; 16]) {
+        if let Some(handler) = &*self.hello_collision_handler.read() {
+            handler(collision, her_ip6);
+        }
+    }
+
+    /// Set the function called when a session's handshake has not progressed for longer
+    /// than its `setup_reset_after_inactivity_seconds`.
+    ///
+    /// Sessions are checked on every `tick()`, the handler is called once per stalled handshake.
+    pub fn set_handshake_timeout_handler<F>(&self, handler: F)
+    where
+        F: Fn([u8; 16], State) + Send + Sync + 'static,
+    {
+        *self.handshake_timeout_handler.write() = Some(Box::new(handler));
+    }
+
+    /// Set the function called whenever the replay protector of a session is reset,
+    /// with the reason and the peer's ip6.
+    ///
+    /// The handler is called with the session locked, so it must not call into the session.
+    pub fn set_replay_reset_handler<F>(&self, handler: F)
+    where
+        F: Fn(ReplayReset, [u8; 16]) + Send + Sync + 'static,
+    {
+        *self.replay_reset_handler.write() = Some(Box::new(handler));
+    }
+
+    fn on_replay_reset(&self, reason: ReplayReset, her_ip6: [u8; 16]) {
+        if let Some(handler) = &*self.replay_reset_handler.read() {
+            handler(reason, her_ip6);
+        }
+    }
+
+    /// Set the function called with the reason and the peer's ip6 whenever an incoming
+    /// packet is dropped, e.g. to count drops per peer.
+    ///
+    /// The handler is called without holding the session lock, but on the thread which is
+    /// decrypting, so it should be fast and must not block.
+    pub fn set_decrypt_error_handler<F>(&self, handler: F)
+    where
+        F: Fn(DecryptErr, [u8; 16]) + Send + Sync + 'static,
+    {
+        *self.decrypt_error_handler.write() = Some(Box::new(handler));
+    }
+
+    fn on_decrypt_error(&self, err: &anyhow::Error, her_ip6: [u8; 16]) {
+        let code = decrypt_err_of(err);
+        self.decrypt_error_counts[code.clone() as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some(kind) = err.downcast_ref::<ReplayKind>() {
+            self.replay_counts[*kind as usize].fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(handler) = &*self.decrypt_error_handler.read() {
+            handler(code, her_ip6);
+        }
+    }
+
+    /// Get the number of packets dropped by all the sessions of this CryptoAuth
+    /// since it was created or last cleared, indexed by `DecryptErr`.
+    ///
+    /// Each dropped packet is counted once, under the same error passed to the handler
+    /// set with `set_decrypt_error_handler()`.
+    pub fn decrypt_error_counts(&self) -> [u64; DecryptErr::COUNT] {
+        let mut counts = [0; DecryptErr::COUNT];
+        for (count, counter) in counts.iter_mut().zip(self.decrypt_error_counts.iter()) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    /// Get the number of packets counted as `DecryptErr::Replay` by `decrypt_error_counts()`,
+    /// split by `ReplayKind`.
+    ///
+    /// Networks duplicate packets but rarely delay them past the replay window,
+    /// so a growing `ReplayKind::TooOld` count is the more suspicious one.
+    pub fn replay_counts(&self) -> [u64; ReplayKind::COUNT] {
+        let mut counts = [0; ReplayKind::COUNT];
+        for (count, counter) in counts.iter_mut().zip(self.replay_counts.iter()) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    /// Reset all the counters returned by `decrypt_error_counts()` and `replay_counts()`
+    /// to zero.
+    pub fn clear_decrypt_error_counts(&self) {
+        for counter in self
+            .decrypt_error_counts
+            .iter()
+            .chain(self.replay_counts.iter())
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Set the function called when a handshake with a login and password is rejected,
+    /// telling an unknown login from a wrong password.
+    ///
+    /// This is a debugging aid for trusted deployments, the peer can't learn the difference
+    /// either way. The handler is called with the session locked, so it must not call into
+    /// the session.
+    pub fn set_auth_failure_handler<F>(&self, handler: F)
+    where
+        F: Fn(AuthFailure, [u8; 16]) + Send + Sync + 'static,
+    {
+        *self.auth_failure_handler.write() = Some(Box::new(handler));
+    }
+
+    fn on_auth_failure(&self, auth_type: AuthType, failure: AuthFailure, her_ip6: [u8; 16]) {
+        if auth_type != AuthType::Two {
+            return;
+        }
+        if let Some(handler) = &*self.auth_failure_handler.read() {
+            handler(failure, her_ip6);
+        }
+    }
+
+    /// Set the maximum number of sessions which may be in the middle of a handshake
+    /// at the same time, further incoming hello packets are dropped by `try_handshake()`.
+    pub fn set_max_handshakes(&self, max: usize) {
+        self.max_handshakes.store(max, Ordering::Relaxed);
+    }
+
+    /// Set the maximum number of live sessions with the same peer ip6,
+    /// creating more sessions fails with `SessionError::TooManySessions`.
+    pub fn set_max_sessions_per_ip6(&self, max: usize) {
+        self.max_sessions_per_ip6.store(max, Ordering::Relaxed);
+    }
+
+    /// Set the minimum length in bytes of passwords accepted by `add_user_ipv6()`.
+    /// Empty passwords are always rejected.
+    ///
+    /// The default of 1 only rejects empty passwords: the C CryptoAuth accepts any password and
+    /// existing configs (and the C tests, which use passwords like "pass") must keep working.
+    /// Set something like 8 or more to refuse short passwords when provisioning.
+    pub fn set_min_password_length(&self, min_length: usize) {
+        self.min_password_length
+            .store(min_length, Ordering::Relaxed);
+    }
+
+    /// Set the maximum number of users, further calls to `add_user_ipv6()` fail with
+    /// `AddUserError::TooManyUsers`. Lowering it below the current number of users
+    /// doesn't remove any of them.
+    pub fn set_max_users(&self, max: usize) {
+        self.max_users.store(max, Ordering::Relaxed);
+    }
+
+    /// Get the maximum number of users, see `set_max_users()`.
+    pub fn max_users(&self) -> usize {
+        self.max_users.load(Ordering::Relaxed)
+    }
+
+    /// Allow several users to share a login with different passwords, which `add_user_ipv6()`
+    /// otherwise rejects with `AddUserError::Duplicate`, as the C implementation does.
+    ///
+    /// A peer authenticating with a shared login (AuthType 2) is accepted with any of its
+    /// passwords. Each of them is tried in turn on every hello with that login, which costs
+    /// a scalar multiplication per password.
+    pub fn set_shared_logins(&self, allowed: bool) {
+        self.shared_logins.store(allowed, Ordering::Relaxed);
+    }
+
+    /// Set how many sessions `render_metrics()` reports on, those with the most traffic.
+    pub fn set_metrics_max_sessions(&self, max: usize) {
+        self.metrics_max_sessions.store(max, Ordering::Relaxed);
+    }
+
+    /// Get the number of sessions reported by `render_metrics()`,
+    /// see `set_metrics_max_sessions()`.
+    pub fn metrics_max_sessions(&self) -> usize {
+        self.metrics_max_sessions.load(Ordering::Relaxed)
+    }
+
+    /// DANGER: log the private key and every session's secrets at debug level, including
+    /// the shared secrets, so anyone who can read the logs can decrypt the traffic.
+    ///
+    /// Only meant for debugging interop problems on a test node, and off by default.
+    pub fn set_log_secret_keys(&self, enabled: bool) {
+        self.log_keys.store(enabled, Ordering::Relaxed);
+        if enabled {
+            log::warn!("Logging secret keys, anyone who can read the logs can decrypt traffic");
+            log::debug!(
+                concat!(
+                    "Logging keys of CryptoAuth:\n",
+                    "    myPrivateKey={}\n",
+                    "     myPublicKey={}\n",
+                ),
+                debug::hex_key(self.keypair.private().raw()),
+                debug::base32_key(self.public_key.raw()),
+            );
+        }
+    }
+
+    fn log_keys(&self) -> bool {
+        self.log_keys.load(Ordering::Relaxed)
+    }
+
     /// Remove all users registered with this CryptoAuth.
     ///
     /// If `login` is not `None`, only users with this id will be removed,
@@ -336,6 +1716,7 @@ impl CryptoAuth {
     /// Returns the number of users removed.
     pub fn remove_users(&self, login: Option<ByteString>) -> usize {
         let mut users = self.users.write();
+        self.purge_expired_users(&mut users);
         let mut count = 0;
         users.retain(|u| {
             let remove = login.is_none() || login.as_deref() == Some(&u.login);
@@ -356,55 +1737,202 @@ impl CryptoAuth {
         count
     }
 
+    /// Remove the users for which `f` returns true, given each user's login and the ip6
+    /// restriction, if any. They are removed for the Noise protocol as well.
+    ///
+    /// Returns the number of users removed.
+    pub fn remove_users_where<F>(&self, f: F) -> usize
+    where
+        F: Fn(&ByteString, Option<&Ip6Restriction>) -> bool,
+    {
+        let mut users = self.users.write();
+        self.purge_expired_users(&mut users);
+        let before = users.len();
+        users.retain(|u| !f(&u.login, u.restricted_to_ip6.as_ref()));
+        self.noise.remove_users_where(&f);
+        let count = before - users.len();
+        log::debug!("Removing [{}] user(s) matching a predicate", count);
+        count
+    }
+
+    /// Remove all the users which are restricted to exactly `ip6`, whatever their login,
+    /// for the Noise protocol as well. Users restricted to a prefix are kept, even one
+    /// which contains `ip6`.
+    ///
+    /// Returns the number of users removed.
+    pub fn remove_users_by_ip6(&self, ip6: IpV6) -> usize {
+        let is_restricted = |restriction: Option<&Ip6Restriction>| {
+            restriction.and_then(Ip6Restriction::exact).map(IpV6::raw) == Some(ip6.raw())
+        };
+        let mut users = self.users.write();
+        self.purge_expired_users(&mut users);
+        let before = users.len();
+        users.retain(|u| !is_restricted(u.restricted_to_ip6.as_ref()));
+        self.noise
+            .remove_users_where(|_, restriction| is_restricted(restriction));
+        let count = before - users.len();
+        log::debug!("Removing [{}] user(s) restricted to [{}]", count, ip6);
+        count
+    }
+
     /// Get a list of all the users' logins.
     pub fn get_users(&self) -> Vec<ByteString> {
+        let now = self.now_seconds();
         self.users
             .read()
             .iter()
+            .filter(|user| !user.is_expired(now))
             .map(|user| user.login.clone())
             .collect()
     }
 
-    /// Search the authorized passwords for one matching this auth header.
-    fn get_auth(&self, auth: &Challenge) -> Option<User> {
-        if auth.auth_type == AuthType::Zero {
-            return None;
+    /// Get the details of all the users: login, the ip6 they are restricted to if any
+    /// and position. No secrets are included.
+    pub fn get_user_info(&self) -> Vec<UserInfo> {
+        let now = self.now_seconds();
+        let users = self.users.read();
+        users
+            .iter()
+            .enumerate()
+            .filter(|(_, user)| !user.is_expired(now))
+            .map(|(index, user)| UserInfo::new(index, user))
+            .collect()
+    }
+
+    /// Reset every live session created with this CryptoAuth back to `State::Init`,
+    /// e.g. after rotating the permanent key. Returns the number of sessions reset.
+    pub fn reset_all_sessions(&self) -> usize {
+        let sessions = self.sessions.live_sessions(None);
+        for session in &sessions {
+            session.reset();
         }
+        sessions.len()
+    }
 
-        let mut count = 0;
+    /// Reset the live sessions with the peer `her_ip6` back to `State::Init`.
+    /// Returns the number of sessions reset.
+    pub fn reset_sessions_for_ip6(&self, her_ip6: &IpV6) -> usize {
+        let sessions = self.sessions.live_sessions(Some(her_ip6.raw()));
+        for session in &sessions {
+            session.reset();
+        }
+        sessions.len()
+    }
 
-        let users = self.users.read();
-        for u in users.iter() {
-            count += 1;
-            match auth.auth_type {
-                AuthType::One => {
-                    if *auth.as_key_bytes() == u.password_hash {
-                        return Some(u.clone());
-                    }
-                }
-                AuthType::Two => {
-                    if *auth.as_key_bytes() == u.user_name_hash {
-                        return Some(u.clone());
-                    }
-                }
-                _ => unreachable!(),
-            }
+    /// Our own ip6, derived from `public_key`.
+    pub fn ipv6(&self) -> Result<IpV6, KeyError> {
+        if !self.keypair.valid_ip6() {
+            return Err(KeyError::BadPublicKey);
         }
+        Ok(self.keypair.ip6().clone())
+    }
 
-        log::debug!("Got unrecognized auth, password count = [{}]", count);
-        None
+    /// Our keys, with the ip6 derived from them.
+    pub fn keypair(&self) -> &Keypair {
+        &self.keypair
     }
-}
 
-pub fn new_session(
-    ca: &Arc<CryptoAuth>,
-    her_pub_key: PublicKey,
-    require_auth: bool,
-    display_name: Option<String>,
-    use_noise: bool,
-) -> Result<Arc<dyn SessionTrait>> {
-    if use_noise {
-        Ok(Arc::new(crypto_noise::Session::new(
+    /// Get the number of registered users, not counting those which have expired.
+    pub fn user_count(&self) -> usize {
+        let now = self.now_seconds();
+        self.users
+            .read()
+            .iter()
+            .filter(|user| !user.is_expired(now))
+            .count()
+    }
+
+    /// Check whether a user with the given login is registered.
+    pub fn has_user(&self, login: &ByteString) -> bool {
+        let now = self.now_seconds();
+        self.users
+            .read()
+            .iter()
+            .any(|user| user.login == *login && !user.is_expired(now))
+    }
+
+    /// Get the authentication counters of the users with this login, summed if there are
+    /// several of them, or `None` if there is no such user.
+    pub fn get_user_stats(&self, login: &ByteString) -> Option<UserStats> {
+        let now = self.now_seconds();
+        let users = self.users.read();
+        let mut found = false;
+        let mut stats = UserStats::default();
+        for user in users
+            .iter()
+            .filter(|u| u.login == *login && !u.is_expired(now))
+        {
+            found = true;
+            stats.auth_successes += user.counters.successes.load(Ordering::Relaxed);
+            stats.auth_failures += user.counters.failures.load(Ordering::Relaxed);
+        }
+        if found {
+            Some(stats)
+        } else {
+            None
+        }
+    }
+
+    /// Get the authentication counters of all the current users together.
+    pub fn get_auth_stats(&self) -> AuthStats {
+        let now = self.now_seconds();
+        let users = self.users.read();
+        let mut stats = AuthStats {
+            unmatched_challenges: self.unmatched_challenges.load(Ordering::Relaxed),
+            ..AuthStats::default()
+        };
+        for user in users.iter().filter(|user| !user.is_expired(now)) {
+            stats.auth_successes += user.counters.successes.load(Ordering::Relaxed);
+            stats.auth_failures += user.counters.failures.load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    fn now_seconds(&self) -> u64 {
+        self.clock.now_seconds() as u64
+    }
+
+    /// Drop the users which have expired, they are only skipped until the list is written.
+    fn purge_expired_users(&self, users: &mut Users) {
+        let now = self.now_seconds();
+        let before = users.len();
+        users.retain(|user| !user.is_expired(now));
+        if users.len() != before {
+            log::debug!("Purging [{}] expired user(s)", before - users.len());
+        }
+        self.noise.purge_expired_users();
+    }
+
+    /// Search the authorized passwords for those matching this auth header.
+    ///
+    /// AuthType 2 only looks at the login, so every user with that login matches and the
+    /// handshake tells them apart by which password decrypts it.
+    fn get_auth(&self, auth: &Challenge) -> Vec<AuthMatch> {
+        let now = self.now_seconds();
+        let users = self.users.read();
+        let matches: Vec<AuthMatch> = users
+            .find(auth)
+            .filter(|u| !u.is_expired(now))
+            .map(AuthMatch::from)
+            .collect();
+
+        if matches.is_empty() && auth.auth_type != AuthType::Zero {
+            log::debug!("Got unrecognized auth, password count = [{}]", users.len());
+            self.unmatched_challenges.fetch_add(1, Ordering::Relaxed);
+        }
+        matches
+    }
+}
+
+pub fn new_session(
+    ca: &Arc<CryptoAuth>,
+    her_pub_key: PublicKey,
+    require_auth: bool,
+    display_name: Option<String>,
+    use_noise: bool,
+) -> Result<Arc<dyn SessionTrait>> {
+    if use_noise {
+        Ok(Arc::new(crypto_noise::Session::new(
             Arc::clone(&ca.noise),
             her_pub_key,
             display_name.unwrap_or("<unknown>".to_owned()),
@@ -414,6 +1942,21 @@ pub fn new_session(
     }
 }
 
+/// Create a legacy CryptoAuth session knowing only the other node's ip6 address.
+pub fn new_session_with_ip6(
+    ca: &Arc<CryptoAuth>,
+    her_ip6: [u8; 16],
+    require_auth: bool,
+    display_name: Option<String>,
+) -> Result<Arc<dyn SessionTrait>> {
+    Ok(Arc::new(Session::new_with_ip6(
+        Arc::clone(ca),
+        her_ip6,
+        require_auth,
+        display_name,
+    )?))
+}
+
 pub fn try_handshake(
     ca: &Arc<CryptoAuth>,
     msg: &mut Message,
@@ -429,9 +1972,19 @@ pub fn try_handshake(
         Ipv6Addr::from(first16)
     };
 
-    let nonce = u32::from_be(*msg.peek::<u32>()?);
+    let nonce = msg.peek_be::<u32>()?;
     match nonce {
         0|1 => {
+            let max_handshakes = ca.max_handshakes.load(Ordering::Relaxed);
+            if ca.sessions.handshakes_in_progress() >= max_handshakes {
+                log::debug!(
+                    "DROP hello from [{}], too many handshakes in progress",
+                    peer_id
+                );
+                let err = DecryptError::DecryptErr(DecryptErr::TooManyHandshakes).into();
+                ca.on_decrypt_error(&err, peer_id.octets());
+                return Err(err);
+            }
             let header = msg.pop::<CryptoHeader>()?;
             let session = Session::new(
                 Arc::clone(ca),
@@ -440,7 +1993,7 @@ pub fn try_handshake(
                 None,
             )?;
             msg.push(header)?;
-            SessionMut::decrypt(&session.inner, msg)?;
+            SessionMut::decrypt(&session.inner, msg, &[])?;
             Ok((TryHandshakeCode::RecvPlaintext, Some(Arc::new(session))))
         },
         cnoise::RECEIVE_INDEX_CTRL => {
@@ -463,7 +2016,52 @@ pub fn try_handshake(
 
 
 impl SessionMut {
+    /// When the nonce reaches this value, the session is reset.
+    const MAX_NONCE: u32 = u32::MAX - 0xF;
+
+    /// Rekeys by the responder which are accepted before the initiator rekeys itself,
+    /// sending a hello with a new temp key.
+    const MAX_PEER_REKEYS: usize = 16;
+
+    /// Padding needed to encrypt a handshake packet, which grows by the size of the CryptoHeader.
+    const HANDSHAKE_SEND_PADDING: usize = CryptoHeader::SIZE;
+
+    /// Padding needed to encrypt a traffic packet, which grows by the authenticator and the nonce.
+    const TRAFFIC_SEND_PADDING: usize = 16 + 4;
+
+    /// Send padding which is always required, whatever the cipher suite needs.
+    const MIN_SEND_PADDING: usize = 36;
+
+    /// Receive padding which is always reported, whatever the cipher suite needs.
+    const MIN_RECV_PADDING: usize = 12;
+
+    /// What a blinded hello adds to a hello: the sealed permanent key and its authenticator.
+    const BLINDED_HELLO_OVERHEAD: usize = 32 + 16;
+
+    /// Offset of the `public_key` field in `CryptoHeader`.
+    const PUBLIC_KEY_OFFS: usize = 4 + Challenge::SIZE + 24;
+
+    /// The nonce `n` steps after `next_nonce`. It never goes past `MAX_NONCE`, which makes
+    /// the next `encrypt()` reset the session instead of wrapping around and reusing a nonce.
+    fn nonce_after(&self, n: u32) -> u32 {
+        self.next_nonce.saturating_add(n).min(Self::MAX_NONCE)
+    }
+
     fn set_auth(&mut self, password: Option<ByteString>, login: Option<ByteString>) {
+        if self.update_auth(password, login) {
+            self.reset();
+        }
+    }
+
+    /// Same as `set_auth()`, but an established session is not reset.
+    fn stage_auth(&mut self, password: Option<ByteString>, login: Option<ByteString>) {
+        if self.update_auth(password, login) && !self.established {
+            self.reset();
+        }
+    }
+
+    /// Store the credentials used in our handshake packets, returns false if they didn't change.
+    fn update_auth(&mut self, password: Option<ByteString>, login: Option<ByteString>) -> bool {
         if password.is_none() && (self.password.is_some() || self.auth_type != AuthType::Zero) {
             self.password = None;
             self.auth_type = AuthType::Zero;
@@ -475,9 +2073,9 @@ impl SessionMut {
                 self.login = login;
             }
         } else {
-            return;
+            return false;
         }
-        self.reset();
+        true
     }
 
     fn get_state(&self) -> State {
@@ -508,7 +2106,8 @@ impl SessionMut {
     }
 
     #[allow(clippy::if_same_then_else)]
-    fn reset_if_timeout(&mut self, event_base: &EventBase) {
+    fn reset_if_timeout(&mut self, clock: &dyn Clock) {
+        self.check_clock(clock.now_seconds());
         if self.next_nonce == State::SentHello as u32 {
             // Lets not reset the session, we just sent one or more hello packets and
             // have not received a response, if they respond after we reset then we'll
@@ -516,7 +2115,7 @@ impl SessionMut {
             return;
         }
 
-        let now_secs = event_base.current_time_seconds() as i64;
+        let now_secs = clock.now_seconds() as i64;
         let time_of_last_packet = self.time_of_last_packet as i64;
         let delta = now_secs - time_of_last_packet;
         if delta < self.setup_reset_after_inactivity_seconds as i64 {
@@ -532,37 +2131,237 @@ impl SessionMut {
         self.reset();
     }
 
+    /// Returns the state if the handshake has stalled and it was not reported yet.
+    fn check_handshake_timeout(&mut self, clock: &dyn Clock) -> Option<State> {
+        let state = self.get_state();
+        match state {
+            State::SentHello | State::ReceivedHello | State::SentKey => {}
+            _ => return None,
+        }
+        if self.handshake_timeout_reported {
+            return None;
+        }
+
+        self.check_clock(clock.now_seconds());
+        let now_secs = clock.now_seconds() as i64;
+        let delta = now_secs - self.time_of_last_packet as i64;
+        if delta < self.setup_reset_after_inactivity_seconds as i64 {
+            return None;
+        }
+
+        debug::log(self, || {
+            format!(
+                "Handshake stalled for [{}] seconds in state {:?}",
+                delta, state
+            )
+        });
+        self.handshake_timeout_reported = true;
+        Some(state)
+    }
+
+    /// If the clock went backwards (NTP step, VM resume...), move the recorded times back to
+    /// `now`, otherwise the timeouts would be computed from times in the future.
+    fn check_clock(&mut self, now: u32) {
+        if now >= self.time_of_last_packet {
+            return;
+        }
+        log::warn!(
+            "Clock went backwards by [{}] seconds, restarting session timeouts",
+            self.time_of_last_packet - now
+        );
+        self.time_of_last_packet = now;
+        self.created_at = self.created_at.min(now);
+        self.clock_regressions = self.clock_regressions.saturating_add(1);
+    }
+
+    /// True if the handshake never got past sending a hello, the session is older than
+    /// `max_setup_age` seconds and nothing was received from the peer for that long.
+    fn is_abandoned(&self, clock: &dyn Clock, max_setup_age: u32) -> bool {
+        if self.next_nonce > State::SentHello as u32 {
+            return false;
+        }
+        let now_secs = clock.now_seconds() as i64;
+        let max_setup_age = max_setup_age as i64;
+        now_secs - self.created_at as i64 > max_setup_age
+            && now_secs - self.time_of_last_packet as i64 > max_setup_age
+    }
+
     /// Does not reset the `replay_protector`
     fn reset(&mut self) {
         self.next_nonce = State::Init as u32;
         self.is_initiator = false;
 
-        self.our_temp_priv_key = [0; 32];
+        self.our_temp_priv_key = SecretBuf32::default();
         self.our_temp_pub_key = [0; 32];
         self.her_temp_pub_key = [0; 32];
-        self.shared_secret = [0; 32];
+        self.shared_secret = SecretBuf32::default();
+        self.established = false;
+        self.handshake_timeout_reported = false;
+        self.prev_session_key = None;
+    }
+
+    /// Reset the handshake but keep accepting traffic encrypted with the current session key
+    /// (or the key from before an earlier rekey) until the new handshake completes.
+    fn reset_for_rekey(&mut self, sess: &SessionInner) {
+        self.keep_session_key(sess);
+        let prev_session_key = self.prev_session_key.take();
+        self.reset();
+        self.prev_session_key = prev_session_key;
+    }
+
+    /// Start a new handshake in the same role, see `Session::rekey()`.
+    fn rekey(&mut self, sess: &SessionInner) {
+        self.keep_session_key(sess);
+        self.shared_secret = SecretBuf32::default();
+        self.established = false;
+        self.handshake_timeout_reported = false;
+        if self.is_initiator {
+            // The next packet is a repeat hello with a new temp key, which she takes for
+            // a new handshake like any hello whose temp key she doesn't know.
+            self.new_temp_keypair(&sess.context);
+            self.her_temp_pub_key = [0; 32];
+            self.next_nonce = State::SentHello as u32;
+        } else {
+            // The next packet is a key packet, made with her temp key from the last hello
+            // and a new temp key of ours.
+            self.next_nonce = State::ReceivedHello as u32;
+        }
+    }
+
+    /// She started a rekey as the responder, with a key packet carrying her new temp key.
+    /// The session goes back to waiting for that key packet, our temp key stays the same.
+    ///
+    /// Every key packet she sent since our last hello can still be decrypted, so her old temp
+    /// key is retired: a key packet carrying it again is a replay, not another rekey.
+    fn accept_rekey(&mut self, sess: &SessionInner) {
+        self.keep_session_key(sess);
+        self.shared_secret = SecretBuf32::default();
         self.established = false;
+        self.handshake_timeout_reported = false;
+        self.retired_her_temp_keys.push(self.her_temp_pub_key);
+        self.her_temp_pub_key = [0; 32];
+        self.next_nonce = State::SentHello as u32;
+    }
+
+    /// Keep accepting traffic encrypted with the current session key, if it is established,
+    /// until a new handshake completes. Otherwise the key from before an earlier rekey is kept.
+    fn keep_session_key(&mut self, sess: &SessionInner) {
+        if self.established {
+            self.prev_session_key = Some(PrevSessionKey {
+                secret: self.shared_secret.clone_secret(),
+                is_initiator: self.is_initiator,
+                replay_protector: Mutex::new(sess.replay_protector.lock().clone()),
+                expires_at: None,
+            });
+        }
+    }
+
+    /// Make up the temp keypair sent in our next hello or key packet.
+    fn new_temp_keypair(&mut self, context: &CryptoAuth) {
+        // Key packets answering the old temp key can't be decrypted anymore
+        self.retired_her_temp_keys.clear();
+        context
+            .rand
+            .random_bytes(self.our_temp_priv_key.as_mut_bytes());
+        #[cfg(any(test, feature = "fuzz"))]
+        {
+            if let Some(key) = self.fixed_temp_priv_key {
+                self.our_temp_priv_key = SecretBuf32::from(key);
+            }
+        }
+        self.our_temp_pub_key = {
+            //TODO Likely to be simplified after using proper types everywhere
+            let private = PrivateKey::from(*self.our_temp_priv_key.as_bytes());
+            *Keypair::from_private(private).public().raw()
+        };
+
+        if context.log_keys() {
+            log::debug!(
+                concat!(
+                    "Generating temporary keypair\n",
+                    "    myTempPrivateKey={}\n",
+                    "     myTempPublicKey={}\n",
+                ),
+                hex::encode(self.our_temp_priv_key.as_bytes()),
+                hex::encode(&self.our_temp_pub_key),
+            );
+        }
     }
 
     fn her_key_known(&self) -> bool {
         !self.her_public_key.is_zero()
     }
 
-    fn encrypt(sess: &SessionInner, msg: &mut Message) -> Result<()> {
+    fn rekey_recommended(&self) -> bool {
+        self.next_nonce >= self.rekey_threshold
+    }
+
+    fn required_send_padding(&self) -> usize {
+        if self.blind_keys && self.next_nonce < State::ReceivedHello as u32 {
+            Self::HANDSHAKE_SEND_PADDING + Self::BLINDED_HELLO_OVERHEAD
+        } else if self.next_nonce < State::ReceivedKey as u32 {
+            Self::HANDSHAKE_SEND_PADDING
+        } else {
+            cmp::max(Self::TRAFFIC_SEND_PADDING, Self::MIN_SEND_PADDING)
+        }
+    }
+
+    fn encrypt(sess: &SessionInner, msg: &mut Message, aad: &[u8]) -> Result<()> {
+        let info = || {
+            let session = sess.session_mut.read();
+            (session.get_state(), Some(session.next_nonce))
+        };
+        let plaintext_len = msg.len() as u64;
+        let ret = trace::instrument(trace::Op::Encrypt, sess, info, || {
+            Self::do_encrypt(sess, msg, aad)
+        });
+        msg.check_canary();
+        if ret.is_ok() {
+            sess.bytes_encrypted
+                .fetch_add(plaintext_len, Ordering::Relaxed);
+        }
+        sess.notify_state_change();
+        ret
+    }
+
+    fn do_encrypt(sess: &SessionInner, msg: &mut Message, aad: &[u8]) -> Result<()> {
         let mut session = sess.session_mut.write();
 
         // If there has been no incoming traffic for a while, reset the connection to state 0.
         // This will prevent "connection in bad state" situations from lasting forever.
         // This will reset the session if it has timed out.
-        session.reset_if_timeout(&sess.context.event_base);
+        session.reset_if_timeout(&*sess.context.clock);
 
         // If the nonce wraps, start over.
-        const MAX_NONCE: u32 = u32::MAX - 0xF;
-        if session.next_nonce >= MAX_NONCE {
+        if session.next_nonce >= SessionMut::MAX_NONCE {
             session.reset();
         }
 
-        ensure!(msg.is_aligned_to(4), EncryptError, "Alignment fault");
+        // Checked before touching the message, so it can be sent again once her key is set.
+        if !session.her_key_known() {
+            return Err(EncryptError::HerKeyUnknown.into());
+        }
+
+        if session.auto_realign && !msg.is_aligned_to(4) {
+            ensure!(msg.realign(4).is_ok(), EncryptError::Misaligned);
+            sess.realigned_messages.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Buffers which can't grow are reported below.
+        let required = session.required_send_padding();
+        if session.auto_grow {
+            let _ = msg.ensure_padding(required);
+        }
+        if msg.pad() < required {
+            let available = msg.pad();
+            return Err(EncryptError::InsufficientPadding {
+                required,
+                available,
+            }
+            .into());
+        }
+
+        ensure!(msg.is_aligned_to(4), EncryptError::Misaligned);
 
         // next_nonce 0: sending hello, we are initiating connection.
         // next_nonce 1: sending another hello, nothing received yet.
@@ -580,58 +2379,141 @@ impl SessionMut {
                 debug::log(&session, || "Doing final step to send message. nonce=4");
                 debug_assert!(!session.our_temp_priv_key.is_zero());
                 debug_assert!(!session.her_temp_pub_key.is_zero());
-                session.shared_secret =
-                    get_shared_secret(session.our_temp_priv_key, session.her_temp_pub_key, None);
+                session.shared_secret = get_shared_secret(
+                    session.our_temp_priv_key.as_bytes(),
+                    session.her_temp_pub_key,
+                    None,
+                    sess.context.log_keys(),
+                );
             }
         }
 
-        ensure!(msg.len() > 0, EncryptError, "Empty packet during handshake");
-        ensure!(msg.pad() >= 36, EncryptError, "Not enough padding");
+        ensure!(msg.len() > 0, EncryptError::EmptyHandshakePayload);
+        ensure!(
+            session.next_nonce < SessionMut::MAX_NONCE,
+            EncryptError::NonceExhausted
+        );
 
         let session = RwLockWriteGuard::downgrade_to_upgradable(session);
 
-        encrypt(
+        let r = encrypt(
             session.next_nonce,
             msg,
-            session.shared_secret,
+            *bind_aad(&session.shared_secret, aad).as_bytes(),
             session.is_initiator,
         );
+        ensure!(r.is_ok(), EncryptError, "no room for the authenticator");
 
         let mut session = RwLockUpgradableReadGuard::upgrade(session);
 
-        let r = msg.push(session.next_nonce.to_be()); // Big-endian push
+        let r = msg.push_be(session.next_nonce);
         ensure!(r.is_ok(), EncryptError, "push nonce failed");
-        session.next_nonce += 1;
+        session.next_nonce = session.nonce_after(1);
         Ok(())
     }
 
-    fn decrypt(sess: &SessionInner, msg: &mut Message) -> Result<()> {
-        let session = sess.session_mut.upgradable_read();
+    fn decrypt(sess: &SessionInner, msg: &mut Message, aad: &[u8]) -> Result<()> {
+        let nonce = msg.peek_be::<u32>().ok();
+        let kind = classify_packet(msg);
+        let info = || (sess.session_mut.read().get_state(), nonce);
+        let mut phase = DecryptPhase::Parse;
+        let ret = trace::instrument(trace::Op::Decrypt, sess, info, || {
+            Self::do_decrypt(sess, msg, aad, &mut phase)
+        });
+        msg.check_canary();
+        sess.notify_state_change();
+        match ret {
+            Ok(()) => {
+                sess.bytes_decrypted
+                    .fetch_add(msg.len() as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => {
+                sess.context.on_decrypt_error(&err, sess.her_ip6);
+                let session = sess.session_mut.read();
+                let failure = DecryptFailure {
+                    code: decrypt_err_of(&err),
+                    nonce,
+                    kind,
+                    phase,
+                    replay: err.downcast_ref::<ReplayKind>().copied(),
+                    state: session.get_state(),
+                    her_ip6: IpV6::from(sess.her_ip6),
+                };
+                sess.last_error
+                    .store(u32::from(failure.code()), Ordering::Relaxed);
+                if log::log_enabled!(log::Level::Debug) {
+                    let now = sess.context.clock.now_seconds();
+                    let code = failure.code.clone();
+                    session.drop_log.log(code, now, &failure.her_ip6, &failure);
+                }
+                drop(session);
+                Err(err.context(failure))
+            }
+        }
+    }
 
-        if msg.len() < 20 {
-            debug::log(&session, || "DROP runt");
-            return Err(DecryptError::DecryptErr(DecryptErr::Runt).into());
+    /// Decrypt `msg`, setting `phase` once it is known which step the packet is for.
+    fn do_decrypt(
+        sess: &SessionInner,
+        msg: &mut Message,
+        aad: &[u8],
+        phase: &mut DecryptPhase,
+    ) -> Result<()> {
+        let session = sess.session_mut.upgradable_read();
+        if log::log_enabled!(log::Level::Debug) {
+            session.drop_log.update(&*sess.context.clock);
         }
 
-        // Outdated check? No longer needed?
-        //ensure!(
-        //    msg.pad() >= 12,
-        //    DecryptError,
-        //    "Need at least 12 bytes of padding in incoming message"
-        //);
-        ensure!(msg.is_aligned_to(4), DecryptError, "Alignment fault");
-        ensure!(msg.cap() % 4 == 0, DecryptError, "Length fault");
+        let packet = match parse_packet(msg.bytes()) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug::log_drop(&session, || format!("DROP malformed packet [{}]", err));
+                return Err(DecryptError::DecryptErr(err).into());
+            }
+        };
+
+        if session.auto_realign && !msg.is_aligned_to(4) && msg.realign(4).is_ok() {
+            sess.realigned_messages.fetch_add(1, Ordering::Relaxed);
+        }
 
-        debug_assert!(msg.len() >= 4); // Due to the check in the beginning
-        let state = msg.pop::<u32>().expect("pop 4 bytes"); // Safe
+        if let Err(problem) = check_recv_buffer(msg) {
+            // Rust owned messages can be moved into a suitable buffer instead.
+            if !session.auto_grow || msg.restage(msg.pad()).is_err() {
+                debug::log_drop(&session, || {
+                    format!("DROP bad message buffer [{}]", problem)
+                });
+                return Err(DecryptError::BadBuffer(problem).into());
+            }
+        }
 
-        let nonce = state.to_be(); // Read as Big-Endian
+        let (nonce, header) = match packet {
+            Packet::Handshake { nonce, header } => (nonce, Some(header)),
+            Packet::Traffic { nonce } => {
+                msg.discard_bytes(4)?;
+                (nonce, None)
+            }
+        };
 
         if !session.established {
-            if nonce >= Nonce::FirstTrafficPacket as u32 {
+            if let Some(header) = header {
+                let mut session = RwLockUpgradableReadGuard::upgrade(session);
+
+                *phase = DecryptPhase::Handshake;
+                session.decrypt_handshake(nonce, msg, header, sess)
+            } else {
+                *phase = DecryptPhase::Traffic;
+                if let Some(ret) = session.decrypt_with_prev_key(nonce, msg, aad, sess) {
+                    if ret.is_ok() {
+                        let mut session = RwLockUpgradableReadGuard::upgrade(session);
+                        session.update_time(msg, sess.context.clone());
+                    }
+                    return ret;
+                }
+
                 if session.next_nonce < State::SentKey as u32 {
                     // This is impossible because we have not exchanged hello and key messages.
-                    debug::log(&session, || {
+                    debug::log_drop(&session, || {
                         format!("DROP Received a run message ({}) to an un-setup session", nonce)
                     });
                     return Err(DecryptError::DecryptErr(DecryptErr::NoSession).into());
@@ -640,46 +2522,66 @@ impl SessionMut {
                 debug::log(&session, || {
                     format!("Trying final handshake step, nonce={}\n", nonce)
                 });
+                *phase = DecryptPhase::Handshake;
 
-                debug_assert!(!session.our_temp_priv_key.is_zero());
-                debug_assert!(!session.her_temp_pub_key.is_zero());
+                // Set by the hello and key packets, which `next_nonce` shows were exchanged.
+                ensure!(
+                    !session.our_temp_priv_key.is_zero() && !session.her_temp_pub_key.is_zero(),
+                    DecryptError,
+                    "temp keys not set for the final handshake step"
+                );
 
-                let secret =
-                    get_shared_secret(session.our_temp_priv_key, session.her_temp_pub_key, None);
+                let secret = get_shared_secret(
+                    session.our_temp_priv_key.as_bytes(),
+                    session.her_temp_pub_key,
+                    None,
+                    sess.context.log_keys(),
+                );
 
-                let ret = session.decrypt_message(nonce, msg, secret, sess);
+                // Hold the write lock while the replay protector is initialized, so that
+                // readers never see it initialized for a session which is not established yet.
+                let mut session = RwLockUpgradableReadGuard::upgrade(session);
 
-                // This prevents a few "ghost" dropped packets at the beginning of a session.
-                sess.replay_protector.lock().init(nonce + 1);
+                let ret = session.decrypt_message(nonce, msg, &bind_aad(&secret, aad), sess);
 
                 if ret.is_ok() {
-                    let mut session = RwLockUpgradableReadGuard::upgrade(session);
+                    // This prevents a few "ghost" dropped packets at the beginning of a session.
+                    // Only done for authenticated packets, a forged nonce would make the replay
+                    // protector drop the real ones.
+                    sess.replay_protector.lock().init(nonce.saturating_add(1));
 
                     debug::log(&session, || "Final handshake step succeeded");
                     session.shared_secret = secret;
+                    // In-flight traffic with the key from before a rekey is still accepted
+                    // for a while.
+                    let overlap = session.rekey_overlap_seconds;
+                    let expires_at = sess.context.clock.now_seconds().saturating_add(overlap);
+                    match &mut session.prev_session_key {
+                        Some(prev) if overlap > 0 => prev.expires_at = Some(expires_at),
+                        prev => *prev = None,
+                    }
 
                     // Now we're in run mode, no more handshake packets will be accepted
                     session.established = true;
-                    session.next_nonce += 3;
+                    session.next_nonce = session.nonce_after(3);
                     session.update_time(msg, sess.context.clone());
                     return Ok(());
                 }
-                debug::log(&session, || "DROP Final handshake step failed");
+                debug::log_drop(&session, || "DROP Final handshake step failed");
                 ret
-            } else {
-                msg.push(state).expect("push state back");
-
-                ensure!(msg.len() >= CryptoHeader::SIZE, DecryptError);
-                let header = msg.peek::<CryptoHeader>().unwrap().clone();
-
-                let mut session = RwLockUpgradableReadGuard::upgrade(session);
-
-                session.decrypt_handshake(nonce, msg, header, sess)
             }
-        } else if nonce >= Nonce::FirstTrafficPacket as u32 {
+        } else if header.is_none() {
             debug_assert!(!session.shared_secret.is_zero());
-
-            let ret = session.decrypt_message(nonce, msg, session.shared_secret, sess);
+            *phase = DecryptPhase::Traffic;
+
+            let secret = bind_aad(&session.shared_secret, aad);
+            let ret = match session.decrypt_message(nonce, msg, &secret, sess) {
+                // Maybe sent before a rekey and still in flight
+                Err(err) if decrypt_err_of(&err) == DecryptErr::Decrypt => session
+                    .decrypt_with_prev_key(nonce, msg, aad, sess)
+                    .unwrap_or(Err(err)),
+                ret => ret,
+            };
             match ret {
                 Ok(_) => {
                     let mut session = RwLockUpgradableReadGuard::upgrade(session);
@@ -688,7 +2590,7 @@ impl SessionMut {
                     Ok(())
                 }
                 Err(err) => {
-                    debug::log(&session, || {
+                    debug::log_drop(&session, || {
                         format!("DROP Failed to decrypt message [{}]", err)
                     });
                     Err(err)
@@ -700,14 +2602,24 @@ impl SessionMut {
             debug::log(&session, || {
                 format!("hello packet during established session nonce=[{}]", nonce)
             });
-            msg.push(state).expect("push state back");
+            *phase = DecryptPhase::Handshake;
+
+            let header = header.ok_or(DecryptError::Internal("hello packet without header"))?;
+            session.decrypt_handshake(nonce, msg, header, sess)
+        } else if session.is_initiator {
+            let mut session = RwLockUpgradableReadGuard::upgrade(session);
 
-            ensure!(msg.len() >= CryptoHeader::SIZE, DecryptError);
-            let header = msg.peek::<CryptoHeader>().unwrap().clone();
+            debug::log(&session, || {
+                format!("key packet during established session nonce=[{}]", nonce)
+            });
+            *phase = DecryptPhase::Handshake;
 
+            // She is rekeying as the responder, see `Session::rekey()`
+            let header = header.ok_or(DecryptError::Internal("key packet without header"))?;
             session.decrypt_handshake(nonce, msg, header, sess)
         } else {
-            debug::log(&session, || {
+            *phase = DecryptPhase::Handshake;
+            debug::log_drop(&session, || {
                 format!(
                     "DROP key packet during established session nonce=[{}]",
                     nonce
@@ -718,6 +2630,10 @@ impl SessionMut {
     }
 
     fn encrypt_handshake(&mut self, msg: &mut Message, context: Arc<CryptoAuth>) -> Result<()> {
+        if !self.her_key_known() {
+            return Err(EncryptError::HerKeyUnknown.into());
+        }
+
         // Prepend message with a CryptoHeader struct
         let r = msg.push(CryptoHeader::default());
         ensure!(r.is_ok(), EncryptError, "push CryptoHeader failed");
@@ -725,7 +2641,9 @@ impl SessionMut {
         // Garbage the auth challenge and set the nonce which follows it
         {
             // Get the just added header as byte slice
-            let header = msg.peek_bytes_mut(CryptoHeader::SIZE).unwrap();
+            let header = msg
+                .peek_bytes_mut(CryptoHeader::SIZE)
+                .map_err(|_| EncryptError::Internal("peek CryptoHeader bytes failed"))?;
             // Offset of the `auth` field in `CryptoHeader` struct
             const OFFS: usize = 4;
             // Total size of the `auth` and `handshake_nonce` fields
@@ -740,12 +2658,17 @@ impl SessionMut {
         }
 
         // Get inplace mutable CryptoHeader ref inside message
-        let header = msg.peek_mut::<CryptoHeader>().unwrap();
-
-        // Set the permanent key
-        header.public_key = *context.public_key.raw();
-
-        ensure!(self.her_key_known(), EncryptError);
+        let header = msg
+            .peek_mut::<CryptoHeader>()
+            .map_err(|_| EncryptError::Internal("peek CryptoHeader failed"))?;
+
+        // Set the permanent key, a blinded hello replaces it once the packet is sealed and the
+        // initiator of a blinded handshake knows the key of the node sending a key packet.
+        if self.blind_keys && self.next_nonce >= State::ReceivedHello as u32 {
+            context.rand.random_bytes(&mut header.public_key);
+        } else {
+            header.public_key = *context.public_key.raw();
+        }
 
         // Password auth
         let password_hash;
@@ -765,31 +2688,12 @@ impl SessionMut {
 
         if self.next_nonce == State::Init as u32 || self.next_nonce == State::ReceivedHello as u32 {
             // If we're sending a hello or a key
-            // Here we make up a temp keypair
-            context.rand.random_bytes(&mut self.our_temp_priv_key);
-            self.our_temp_pub_key = {
-                //TODO Likely to be simplified after using proper types everywhere
-                let priv_key = PrivateKey::from(self.our_temp_priv_key);
-                let pub_key = crypto_scalarmult_curve25519_base(&priv_key);
-                *pub_key.raw()
-            };
-
-            if CryptoAuth::LOG_KEYS {
-                log::debug!(
-                    concat!(
-                        "Generating temporary keypair\n",
-                        "    myTempPrivateKey={}\n",
-                        "     myTempPublicKey={}\n",
-                    ),
-                    hex::encode(&self.our_temp_priv_key),
-                    hex::encode(&self.our_temp_pub_key),
-                );
-            }
+            self.new_temp_keypair(&context);
         }
 
         header.encrypted_temp_key = self.our_temp_pub_key;
 
-        if CryptoAuth::LOG_KEYS {
+        if context.log_keys() {
             log::debug!(
                 concat!("Wrapping temp public key:\n", "    {}\n",),
                 hex::encode(&header.encrypted_temp_key),
@@ -816,9 +2720,10 @@ impl SessionMut {
         let shared_secret;
         if self.next_nonce < State::ReceivedHello as u32 {
             shared_secret = get_shared_secret(
-                *context.private_key.raw(),
+                context.keypair.private().raw(),
                 *self.her_public_key.raw(),
-                password_hash,
+                password_hash.as_ref(),
+                context.log_keys(),
             );
 
             self.is_initiator = true;
@@ -830,15 +2735,16 @@ impl SessionMut {
             // her_temp_pub_key was set by decrypt_handshake()
             debug_assert!(!self.her_temp_pub_key.is_zero());
             shared_secret = get_shared_secret(
-                *context.private_key.raw(),
+                context.keypair.private().raw(),
                 self.her_temp_pub_key,
-                password_hash,
+                password_hash.as_ref(),
+                context.log_keys(),
             );
 
             ensure!(self.next_nonce <= State::SentKey as u32, EncryptError);
             self.next_nonce = State::SentKey as u32;
 
-            if CryptoAuth::LOG_KEYS {
+            if context.log_keys() {
                 log::debug!(
                     concat!("Using their temp public key:\n", "    {}\n",),
                     hex::encode(&self.her_temp_pub_key),
@@ -854,11 +2760,14 @@ impl SessionMut {
         let handshake_nonce = header.handshake_nonce;
 
         // Temporarily remove CryptoHeader until the encrypted_temp_key field.
-        let mut saved = msg.pop_bytes(CryptoHeader::SIZE - 32).expect("pop");
+        let mut saved = msg
+            .pop_bytes(CryptoHeader::SIZE - 32)
+            .map_err(|_| EncryptError::Internal("pop CryptoHeader failed"))?;
 
-        encrypt_rnd_nonce(handshake_nonce, msg, shared_secret);
+        let r = encrypt_rnd_nonce(handshake_nonce, msg, *shared_secret.as_bytes());
+        ensure!(r.is_ok(), EncryptError, "no room for the authenticator");
 
-        if CryptoAuth::LOG_KEYS {
+        if context.log_keys() {
             log::debug!(
                 concat!(
                     "Encrypting message with:\n",
@@ -867,16 +2776,101 @@ impl SessionMut {
                     "   cipher: {}\n",
                 ),
                 hex::encode(handshake_nonce),
-                debug::hex_key(&shared_secret),
-                hex::encode(&msg.bytes()[0..32]), //TODO correct? no?
+                debug::hex_key(shared_secret.as_bytes()),
+                msg.hexdump().limit(32),
             );
         }
 
         // Put CryptoHeader back (without last 16 bytes,
         // because encrypt_rnd_nonce adds these 16 bytes of authenticator).
         saved.truncate(saved.len() - 16);
-        msg.push_bytes(&saved).expect("push");
+        if self.blind_keys && self.next_nonce < State::ReceivedHello as u32 {
+            let blinded_key = self.blind_hello(msg, &handshake_nonce, &context)?;
+            saved[Self::PUBLIC_KEY_OFFS..].copy_from_slice(&blinded_key);
+        }
+        let r = msg.push_bytes(&saved);
+        ensure!(r.is_ok(), EncryptError, "push CryptoHeader back failed");
+        msg.check_canary();
+
+        Ok(())
+    }
+
+    /// Seal our permanent key in front of an encrypted hello with a one-time key, which is
+    /// returned to stand in for the permanent key in the CryptoHeader.
+    ///
+    /// The seal is keyed by the one-time key and her permanent key, the hello under it by ours
+    /// and hers as usual, so only she can tell who a hello is from and that it is genuine.
+    fn blind_hello(
+        &self,
+        msg: &mut Message,
+        handshake_nonce: &[u8; 24],
+        context: &CryptoAuth,
+    ) -> Result<[u8; 32]> {
+        let mut blinding_key = SecretBuf32::default();
+        context.rand.random_bytes(blinding_key.as_mut_bytes());
+        let blinded_key = {
+            let private = PrivateKey::from(*blinding_key.as_bytes());
+            *Keypair::from_private(private).public().raw()
+        };
+        let seal_secret = get_blinding_secret(
+            blinding_key.as_bytes(),
+            *self.her_public_key.raw(),
+            context.log_keys(),
+        );
+        let r = msg.push_bytes(context.public_key.raw());
+        ensure!(r.is_ok(), EncryptError, "no room for the blinded key");
+        let r = encrypt_rnd_nonce(*handshake_nonce, msg, *seal_secret.as_bytes());
+        ensure!(
+            r.is_ok(),
+            EncryptError,
+            "no room for the seal authenticator"
+        );
+        Ok(blinded_key)
+    }
 
+    /// Open the seal of a blinded hello, see `blind_hello()`. On success `msg` and `header`
+    /// are the hello with her permanent key, as if it had not been blinded.
+    fn unblind_hello(
+        &self,
+        msg: &mut Message,
+        header: &mut HandshakeHeader,
+        sess: &SessionInner,
+    ) -> Result<()> {
+        // The caller checked there is a whole CryptoHeader, which is enough for the seal:
+        // a hello which is too short to be blinded fails like one which isn't blinded.
+        if header.public_key.is_zero() {
+            debug::log_drop(self, || "DROP blinded hello with a zero key");
+            return Err(DecryptError::DecryptErr(DecryptErr::Wiseguy).into());
+        }
+        let seal_secret = get_blinding_secret(
+            sess.context.keypair.private().raw(),
+            header.public_key,
+            sess.context.log_keys(),
+        );
+        let mut prefix = msg
+            .pop_bytes(Self::PUBLIC_KEY_OFFS + 32)
+            .map_err(|_| DecryptError::Internal("pop blinded hello prefix failed"))?;
+        if decrypt_rnd_nonce(header.handshake_nonce, msg, *seal_secret.as_bytes()).is_err() {
+            let r = msg.push_bytes(&prefix);
+            ensure!(
+                r.is_ok(),
+                DecryptError,
+                "push blinded hello prefix back failed"
+            );
+            debug::log_drop(self, || "DROP hello which could not be unblinded");
+            return Err(DecryptError::DecryptErr(DecryptErr::Blinding).into());
+        }
+        let her_key = msg
+            .pop_bytes(32)
+            .map_err(|_| DecryptError::Internal("pop unblinded key failed"))?;
+        header.public_key.copy_from_slice(&her_key);
+        prefix[Self::PUBLIC_KEY_OFFS..].copy_from_slice(&her_key);
+        let r = msg.push_bytes(&prefix);
+        ensure!(
+            r.is_ok(),
+            DecryptError,
+            "push unblinded hello prefix failed"
+        );
         Ok(())
     }
 
@@ -884,11 +2878,27 @@ impl SessionMut {
         &mut self,
         nonce: u32,
         msg: &mut Message,
-        mut header: CryptoHeader,
+        header: HandshakeHeader,
+        sess: &SessionInner,
+    ) -> Result<()> {
+        let state = self.get_state();
+        let info = || (state, Some(nonce));
+        let ret = trace::instrument(trace::Op::DecryptHandshake, sess, info, || {
+            self.do_decrypt_handshake(nonce, msg, header, sess)
+        });
+        msg.check_canary();
+        ret
+    }
+
+    fn do_decrypt_handshake(
+        &mut self,
+        nonce: u32,
+        msg: &mut Message,
+        mut header: HandshakeHeader,
         sess: &SessionInner,
     ) -> Result<()> {
         if msg.len() < CryptoHeader::SIZE {
-            debug::log(self, || "DROP runt");
+            debug::log_drop(self, || "DROP runt");
             return Err(DecryptError::DecryptErr(DecryptErr::Runt).into());
         }
 
@@ -899,56 +2909,97 @@ impl SessionMut {
         // next_nonce 3: receiving first data packet.
         // next_nonce >3: handshake complete
 
-        ensure!(self.her_key_known(), DecryptError);
-        if *self.her_public_key.raw() != header.public_key {
-            debug::log(self, || {
-                "DROP a packet with different public key than this session"
+        if self.blind_keys && nonce < Nonce::Key as u32 {
+            self.unblind_hello(msg, &mut header, sess)?;
+        }
+
+        // The authenticator and the encrypted temp key are not checked here
+        let crypto_header = CryptoHeader {
+            nonce: nonce.to_be(),
+            auth: header.auth.clone(),
+            handshake_nonce: header.handshake_nonce,
+            public_key: header.public_key,
+            ..CryptoHeader::default()
+        };
+        let her_key_known = self.her_key_known();
+        // The key field of a blinded key packet is random, the secret checks her key instead.
+        let blinded_key_packet = self.blind_keys && nonce >= Nonce::Key as u32;
+        let expected_key = if her_key_known && !blinded_key_packet {
+            Some(&self.her_public_key)
+        } else {
+            None
+        };
+        if let Err(e) = crypto_header.validate(expected_key) {
+            let err = match e {
+                // A zero key is rejected before it's compared with an unknown (zero) key of ours.
+                HeaderError::ZeroPublicKey => DecryptErr::Wiseguy,
+                HeaderError::WrongPublicKey => DecryptErr::WrongPermPubkey,
+                HeaderError::UnknownAuthType => DecryptErr::UnrecognizedAuth,
+                HeaderError::NotHandshake => DecryptErr::InvalidPacket,
+            };
+            debug::log_drop(self, || {
+                format!("DROP handshake with invalid header: {}", e)
             });
-            return Err(DecryptError::DecryptErr(DecryptErr::WrongPermPubkey).into());
+            return Err(DecryptError::DecryptErr(err).into());
         }
 
-        ensure!(
-            (self.next_nonce < State::ReceivedHello as u32) == self.her_temp_pub_key.is_zero(),
+        if !her_key_known {
+            // Session was created from an ip6, the key must hash to it.
+            if ip6_from_key(&header.public_key) != sess.her_ip6 {
+                debug::log_drop(self, || "DROP packet with key not matching the session ip6");
+                return Err(DecryptError::DecryptErr(DecryptErr::WrongPermPubkey).into());
+            }
+            self.her_public_key = PublicKey::from(header.public_key);
+        }
+
+        ensure!(
+            (self.next_nonce < State::ReceivedHello as u32) == self.her_temp_pub_key.is_zero(),
             DecryptError,
         );
 
-        let user_opt = sess.context.get_auth(&header.auth);
-        let has_user = user_opt.is_some();
-
-        let password_hash;
-
-        if let Some(user) = user_opt {
-            password_hash = Some(user.secret);
-            let restricted_to_ip6 = user.restricted_to_ip6;
-            if let Some(rip6) = restricted_to_ip6 {
-                let ip6_matches_key = {
-                    let pub_key = &self.her_public_key;
-                    rip6 == ip6_from_key(pub_key.raw())
-                };
-                if !ip6_matches_key {
-                    debug::log(self, || "DROP packet with key not matching restrictedToIp6");
-                    return Err(DecryptError::DecryptErr(DecryptErr::IpRestricted).into());
-                }
+        let mut users = sess.context.get_auth(&header.auth);
+        let has_user = !users.is_empty();
+
+        // `her_ip6` is derived from `her_public_key`, no need to hash the key again.
+        let her_ip6 = IpV6::from(sess.her_ip6);
+        users.retain(|user| {
+            let allowed = user
+                .restricted_to_ip6
+                .as_ref()
+                .map_or(true, |r| r.allows(&her_ip6));
+            if !allowed {
+                user.counters.failures.fetch_add(1, Ordering::Relaxed);
             }
-        } else {
-            password_hash = None;
+            allowed
+        });
+        if has_user && users.is_empty() {
+            debug::log_drop(self, || "DROP packet with key not matching restrictedToIp6");
+            return Err(DecryptError::DecryptErr(DecryptErr::IpRestricted).into());
+        }
+
+        if !has_user {
+            sess.context.on_auth_failure(
+                header.auth.auth_type,
+                AuthFailure::UnknownLogin,
+                sess.her_ip6,
+            );
         }
 
         if self.require_auth && !has_user {
-            debug::log(self, || "DROP message because auth was not given");
+            debug::log_drop(self, || "DROP message because auth was not given");
             return Err(DecryptError::DecryptErr(DecryptErr::AuthRequired).into());
         }
 
         if !has_user && header.auth.auth_type != AuthType::Zero {
-            debug::log(self, || "DROP message with unrecognized authenticator");
+            debug::log_drop(self, || "DROP message with unrecognized authenticator");
             return Err(DecryptError::DecryptErr(DecryptErr::UnrecognizedAuth).into());
         }
 
         // What the nextNonce will become if this packet is valid.
         let mut next_nonce: u32;
 
-        // The secret for decrypting this message.
-        let shared_secret: [u8; 32];
+        // Our private key for decrypting this message, to be combined with a password.
+        let our_key: &[u8; 32];
 
         if nonce < Nonce::Key as u32 {
             // Nonce::Hello or Nonce::RepeatHello
@@ -964,12 +3015,7 @@ impl SessionMut {
                 )
             });
 
-            shared_secret = get_shared_secret(
-                *sess.context.private_key.raw(),
-                *self.her_public_key.raw(),
-                password_hash,
-            );
-
+            our_key = sess.context.keypair.private().raw();
             next_nonce = State::ReceivedHello as u32;
         } else {
             if nonce == Nonce::Key as u32 {
@@ -980,85 +3026,146 @@ impl SessionMut {
             }
 
             if !self.is_initiator {
-                debug::log(self, || "DROP a stray key packet");
+                debug::log_drop(self, || "DROP a stray key packet");
                 return Err(DecryptError::DecryptErr(DecryptErr::StrayKey).into());
             }
 
             // We sent the hello, this is a key
-            shared_secret = get_shared_secret(
-                self.our_temp_priv_key,
-                *self.her_public_key.raw(),
-                password_hash,
-            );
-
+            our_key = self.our_temp_priv_key.as_bytes();
             next_nonce = State::ReceivedKey as u32;
         }
 
         // Shift it on top of the authenticator before the encrypted public key
         msg.discard_bytes(CryptoHeader::SIZE - 48)
-            .expect("discard above authenticator");
+            .map_err(|_| DecryptError::DecryptErr(DecryptErr::Runt))?;
 
-        if CryptoAuth::LOG_KEYS {
-            log::debug!(
-                concat!(
-                    "Decrypting message with:\n",
-                    "    nonce: {}\n",
-                    "   secret: {}\n",
-                    "   cipher: {}\n",
-                ),
-                hex::encode(&header.handshake_nonce),
-                debug::hex_key(&shared_secret),
-                hex::encode(&msg.bytes()[0..32]), //TODO correct? no?
+        // Users sharing a login are told apart by their password: the first one whose
+        // secret decrypts the packet is the one the peer authenticated as.
+        let password_hashes: Vec<_> = if has_user {
+            users.iter().map(|user| Some(&user.secret)).collect()
+        } else {
+            vec![None]
+        };
+        let mut decrypted = false;
+        let mut authenticated_as = None;
+        for (i, password_hash) in password_hashes.into_iter().enumerate() {
+            let shared_secret = get_shared_secret(
+                our_key,
+                *self.her_public_key.raw(),
+                password_hash,
+                sess.context.log_keys(),
             );
-        }
 
-        // Decrypt her temp public key and the message.
-        let r = decrypt_rnd_nonce(header.handshake_nonce, msg, shared_secret);
-        if r.is_err() {
-            header.wipe(); // Just in case
-            debug::log(self, || {
+            if sess.context.log_keys() {
+                log::debug!(
+                    concat!(
+                        "Decrypting message with:\n",
+                        "    nonce: {}\n",
+                        "   secret: {}\n",
+                        "   cipher: {}\n",
+                    ),
+                    hex::encode(&header.handshake_nonce),
+                    debug::hex_key(shared_secret.as_bytes()),
+                    msg.hexdump().limit(32),
+                );
+            }
+
+            // Decrypt her temp public key and the message, `msg` is unchanged on failure.
+            if decrypt_rnd_nonce(header.handshake_nonce, msg, *shared_secret.as_bytes()).is_ok() {
+                decrypted = true;
+                authenticated_as = users.get(i).map(|user| Arc::clone(&user.counters));
+                break;
+            }
+        }
+        if !decrypted {
+            let auth_type = header.auth.auth_type;
+            for user in &users {
+                user.counters.failures.fetch_add(1, Ordering::Relaxed);
+            }
+            if has_user {
+                sess.context
+                    .on_auth_failure(auth_type, AuthFailure::WrongPassword, sess.her_ip6);
+            }
+            (&mut header).wipe(); // Just in case
+            debug::log_drop(self, || {
                 format!("DROP message with nonce [{}], decryption failed", nonce)
             });
+            // The login matched but none of its passwords did, which is an auth failure.
+            if has_user && auth_type == AuthType::Two {
+                return Err(DecryptError::DecryptErr(DecryptErr::UnrecognizedAuth).into());
+            }
             return Err(DecryptError::DecryptErr(DecryptErr::HandshakeDecryptFailed).into());
         }
 
-        header.encrypted_temp_key = msg.pop().expect("pop encrypted_temp_key");
+        let her_temp_key: [u8; 32] = msg
+            .pop()
+            .map_err(|_| DecryptError::DecryptErr(DecryptErr::Runt))?;
 
-        if header.encrypted_temp_key.is_zero() {
+        if her_temp_key.is_zero() {
             // We need to reject 0 public keys outright because they will be confused with "unknown"
-            debug::log(self, || "DROP message with zero as temp public key");
+            debug::log_drop(self, || "DROP message with zero as temp public key");
             return Err(DecryptError::DecryptErr(DecryptErr::Wiseguy).into());
         }
 
-        if CryptoAuth::LOG_KEYS {
+        if sess.context.log_keys() {
             log::debug!(
                 concat!("Unwrapping temp public key:\n", "    {}\n",),
-                hex::encode(&header.encrypted_temp_key),
+                hex::encode(&her_temp_key),
             );
         }
 
+        if nonce >= Nonce::Key as u32 && self.retired_her_temp_keys.contains(&her_temp_key) {
+            debug::log_drop(self, || "DROP replayed key packet with a retired temp key");
+            let err = if self.established {
+                DecryptErr::KeyPktEstablishedSession
+            } else {
+                DecryptErr::InvalidPacket
+            };
+            return Err(DecryptError::DecryptErr(err).into());
+        }
+
+        if self.established && nonce >= Nonce::Key as u32 {
+            // A key packet sent after the handshake, which only the responder's rekey does
+            if self.her_temp_pub_key == her_temp_key {
+                debug::log_drop(self, || "DROP key packet with the temp key of the session");
+                let err = DecryptErr::KeyPktEstablishedSession;
+                return Err(DecryptError::DecryptErr(err).into());
+            }
+            if self.retired_her_temp_keys.len() >= Self::MAX_PEER_REKEYS {
+                // Rekey from our side instead, with a hello she answers as usual. Her key
+                // packets for our old temp key can't be decrypted anymore.
+                debug::log_drop(self, || "DROP key packet, too many rekeys by her, rekeying");
+                self.rekey(sess);
+                sess.reset_replay_protector(self, ReplayReset::Rekey);
+                let err = DecryptErr::KeyPktEstablishedSession;
+                return Err(DecryptError::DecryptErr(err).into());
+            }
+            debug::log(self, || "Key packet with a new temp key, she is rekeying");
+            self.accept_rekey(sess);
+        }
+
         // Post-decryption checking
         if nonce == Nonce::Hello as u32 {
             // A new hello packet
-            if self.her_temp_pub_key == header.encrypted_temp_key {
+            if self.her_temp_pub_key == her_temp_key {
                 // Possible replay attack or duped packet
-                debug::log(self, || "DROP dupe hello packet with same temp key");
+                debug::log_drop(self, || "DROP dupe hello packet with same temp key");
                 return Err(DecryptError::DecryptErr(DecryptErr::InvalidPacket).into());
             }
         } else if nonce == Nonce::Key as u32 && self.next_nonce >= State::ReceivedKey as u32 {
             // We accept a new key packet and let it change the session since the other end might have
             // killed off the session while it was in the midst of setting up.
             // This is NOT a repeat key packet because it's nonce is 2, not 3.
-            if self.her_temp_pub_key == header.encrypted_temp_key {
+            if self.her_temp_pub_key == her_temp_key {
                 ensure!(!self.her_temp_pub_key.is_zero(), DecryptError);
-                debug::log(self, || "DROP dupe key packet with same temp key");
+                debug::log_drop(self, || "DROP dupe key packet with same temp key");
                 return Err(DecryptError::DecryptErr(DecryptErr::InvalidPacket).into());
             }
         } else if nonce == Nonce::RepeatKey as u32 && self.next_nonce >= State::ReceivedKey as u32 {
             // Got a repeat key packet, make sure the temp key is the same as the one we know.
-            if self.her_temp_pub_key != header.encrypted_temp_key {
+            if self.her_temp_pub_key != her_temp_key {
                 ensure!(!self.her_temp_pub_key.is_zero(), DecryptError);
-                debug::log(self, || "DROP repeat key packet with different temp key");
+                debug::log_drop(self, || "DROP repeat key packet with different temp key");
                 return Err(DecryptError::DecryptErr(DecryptErr::InvalidPacket).into());
             }
         }
@@ -1084,41 +3191,39 @@ impl SessionMut {
             );
             match self.next_nonce {
                 INIT | RECEIVED_HELLO | SENT_KEY => {
-                    debug::log(self, || "DROP stray key packet");
+                    debug::log_drop(self, || "DROP stray key packet");
                     return Err(DecryptError::DecryptErr(DecryptErr::StrayKey).into());
                 }
                 SENT_HELLO => {
-                    self.her_temp_pub_key = header.encrypted_temp_key;
+                    self.her_temp_pub_key = her_temp_key;
                 }
                 RECEIVED_KEY => {
                     if nonce == Nonce::Key as u32 {
-                        self.her_temp_pub_key = header.encrypted_temp_key;
+                        self.her_temp_pub_key = her_temp_key;
                     } else {
-                        ensure!(
-                            self.her_temp_pub_key == header.encrypted_temp_key,
-                            DecryptError
-                        );
+                        ensure!(self.her_temp_pub_key == her_temp_key, DecryptError);
                     }
                 }
                 _ => {
                     ensure!(!self.established, DecryptError);
                     if nonce == Nonce::Key as u32 {
-                        self.her_temp_pub_key = header.encrypted_temp_key;
+                        self.her_temp_pub_key = her_temp_key;
                         debug::log(self, || "New key packet, recalculating shared secret");
 
                         debug_assert!(!self.our_temp_priv_key.is_zero());
                         debug_assert!(!self.her_temp_pub_key.is_zero());
 
-                        self.shared_secret =
-                            get_shared_secret(self.our_temp_priv_key, self.her_temp_pub_key, None);
-                    } else {
-                        ensure!(
-                            self.her_temp_pub_key == header.encrypted_temp_key,
-                            DecryptError
+                        self.shared_secret = get_shared_secret(
+                            self.our_temp_priv_key.as_bytes(),
+                            self.her_temp_pub_key,
+                            None,
+                            sess.context.log_keys(),
                         );
+                    } else {
+                        ensure!(self.her_temp_pub_key == her_temp_key, DecryptError);
                     }
 
-                    next_nonce = self.next_nonce + 1;
+                    next_nonce = self.nonce_after(1);
                     debug::log(self, || "New key packet but we are already sending data");
                 }
             }
@@ -1127,21 +3232,27 @@ impl SessionMut {
                 nonce == Nonce::Hello as u32 || nonce == Nonce::RepeatHello as u32,
                 DecryptError
             );
-            if self.her_temp_pub_key != header.encrypted_temp_key {
+            if self.her_temp_pub_key != her_temp_key {
                 // Fresh new hello packet, we should reset the session.
                 match self.next_nonce {
                     SENT_HELLO => {
-                        if self.her_public_key < sess.context.public_key {
+                        if self
+                            .her_public_key
+                            .wire_cmp(&sess.context.public_key)
+                            .is_lt()
+                        {
                             // It's a hello and we are the initiator but their permanent public key is
                             // numerically lower than ours, this is so that in the event of two hello
                             // packets crossing on the wire, the nodes will agree on who is the
-                            // initiator.
+                            // initiator. Both keys are public, so this needn't be constant-time.
                             debug::log(self, || {
                                 "Incoming hello from node with lower key, resetting"
                             });
-                            self.reset();
-                            sess.replay_protector.lock().reset();
-                            self.her_temp_pub_key = header.encrypted_temp_key;
+                            sess.context
+                                .on_hello_collision(HelloCollision::Yielded, sess.her_ip6);
+                            self.reset_for_rekey(sess);
+                            sess.reset_replay_protector(self, ReplayReset::HelloCollision);
+                            self.her_temp_pub_key = her_temp_key;
                         } else {
                             // We are the initiator and thus we are sending HELLO packets, however they
                             // have sent a hello to us and we already sent a HELLO
@@ -1150,17 +3261,22 @@ impl SessionMut {
                             debug::log(self, || {
                                 "Incoming hello from node with higher key, not resetting"
                             });
+                            sess.context
+                                .on_hello_collision(HelloCollision::StoodFirm, sess.her_ip6);
+                            if let Some(counters) = authenticated_as {
+                                counters.successes.fetch_add(1, Ordering::Relaxed);
+                            }
                             return Ok(());
                         }
                     }
                     INIT => {
-                        self.her_temp_pub_key = header.encrypted_temp_key;
+                        self.her_temp_pub_key = her_temp_key;
                     }
                     _ => {
                         debug::log(self, || "Incoming hello packet resetting session");
-                        self.reset();
-                        sess.replay_protector.lock().reset();
-                        self.her_temp_pub_key = header.encrypted_temp_key;
+                        self.reset_for_rekey(sess);
+                        sess.reset_replay_protector(self, ReplayReset::NewHello);
+                        self.her_temp_pub_key = her_temp_key;
                     }
                 }
             } else {
@@ -1170,7 +3286,7 @@ impl SessionMut {
                         next_nonce = self.next_nonce;
                     }
                     _ => {
-                        debug::log(self, || "DROP Incoming repeat hello");
+                        debug::log_drop(self, || "DROP Incoming repeat hello");
                         // We already know the key which is being used for this hello packet and
                         // our state has advanced past RECEIVED_HELLO or SENT_KEY or perhaps we
                         // are the initiator of this session and they're sending us what should
@@ -1180,7 +3296,7 @@ impl SessionMut {
                 }
             }
         } else {
-            unreachable!("should never happen");
+            return Err(DecryptError::Internal("unexpected handshake state").into());
         }
 
         // Nonce can never go backward and can only "not advance" if they're 0,1,2,3,4 session state.
@@ -1191,8 +3307,12 @@ impl SessionMut {
             "nonce sequence error",
         );
         self.next_nonce = next_nonce;
+        self.peer_auth_type = Some(header.auth.auth_type);
+        if let Some(counters) = authenticated_as {
+            counters.successes.fetch_add(1, Ordering::Relaxed);
+        }
 
-        sess.replay_protector.lock().reset();
+        sess.reset_replay_protector(self, ReplayReset::HandshakeAdvanced);
 
         Ok(())
     }
@@ -1202,29 +3322,100 @@ impl SessionMut {
         &self,
         nonce: u32,
         content: &mut Message,
-        secret: [u8; 32],
+        secret: &SecretBuf32,
         sess: &SessionInner,
     ) -> Result<()> {
         // Decrypt with authentication and replay prevention.
-        let r = decrypt(nonce, content, secret, self.is_initiator);
+        let r = decrypt(nonce, content, *secret.as_bytes(), self.is_initiator);
         if r.is_err() {
-            debug::log(self, || "DROP authenticated decryption failed");
+            debug::log_drop(self, || "DROP authenticated decryption failed");
             return Err(DecryptError::DecryptErr(DecryptErr::Decrypt).into());
         }
 
-        if !sess.replay_protector.lock().check_nonce(nonce) {
-            debug::log(self, || {
-                format!("DROP nonce checking failed nonce=[{}]", nonce)
+        let mut replay_protector = sess.replay_protector.lock();
+        if !replay_protector.check_nonce(nonce) {
+            let kind = replay_protector.replay_kind(nonce);
+            drop(replay_protector);
+            debug::log_drop(self, || {
+                format!(
+                    "DROP nonce checking failed nonce=[{}] {}",
+                    nonce,
+                    fmt_replay(kind)
+                )
             });
-            return Err(DecryptError::DecryptErr(DecryptErr::Replay).into());
+            return Err(replay_error(kind));
         }
 
         Ok(())
     }
 
+    /// Try to decrypt a traffic packet with the key from before a rekey.
+    ///
+    /// Returns `None` if there is no such key, it has expired or the packet was not encrypted
+    /// with it, in which case `msg` is untouched.
+    fn decrypt_with_prev_key(
+        &self,
+        nonce: u32,
+        msg: &mut Message,
+        aad: &[u8],
+        sess: &SessionInner,
+    ) -> Option<Result<()>> {
+        let prev = self.prev_session_key.as_ref()?;
+        if let Some(expires_at) = prev.expires_at {
+            if sess.context.clock.now_seconds() >= expires_at {
+                return None;
+            }
+        }
+        let secret = bind_aad(&prev.secret, aad);
+        if decrypt(nonce, msg, *secret.as_bytes(), prev.is_initiator).is_err() {
+            return None;
+        }
+
+        let mut replay_protector = prev.replay_protector.lock();
+        if !replay_protector.check_nonce(nonce) {
+            let kind = replay_protector.replay_kind(nonce);
+            drop(replay_protector);
+            debug::log_drop(self, || {
+                let kind = fmt_replay(kind);
+                format!(
+                    "DROP nonce checking failed nonce=[{}] {} (previous key)",
+                    nonce, kind
+                )
+            });
+            return Some(Err(replay_error(kind)));
+        }
+
+        debug::log(self, || {
+            format!(
+                "Decrypted with the key from before the rekey, nonce={}",
+                nonce
+            )
+        });
+        Some(Ok(()))
+    }
+
     #[inline]
     fn update_time(&mut self, _msg: &Message, context: Arc<CryptoAuth>) {
-        self.time_of_last_packet = context.event_base.current_time_seconds();
+        let now = context.clock.now_seconds();
+        self.check_clock(now);
+        self.time_of_last_packet = now;
+        self.handshake_timeout_reported = false;
+    }
+}
+
+/// `DecryptErr::Replay`, with the `ReplayKind` as context for `DecryptFailure::replay_kind()`.
+fn replay_error(kind: Option<ReplayKind>) -> anyhow::Error {
+    let err = anyhow::Error::from(DecryptError::DecryptErr(DecryptErr::Replay));
+    match kind {
+        Some(kind) => err.context(kind),
+        None => err,
+    }
+}
+
+fn fmt_replay(kind: Option<ReplayKind>) -> String {
+    match kind {
+        Some(kind) => format!("[{}]", kind),
+        None => String::new(),
     }
 }
 
@@ -1239,7 +3430,7 @@ pub struct PlaintextRecv(Arc<SessionInner>);
 impl IfRecv for PlaintextRecv {
     fn recv(&self, m: &mut Message) -> Result<()> {
         anyhow::ensure!(m.len() > 0, "Zero-length message is prohibited"); // No real message can be 0 bytes in length
-        SessionMut::encrypt(&self.0, m)?;
+        SessionMut::encrypt(&self.0, m, &[])?;
         self.0.cipher_pvt.send(m)
     }
 }
@@ -1253,7 +3444,7 @@ impl IfRecv for CiphertextRecv {
         first16.copy_from_slice(m.peek_bytes(16)?);
         log::debug!("Decrypt msg {}", m.len());
 
-        match SessionMut::decrypt(&self.0, m) {
+        match SessionMut::decrypt(&self.0, m, &[]) {
             Ok(()) => {
                 m.push(0_u32)?;
                 self.0.plain_pvt.send(m)
@@ -1263,7 +3454,7 @@ impl IfRecv for CiphertextRecv {
                 let ee = match e.downcast_ref::<DecryptError>() {
                     Some(ee) => match ee {
                         DecryptError::DecryptErr(ee) => ee,
-                        DecryptError::Internal(_) => {
+                        DecryptError::BadBuffer(_) | DecryptError::Internal(_) => {
                             return Err(e);
                         }
                     },
@@ -1273,8 +3464,8 @@ impl IfRecv for CiphertextRecv {
                 }
                 .clone() as u32;
                 m.clear();
-                m.push(((*self.0).session_mut.read().get_state() as u32).to_be())?;
-                m.push(ee.to_be())?;
+                m.push_be((*self.0).session_mut.read().get_state() as u32)?;
+                m.push_be(ee)?;
                 m.push_bytes(&first16)?;
                 m.push(ee)?;
                 self.0.plain_pvt.send(m)
@@ -1283,7 +3474,7 @@ impl IfRecv for CiphertextRecv {
     }
 }
 
-struct Session {
+pub(crate) struct Session {
     inner: Arc<SessionInner>,
     ifaces: Mutex<Option<(Iface, Iface)>>,
 }
@@ -1291,6 +3482,8 @@ struct Session {
 impl Session {
     const DEFAULT_RESET_AFTER_INACTIVITY_SECONDS: u32 = 60;
     const DEFAULT_SETUP_RESET_AFTER_INACTIVITY_SECONDS: u32 = 10;
+    const DEFAULT_REKEY_THRESHOLD: u32 = SessionMut::MAX_NONCE - (1 << 24);
+    const DEFAULT_REKEY_OVERLAP_SECONDS: u32 = 10;
 
     fn new(
         context: Arc<CryptoAuth>,
@@ -1298,13 +3491,60 @@ impl Session {
         require_auth: bool,
         display_name: Option<String>,
     ) -> Result<Self> {
-        let now = context.event_base.current_time_seconds();
-
         if her_pub_key.is_zero() {
             return Err(KeyError::ZeroPublicKey.into());
         }
         let her_ip6 = ip6_from_key(&her_pub_key.raw());
 
+        Self::new_inner(context, her_pub_key, her_ip6, require_auth, display_name)
+    }
+
+    /// Create a session from the other node's public key in the usual base32 ".k" form,
+    /// as found in config files and RPC calls.
+    pub fn new_from_key_str(
+        context: Arc<CryptoAuth>,
+        key_str: &str,
+        require_auth: bool,
+        display_name: Option<String>,
+    ) -> Result<Self, SessionError> {
+        let her_pub_key =
+            PublicKey::try_from(key_str.to_string()).map_err(|_| SessionError::BadKeyString)?;
+        Self::new(context, her_pub_key, require_auth, display_name).map_err(|err| {
+            match err.downcast::<KeyError>() {
+                Ok(err) => SessionError::Key(err),
+                Err(err) => err
+                    .downcast::<SessionError>()
+                    .unwrap_or(SessionError::Key(KeyError::BadPublicKey)),
+            }
+        })
+    }
+
+    /// Create a session knowing only the other node's ip6 address.
+    /// Her public key is learned from the first handshake packet whose key hashes to `her_ip6`.
+    fn new_with_ip6(
+        context: Arc<CryptoAuth>,
+        her_ip6: [u8; 16],
+        require_auth: bool,
+        display_name: Option<String>,
+    ) -> Result<Self> {
+        if her_ip6 == [0; 16] {
+            return Err(KeyError::ZeroIp6.into());
+        }
+        let her_pub_key = PublicKey::from([0; 32]);
+
+        Self::new_inner(context, her_pub_key, her_ip6, require_auth, display_name)
+    }
+
+    fn new_inner(
+        context: Arc<CryptoAuth>,
+        her_pub_key: PublicKey,
+        her_ip6: [u8; 16],
+        require_auth: bool,
+        display_name: Option<String>,
+    ) -> Result<Self> {
+        let now = context.clock.now_seconds();
+        let span = trace::SessionSpan::new(her_ip6, display_name.as_deref());
+
         let (mut plaintext, plain_pvt) = iface::new("CryptoAuth::Session plaintext");
         let (mut ciphertext, cipher_pvt) = iface::new("CryptoAuth::Session ciphertext");
 
@@ -1315,15 +3555,30 @@ impl Session {
                 reset_after_inactivity_seconds: Self::DEFAULT_RESET_AFTER_INACTIVITY_SECONDS,
                 setup_reset_after_inactivity_seconds:
                     Self::DEFAULT_SETUP_RESET_AFTER_INACTIVITY_SECONDS,
-                shared_secret: [0; 32],
+                rekey_threshold: Self::DEFAULT_REKEY_THRESHOLD,
+                handshake_timeout_reported: false,
+                prev_session_key: None,
+                retired_her_temp_keys: Vec::new(),
+                rekey_overlap_seconds: Self::DEFAULT_REKEY_OVERLAP_SECONDS,
+                auto_grow: false,
+                auto_realign: false,
+                blind_keys: false,
+                responder_rekey: false,
+                drop_log: DropLog::default(),
+                shared_secret: SecretBuf32::default(),
                 her_temp_pub_key: [0; 32],
-                our_temp_priv_key: [0; 32],
+                our_temp_priv_key: SecretBuf32::default(),
                 our_temp_pub_key: [0; 32],
+                #[cfg(any(test, feature = "fuzz"))]
+                fixed_temp_priv_key: None,
                 password: None,
                 login: None,
                 next_nonce: State::Init as u32,
                 time_of_last_packet: now,
+                created_at: now,
+                clock_regressions: 0,
                 auth_type: AuthType::Zero,
+                peer_auth_type: None,
                 is_initiator: false,
                 require_auth,
                 established: false,
@@ -1333,19 +3588,33 @@ impl Session {
             her_ip6,
             plain_pvt,
             cipher_pvt,
+            state_change_handler: RwLock::new(None),
+            reported_state: Mutex::new(State::Init),
+            span,
+            bytes_encrypted: AtomicU64::new(0),
+            bytes_decrypted: AtomicU64::new(0),
+            realigned_messages: AtomicU64::new(0),
+            last_error: AtomicU32::new(DecryptErr::None as u32),
+            stats_cache: StatsCache::default(),
         });
 
+        let max_sessions = inner.context.max_sessions_per_ip6.load(Ordering::Relaxed);
+        inner.context.sessions.register(&inner, max_sessions)?;
+
         plaintext.set_receiver(PlaintextRecv(Arc::clone(&inner)));
         ciphertext.set_receiver(CiphertextRecv(Arc::clone(&inner)));
 
-        Ok(Session{inner, ifaces: Mutex::new(Some((plaintext,ciphertext)))})
+        Ok(Session {
+            inner,
+            ifaces: Mutex::new(Some((plaintext, ciphertext))),
+        })
     }
 }
 
 impl Session {
     /// Encrypts the message inplace. The new content of `msg` should be sent to the peer.
     fn encrypt_msg(&self, msg: &mut Message) -> Result<()> {
-        SessionMut::encrypt(&self.inner, msg)
+        SessionMut::encrypt(&self.inner, msg, &[])
     }
 
     /// Decrypt a packet from the peer inplace. If the msg is non-empty, it is the
@@ -1354,13 +3623,418 @@ impl Session {
     /// Additional messages might be sent to the peer (in the handshake phase),
     /// the corresponding iface is used in that case.
     fn decrypt_msg(&self, msg: &mut Message) -> Result<()> {
-        SessionMut::decrypt(&self.inner, msg)
+        SessionMut::decrypt(&self.inner, msg, &[])
+    }
+
+    /// Same as `encrypt_msg()`, but traffic packets are additionally authenticated
+    /// with the associated data `aad`, which is not sent.
+    /// Handshake packets are not bound to `aad`.
+    ///
+    /// Empty `aad` is equivalent to `encrypt_msg()`.
+    pub fn encrypt_with_aad(&self, msg: &mut Message, aad: &[u8]) -> Result<()> {
+        SessionMut::encrypt(&self.inner, msg, aad)
+    }
+
+    /// Same as `decrypt_msg()`, but traffic packets must have been encrypted with
+    /// the same associated data `aad`, otherwise decryption fails.
+    ///
+    /// Empty `aad` is equivalent to `decrypt_msg()`.
+    pub fn decrypt_with_aad(&self, msg: &mut Message, aad: &[u8]) -> Result<()> {
+        SessionMut::decrypt(&self.inner, msg, aad)
+    }
+
+    /// Encrypt the concatenation of `bufs` into `out`, copying the plaintext once
+    /// instead of first assembling it in a message. Any data in `out` is discarded.
+    ///
+    /// As with `encrypt_msg()`, `out` needs room for the plaintext and
+    /// `required_send_padding()` bytes in front of it, unless auto grow is enabled.
+    pub fn encrypt_vectored(&self, bufs: &[IoSlice<'_>], out: &mut Message) -> Result<()> {
+        out.clear();
+        if self.inner.session_mut.read().auto_grow {
+            let len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+            out.ensure_padding(len + self.required_send_padding())?;
+        }
+        for buf in bufs.iter().rev() {
+            out.push_bytes(buf)?;
+        }
+        self.encrypt_msg(out)
+    }
+
+    /// Decrypt a packet from the peer like `decrypt_msg()`, writing the plaintext to `out`
+    /// and returning its length. Hello and key packets carry a payload as traffic does.
+    ///
+    /// `out` is checked before decrypting, so that a packet is not lost because of it:
+    /// it must have room for the message minus the nonce and the authenticator. This bound
+    /// is conservative for hello and key packets, which lose the whole CryptoHeader.
+    pub fn decrypt_into(&self, msg: &mut Message, out: &mut [u8]) -> Result<usize, DecryptError> {
+        let required = msg.len().saturating_sub(SessionMut::TRAFFIC_SEND_PADDING);
+        if out.len() < required {
+            let problem = BufferProblem::OutputTooSmall {
+                len: out.len(),
+                required,
+            };
+            return Err(DecryptError::BadBuffer(problem));
+        }
+        self.decrypt_msg(msg).map_err(|err| {
+            err.downcast::<DecryptError>()
+                .unwrap_or(DecryptError::Internal("unexpected decrypt error"))
+        })?;
+        let len = msg.len();
+        out[..len].copy_from_slice(msg.bytes());
+        Ok(len)
+    }
+
+    /// Decrypt a packet from the peer given as plain bytes, returning the plaintext,
+    /// which for a hello or key packet is the payload it carries.
+    ///
+    /// Meant for untrusted input such as fuzzers feed: it never panics, whatever `bytes` are.
+    /// The message is built to fit `decrypt_msg()` whatever the length of `bytes`.
+    pub fn decrypt_untrusted(&self, bytes: &[u8]) -> Result<Vec<u8>, DecryptErr> {
+        let mut msg = Message::rnew((bytes.len() + 3) & !3);
+        msg.push_bytes(bytes).map_err(|_| DecryptErr::Internal)?;
+        // Data of an odd length starts misaligned and ends the capacity early, move it
+        if check_recv_buffer(&msg).is_err() {
+            msg.restage(msg.pad()).map_err(|_| DecryptErr::Internal)?;
+        }
+        self.decrypt_msg(&mut msg).map_err(|e| decrypt_err_of(&e))?;
+        Ok(msg.bytes().to_vec())
+    }
+
+    /// Check whether `decrypt_msg()` would accept `msg`, without changing the session
+    /// or consuming the nonce in the replay window, so the packet can still be decrypted.
+    ///
+    /// Only traffic packets of an established session can be checked, anything else
+    /// gives `DecryptErr::NoSession`.
+    pub fn can_decrypt(&self, msg: &Message) -> Result<(), DecryptErr> {
+        let nonce = match parse_packet(msg.bytes())? {
+            Packet::Traffic { nonce } => nonce,
+            Packet::Handshake { .. } => return Err(DecryptErr::NoSession),
+        };
+
+        let session = self.inner.session_mut.read();
+        if !session.established {
+            return Err(DecryptErr::NoSession);
+        }
+
+        let mut content = Message::rnew(msg.len());
+        content
+            .push_bytes(&msg.bytes()[4..])
+            .map_err(|_| DecryptErr::Internal)?;
+        let secret = bind_aad(&session.shared_secret, &[]);
+        if decrypt(
+            nonce,
+            &mut content,
+            *secret.as_bytes(),
+            session.is_initiator,
+        )
+        .is_err()
+        {
+            return Err(DecryptErr::Decrypt);
+        }
+
+        if !self.inner.replay_protector.lock().would_accept(nonce) {
+            return Err(DecryptErr::Replay);
+        }
+        Ok(())
+    }
+
+    /// Set her public key on a session which was created knowing only her ip6.
+    ///
+    /// The key must hash to the session's ip6. Setting the key which is already known is a no-op.
+    pub fn set_her_public_key(&self, key: PublicKey) -> Result<(), KeyError> {
+        if key.is_zero() {
+            return Err(KeyError::ZeroPublicKey);
+        }
+        let mut session = self.inner.session_mut.write();
+        if session.her_key_known() {
+            return if session.her_public_key.ct_eq(&key) {
+                Ok(())
+            } else {
+                Err(KeyError::KeyAlreadySet)
+            };
+        }
+        if ip6_from_key(key.raw()) != self.inner.her_ip6 {
+            return Err(KeyError::Ip6Mismatch);
+        }
+        session.her_public_key = key;
+        Ok(())
+    }
+
+    /// Set or clear the display name used in logs and returned by `get_name()`.
+    pub fn set_name(&self, name: Option<String>) {
+        self.inner.span.set_display_name(name.as_deref());
+        self.inner.session_mut.write().display_name = name;
+    }
+
+    /// The other node's temporary public key, if it has been learned during the handshake.
+    pub fn her_temp_pubkey(&self) -> Option<[u8; 32]> {
+        let session = self.inner.session_mut.read();
+        if session.her_temp_pub_key.is_zero() {
+            None
+        } else {
+            Some(session.her_temp_pub_key)
+        }
+    }
+
+    /// The method this session uses to authenticate with the other party.
+    pub fn auth_type(&self) -> AuthType {
+        self.inner.session_mut.read().auth_type
+    }
+
+    /// Whether this session authenticates with a password (with or without a login).
+    pub fn is_authenticated_mode(&self) -> bool {
+        self.inner.session_mut.read().auth_type != AuthType::Zero
+    }
+
+    /// The login this session authenticates with, `None` unless it uses a login and password.
+    pub fn login(&self) -> Option<ByteString> {
+        let session = self.inner.session_mut.read();
+        match session.auth_type {
+            AuthType::Two => session.login.clone(),
+            _ => None,
+        }
+    }
+
+    /// The method the other party authenticated with in the last accepted handshake packet,
+    /// `None` if no handshake packet was accepted yet.
+    pub fn peer_auth_type(&self) -> Option<AuthType> {
+        self.inner.session_mut.read().peer_auth_type
+    }
+
+    /// The nonce which will be used for the next outgoing packet.
+    pub fn next_nonce(&self) -> u32 {
+        self.inner.session_mut.read().next_nonce
+    }
+
+    /// True if the session is approaching nonce exhaustion and should be rekeyed.
+    pub fn rekey_recommended(&self) -> bool {
+        self.inner.session_mut.read().rekey_recommended()
+    }
+
+    /// DANGER: the shared secret of the established session, which decrypts all its traffic.
+    ///
+    /// Only built with the `insecure-diagnostics` feature, for decoding captured traffic
+    /// in a lab. `None` until the session is established.
+    #[cfg(feature = "insecure-diagnostics")]
+    pub fn export_shared_secret(&self) -> Option<[u8; 32]> {
+        let session = self.inner.session_mut.read();
+        if session.established {
+            Some(*session.shared_secret.as_bytes())
+        } else {
+            None
+        }
+    }
+
+    /// Number of times the clock was seen going backwards, after which the session's
+    /// timeouts were restarted.
+    pub fn clock_regressions(&self) -> u32 {
+        self.inner.session_mut.read().clock_regressions
+    }
+
+    /// Plaintext bytes encrypted and decrypted by this session, as `(encrypted, decrypted)`.
+    ///
+    /// These count every byte since the session was created, resets don't clear them.
+    pub fn byte_stats(&self) -> (u64, u64) {
+        (
+            self.inner.bytes_encrypted.load(Ordering::Relaxed),
+            self.inner.bytes_decrypted.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Collect the state and the counters of this session for admin tools.
+    ///
+    /// This doesn't wait for the replay protector: if another thread holds it, the replay
+    /// counters are those of an earlier dump and `stale_stats` is set.
+    pub fn dump(&self) -> SessionDump {
+        let now = self.inner.context.clock.now_seconds();
+        let session = self.inner.session_mut.read();
+        let (stats, stale_stats) = self.inner.cached_stats();
+        let last_error = self.inner.last_error.load(Ordering::Relaxed);
+        SessionDump {
+            state: session.get_state(),
+            her_ip6: Ipv6Addr::from(self.inner.her_ip6).to_string(),
+            her_public_key: if session.her_key_known() {
+                Some(session.her_public_key.to_base32())
+            } else {
+                None
+            },
+            display_name: session.display_name.clone(),
+            is_initiator: session.is_initiator,
+            next_nonce: session.next_nonce,
+            idle_seconds: now.saturating_sub(session.time_of_last_packet),
+            bytes_encrypted: self.inner.bytes_encrypted.load(Ordering::Relaxed),
+            bytes_decrypted: self.inner.bytes_decrypted.load(Ordering::Relaxed),
+            received_packets: stats.received_packets,
+            duplicate_packets: stats.duplicate_packets,
+            lost_packets: stats.lost_packets,
+            received_unexpected: stats.received_unexpected,
+            stale_stats,
+            last_error: DecryptErr::try_from(last_error)
+                .ok()
+                .filter(|err| *err != DecryptErr::None),
+        }
+    }
+
+    /// Number of messages which were not 4 byte aligned and had to be moved,
+    /// see `set_auto_realign()`.
+    pub fn realigned_messages(&self) -> u64 {
+        self.inner.realigned_messages.load(Ordering::Relaxed)
+    }
+
+    /// Set the nonce value after which a rekey is recommended.
+    pub fn set_rekey_threshold(&self, threshold: u32) {
+        self.inner.session_mut.write().rekey_threshold = threshold.min(SessionMut::MAX_NONCE);
+    }
+
+    /// Set for how many seconds traffic encrypted with the key from before a rekey (ours or
+    /// the peer's) is still accepted once the new key is established, zero to stop at once.
+    pub fn set_rekey_overlap(&self, seconds: u32) {
+        self.inner.session_mut.write().rekey_overlap_seconds = seconds;
+    }
+
+    /// Allow `rekey()` when this session is the responder, off by default.
+    ///
+    /// Only enable this if the peer is known to accept a rekey by the responder: the C
+    /// CryptoAuth and older versions of this one drop key packets on an established session
+    /// with `KEY_PKT_ESTABLISHED_SESSION`, so everything sent after the rekey would be lost.
+    pub fn set_responder_rekey(&self, enabled: bool) {
+        self.inner.session_mut.write().responder_rekey = enabled;
+    }
+
+    /// If enabled, a message passed to `encrypt_msg()` with less padding than
+    /// `required_send_padding()` is moved into a bigger buffer instead of failing.
+    ///
+    /// Likewise a message passed to `decrypt_msg()` which is misaligned or has a bad capacity
+    /// is moved instead of failing with `DecryptError::BadBuffer`.
+    ///
+    /// Only messages whose buffer is owned by Rust can be moved, see `Message::restage()`.
+    pub fn set_auto_grow(&self, enabled: bool) {
+        self.inner.session_mut.write().auto_grow = enabled;
+    }
+
+    /// If enabled, the permanent keys are hidden from anyone watching the handshake. Off by
+    /// default.
+    ///
+    /// A blinded hello carries a one-time key where the permanent key goes, the real key is
+    /// sealed so that only her node can read it and checked against her key as usual. In key
+    /// packets the key field is random, the initiator knows the key of the other node already.
+    ///
+    /// Hellos can't negotiate this, both nodes must be configured for it out of band. A node
+    /// which doesn't expect blinded hellos drops them (with `WRONG_PERM_PUBKEY` if it knows her
+    /// key) and a session with blinding enabled drops other hellos with `DecryptErr::Blinding`.
+    /// The sessions `try_handshake()` creates can't use it, they take the key from the hello.
+    ///
+    /// A blinded hello needs 48 more bytes of padding, see `required_send_padding()`.
+    pub fn set_key_blinding(&self, enabled: bool) {
+        self.inner.session_mut.write().blind_keys = enabled;
+    }
+
+    /// If enabled, a message passed to `encrypt_msg()` or `decrypt_msg()` which is not
+    /// 4 byte aligned is moved within its buffer instead of failing, see `Message::realign()`.
+    ///
+    /// This costs a copy of the message, `realigned_messages()` tells how often it happens.
+    pub fn set_auto_realign(&self, enabled: bool) {
+        self.inner.session_mut.write().auto_realign = enabled;
+    }
+
+    /// Padding the message passed to the next `encrypt_msg()` must have: the size of the
+    /// CryptoHeader until the handshake is done, then the authenticator and the nonce but
+    /// never less than 36 bytes, which is what was always required.
+    ///
+    /// A session which is reset needs handshake padding again, transports which can't
+    /// reallocate should keep that much.
+    pub fn required_send_padding(&self) -> usize {
+        self.inner.session_mut.read().required_send_padding()
+    }
+
+    /// Padding the message passed to `decrypt_msg()` should have.
+    /// Decryption only ever shrinks the message, this is the 12 byte floor callers have
+    /// always reserved.
+    pub fn required_recv_padding(&self) -> usize {
+        SessionMut::MIN_RECV_PADDING
+    }
+
+    /// True if the session is still in `Init` or `SentHello` more than `max_setup_age`
+    /// seconds after it was created, with nothing received from the peer in that time.
+    ///
+    /// `reset_if_timeout()` never resets a session which sent a hello, so a manager can use
+    /// this to reap half-open sessions which will never be answered.
+    pub fn is_abandoned(&self, max_setup_age: u32) -> bool {
+        self.inner
+            .session_mut
+            .read()
+            .is_abandoned(&*self.inner.context.clock, max_setup_age)
+    }
+
+    /// Change the credentials used to authenticate with the other party, like `set_auth()`,
+    /// but without dropping an established session.
+    ///
+    /// `set_auth()` resets the session so the new credentials are used right away. Here an
+    /// established session keeps running and the new credentials are only sent in the next
+    /// handshake, after a `rekey()` or a reset. A session which is not established is reset
+    /// as with `set_auth()`.
+    pub fn stage_auth(&self, password: Option<ByteString>, login: Option<ByteString>) {
+        self.inner.session_mut.write().stage_auth(password, login);
+        self.inner.notify_state_change();
+    }
+
+    /// Use `key` as the temp private key of every following hello or key packet
+    /// instead of a random one.
+    #[cfg(any(test, feature = "fuzz"))]
+    fn set_fixed_temp_priv_key(&self, key: [u8; 32]) {
+        self.inner.session_mut.write().fixed_temp_priv_key = Some(key);
+    }
+
+    /// Start a new handshake on an established session without dropping it.
+    ///
+    /// The session keeps its role and never goes back to `State::Init`: the initiator makes
+    /// up a new temp key and moves to `SentHello`, its next packet is a repeat hello. The
+    /// responder moves to `ReceivedHello`, its next packet is a key packet with a new temp key.
+    /// Both carry their payload as usual. Until the new handshake completes, traffic from
+    /// the peer encrypted with the old key is still accepted, so packets in flight at the
+    /// time of the rekey are not lost.
+    ///
+    /// The initiator drops key packets carrying a temp key it already saw, so a replayed
+    /// key packet can't restart the handshake. After a few rekeys by the responder, the
+    /// initiator drops the next one and rekeys itself instead, with a hello.
+    ///
+    /// The responder can only rekey if `set_responder_rekey()` was enabled, because peers
+    /// running the C CryptoAuth drop its key packets. The initiator's rekey works with any peer.
+    pub fn rekey(&self) -> Result<()> {
+        let mut session = self.inner.session_mut.write();
+        ensure!(
+            session.established,
+            EncryptError,
+            "session is not established"
+        );
+        ensure!(
+            session.is_initiator || session.responder_rekey,
+            EncryptError,
+            "the peer may not accept a rekey by the responder"
+        );
+        debug::log(&session, || "Rekeying session");
+        session.rekey(&self.inner);
+        self.inner
+            .reset_replay_protector(&mut session, ReplayReset::Rekey);
+        drop(session);
+        self.inner.notify_state_change();
+        Ok(())
+    }
+
+    /// Set the handler which is called with the new state whenever the session moves to
+    /// another state, e.g. becomes `Established` or is reset to `Init`.
+    ///
+    /// The handler is called without holding the session lock, but on the thread which is
+    /// encrypting or decrypting, so it should be fast and must not block.
+    pub fn on_state_change(&self, handler: Box<StateChangeHandler>) {
+        *self.inner.state_change_handler.write() = Some(handler);
     }
 }
 
 impl SessionTrait for Session {
     fn set_auth(&self, password: Option<ByteString>, login: Option<ByteString>) {
-        self.inner.session_mut.write().set_auth(password, login)
+        self.inner.session_mut.write().set_auth(password, login);
+        self.inner.notify_state_change();
     }
 
     fn get_state(&self) -> State {
@@ -1380,31 +4054,18 @@ impl SessionTrait for Session {
     }
 
     fn stats(&self) -> CryptoStats {
-        // Stats come from the replay protector
-        let rp = self.inner.replay_protector.lock();
-        let stats = rp.stats();
-        CryptoStats {
-            lost_packets: stats.lost_packets as u64,
-            received_unexpected: stats.received_unexpected as u64,
-            received_packets: stats.received_packets as u64,
-            duplicate_packets: stats.duplicate_packets as u64,
-            noise_proto: false,
-        }
+        self.inner.stats()
     }
 
     fn reset_if_timeout(&self) {
         self.inner.session_mut
             .write()
-            .reset_if_timeout(&self.inner.context.event_base)
+            .reset_if_timeout(&*self.inner.context.clock);
+        self.inner.notify_state_change();
     }
 
     fn reset(&self) {
-        // Make sure we're write() session_mut when we do the replay because
-        // decrypt threads will read() session_mut
-        let mut session_mut = self.inner.session_mut.write();
-        let mut replay_protector = self.inner.replay_protector.lock();
-        replay_protector.reset();
-        session_mut.reset();
+        self.inner.reset();
     }
 
     fn her_key_known(&self) -> bool {
@@ -1416,7 +4077,19 @@ impl SessionTrait for Session {
     }
 
     fn tick(&self, _: &mut Allocator) -> Result<Option<Message>> {
-        Ok(None)
+        let context = &self.inner.context;
+        let stalled = self
+            .inner
+            .session_mut
+            .write()
+            .check_handshake_timeout(&*context.clock);
+        // Call the handler without holding the session lock.
+        if let Some(state) = stalled {
+            if let Some(handler) = &*context.handshake_timeout_handler.read() {
+                handler(self.inner.her_ip6, state);
+            }
+        }
+        Ok(None)
     }
 
     fn cjdns_ver(&self) -> u32 {
@@ -1424,6 +4097,16 @@ impl SessionTrait for Session {
     }
 }
 
+impl CryptoSession for Session {
+    fn encrypt(&self, msg: &mut Message) -> Result<()> {
+        self.encrypt_msg(msg)
+    }
+
+    fn decrypt(&self, msg: &mut Message) -> Result<()> {
+        self.decrypt_msg(msg)
+    }
+}
+
 /// Get a shared secret.
 ///
 /// Arg `password_hash` is a 32 byte value known to both ends,
@@ -1433,10 +4116,11 @@ impl SessionTrait for Session {
 /// If this field is `None`, the secret will be generated without the password.
 #[inline]
 fn get_shared_secret(
-    my_private_key: [u8; 32],
+    my_private_key: &[u8; 32],
     her_public_key: [u8; 32],
-    password_hash: Option<[u8; 32]>,
-) -> [u8; 32] {
+    password_hash: Option<&SecretBuf32>,
+    log_keys: bool,
+) -> SecretBuf32 {
     let output_secret = if let Some(password_hash) = password_hash {
         use sodiumoxide::crypto::scalarmult::curve25519::*;
 
@@ -1456,29 +4140,31 @@ fn get_shared_secret(
         let buff = Buff {
             components: Components {
                 key: {
-                    let n = Scalar(my_private_key);
+                    let n = Scalar(*my_private_key);
                     let p = GroupElement(her_public_key);
-                    scalarmult(&n, &p)
-                        .expect("crypto::scalarmult::curve25519")
-                        .0
+                    // Her key comes from the peer and libsodium refuses one of low order.
+                    // The key is then all zero, as `precompute()` leaves it without a password.
+                    let key = scalarmult(&n, &p).map(|k| k.0).unwrap_or([0; 32]);
+                    n.wipe(); // Wipe sensitive data on the stack
+                    key
                 },
-                passwd: { password_hash },
+                passwd: *password_hash.as_bytes(),
             },
         };
 
         let bytes = unsafe { buff.bytes };
-        crypto_hash_sha256(&bytes)
+        SecretBuf32::from(crypto_hash_sha256(&bytes))
     } else {
         use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::*;
 
         let pk = PublicKey(her_public_key);
-        let sk = SecretKey(my_private_key);
+        let sk = SecretKey(*my_private_key);
 
         let key = precompute(&pk, &sk);
-        key.0
+        SecretBuf32::from(key.0)
     };
 
-    if CryptoAuth::LOG_KEYS {
+    if log_keys {
         log::debug!(
             concat!(
                 "Generated a shared secret:\n",
@@ -1487,40 +4173,230 @@ fn get_shared_secret(
                 "    passwordHash={}\n",
                 "    outputSecret={}\n",
             ),
-            debug::hex_pub_key(&my_private_key),
-            debug::hex_key(&her_public_key),
-            debug::hex_key_opt(password_hash.as_ref()),
-            debug::hex_key(&output_secret),
+            debug::base32_pub_key(my_private_key),
+            debug::base32_key(&her_public_key),
+            debug::hex_key_opt(password_hash.map(SecretBuf32::as_bytes)),
+            debug::hex_key(output_secret.as_bytes()),
         );
     }
 
     output_secret
 }
 
-#[inline]
-fn hash_password(login: &[u8], password: &[u8], auth_type: AuthType) -> ([u8; 32], Challenge) {
-    let secret_out = crypto_hash_sha256(password);
+/// The secret sealing the permanent key in a blinded hello, see `SessionMut::blind_hello()`.
+///
+/// It is hashed apart from `get_shared_secret()`, which gives the same secret for the same
+/// two keys: a hello which isn't blinded must not open as the seal of a blinded one.
+fn get_blinding_secret(
+    my_private_key: &[u8; 32],
+    her_public_key: [u8; 32],
+    log_keys: bool,
+) -> SecretBuf32 {
+    const LABEL: &[u8] = b"CryptoAuth blinded hello";
+    let shared_secret = get_shared_secret(my_private_key, her_public_key, None, log_keys);
+    hash_secret(&shared_secret, LABEL)
+}
+
+/// A packet from the peer, as decoded by `parse_packet()`.
+#[derive(Debug, Clone)]
+pub enum Packet {
+    /// Hello or key packet.
+    Handshake { nonce: u32, header: HandshakeHeader },
+
+    /// Traffic packet, possibly the final step of the handshake.
+    Traffic { nonce: u32 },
+}
 
-    let tmp_buf = match auth_type {
-        AuthType::One => crypto_hash_sha256(&secret_out),
-        AuthType::Two => crypto_hash_sha256(login),
-        _ => panic!("Unsupported auth type [{}]", auth_type as u8),
+/// The fields of the CryptoHeader of a handshake packet which are read before it is
+/// decrypted. They are copied out since the message is decrypted in place; the encrypted
+/// temp key and its authenticator are only read as part of the decryption.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct HandshakeHeader {
+    pub auth: Challenge,
+    pub handshake_nonce: [u8; 24],
+    pub public_key: [u8; 32],
+}
+
+/// Decode the nonce and, for handshake packets, the CryptoHeader at the beginning of `bytes`.
+///
+/// This is the only place where the layout of packets from the peer is trusted, it does not
+/// depend on any session state so fuzzers can drive it directly.
+pub fn parse_packet(bytes: &[u8]) -> Result<Packet, DecryptErr> {
+    // The nonce and the authenticator, at least
+    if bytes.len() < 20 {
+        return Err(DecryptErr::Runt);
+    }
+    let mut nonce = [0; 4];
+    nonce.copy_from_slice(&bytes[0..4]);
+    let nonce = u32::from_be_bytes(nonce);
+
+    if nonce >= Nonce::FirstTrafficPacket as u32 {
+        return Ok(Packet::Traffic { nonce });
+    }
+
+    if bytes.len() < CryptoHeader::SIZE {
+        return Err(DecryptErr::Runt);
+    }
+    // Offsets of the fields in `CryptoHeader`
+    const AUTH_OFFS: usize = 4;
+    const HANDSHAKE_NONCE_OFFS: usize = AUTH_OFFS + Challenge::SIZE;
+    const PUBLIC_KEY_OFFS: usize = HANDSHAKE_NONCE_OFFS + 24;
+
+    let mut auth = [0; Challenge::SIZE];
+    auth.copy_from_slice(&bytes[AUTH_OFFS..HANDSHAKE_NONCE_OFFS]);
+    let auth = Challenge::parse(&auth).ok_or(DecryptErr::UnrecognizedAuth)?;
+    // AuthType Three is only used by the Noise protocol.
+    if auth.auth_type() == AuthType::Three {
+        return Err(DecryptErr::UnrecognizedAuth);
+    }
+    let mut header = HandshakeHeader {
+        auth,
+        handshake_nonce: [0; 24],
+        public_key: [0; 32],
+    };
+    header
+        .handshake_nonce
+        .copy_from_slice(&bytes[HANDSHAKE_NONCE_OFFS..PUBLIC_KEY_OFFS]);
+    header
+        .public_key
+        .copy_from_slice(&bytes[PUBLIC_KEY_OFFS..PUBLIC_KEY_OFFS + 32]);
+
+    Ok(Packet::Handshake { nonce, header })
+}
+
+/// Kind of a packet from the peer, as told by `classify_packet()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    Hello,
+    RepeatHello,
+    Key,
+    RepeatKey,
+    /// Traffic packet, possibly the final step of the handshake.
+    Traffic,
+}
+
+/// Tell the kind of a packet from the peer by its nonce, without any crypto and without
+/// changing `msg`, e.g. to pick the session to hand it to.
+///
+/// `None` if the packet is too short to be valid.
+pub fn classify_packet(msg: &Message) -> Option<PacketKind> {
+    let bytes = msg.bytes();
+    // The nonce and the authenticator, at least
+    if bytes.len() < 20 {
+        return None;
+    }
+    let nonce = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    let kind = match nonce {
+        n if n == Nonce::Hello as u32 => PacketKind::Hello,
+        n if n == Nonce::RepeatHello as u32 => PacketKind::RepeatHello,
+        n if n == Nonce::Key as u32 => PacketKind::Key,
+        n if n == Nonce::RepeatKey as u32 => PacketKind::RepeatKey,
+        _ => return Some(PacketKind::Traffic),
     };
+    if bytes.len() < CryptoHeader::SIZE {
+        return None;
+    }
+    Some(kind)
+}
+
+/// Clock of the fuzz targets, always at the same time.
+#[cfg(any(test, feature = "fuzz"))]
+struct FixedClock(u32);
+
+#[cfg(any(test, feature = "fuzz"))]
+impl Clock for FixedClock {
+    fn now_seconds(&self) -> u32 {
+        self.0
+    }
+}
+
+/// Decrypt `data` as if it came from the peer at time `now`, both in a new session and in
+/// one which has sent a hello. Used by the `decrypt` fuzz target, it must never panic.
+#[cfg(any(test, feature = "fuzz"))]
+pub fn fuzz_decrypt(data: &[u8], now: u32) {
+    let our_key = PrivateKey::from([1; 32]);
+    let her_key = crypto_scalarmult_curve25519_base(&PrivateKey::from([2; 32]));
+    let ca = Arc::new(CryptoAuth::new(
+        Some(our_key),
+        FixedClock(now),
+        Random::Fake,
+    ));
+
+    let new_session = || Session::new(Arc::clone(&ca), her_key.clone(), false, None);
+    let fresh = new_session().expect("new session");
+    let sent_hello = new_session().expect("new session");
+    let mut hello = Message::rnew(CryptoHeader::SIZE + 16);
+    hello.push_bytes(&[0; 16]).expect("push");
+    sent_hello.encrypt_msg(&mut hello).expect("encrypt hello");
+
+    for session in &[fresh, sent_hello] {
+        let _ = session.decrypt_untrusted(data);
+    }
+}
 
-    let mut challenge_out = Challenge {
-        auth_type,
-        lookup: [0; 7],
-        require_packet_auth_and_derivation_count: 0,
-        additional: 0,
+/// Decrypt `data` as if it came from the peer of an established session.
+/// Used by the `decrypt_established` fuzz target, it must never panic.
+#[cfg(any(test, feature = "fuzz"))]
+pub fn fuzz_decrypt_established(data: &[u8]) {
+    let new_session = |key: [u8; 32], her_key: [u8; 32], temp_key: [u8; 32]| {
+        let her_key = crypto_scalarmult_curve25519_base(&PrivateKey::from(her_key));
+        let ca = CryptoAuth::new(Some(PrivateKey::from(key)), FixedClock(0), Random::Fake);
+        let session = Session::new(Arc::new(ca), her_key, false, None).expect("new session");
+        // Different temp keys on both sides, as with a real random source
+        session.set_fixed_temp_priv_key(temp_key);
+        session
     };
-    challenge_out.lookup.copy_from_slice(&tmp_buf[1..8]);
+    let our_session = new_session([1; 32], [2; 32], [3; 32]);
+    let her_session = new_session([2; 32], [1; 32], [4; 32]);
+
+    for (from, to) in &[(&our_session, &her_session), (&her_session, &our_session)].repeat(2) {
+        let mut msg = Message::rnew(CryptoHeader::SIZE + 16);
+        msg.push_bytes(&[0; 16]).expect("push");
+        from.encrypt_msg(&mut msg).expect("encrypt");
+        to.decrypt_msg(&mut msg).expect("decrypt");
+    }
+    let _ = our_session.decrypt_untrusted(data);
+}
+
+/// Derive the key used for a traffic packet with associated data `aad`, as sha256(secret || aad).
+///
+/// The AAD is bound through key derivation only: it is not sent and not checked as AEAD
+/// associated data, so a packet sealed with different AAD simply fails to decrypt.
+/// Empty `aad` leaves the secret unchanged, keeping compatibility with peers not using it.
+fn bind_aad(secret: &SecretBuf32, aad: &[u8]) -> SecretBuf32 {
+    if aad.is_empty() {
+        return secret.clone_secret();
+    }
+    hash_secret(secret, aad)
+}
+
+/// sha256(secret || label), hashed without copying the secret into a buffer of its own.
+fn hash_secret(secret: &SecretBuf32, label: &[u8]) -> SecretBuf32 {
+    // libsodium wipes the hash state when it is finalized.
+    let mut state = sha256::State::new();
+    state.update(secret.as_bytes());
+    state.update(label);
+    let sha256::Digest(hash) = state.finalize();
+    SecretBuf32::from(hash)
+}
 
-    (secret_out, challenge_out)
+/// Derive the secret and the challenge lookup for a password, as both ends of a handshake do.
+///
+/// This is part of the wire protocol, so it must not change: the secret is sha256(password)
+/// and the lookup is taken from sha256(secret) for AuthType 1 or sha256(login) for AuthType 2.
+/// For AuthType 1 this means every hello carries 56 bits of an unsalted, fast hash of the
+/// password, which an eavesdropper can check password guesses against offline, so passwords
+/// must be long random strings rather than anything which could be guessed.
+#[inline]
+fn hash_password(login: &[u8], password: &[u8], auth_type: AuthType) -> (SecretBuf32, Challenge) {
+    Challenge::derive(login, password, auth_type)
 }
 
 /// Encrypt a packet.
 #[inline]
-fn encrypt(nonce: u32, msg: &mut Message, secret: [u8; 32], is_initiator: bool) {
+fn encrypt(nonce: u32, msg: &mut Message, secret: [u8; 32], is_initiator: bool) -> Result<(), ()> {
     #[repr(C)]
     union Nonce {
         ints: [u32; 2],
@@ -1532,7 +4408,24 @@ fn encrypt(nonce: u32, msg: &mut Message, secret: [u8; 32], is_initiator: bool)
         nonce_as.ints[offs] = nonce.to_le(); // Little-endian nonce
         nonce_as.bytes
     };
-    encrypt_rnd_nonce(nonce_bytes, msg, secret);
+    encrypt_rnd_nonce(nonce_bytes, msg, secret)
+}
+
+/// Check the message buffer fits what `Session::decrypt_msg()` requires.
+fn check_recv_buffer(msg: &Message) -> Result<(), BufferProblem> {
+    if !msg.is_aligned_to(4) {
+        return Err(BufferProblem::Alignment {
+            addr: msg.data_ptr(),
+            align: 4,
+        });
+    }
+    if msg.cap() % 4 != 0 {
+        return Err(BufferProblem::Capacity {
+            cap: msg.cap(),
+            multiple: 4,
+        });
+    }
+    Ok(())
 }
 
 /// Decrypt a packet.
@@ -1555,7 +4448,7 @@ fn decrypt(nonce: u32, msg: &mut Message, secret: [u8; 32], is_initiator: bool)
 /// Encrypt and authenticate.
 /// Grows the message by 16 bytes.
 #[inline]
-fn encrypt_rnd_nonce(nonce: [u8; 24], msg: &mut Message, secret: [u8; 32]) {
+fn encrypt_rnd_nonce(nonce: [u8; 24], msg: &mut Message, secret: [u8; 32]) -> Result<(), ()> {
     //msg.push_bytes(&[0; 32]).expect("pad >= 32");
 
     {
@@ -1565,14 +4458,17 @@ fn encrypt_rnd_nonce(nonce: [u8; 24], msg: &mut Message, secret: [u8; 32]) {
         let key = PrecomputedKey(secret);
         //TODO this data copying is suboptimal. Need proper fn binding.
         let encrypted = seal_precomputed(bytes, &nonce, &key); // adds 16 bytes
-        msg.push_bytes(&[0; 16]).expect("pad >= 16"); // also grow orig msg
+        msg.push_bytes(&[0; 16]).map_err(|_| ())?; // also grow orig msg
         let dest = msg.bytes_mut();
-        assert_eq!(dest.len(), encrypted.len());
+        // Unreachable: sealing adds exactly the 16 bytes pushed
+        debug_assert_eq!(dest.len(), encrypted.len());
         dest.copy_from_slice(&encrypted);
     }
 
     // Pop 16 bytes despite we pushed 32
     //msg.discard_bytes(16).expect("discard");
+
+    Ok(())
 }
 
 /// Decrypt and authenticate.
@@ -1592,9 +4488,11 @@ fn decrypt_rnd_nonce(nonce: [u8; 24], msg: &mut Message, secret: [u8; 32]) -> Re
         let key = PrecomputedKey(secret);
         //TODO this data copying is suboptimal. Need proper fn binding.
         let decrypted = open_precomputed(bytes, &nonce, &key)?; // 16 bytes less
-        msg.discard_bytes(16).expect("discard 16 bytes"); // Also shrink msg
+        msg.discard_bytes(16).map_err(|_| ())?; // Also shrink msg
         let dest = msg.bytes_mut();
-        assert_eq!(dest.len(), decrypted.len());
+        if dest.len() != decrypted.len() {
+            return Err(());
+        }
         dest.copy_from_slice(&decrypted);
     }
 
@@ -1603,12 +4501,145 @@ fn decrypt_rnd_nonce(nonce: [u8; 24], msg: &mut Message, secret: [u8; 32]) -> Re
     Ok(())
 }
 
+/// Structured tracing of session operations, enabled with the `tracing` feature.
+///
+/// Only the peer ip6, the display name and a session id identify a session here,
+/// key material is never recorded.
+mod trace {
+    use anyhow::Result;
+
+    use super::types::State;
+    use super::SessionInner;
+
+    #[derive(Clone, Copy)]
+    pub(super) enum Op {
+        Encrypt,
+        Decrypt,
+        DecryptHandshake,
+    }
+
+    /// The span of a session, a new one is made for every session.
+    #[cfg(feature = "tracing")]
+    pub(super) struct SessionSpan(tracing::Span);
+
+    #[cfg(feature = "tracing")]
+    impl SessionSpan {
+        pub(super) fn new(her_ip6: [u8; 16], display_name: Option<&str>) -> Self {
+            use std::net::Ipv6Addr;
+            use std::sync::atomic::{AtomicU64, Ordering};
+
+            use tracing::field::Empty;
+
+            static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let her_ip6 = Ipv6Addr::from(her_ip6);
+            let span = SessionSpan(tracing::info_span!(
+                "session",
+                id,
+                %her_ip6,
+                display_name = Empty
+            ));
+            span.set_display_name(display_name);
+            span
+        }
+
+        pub(super) fn set_display_name(&self, display_name: Option<&str>) {
+            if let Some(display_name) = display_name {
+                self.0
+                    .record("display_name", &tracing::field::display(display_name));
+            }
+        }
+
+        pub(super) fn state_change(&self, from: State, to: State) {
+            tracing::info!(parent: &self.0, ?from, ?to, "state change");
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(super) struct SessionSpan;
+
+    #[cfg(not(feature = "tracing"))]
+    impl SessionSpan {
+        #[inline(always)]
+        pub(super) fn new(_her_ip6: [u8; 16], _display_name: Option<&str>) -> Self {
+            SessionSpan
+        }
+
+        #[inline(always)]
+        pub(super) fn set_display_name(&self, _display_name: Option<&str>) {}
+
+        #[inline(always)]
+        pub(super) fn state_change(&self, _from: State, _to: State) {}
+    }
+
+    /// Run `f` inside a span carrying the peer ip6 together with the session state and
+    /// the packet nonce returned by `info`. Failed decryptions are recorded as events.
+    ///
+    /// Encryption and decryption are in the span of the session `sess`,
+    /// handshakes in the span of the decryption.
+    #[cfg(feature = "tracing")]
+    pub(super) fn instrument<T, I, F>(op: Op, sess: &SessionInner, info: I, f: F) -> Result<T>
+    where
+        I: FnOnce() -> (State, Option<u32>),
+        F: FnOnce() -> Result<T>,
+    {
+        use std::net::Ipv6Addr;
+
+        use tracing::field::Empty;
+
+        use super::DecryptError;
+
+        if !tracing::enabled!(tracing::Level::DEBUG) {
+            return f();
+        }
+
+        let (state, nonce) = info();
+        let her_ip6 = Ipv6Addr::from(sess.her_ip6);
+        let parent = &sess.span.0;
+        let span = match op {
+            Op::Encrypt => {
+                tracing::debug_span!(parent: parent, "encrypt", %her_ip6, ?state, nonce = Empty)
+            }
+            Op::Decrypt => {
+                tracing::debug_span!(parent: parent, "decrypt", %her_ip6, ?state, nonce = Empty)
+            }
+            Op::DecryptHandshake => {
+                tracing::debug_span!("decrypt_handshake", %her_ip6, ?state, nonce = Empty)
+            }
+        };
+        if let Some(nonce) = nonce {
+            span.record("nonce", &nonce);
+        }
+        let _enter = span.enter();
+
+        let res = f();
+        if let (Op::Decrypt, Err(e)) = (op, &res) {
+            match e.downcast_ref::<DecryptError>() {
+                Some(DecryptError::DecryptErr(err)) => tracing::debug!(err = %err, "DROP"),
+                _ => tracing::debug!(err = %e, "DROP internal error"),
+            }
+        }
+        res
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[inline(always)]
+    pub(super) fn instrument<T, I, F>(_op: Op, _sess: &SessionInner, _info: I, f: F) -> Result<T>
+    where
+        I: FnOnce() -> (State, Option<u32>),
+        F: FnOnce() -> Result<T>,
+    {
+        f()
+    }
+}
+
 mod debug {
     use std::convert::TryFrom;
 
-    use cjdns_keys::{IpV6, PrivateKey};
+    use cjdns_keys::{IpV6, PrivateKey, PublicKey};
 
-    use crate::crypto::utils::crypto_scalarmult_curve25519_base;
+    use crate::crypto::keys::{Keypair, PublicKeyExt};
     use crate::crypto::zero::IsZero;
 
     use super::SessionMut;
@@ -1635,9 +4666,24 @@ mod debug {
         }
     }
 
+    /// Same as `log()`, for the lines of dropped packets, which are left out while the
+    /// session's drop log is suppressing them.
+    #[inline]
+    pub(super) fn log_drop<S, F>(session: &SessionMut, msg: F)
+    where
+        S: AsRef<str>,
+        F: FnOnce() -> S,
+    {
+        if !session.drop_log.is_quiet() {
+            log(session, msg);
+        }
+    }
+
     #[inline]
     fn get_ip6(session: &SessionMut) -> String {
-        assert!(session.her_key_known());
+        if !session.her_key_known() {
+            return "<unknown key>".to_string();
+        }
         match IpV6::try_from(&session.her_public_key) {
             Ok(ipv6) => ipv6.to_string(),
             Err(e) => e.to_string(),
@@ -1662,14 +4708,23 @@ mod debug {
         }
     }
 
+    /// Public keys are logged in the base32 ".k" form, which is what operators search for.
+    #[inline]
+    pub(super) fn base32_key(key: &[u8; 32]) -> String {
+        if key.is_zero() {
+            "NULL".to_string()
+        } else {
+            PublicKey::from(*key).to_base32()
+        }
+    }
+
     #[inline]
-    pub(super) fn hex_pub_key(private_key: &[u8; 32]) -> String {
+    pub(super) fn base32_pub_key(private_key: &[u8; 32]) -> String {
         if private_key.is_zero() {
             "NULL".to_string()
         } else {
-            let private_key = PrivateKey::from(*private_key);
-            let public_key = crypto_scalarmult_curve25519_base(&private_key);
-            hex_key(public_key.raw())
+            let keypair = Keypair::from_private(PrivateKey::from(*private_key));
+            base32_key(keypair.public().raw())
         }
     }
 }
@@ -1677,6 +4732,8 @@ mod debug {
 #[cfg(test)]
 mod tests {
     use std::cell::RefCell;
+    #[cfg(feature = "tracing")]
+    use std::net::Ipv6Addr;
     use std::rc::Rc;
     use std::sync::Arc;
 
@@ -1686,6 +4743,7 @@ mod tests {
     use crate::cffi;
     use crate::crypto::random::Random;
     use crate::external::interface::iface::Iface;
+    use crate::external::memory::allocator::Allocator;
     use crate::interface::wire::message::Message;
     use crate::util::events::EventBase;
     use crate::crypto::session::SessionTrait;
@@ -1726,7 +4784,7 @@ mod tests {
         let secret = [142_u8; 32];
 
         // Encrypt
-        super::encrypt_rnd_nonce(nonce, &mut msg1, secret);
+        super::encrypt_rnd_nonce(nonce, &mut msg1, secret).unwrap();
         unsafe {
             cffi::CryptoAuth_encryptRndNonce(
                 nonce[..].as_ptr(),
@@ -1760,6 +4818,60 @@ mod tests {
         assert_eq!(msg2.pop_bytes(LEN).unwrap(), TEST_STRING);
     }
 
+    #[test]
+    pub fn test_cross_crypto_header_layout() {
+        use crate::crypto::crypto_header::{AuthType, Challenge, CryptoHeader};
+
+        // Every field has different bytes, so a field read from the wrong offset is noticed
+        let mut header = CryptoHeader {
+            nonce: 0x0102_0304_u32.to_be(),
+            auth: Challenge {
+                auth_type: AuthType::Two,
+                lookup: [5, 6, 7, 8, 9, 10, 11],
+                require_packet_auth_and_derivation_count: u16::from_ne_bytes([12, 13]),
+                additional: u16::from_ne_bytes([14, 15]),
+            },
+            handshake_nonce: [16; 24],
+            public_key: [17; 32],
+            authenticator: [18; 16],
+            encrypted_temp_key: [19; 32],
+        };
+        let mut msg = mk_msg(256);
+        msg.push_bytes(header.as_mut_bytes()).unwrap();
+
+        let mut c_header = std::mem::MaybeUninit::<cffi::CryptoHeader>::zeroed();
+        let c_header = unsafe {
+            cffi::CryptoAuth_readHeader(msg.as_c_message(), c_header.as_mut_ptr());
+            c_header.assume_init()
+        };
+
+        assert_eq!(c_header.nonce, 0x0102_0304, "nonce");
+        assert_eq!(c_header.auth.type_, AuthType::Two as u8, "auth.auth_type");
+        assert_eq!(c_header.auth.lookup, header.auth.lookup, "auth.lookup");
+        assert_eq!(
+            c_header.auth.requirePacketAuthAndDerivationCount,
+            header.auth.require_packet_auth_and_derivation_count,
+            "auth.require_packet_auth_and_derivation_count"
+        );
+        assert_eq!(
+            c_header.auth.additional, header.auth.additional,
+            "auth.additional"
+        );
+        assert_eq!(
+            c_header.handshakeNonce, header.handshake_nonce,
+            "handshake_nonce"
+        );
+        assert_eq!(c_header.publicKey, header.public_key, "public_key");
+        assert_eq!(
+            c_header.authenticator, header.authenticator,
+            "authenticator"
+        );
+        assert_eq!(
+            c_header.encryptedTempKey, header.encrypted_temp_key,
+            "encrypted_temp_key"
+        );
+    }
+
     #[test]
     pub fn test_encrypt_decrypt_without_auth() {
         let keys_api = CJDNSKeysApi::new().unwrap();
@@ -1778,6 +4890,7 @@ mod tests {
                 ByteString::from(name.to_string()),
                 Some(ByteString::from(name.to_string())),
                 None,
+                None,
             );
             assert_eq!(res.err(), None);
 
@@ -1832,6 +4945,7 @@ mod tests {
                 ByteString::from(name.to_string()),
                 Some(ByteString::from(name.to_string())),
                 None,
+                None,
             );
             assert_eq!(res.err(), None);
 
@@ -1877,6 +4991,33 @@ mod tests {
         assert_eq!(msg.bytes(), b"HelloWorld012345");
     }
 
+    #[test]
+    pub fn test_cross_compare_keys() {
+        use crate::crypto::keys::PublicKeyExt;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let mut keys: Vec<[u8; 32]> = (0..8)
+            .map(|_| *keys_api.key_pair().public_key.raw())
+            .collect();
+        // Keys which differ only in the last byte, and in the sign bit of a byte
+        let base = keys[0];
+        for (i, last) in [0x00, 0x01, 0x7f, 0x80, 0xfe, 0xff].iter().enumerate() {
+            let mut key = base;
+            key[31] = *last;
+            keys.push(key);
+            key[i] ^= 0x80;
+            keys.push(key);
+        }
+
+        for a in &keys {
+            for b in &keys {
+                let c = unsafe { cffi::CryptoAuth_compareKeys(a.as_ptr(), b.as_ptr()) };
+                let rust = PublicKey::from(*a).wire_cmp(&PublicKey::from(*b));
+                assert_eq!(rust, c.cmp(&0), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
     #[test]
     pub fn test_cross_encrypt_decrypt_rust_to_c() {
         let keys_api = CJDNSKeysApi::new().unwrap();
@@ -1896,6 +5037,7 @@ mod tests {
                 ByteString::from(name.to_string()),
                 Some(ByteString::from(name.to_string())),
                 None,
+                None,
             );
             assert_eq!(res.err(), None);
 
@@ -2030,6 +5172,7 @@ mod tests {
                 ByteString::from(name.to_string()),
                 Some(ByteString::from(name.to_string())),
                 None,
+                None,
             );
             assert_eq!(res.err(), None);
 
@@ -2049,6 +5192,268 @@ mod tests {
         assert_eq!(msg.bytes(), b"HelloWorld012345");
     }
 
+    #[test]
+    pub fn test_cross_c_challenge_lookup() {
+        use std::os::raw::c_char;
+
+        use super::Packet;
+        use crate::crypto::crypto_header::AuthType;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let alloc = mk_alloc(1 << 20);
+        let c_ca = unsafe {
+            cffi::CryptoAuth_new(
+                alloc,
+                my_keys.private_key.as_ptr(),
+                cffi::EventBase_new(alloc),
+                std::ptr::null_mut(),
+                fake_random(),
+            )
+        };
+
+        let rust_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key),
+            EventBase {},
+            Random::Legacy(fake_random()),
+        ));
+        rust_ca
+            .add_user_ipv6(
+                ByteString::from("pass".to_string()),
+                Some(ByteString::from("alice".to_string())),
+                None,
+                None,
+            )
+            .unwrap();
+
+        // C uses AuthType One without a login and AuthType Two with one
+        let logins: [(Option<&[u8]>, AuthType); 2] =
+            [(None, AuthType::One), (Some(b"alice\0"), AuthType::Two)];
+        for &(login, auth_type) in logins.iter() {
+            let c_session = unsafe {
+                let password = cffi::String_new(b"pass\0".as_ptr() as *const c_char, alloc);
+                let login = login.map_or(std::ptr::null_mut(), |login| {
+                    cffi::String_new(login.as_ptr() as *const c_char, alloc)
+                });
+                let session = cffi::CryptoAuth_newSession(
+                    c_ca,
+                    alloc,
+                    her_keys.public_key.as_ptr(),
+                    false,
+                    b"c session\0".as_ptr() as *const c_char,
+                    false,
+                );
+                cffi::CryptoAuth_setAuth(password, login, session);
+                session
+            };
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            let res = unsafe { cffi::CryptoAuth_encrypt(c_session, msg.as_c_message()) };
+            assert_eq!(res, 0);
+
+            // The challenge made by C finds the user by its lookup bytes
+            let header = match super::parse_packet(msg.bytes()) {
+                Ok(Packet::Handshake { nonce: 0, header }) => header,
+                other => panic!("unexpected {:?}", other),
+            };
+            assert_eq!(header.auth.auth_type(), auth_type);
+            let (secret, challenge) = super::hash_password(b"alice", b"pass", auth_type);
+            assert_eq!(header.auth.lookup, challenge.lookup);
+            let found = rust_ca.get_auth(&header.auth);
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].secret.as_bytes(), secret.as_bytes());
+
+            // And the hello is accepted with the password of that user
+            let rust_session =
+                super::Session::new(Arc::clone(&rust_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+            rust_session.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        }
+    }
+
+    #[test]
+    pub fn test_cross_handshake_packets_byte_identical() {
+        use std::os::raw::c_char;
+
+        // Fixed keys and deterministic random on both sides, so the packets must be identical
+        let my_priv_key = PrivateKey::from([0x11; 32]);
+        let her_priv_key = PrivateKey::from([0x22; 32]);
+        let my_pub_key = super::crypto_scalarmult_curve25519_base(&my_priv_key);
+        let her_pub_key = super::crypto_scalarmult_curve25519_base(&her_priv_key);
+
+        fn rust_session(priv_key: &PrivateKey, her_pub_key: &PublicKey) -> super::Session {
+            let ca = super::CryptoAuth::new(
+                Some(priv_key.clone()),
+                EventBase {},
+                Random::Legacy(fake_random()),
+            );
+            super::Session::new(Arc::new(ca), her_pub_key.clone(), false, None).unwrap()
+        }
+
+        fn c_session(
+            priv_key: &PrivateKey,
+            her_pub_key: &PublicKey,
+        ) -> *mut cffi::CryptoAuth_Session {
+            unsafe {
+                let alloc = cffi::MallocAllocator__new(1 << 20, "".as_ptr() as *const c_char, 0);
+                let event_base = cffi::EventBase_new(alloc);
+                let ca = cffi::CryptoAuth_new(
+                    alloc,
+                    priv_key.as_ptr(),
+                    event_base,
+                    std::ptr::null_mut(),
+                    fake_random(),
+                );
+                cffi::CryptoAuth_newSession(
+                    ca,
+                    alloc,
+                    her_pub_key.as_ptr(),
+                    false,
+                    std::ptr::null_mut(),
+                    false,
+                )
+            }
+        }
+
+        fn copy_msg(msg: &Message) -> Message {
+            let mut copy = mk_msg(256);
+            copy.push_bytes(msg.bytes()).unwrap();
+            copy
+        }
+
+        // Hello
+        let rust_me = rust_session(&my_priv_key, &her_pub_key);
+        let c_me = c_session(&my_priv_key, &her_pub_key);
+
+        let mut rust_hello = mk_msg(256);
+        rust_hello.push_bytes(b"HelloWorld012345").unwrap();
+        let mut c_hello = copy_msg(&rust_hello);
+
+        rust_me.encrypt_msg(&mut rust_hello).unwrap();
+        let res = unsafe { cffi::CryptoAuth_encrypt(c_me, c_hello.as_c_message()) };
+        assert_eq!(res, 0);
+        assert_eq!(
+            rust_hello.bytes(),
+            c_hello.bytes(),
+            "Hello packets are different"
+        );
+
+        // Key, in reply to the same hello
+        let rust_her = rust_session(&her_priv_key, &my_pub_key);
+        let c_her = c_session(&her_priv_key, &my_pub_key);
+
+        let mut msg = copy_msg(&rust_hello);
+        rust_her.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+        let mut msg = copy_msg(&rust_hello);
+        let res = unsafe { cffi::CryptoAuth_decrypt(c_her, msg.as_c_message()) };
+        assert_eq!(res, cffi::CryptoAuth_DecryptErr::CryptoAuth_DecryptErr_NONE);
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+
+        let mut rust_key = mk_msg(256);
+        rust_key.push_bytes(b"HelloWorld012345").unwrap();
+        let mut c_key = copy_msg(&rust_key);
+
+        rust_her.encrypt_msg(&mut rust_key).unwrap();
+        let res = unsafe { cffi::CryptoAuth_encrypt(c_her, c_key.as_c_message()) };
+        assert_eq!(res, 0);
+        assert_eq!(rust_key.bytes(), c_key.bytes(), "Key packets are different");
+    }
+
+    #[test]
+    pub fn test_cross_rekey_c_peer() {
+        use std::os::raw::c_char;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+        use cffi::CryptoAuth_DecryptErr as C;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let c_keys = keys_api.key_pair();
+        let rust_keys = keys_api.key_pair();
+
+        fn c_session(
+            priv_key: &PrivateKey,
+            her_pub_key: &PublicKey,
+        ) -> *mut cffi::CryptoAuth_Session {
+            unsafe {
+                let alloc = cffi::MallocAllocator__new(1 << 20, "".as_ptr() as *const c_char, 0);
+                let event_base = cffi::EventBase_new(alloc);
+                let ca = cffi::CryptoAuth_new(
+                    alloc,
+                    priv_key.as_ptr(),
+                    event_base,
+                    std::ptr::null_mut(),
+                    fake_random(),
+                );
+                cffi::CryptoAuth_newSession(
+                    ca,
+                    alloc,
+                    her_pub_key.as_ptr(),
+                    false,
+                    std::ptr::null_mut(),
+                    false,
+                )
+            }
+        }
+
+        // A rekey needs a new temp key, which the fake random wouldn't give
+        let rust_session = |priv_key: &PrivateKey, her_pub_key: &PublicKey| {
+            let rand = Random::new_sodium().unwrap();
+            let ca = super::CryptoAuth::new(Some(priv_key.clone()), EventBase {}, rand);
+            super::Session::new(Arc::new(ca), her_pub_key.clone(), false, None).unwrap()
+        };
+        let to_rust = |from: *mut cffi::CryptoAuth_Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            let res = unsafe { cffi::CryptoAuth_encrypt(from, msg.as_c_message()) };
+            assert_eq!(res, 0);
+            to.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        };
+        let to_c = |from: &super::Session, to: *mut cffi::CryptoAuth_Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            let res = unsafe { cffi::CryptoAuth_decrypt(to, msg.as_c_message()) };
+            if res == C::CryptoAuth_DecryptErr_NONE {
+                assert_eq!(msg.bytes(), b"HelloWorld012345");
+            }
+            res
+        };
+
+        // Rust initiator, its rekey is a hello which the C responder takes as a new handshake
+        let c_her = c_session(&c_keys.private_key, &rust_keys.public_key);
+        let rust_me = rust_session(&rust_keys.private_key, &c_keys.public_key);
+        for _ in 0..2 {
+            assert_eq!(to_c(&rust_me, c_her), C::CryptoAuth_DecryptErr_NONE);
+            to_rust(c_her, &rust_me);
+        }
+        assert_eq!(rust_me.get_state(), State::Established);
+        rust_me.rekey().unwrap();
+        for _ in 0..2 {
+            assert_eq!(to_c(&rust_me, c_her), C::CryptoAuth_DecryptErr_NONE);
+            to_rust(c_her, &rust_me);
+        }
+        assert_eq!(rust_me.get_state(), State::Established);
+
+        // Rust responder, the C initiator drops the key packets of its rekey
+        let c_me = c_session(&c_keys.private_key, &rust_keys.public_key);
+        let rust_her = rust_session(&rust_keys.private_key, &c_keys.public_key);
+        for _ in 0..2 {
+            to_rust(c_me, &rust_her);
+            assert_eq!(to_c(&rust_her, c_me), C::CryptoAuth_DecryptErr_NONE);
+        }
+        assert_eq!(rust_her.get_state(), State::Established);
+        assert!(rust_her.rekey().is_err());
+        rust_her.set_responder_rekey(true);
+        rust_her.rekey().unwrap();
+        let res = to_c(&rust_her, c_me);
+        assert_eq!(res, C::CryptoAuth_DecryptErr_KEY_PKT_ESTABLISHED_SESSION);
+    }
+
     fn fake_random() -> *mut cffi::Random_t {
         use std::os::raw::c_char;
         unsafe {
@@ -2070,6 +5475,7 @@ mod tests {
             ByteString::from(name.to_string()),
             Some(ByteString::from(name.to_string())),
             None,
+            None,
         );
         assert_eq!(res.err(), None);
 
@@ -2082,6 +5488,59 @@ mod tests {
         (sess, pt, ct)
     }
 
+    /// Start a Noise handshake with `her_ca` from the node with `my_key`, authenticated as
+    /// `login` with `password`, returning why it was refused, if it was.
+    fn noise_hello(
+        her_ca: &Arc<super::CryptoAuth>,
+        my_key: PrivateKey,
+        login: &str,
+        password: &str,
+    ) -> Result<(), super::DecryptErr> {
+        use crate::external::interface::iface::{self, IfRecv};
+
+        struct Capture(Rc<RefCell<Vec<u8>>>);
+        impl IfRecv for Capture {
+            fn recv(&self, m: &mut Message) -> anyhow::Result<()> {
+                self.0.borrow_mut().extend_from_slice(m.bytes());
+                Ok(())
+            }
+        }
+
+        let rand = Random::new_sodium().unwrap();
+        let my_ca = super::CryptoAuth::new(Some(my_key), EventBase {}, rand);
+        let session = crate::crypto::crypto_noise::Session::new(
+            Arc::clone(&my_ca.noise),
+            her_ca.public_key.clone(),
+            "her".to_owned(),
+        )
+        .unwrap();
+        session.set_auth(
+            Some(ByteString::from(password.to_string())),
+            Some(ByteString::from(login.to_string())),
+        );
+        let (mut plain, mut cipher) = session.ifaces().unwrap();
+        let (mut plain_in, plain_in_pvt) = iface::new("plaintext");
+        plain_in.plumb(&mut plain).unwrap();
+        let hello = Rc::new(RefCell::new(Vec::new()));
+        let (mut cipher_out, _cipher_out_pvt) = iface::new("ciphertext");
+        cipher_out.set_receiver(Capture(Rc::clone(&hello)));
+        cipher_out.plumb(&mut cipher).unwrap();
+
+        // The first packet to send starts the handshake
+        let mut msg = mk_msg(512);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        plain_in_pvt.send(&mut msg).unwrap();
+
+        let mut msg = mk_msg(512);
+        msg.push_bytes(&hello.borrow()).unwrap();
+        msg.push_bytes(&super::ip6_from_key(my_ca.public_key.raw()))
+            .unwrap();
+        match super::try_handshake(her_ca, &mut msg, true) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(super::decrypt_err_of(&err)),
+        }
+    }
+
     #[test]
     pub fn test_wireguard_iface_encrypt_decrypt() {
         let keys_api = CJDNSKeysApi::new().unwrap();
@@ -2226,4 +5685,4927 @@ mod tests {
         assert_eq!(bob_received_text.borrow().as_slice(), b"Goodbye Universe");
         assert_eq!(alice_received_text.borrow().as_slice(), b"Hello World"); // still unchanged
     }
+
+    #[test]
+    pub fn test_user_count_and_has_user() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+
+        let alice = ByteString::from("alice".to_string());
+        let bob = ByteString::from("bob".to_string());
+        assert_eq!(ca.user_count(), 0);
+        assert!(!ca.has_user(&alice));
+
+        ca.add_user_ipv6(alice.clone(), Some(alice.clone()), None, None)
+            .unwrap();
+        ca.add_user_ipv6(bob.clone(), Some(bob.clone()), None, None)
+            .unwrap();
+        assert_eq!(ca.user_count(), 2);
+        assert!(ca.has_user(&alice));
+        assert!(ca.has_user(&bob));
+
+        assert_eq!(ca.remove_users(Some(alice.clone())), 1);
+        assert_eq!(ca.user_count(), 1);
+        assert!(!ca.has_user(&alice));
+        assert!(ca.has_user(&bob));
+
+        assert_eq!(ca.remove_users(None), 1);
+        assert_eq!(ca.user_count(), 0);
+        assert!(!ca.has_user(&bob));
+    }
+
+    #[test]
+    pub fn test_session_bound_to_ip6() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        fn mk_ca(priv_key: PrivateKey) -> Arc<super::CryptoAuth> {
+            Arc::new(super::CryptoAuth::new(
+                Some(priv_key),
+                EventBase {},
+                Random::Fake,
+            ))
+        }
+
+        fn mk_hello(priv_key: PrivateKey, her_pub_key: PublicKey) -> Message {
+            let sess = super::Session::new(mk_ca(priv_key), her_pub_key, false, None).unwrap();
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        }
+
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        let my_ca = mk_ca(my_keys.private_key.clone());
+
+        // Hello from a key which doesn't hash to the ip6 is rejected
+        let my_session =
+            super::Session::new_with_ip6(Arc::clone(&my_ca), her_ip6, false, None).unwrap();
+        assert!(!my_session.her_key_known());
+        let mut msg = mk_hello(other_keys.private_key, my_keys.public_key.clone());
+        let err = my_session.decrypt_msg(&mut msg).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(
+                super::DecryptErr::WrongPermPubkey
+            ))
+        );
+        assert!(!my_session.her_key_known());
+
+        // Hello from the matching key is accepted and the key is adopted
+        let mut msg = mk_hello(her_keys.private_key, my_keys.public_key);
+        my_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+        assert!(my_session.her_key_known());
+        assert_eq!(my_session.get_her_pubkey(), *her_keys.public_key.raw());
+    }
+
+    #[test]
+    pub fn test_rekey_recommended() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let sess = super::Session::new(Arc::new(ca), her_keys.public_key, false, None).unwrap();
+        assert_eq!(sess.next_nonce(), 0);
+        assert!(!sess.rekey_recommended());
+
+        let threshold = super::Session::DEFAULT_REKEY_THRESHOLD;
+        sess.inner.session_mut.write().next_nonce = threshold - 1;
+        assert_eq!(sess.next_nonce(), threshold - 1);
+        assert!(!sess.rekey_recommended());
+
+        sess.inner.session_mut.write().next_nonce = threshold;
+        assert_eq!(sess.next_nonce(), threshold);
+        assert!(sess.rekey_recommended());
+
+        sess.set_rekey_threshold(threshold + 1);
+        assert!(!sess.rekey_recommended());
+    }
+
+    #[test]
+    pub fn test_anon_login_not_reused() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+
+        let pass = |p: &str| ByteString::from(p.to_string());
+        ca.add_user_ipv6(pass("a"), None, None, None).unwrap();
+        ca.add_user_ipv6(pass("b"), None, None, None).unwrap();
+        let first = ca.get_users();
+        assert_eq!(first.len(), 2);
+        assert_ne!(first[0], first[1]);
+
+        assert_eq!(ca.remove_users(Some(first[0].clone())), 1);
+        ca.add_user_ipv6(pass("c"), None, None, None).unwrap();
+        let users = ca.get_users();
+        assert_eq!(users.len(), 2);
+        assert_ne!(users[0], users[1]);
+        assert!(!first.contains(&users[1]));
+
+        // A generator producing already taken logins is skipped past
+        ca.set_anon_login_generator(|n| ByteString::from(format!("Anon #{}", (n + 1) % 4)));
+        let login = Some(ByteString::from("Anon #0".to_string()));
+        ca.add_user_ipv6(pass("d"), login, None, None).unwrap();
+        ca.add_user_ipv6(pass("e"), None, None, None).unwrap();
+        let users = ca.get_users();
+        assert_eq!(users.len(), 4);
+        for (i, u) in users.iter().enumerate() {
+            assert!(!users[i + 1..].contains(u));
+        }
+
+        // A generator which can't produce a free login fails
+        ca.set_anon_login_generator(|_| ByteString::from("Anon".to_string()));
+        ca.add_user_ipv6(pass("f"), None, None, None).unwrap();
+        assert!(ca.add_user_ipv6(pass("g"), None, None, None).is_err());
+        assert_eq!(ca.user_count(), 5);
+    }
+
+    #[test]
+    pub fn test_set_name() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let sess = super::Session::new(Arc::new(ca), her_keys.public_key, false, None).unwrap();
+        assert_eq!(sess.get_name(), None);
+
+        sess.set_name(Some("bob".to_string()));
+        assert_eq!(sess.get_name(), Some("bob".to_string()));
+
+        sess.set_name(Some("alice".to_string()));
+        assert_eq!(sess.get_name(), Some("alice".to_string()));
+
+        sess.set_name(None);
+        assert_eq!(sess.get_name(), None);
+    }
+
+    #[test]
+    pub fn test_her_temp_pubkey() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+        assert_eq!(my_session.her_temp_pubkey(), None);
+        assert_eq!(her_session.her_temp_pubkey(), None);
+
+        let mut msg = mk_msg(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut msg).unwrap();
+        assert_eq!(my_session.her_temp_pubkey(), None);
+
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let my_temp_pub_key = my_session.inner.session_mut.read().our_temp_pub_key;
+        assert_ne!(my_temp_pub_key, [0; 32]);
+        assert_eq!(her_session.her_temp_pubkey(), Some(my_temp_pub_key));
+    }
+
+    #[test]
+    pub fn test_encrypt_decrypt_with_aad() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let send = |from: &super::Session, to: &super::Session, aad: &[u8], to_aad: &[u8]| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_with_aad(&mut msg, aad).unwrap();
+            to.decrypt_with_aad(&mut msg, to_aad)
+                .map(|_| msg.bytes().to_vec())
+        };
+
+        // Handshake: hello, key, then the first traffic packets
+        assert_eq!(
+            send(&my_session, &her_session, b"", b"").unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session, b"", b"").unwrap(),
+            b"HelloWorld012345"
+        );
+        let label = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(
+            send(&my_session, &her_session, &label, &label).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session, &label, &label).unwrap(),
+            b"HelloWorld012345"
+        );
+
+        // Established session
+        assert_eq!(
+            send(&my_session, &her_session, &label, &label).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&my_session, &her_session, b"", b"").unwrap(),
+            b"HelloWorld012345"
+        );
+
+        let err = send(&my_session, &her_session, &label, &[1, 2, 3, 4, 5, 6, 7, 9]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(super::DecryptErr::Decrypt))
+        );
+        let err = send(&my_session, &her_session, &label, b"").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(super::DecryptErr::Decrypt))
+        );
+    }
+
+    #[test]
+    pub fn test_hello_collision_handler() {
+        use parking_lot::Mutex;
+
+        use super::HelloCollision;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        fn mk_sess(
+            my_priv_key: PrivateKey,
+            her_pub_key: PublicKey,
+        ) -> (super::Session, Arc<Mutex<Vec<(HelloCollision, [u8; 16])>>>) {
+            let ca = super::CryptoAuth::new(Some(my_priv_key), EventBase {}, Random::Fake);
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let events2 = Arc::clone(&events);
+            ca.set_hello_collision_handler(move |collision, ip6| {
+                events2.lock().push((collision, ip6))
+            });
+            let sess = super::Session::new(Arc::new(ca), her_pub_key, false, None).unwrap();
+            (sess, events)
+        }
+
+        let (my_session, my_events) =
+            mk_sess(my_keys.private_key.clone(), her_keys.public_key.clone());
+        let (her_session, her_events) =
+            mk_sess(her_keys.private_key.clone(), my_keys.public_key.clone());
+
+        // Both send hello at the same time
+        let mut my_hello = mk_msg(256);
+        my_hello.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut my_hello).unwrap();
+        let mut her_hello = mk_msg(256);
+        her_hello.push_bytes(b"HelloWorld012345").unwrap();
+        her_session.encrypt_msg(&mut her_hello).unwrap();
+
+        her_session.decrypt_msg(&mut my_hello).unwrap();
+        my_session.decrypt_msg(&mut her_hello).unwrap();
+
+        // The node with the higher key yields
+        let (my_resolution, her_resolution) = if my_keys.public_key > her_keys.public_key {
+            (HelloCollision::Yielded, HelloCollision::StoodFirm)
+        } else {
+            (HelloCollision::StoodFirm, HelloCollision::Yielded)
+        };
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        let my_ip6 = super::ip6_from_key(my_keys.public_key.raw());
+        assert_eq!(*my_events.lock(), vec![(my_resolution, her_ip6)]);
+        assert_eq!(*her_events.lock(), vec![(her_resolution, my_ip6)]);
+    }
+
+    #[test]
+    pub fn test_ip_restricted_user() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        let try_hello = |restricted_to_ip6: [u8; 16]| {
+            let pass = ByteString::from("pass".to_string());
+            let my_ca = super::CryptoAuth::new(
+                Some(my_keys.private_key.clone()),
+                EventBase {},
+                Random::Fake,
+            );
+            let my_session =
+                super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(Some(pass.clone()), None);
+
+            let her_ca = super::CryptoAuth::new(
+                Some(her_keys.private_key.clone()),
+                EventBase {},
+                Random::Fake,
+            );
+            her_ca
+                .add_user_ipv6(pass, None, Some(restricted_to_ip6), None)
+                .unwrap();
+            let her_session =
+                super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            her_session.decrypt_msg(&mut msg)
+        };
+
+        assert!(try_hello(super::ip6_from_key(my_keys.public_key.raw())).is_ok());
+
+        let err = try_hello(super::ip6_from_key(other_keys.public_key.raw())).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(
+                super::DecryptErr::IpRestricted
+            ))
+        );
+    }
+
+    #[test]
+    pub fn test_ip_prefix_restricted_user() {
+        use super::Ip6Restriction;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        let try_hello = |restriction: Ip6Restriction| {
+            let pass = ByteString::from("pass".to_string());
+            let my_ca = super::CryptoAuth::new(
+                Some(my_keys.private_key.clone()),
+                EventBase {},
+                Random::Fake,
+            );
+            let my_session =
+                super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(Some(pass.clone()), None);
+
+            let her_ca = super::CryptoAuth::new(
+                Some(her_keys.private_key.clone()),
+                EventBase {},
+                Random::Fake,
+            );
+            her_ca
+                .add_user_restricted(pass, None, Some(restriction), None)
+                .unwrap();
+            let her_session =
+                super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            her_session
+                .decrypt_msg(&mut msg)
+                .err()
+                .map(|e| e.downcast::<super::DecryptError>())
+        };
+        let accepted = |restriction| try_hello(restriction).is_none();
+        let restricted = |restriction| {
+            matches!(
+                try_hello(restriction),
+                Some(Ok(super::DecryptError::DecryptErr(
+                    super::DecryptErr::IpRestricted
+                )))
+            )
+        };
+
+        let my_ip6 = super::ip6_from_key(my_keys.public_key.raw());
+        // Same first 64 bits as my address, but not my address
+        let mut sibling = my_ip6;
+        sibling[15] ^= 1;
+        sibling[8] ^= 0x80;
+        let other_ip6 = super::ip6_from_key(other_keys.public_key.raw());
+        let prefix = |addr: [u8; 16], bits| Ip6Restriction::Prefix {
+            addr: addr.into(),
+            bits,
+        };
+
+        assert!(accepted(prefix([0; 16], 0)));
+        assert!(accepted(prefix(other_ip6, 8)));
+        assert!(restricted(prefix([0xfd; 16], 8)));
+        assert!(accepted(prefix(sibling, 64)));
+        assert!(restricted(prefix(sibling, 65)));
+        assert!(restricted(prefix(other_ip6, 64)));
+        assert!(accepted(prefix(my_ip6, 128)));
+        assert!(restricted(prefix(sibling, 128)));
+
+        // Exact restrictions are unchanged
+        assert!(accepted(Ip6Restriction::Exact(my_ip6.into())));
+        assert!(restricted(Ip6Restriction::Exact(sibling.into())));
+    }
+
+    #[test]
+    pub fn test_add_user_restricted() {
+        use super::Ip6Restriction;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+
+        let ip6 = super::IpV6::from([0xfc, 0x12, 0x34, 0x56, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let prefix = |bits| Ip6Restriction::Prefix {
+            addr: ip6.clone(),
+            bits,
+        };
+        let add = |password: &str, restriction| {
+            let password = ByteString::from(password.to_string());
+            ca.add_user_restricted(password, None, restriction, None)
+        };
+        assert_eq!(
+            add("pass1", Some(prefix(129))),
+            Err(super::AddUserError::BadPrefixLength(129))
+        );
+        assert_eq!(ca.user_count(), 0);
+        add("pass1", Some(prefix(64))).unwrap();
+        add("pass2", Some(Ip6Restriction::Exact(ip6.clone()))).unwrap();
+        add("pass3", None).unwrap();
+
+        let restrictions: Vec<_> = ca
+            .get_user_info()
+            .into_iter()
+            .map(|u| u.restricted_to_ip6)
+            .collect();
+        assert_eq!(
+            restrictions,
+            vec![
+                Some(prefix(64)),
+                Some(Ip6Restriction::Exact(ip6.clone())),
+                None
+            ]
+        );
+
+        // Only exact restrictions are removed by address
+        assert_eq!(ca.remove_users_by_ip6(ip6.clone()), 1);
+        assert_eq!(ca.get_user_info()[0].restricted_to_ip6, Some(prefix(64)));
+        let removed =
+            ca.remove_users_where(|_, r| matches!(r, Some(Ip6Restriction::Prefix { .. })));
+        assert_eq!(removed, 1);
+        assert_eq!(ca.user_count(), 1);
+    }
+
+    #[test]
+    pub fn test_zero_perm_key_in_handshake() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let mk_hello = || {
+            let my_ca = super::CryptoAuth::new(
+                Some(my_keys.private_key.clone()),
+                EventBase {},
+                Random::Fake,
+            );
+            let my_session =
+                super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            // Zero the permanent public key in the CryptoHeader
+            msg.bytes_mut()[40..72].copy_from_slice(&[0; 32]);
+            msg
+        };
+        let her_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+        let expected = Some(&super::DecryptError::DecryptErr(super::DecryptErr::Wiseguy));
+
+        let her_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), false, None)
+                .unwrap();
+        let err = her_session.decrypt_msg(&mut mk_hello()).unwrap_err();
+        assert_eq!(err.downcast_ref::<super::DecryptError>(), expected);
+
+        // Session with her key unknown
+        let my_ip6 = super::ip6_from_key(my_keys.public_key.raw());
+        let her_session =
+            super::Session::new_with_ip6(Arc::clone(&her_ca), my_ip6, false, None).unwrap();
+        let err = her_session.decrypt_msg(&mut mk_hello()).unwrap_err();
+        assert_eq!(err.downcast_ref::<super::DecryptError>(), expected);
+        assert!(!her_session.her_key_known());
+    }
+
+    #[test]
+    pub fn test_unknown_auth_type_in_handshake() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+        let my_session =
+            super::Session::new(my_ca, her_keys.public_key.clone(), false, None).unwrap();
+        let mut hello = mk_msg(256);
+        hello.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut hello).unwrap();
+        let hello = hello.bytes().to_vec();
+
+        let her_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        let her_session = super::Session::new(her_ca, my_keys.public_key, false, None).unwrap();
+        let expected = Some(&super::DecryptError::DecryptErr(
+            super::DecryptErr::UnrecognizedAuth,
+        ));
+
+        // Every auth type byte a fuzzer could put on the wire, the session is left untouched
+        for auth_type in 3..=0xff_u8 {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(&hello).unwrap();
+            msg.bytes_mut()[4] = auth_type;
+            let err = her_session.decrypt_msg(&mut msg).unwrap_err();
+            assert_eq!(err.downcast_ref::<super::DecryptError>(), expected);
+            assert_eq!(her_session.get_state(), State::Init);
+        }
+
+        // The unmodified hello is still accepted
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&hello).unwrap();
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+    }
+
+    #[test]
+    pub fn test_handshake_timeout_handler() {
+        use parking_lot::Mutex;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = Arc::clone(&events);
+        ca.set_handshake_timeout_handler(move |ip6, state| events2.lock().push((ip6, state)));
+        let sess =
+            super::Session::new(Arc::new(ca), her_keys.public_key.clone(), false, None).unwrap();
+        let mut alloc = Allocator::new(1024);
+
+        // Not in a handshake
+        sess.inner.session_mut.write().time_of_last_packet -= 100;
+        sess.tick(&mut alloc).unwrap();
+        assert!(events.lock().is_empty());
+
+        // Send hello which is never answered
+        let mut msg = mk_msg(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        sess.encrypt_msg(&mut msg).unwrap();
+        assert_eq!(sess.get_state(), State::SentHello);
+        let setup_timeout = super::Session::DEFAULT_SETUP_RESET_AFTER_INACTIVITY_SECONDS;
+        let now = EventBase {}.current_time_seconds();
+        sess.inner.session_mut.write().time_of_last_packet = now;
+        sess.tick(&mut alloc).unwrap();
+        assert!(events.lock().is_empty());
+
+        // Drive the clock forward past the setup timeout
+        sess.inner.session_mut.write().time_of_last_packet = now - setup_timeout - 1;
+        sess.tick(&mut alloc).unwrap();
+        sess.tick(&mut alloc).unwrap();
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        assert_eq!(*events.lock(), vec![(her_ip6, State::SentHello)]);
+    }
+
+    #[test]
+    pub fn test_session_manager_snapshot() {
+        use std::net::Ipv6Addr;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let keys = (0..4).map(|_| keys_api.key_pair()).collect::<Vec<_>>();
+
+        let ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+        let mk_sess = |pub_key: &PublicKey, name: &str| {
+            super::Session::new(
+                Arc::clone(&ca),
+                pub_key.clone(),
+                false,
+                Some(name.to_string()),
+            )
+            .unwrap()
+        };
+        let mk_hello = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+
+        // Init, omitted
+        let _init = mk_sess(&keys[0].public_key, "init");
+
+        // SentHello
+        let sent_hello = mk_sess(&keys[1].public_key, "sent hello");
+        mk_hello(&sent_hello);
+
+        // ReceivedHello
+        let received_hello = mk_sess(&keys[2].public_key, "received hello");
+        let her_ca = super::CryptoAuth::new(
+            Some(keys[2].private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        );
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None).unwrap();
+        received_hello
+            .decrypt_msg(&mut mk_hello(&her_session))
+            .unwrap();
+
+        // Dropped, omitted
+        let dropped = mk_sess(&keys[3].public_key, "dropped");
+        mk_hello(&dropped);
+        drop(dropped);
+
+        let ip6 = |i: usize| Ipv6Addr::from(super::ip6_from_key(keys[i].public_key.raw()));
+        let mut expected = vec![
+            (ip6(1), State::SentHello, Some("sent hello".to_string())),
+            (
+                ip6(2),
+                State::ReceivedHello,
+                Some("received hello".to_string()),
+            ),
+        ];
+        expected.sort_by_key(|e| e.0);
+
+        let snapshot = ca.sessions.snapshot();
+        let summaries = snapshot
+            .iter()
+            .map(|s| (s.her_ip6, s.state, s.display_name.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(summaries, expected);
+        let received = snapshot
+            .iter()
+            .find(|s| s.state == State::ReceivedHello)
+            .unwrap();
+        assert_eq!(received.stats, received_hello.stats());
+    }
+
+    #[test]
+    pub fn test_inactivity_reset_with_manual_clock() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+        use crate::util::events::ManualClock;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let clock = Arc::new(ManualClock::new(1_000_000));
+
+        let mk_pair = || {
+            let my_ca = super::CryptoAuth::new(
+                Some(my_keys.private_key.clone()),
+                Arc::clone(&clock),
+                Random::Fake,
+            );
+            let my_session =
+                super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            let her_ca = super::CryptoAuth::new(
+                Some(her_keys.private_key.clone()),
+                Arc::clone(&clock),
+                Random::Fake,
+            );
+            let her_session =
+                super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None)
+                    .unwrap();
+            (my_session, her_session)
+        };
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+
+        // Setup timeout
+        let (my_session, her_session) = mk_pair();
+        send(&my_session, &her_session);
+        assert_eq!(my_session.get_state(), State::SentHello);
+        assert_eq!(her_session.get_state(), State::ReceivedHello);
+        clock.advance(super::Session::DEFAULT_SETUP_RESET_AFTER_INACTIVITY_SECONDS - 1);
+        her_session.reset_if_timeout();
+        assert_eq!(her_session.get_state(), State::ReceivedHello);
+        clock.advance(1);
+        her_session.reset_if_timeout();
+        assert_eq!(her_session.get_state(), State::Init);
+        // Sessions which sent a hello are never reset
+        my_session.reset_if_timeout();
+        assert_eq!(my_session.get_state(), State::SentHello);
+
+        // Established session timeout
+        let (my_session, her_session) = mk_pair();
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        assert_eq!(my_session.get_state(), State::Established);
+        clock.advance(super::Session::DEFAULT_RESET_AFTER_INACTIVITY_SECONDS - 1);
+        my_session.reset_if_timeout();
+        assert_eq!(my_session.get_state(), State::Established);
+        clock.advance(1);
+        my_session.reset_if_timeout();
+        assert_eq!(my_session.get_state(), State::Init);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    pub fn test_tracing_spans() {
+        use std::fmt::Debug;
+
+        use parking_lot::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Name and fields of every span and event, in creation order.
+        type Records = Arc<Mutex<Vec<(String, Vec<(String, String)>)>>>;
+
+        struct Fields<'a>(&'a mut Vec<(String, String)>);
+
+        impl Visit for Fields<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                self.0
+                    .push((field.name().to_string(), format!("{:?}", value)));
+            }
+        }
+
+        struct Recorder(Records);
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut fields = Vec::new();
+                span.record(&mut Fields(&mut fields));
+                let mut records = self.0.lock();
+                records.push((span.metadata().name().to_string(), fields));
+                Id::from_u64(records.len() as u64)
+            }
+
+            fn record(&self, span: &Id, values: &Record<'_>) {
+                let mut records = self.0.lock();
+                let idx = span.into_u64() as usize - 1;
+                values.record(&mut Fields(&mut records[idx].1));
+            }
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let level = event.metadata().level().to_string();
+                let mut fields = vec![("level".to_string(), level)];
+                event.record(&mut Fields(&mut fields));
+                self.0.lock().push(("event".to_string(), fields));
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None).unwrap();
+
+        let records = Records::default();
+        tracing::subscriber::with_default(Recorder(Arc::clone(&records)), || {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            let mut replayed = mk_msg(256);
+            replayed.push_bytes(msg.bytes()).unwrap();
+            her_session.decrypt_msg(&mut msg).unwrap();
+            her_session.decrypt_msg(&mut replayed).unwrap_err();
+        });
+
+        let field = |name: &str, value: String| (name.to_string(), value);
+        let her_ip6 = Ipv6Addr::from(super::ip6_from_key(her_keys.public_key.raw())).to_string();
+        let my_ip6 = Ipv6Addr::from(super::ip6_from_key(my_keys.public_key.raw())).to_string();
+        let records = records.lock();
+        let names = records.iter().map(|r| r.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "encrypt",
+                "event",
+                "decrypt",
+                "decrypt_handshake",
+                "event",
+                "decrypt",
+                "decrypt_handshake",
+                "event"
+            ]
+        );
+        assert_eq!(
+            records[0].1,
+            [
+                field("her_ip6", her_ip6.clone()),
+                field("state", "Init".to_string()),
+                field("nonce", "0".to_string()),
+            ]
+        );
+        for expected in [
+            field("level", "INFO".to_string()),
+            field("message", "state change".to_string()),
+            field("from", "Init".to_string()),
+            field("to", "SentHello".to_string()),
+        ]
+        .iter()
+        {
+            assert!(records[1].1.contains(expected));
+        }
+        assert_eq!(
+            records[3].1,
+            [
+                field("her_ip6", my_ip6),
+                field("state", "Init".to_string()),
+                field("nonce", "0".to_string()),
+            ]
+        );
+        assert!(records[4]
+            .1
+            .contains(&field("to", "ReceivedHello".to_string())));
+        assert!(records[7].1.contains(&field("level", "DEBUG".to_string())));
+        assert!(records[7]
+            .1
+            .contains(&field("err", "INVALID_PACKET".to_string())));
+        drop(records);
+
+        // A session created while tracing is on has a span of its own, without any keys
+        let records = Records::default();
+        tracing::subscriber::with_default(Recorder(Arc::clone(&records)), || {
+            let keys = keys_api.key_pair();
+            let ca = super::CryptoAuth::new(Some(keys.private_key), EventBase {}, Random::Fake);
+            let session = super::Session::new(
+                Arc::new(ca),
+                her_keys.public_key.clone(),
+                false,
+                Some("alice".to_string()),
+            )
+            .unwrap();
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            session.encrypt_msg(&mut msg).unwrap();
+            session.set_name(Some("bob".to_string()));
+        });
+        let records = records.lock();
+        let names = records.iter().map(|r| r.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["session", "encrypt", "event"]);
+        let session_fields = &records[0].1;
+        assert_eq!(session_fields[0].0, "id");
+        assert_eq!(
+            session_fields[1..],
+            [
+                field("her_ip6", her_ip6),
+                field("display_name", "alice".to_string()),
+                field("display_name", "bob".to_string()),
+            ]
+        );
+        let her_key = hex::encode(her_keys.public_key.raw());
+        for (_, fields) in records.iter() {
+            assert!(fields.iter().all(|(_, value)| !value.contains(&her_key)));
+        }
+    }
+
+    #[test]
+    pub fn test_auth_type_accessors() {
+        use crate::crypto::crypto_header::AuthType;
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let user = ByteString::from("alice".to_string());
+        her_ca
+            .add_user_ipv6(user.clone(), Some(user.clone()), None, None)
+            .unwrap();
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, true, None).unwrap();
+
+        my_session.set_auth(Some(user.clone()), Some(user));
+        assert_eq!(my_session.auth_type(), AuthType::Two);
+        assert_eq!(my_session.peer_auth_type(), None);
+        assert_eq!(her_session.auth_type(), AuthType::Zero);
+        assert_eq!(her_session.peer_auth_type(), None);
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+
+        // Hello
+        send(&my_session, &her_session);
+        assert_eq!(her_session.peer_auth_type(), Some(AuthType::Two));
+        assert_eq!(my_session.peer_auth_type(), None);
+
+        // Key
+        send(&her_session, &my_session);
+        assert_eq!(my_session.peer_auth_type(), Some(AuthType::Zero));
+
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(my_session.auth_type(), AuthType::Two);
+        assert_eq!(my_session.peer_auth_type(), Some(AuthType::Zero));
+        assert_eq!(her_session.auth_type(), AuthType::Zero);
+        assert_eq!(her_session.peer_auth_type(), Some(AuthType::Two));
+    }
+
+    #[test]
+    pub fn test_max_handshakes() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+        my_ca.set_max_handshakes(3);
+
+        let mk_peer = || {
+            let her_keys = keys_api.key_pair();
+            let her_ca =
+                super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+            super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None).unwrap()
+        };
+        let mk_msg_from = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+        let try_handshake = |sess: &super::Session| {
+            let mut msg = mk_msg_from(sess);
+            // Sender's ip6
+            msg.push_bytes(&super::ip6_from_key(sess.inner.context.public_key.raw()))
+                .unwrap();
+            super::try_handshake(&my_ca, &mut msg, false)
+        };
+
+        // An established session doesn't count
+        let peer = mk_peer();
+        let her_pub_key = peer.inner.context.public_key.clone();
+        let established =
+            super::Session::new(Arc::clone(&my_ca), her_pub_key, false, None).unwrap();
+        established.decrypt_msg(&mut mk_msg_from(&peer)).unwrap();
+        peer.decrypt_msg(&mut mk_msg_from(&established)).unwrap();
+        established.decrypt_msg(&mut mk_msg_from(&peer)).unwrap();
+        peer.decrypt_msg(&mut mk_msg_from(&established)).unwrap();
+        assert_eq!(established.get_state(), State::Established);
+        assert_eq!(my_ca.sessions.handshakes_in_progress(), 0);
+
+        let mut half_open = Vec::new();
+        for i in 0..3 {
+            let (_, sess) = try_handshake(&mk_peer()).unwrap();
+            half_open.push(sess.unwrap());
+            assert_eq!(my_ca.sessions.handshakes_in_progress(), i + 1);
+        }
+
+        let err = try_handshake(&mk_peer()).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(
+                super::DecryptErr::TooManyHandshakes
+            ))
+        );
+
+        // Established session keeps working
+        let mut msg = mk_msg_from(&peer);
+        established.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+
+        // Once a half open session goes away, there is room for another one
+        half_open.pop();
+        assert_eq!(my_ca.sessions.handshakes_in_progress(), 2);
+        assert!(try_handshake(&mk_peer()).is_ok());
+    }
+
+    #[test]
+    pub fn test_add_user_default_password_policy() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let pass = |p: &str| ByteString::from(p.to_string());
+
+        assert_eq!(
+            ca.add_user_ipv6(pass(""), Some(pass("a")), None, None),
+            Err(super::AddUserError::EmptyPassword)
+        );
+        assert_eq!(
+            ca.add_user_ipv6(pass("p"), Some(pass("b")), None, None),
+            Ok(())
+        );
+        assert_eq!(
+            ca.add_user_ipv6(pass("pass"), Some(pass("c")), None, None),
+            Ok(())
+        );
+        assert_eq!(ca.user_count(), 2);
+    }
+
+    #[test]
+    pub fn test_add_user_weak_password() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let pass = |p: &str| ByteString::from(p.to_string());
+
+        ca.set_min_password_length(8);
+        assert_eq!(
+            ca.add_user_ipv6(pass("1234567"), Some(pass("b")), None, None),
+            Err(super::AddUserError::WeakPassword { min_length: 8 })
+        );
+        assert_eq!(
+            ca.add_user_ipv6(pass("12345678"), Some(pass("c")), None, None),
+            Ok(())
+        );
+        assert_eq!(ca.get_users(), vec![pass("c")]);
+    }
+
+    #[test]
+    pub fn test_add_user_max_users() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let pass = |p: &str| ByteString::from(p.to_string());
+        assert_eq!(ca.max_users(), super::CryptoAuth::DEFAULT_MAX_USERS);
+
+        ca.set_max_users(2);
+        assert_eq!(ca.add_user_ipv6(pass("pass1"), None, None, None), Ok(()));
+        assert_eq!(ca.add_user_ipv6(pass("pass2"), None, None, None), Ok(()));
+        assert_eq!(
+            ca.add_user_ipv6(pass("pass3"), None, None, None),
+            Err(super::AddUserError::TooManyUsers { max: 2 })
+        );
+        assert_eq!(ca.user_count(), 2);
+
+        // Lowering the limit keeps the existing users
+        ca.set_max_users(1);
+        assert_eq!(ca.user_count(), 2);
+        assert_eq!(
+            ca.add_user_ipv6(pass("pass3"), None, None, None),
+            Err(super::AddUserError::TooManyUsers { max: 1 })
+        );
+
+        ca.set_max_users(3);
+        assert_eq!(ca.max_users(), 3);
+        assert_eq!(ca.add_user_ipv6(pass("pass3"), None, None, None), Ok(()));
+        assert_eq!(ca.user_count(), 3);
+
+        // Removing a user makes room again
+        let login = ca.get_users()[0].clone();
+        ca.remove_users(Some(login));
+        assert_eq!(ca.add_user_ipv6(pass("pass4"), None, None, None), Ok(()));
+    }
+
+    #[test]
+    pub fn test_set_her_public_key() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        let ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        let sess = super::Session::new_with_ip6(Arc::clone(&ca), her_ip6, false, None).unwrap();
+
+        assert_eq!(
+            sess.set_her_public_key(PublicKey::from([0; 32])),
+            Err(super::KeyError::ZeroPublicKey)
+        );
+        assert_eq!(
+            sess.set_her_public_key(other_keys.public_key.clone()),
+            Err(super::KeyError::Ip6Mismatch)
+        );
+        assert!(!sess.her_key_known());
+
+        assert_eq!(sess.set_her_public_key(her_keys.public_key.clone()), Ok(()));
+        assert!(sess.her_key_known());
+        assert_eq!(sess.get_her_pubkey(), *her_keys.public_key.raw());
+        assert_eq!(sess.get_her_ip6(), her_ip6);
+        assert_eq!(sess.set_her_public_key(her_keys.public_key.clone()), Ok(()));
+
+        // Key already set at creation
+        let sess = super::Session::new(ca, her_keys.public_key.clone(), false, None).unwrap();
+        assert_eq!(sess.set_her_public_key(her_keys.public_key.clone()), Ok(()));
+        assert_eq!(
+            sess.set_her_public_key(other_keys.public_key),
+            Err(super::KeyError::KeyAlreadySet)
+        );
+        assert_eq!(sess.get_her_pubkey(), *her_keys.public_key.raw());
+    }
+
+    #[test]
+    pub fn test_rekey() {
+        use parking_lot::Mutex;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        // The fake random would produce the same temp key again, which looks like a dupe packet
+        let rand = Random::new_sodium().unwrap();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, rand);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let rand = Random::new_sodium().unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, rand);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+        her_session.set_responder_rekey(true);
+
+        let encrypt = |from: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+        let decrypt = |to: &super::Session, mut msg: Message| {
+            to.decrypt_msg(&mut msg).map(|_| msg.bytes().to_vec())
+        };
+        let send = |from: &super::Session, to: &super::Session| decrypt(to, encrypt(from));
+
+        // Not established yet
+        assert!(my_session.rekey().is_err());
+
+        // The old key stops being accepted as soon as the new one is established
+        my_session.set_rekey_overlap(0);
+        her_session.set_rekey_overlap(0);
+
+        for _ in 0..2 {
+            assert_eq!(
+                send(&my_session, &her_session).unwrap(),
+                b"HelloWorld012345"
+            );
+            assert_eq!(
+                send(&her_session, &my_session).unwrap(),
+                b"HelloWorld012345"
+            );
+        }
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+
+        let states = Arc::new(Mutex::new(Vec::new()));
+        for session in [&my_session, &her_session].iter() {
+            let states = Arc::clone(&states);
+            session.on_state_change(Box::new(move |state| states.lock().push(state)));
+        }
+        let is_initiator = |session: &super::Session| session.inner.session_mut.read().is_initiator;
+
+        // Packets in flight in both directions while the rekey starts
+        let to_her = encrypt(&my_session);
+        let to_me = encrypt(&her_session);
+        let late = encrypt(&her_session);
+
+        // The initiator rekeys with a hello
+        my_session.rekey().unwrap();
+        assert_eq!(my_session.get_state(), State::SentHello);
+        assert!(is_initiator(&my_session));
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(her_session.get_state(), State::ReceivedHello);
+
+        // Traffic with the old key is still accepted during the rekey
+        assert_eq!(decrypt(&her_session, to_her).unwrap(), b"HelloWorld012345");
+        assert_eq!(decrypt(&my_session, to_me).unwrap(), b"HelloWorld012345");
+
+        // Finish the new handshake
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        let stale = encrypt(&her_session);
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(decrypt(&my_session, stale).unwrap(), b"HelloWorld012345");
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+
+        // The old key is no longer accepted
+        let err = decrypt(&my_session, late).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(super::DecryptErr::Decrypt))
+        );
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+
+        // The responder rekeys with a key packet
+        let to_her = encrypt(&my_session);
+        her_session.rekey().unwrap();
+        assert_eq!(her_session.get_state(), State::ReceivedHello);
+        assert!(!is_initiator(&her_session));
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(my_session.get_state(), State::ReceivedKey);
+        assert_eq!(decrypt(&her_session, to_her).unwrap(), b"HelloWorld012345");
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+        assert!(is_initiator(&my_session) && !is_initiator(&her_session));
+
+        // Neither session went through Init
+        let states = states.lock();
+        assert!(states.contains(&State::ReceivedKey));
+        assert!(!states.contains(&State::Init), "{:?}", *states);
+    }
+
+    #[test]
+    pub fn test_rekey_replayed_key_packet() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let rand = Random::new_sodium().unwrap();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, rand);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let rand = Random::new_sodium().unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, rand);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+        her_session.set_responder_rekey(true);
+
+        let encrypt = |from: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg.bytes().to_vec()
+        };
+        let decrypt = |to: &super::Session, bytes: &[u8]| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(bytes).unwrap();
+            to.decrypt_msg(&mut msg).map(|_| msg.bytes().to_vec())
+        };
+        let send = |from: &super::Session, to: &super::Session| decrypt(to, &encrypt(from));
+        let assert_err = |res: Result<Vec<u8>>, expected: super::DecryptErr| {
+            let err = res.unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<super::DecryptError>(),
+                Some(&super::DecryptError::DecryptErr(expected))
+            );
+        };
+
+        // Keep the first key packet of the session
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        let first_key_pkt = encrypt(&her_session);
+        assert_eq!(
+            decrypt(&my_session, &first_key_pkt).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(my_session.get_state(), State::Established);
+
+        // The responder rekeys
+        her_session.rekey().unwrap();
+        let rekey_pkt = encrypt(&her_session);
+        assert_eq!(
+            decrypt(&my_session, &rekey_pkt).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+
+        // Neither the first key packet nor the rekey one restarts the handshake
+        for replayed in [&first_key_pkt, &rekey_pkt].iter() {
+            let res = decrypt(&my_session, replayed);
+            assert_err(res, super::DecryptErr::KeyPktEstablishedSession);
+            assert_eq!(my_session.get_state(), State::Established);
+        }
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+
+        // Once the initiator sent a hello with a new temp key, old key packets don't decrypt
+        my_session.rekey().unwrap();
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        let res = decrypt(&my_session, &first_key_pkt);
+        assert_err(res, super::DecryptErr::HandshakeDecryptFailed);
+    }
+
+    #[test]
+    pub fn test_rekey_many_peer_rekeys() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let rand = Random::new_sodium().unwrap();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, rand);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let rand = Random::new_sodium().unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, rand);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+        her_session.set_responder_rekey(true);
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).map(|_| msg.bytes().to_vec())
+        };
+        let handshake = || {
+            assert_eq!(
+                send(&my_session, &her_session).unwrap(),
+                b"HelloWorld012345"
+            );
+            assert_eq!(
+                send(&her_session, &my_session).unwrap(),
+                b"HelloWorld012345"
+            );
+            assert_eq!(my_session.get_state(), State::Established);
+            assert_eq!(her_session.get_state(), State::Established);
+        };
+
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        handshake();
+
+        for _ in 0..super::SessionMut::MAX_PEER_REKEYS {
+            her_session.rekey().unwrap();
+            assert_eq!(
+                send(&her_session, &my_session).unwrap(),
+                b"HelloWorld012345"
+            );
+            handshake();
+        }
+
+        // One more is dropped, the initiator rekeys with a hello instead
+        her_session.rekey().unwrap();
+        let err = send(&her_session, &my_session).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(
+                super::DecryptErr::KeyPktEstablishedSession
+            ))
+        );
+        assert_eq!(my_session.get_state(), State::SentHello);
+        assert_eq!(
+            send(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        handshake();
+
+        // Her rekeys are accepted again
+        her_session.rekey().unwrap();
+        assert_eq!(
+            send(&her_session, &my_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        handshake();
+    }
+
+    #[test]
+    pub fn test_aad_absent_is_wire_compatible() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        // Peers which know nothing about associated data
+        let send_plain = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_with_aad(&mut msg, b"")
+                .map(|_| msg.bytes().to_vec())
+        };
+        let send_aad = |from: &super::Session, to: &super::Session, aad: &[u8]| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_with_aad(&mut msg, aad).unwrap();
+            to.decrypt_msg(&mut msg).map(|_| msg.bytes().to_vec())
+        };
+
+        assert_eq!(
+            send_plain(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send_aad(&her_session, &my_session, b"").unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send_plain(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send_aad(&her_session, &my_session, b"").unwrap(),
+            b"HelloWorld012345"
+        );
+        assert_eq!(
+            send_plain(&my_session, &her_session).unwrap(),
+            b"HelloWorld012345"
+        );
+
+        // A peer which doesn't bind the associated data can't decrypt a packet which is bound to it
+        let err = send_aad(&her_session, &my_session, b"label").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::DecryptError>(),
+            Some(&super::DecryptError::DecryptErr(super::DecryptErr::Decrypt))
+        );
+    }
+
+    #[test]
+    pub fn test_state_change_handler() {
+        use parking_lot::Mutex;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let states = Arc::new(Mutex::new(Vec::new()));
+        {
+            let states = Arc::clone(&states);
+            my_session.on_state_change(Box::new(move |state| states.lock().push(state)));
+        }
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        // No transition, no call
+        send(&my_session, &her_session);
+        my_session.reset();
+        my_session.reset();
+
+        assert_eq!(
+            *states.lock(),
+            [
+                State::SentHello,
+                State::ReceivedKey,
+                State::Established,
+                State::Init
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_auth_type_follows_set_auth() {
+        use crate::crypto::crypto_header::AuthType;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let session = super::Session::new(Arc::new(ca), her_keys.public_key, false, None).unwrap();
+        assert_eq!(session.auth_type(), AuthType::Zero);
+
+        session.set_auth(Some(ByteString::from("pass1".to_string())), None);
+        assert_eq!(session.auth_type(), AuthType::One);
+
+        let login = ByteString::from("alice".to_string());
+        session.set_auth(Some(ByteString::from("pass2".to_string())), Some(login));
+        assert_eq!(session.auth_type(), AuthType::Two);
+
+        session.set_auth(None, None);
+        assert_eq!(session.auth_type(), AuthType::Zero);
+    }
+
+    #[test]
+    pub fn test_add_user_empty_password() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let pass = |p: &str| ByteString::from(p.to_string());
+
+        assert_eq!(
+            ca.add_user_ipv6(pass(""), None, None, None),
+            Err(super::AddUserError::EmptyPassword)
+        );
+        assert_eq!(
+            ca.add_user_ipv6(pass(""), Some(pass("a")), None, None),
+            Err(super::AddUserError::EmptyPassword)
+        );
+
+        // Even when no minimum length is configured
+        ca.set_min_password_length(0);
+        assert_eq!(
+            ca.add_user_ipv6(pass(""), Some(pass("b")), None, None),
+            Err(super::AddUserError::EmptyPassword)
+        );
+        assert_eq!(ca.user_count(), 0);
+    }
+
+    #[test]
+    pub fn test_max_sessions_per_ip6() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        let ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        ca.set_max_sessions_per_ip6(2);
+
+        let new_session =
+            |key: &PublicKey| super::Session::new(Arc::clone(&ca), key.clone(), false, None);
+        let first = new_session(&her_keys.public_key).unwrap();
+        let second = new_session(&her_keys.public_key).unwrap();
+        let err = new_session(&her_keys.public_key).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<super::SessionError>(),
+            Some(&super::SessionError::TooManySessions { max: 2 })
+        );
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        let err = super::Session::new_with_ip6(Arc::clone(&ca), her_ip6, false, None)
+            .err()
+            .unwrap();
+        assert!(err.downcast_ref::<super::SessionError>().is_some());
+
+        // The cap is per ip6
+        let _other = new_session(&other_keys.public_key).unwrap();
+
+        // Dropping a session makes room for a new one
+        drop(first);
+        let _third = new_session(&her_keys.public_key).unwrap();
+        assert!(new_session(&her_keys.public_key).is_err());
+        drop(second);
+    }
+
+    #[test]
+    pub fn test_required_padding() {
+        use crate::crypto::crypto_header::CryptoHeader;
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let mk_msg_with_pad = |pad: usize| {
+            let mut msg = mk_msg(pad + 16);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            assert_eq!(msg.pad(), pad);
+            msg
+        };
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg_with_pad(from.required_send_padding());
+            from.encrypt_msg(&mut msg).unwrap();
+
+            // Move the ciphertext into a message with exactly the required padding
+            let bytes = msg.bytes().to_vec();
+            let mut msg = mk_msg(to.required_recv_padding() + bytes.len());
+            msg.push_bytes(&bytes).unwrap();
+            assert_eq!(msg.pad(), to.required_recv_padding());
+            to.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        };
+        let check_too_short = |from: &super::Session| {
+            let state = from.get_state();
+            let required = from.required_send_padding();
+            let mut msg = mk_msg_with_pad(required - 1);
+            let err = from.encrypt_msg(&mut msg).unwrap_err();
+            let expected = super::EncryptError::InsufficientPadding {
+                required,
+                available: required - 1,
+            };
+            assert_eq!(err.downcast_ref::<super::EncryptError>(), Some(&expected));
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+            assert_eq!(from.get_state(), state);
+        };
+
+        // Handshake packets
+        assert_eq!(my_session.required_send_padding(), CryptoHeader::SIZE);
+        check_too_short(&my_session);
+        send(&my_session, &her_session);
+        assert_eq!(her_session.required_send_padding(), CryptoHeader::SIZE);
+        check_too_short(&her_session);
+        send(&her_session, &my_session);
+
+        // Traffic packets
+        assert_eq!(my_session.required_send_padding(), 36);
+        check_too_short(&my_session);
+        send(&my_session, &her_session);
+        assert_eq!(her_session.required_send_padding(), 36);
+        check_too_short(&her_session);
+        send(&her_session, &my_session);
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+        send(&my_session, &her_session);
+    }
+
+    #[test]
+    pub fn test_wire_nonce_layout() {
+        use crate::crypto::crypto_header::CryptoHeader;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let encrypt = |from: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+        let mut sent = Vec::new();
+        let mut send = |from: &super::Session, to: &super::Session| {
+            let mut msg = encrypt(from);
+            sent.push((msg.len(), msg.bytes()[0..4].to_vec()));
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+        // Hello, repeat hello, key, then traffic
+        encrypt(&my_session);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        let handshake_len = CryptoHeader::SIZE + 16;
+        assert_eq!(
+            sent,
+            vec![
+                (handshake_len, vec![0, 0, 0, 1]),
+                (handshake_len, vec![0, 0, 0, 2]),
+                (4 + 16 + 16, vec![0, 0, 0, 4]),
+                (4 + 16 + 16, vec![0, 0, 0, 6]),
+            ]
+        );
+
+        // A traffic packet is the big-endian nonce followed by the ciphertext
+        let (nonce, secret, is_initiator) = {
+            let session = my_session.inner.session_mut.read();
+            (
+                session.next_nonce,
+                *session.shared_secret.as_bytes(),
+                session.is_initiator,
+            )
+        };
+        let mut expected = mk_msg(256);
+        expected.push_bytes(b"HelloWorld012345").unwrap();
+        super::encrypt(nonce, &mut expected, secret, is_initiator).unwrap();
+        expected.push_bytes(&nonce.to_be_bytes()).unwrap();
+        assert_eq!(encrypt(&my_session).bytes(), expected.bytes());
+    }
+
+    #[test]
+    pub fn test_auto_grow() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+        my_session.set_auto_grow(true);
+        her_session.set_auto_grow(true);
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = Message::rnew(0);
+            msg.ensure_padding(16).unwrap();
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        };
+        for _ in 0..2 {
+            send(&my_session, &her_session);
+            send(&her_session, &my_session);
+        }
+        assert_eq!(my_session.get_state(), State::Established);
+
+        // A message whose buffer belongs to C still needs the padding
+        let mut msg = mk_msg(16);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        let err = my_session.encrypt_msg(&mut msg).unwrap_err();
+        assert!(err.downcast_ref::<super::EncryptError>().is_some());
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+    }
+
+    #[test]
+    pub fn test_pooled_messages() {
+        use crate::interface::wire::message_pool::MessagePool;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let pool = MessagePool::new(1, 1400, MessagePool::DEFAULT_PADDING);
+        let payload = [0x5a_u8; 1400];
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = pool.get();
+            msg.push_bytes(&payload).unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), &payload[..]);
+        };
+        for _ in 0..3 {
+            send(&my_session, &her_session);
+            send(&her_session, &my_session);
+        }
+        assert_eq!(pool.idle_count(), 1);
+    }
+
+    #[test]
+    pub fn test_decrypt_bad_buffer() {
+        use super::{BufferProblem, DecryptError};
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let encrypt = |from: &super::Session, payload: &[u8]| {
+            let mut msg = Message::rnew(256 + payload.len());
+            msg.push_bytes(payload).unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg.bytes().to_vec()
+        };
+        for _ in 0..2 {
+            let ct = encrypt(&my_session, b"HelloWorld012345");
+            let mut msg = Message::rnew(256);
+            msg.push_bytes(&ct).unwrap();
+            her_session.decrypt_msg(&mut msg).unwrap();
+            let ct = encrypt(&her_session, b"HelloWorld012345");
+            let mut msg = Message::rnew(256);
+            msg.push_bytes(&ct).unwrap();
+            my_session.decrypt_msg(&mut msg).unwrap();
+        }
+
+        // The data ends where the padding does, so the padding decides the alignment.
+        let bad_buffer = |err: anyhow::Error| match err.downcast_ref::<DecryptError>() {
+            Some(DecryptError::BadBuffer(problem)) => problem.clone(),
+            _ => panic!("unexpected error {}", err),
+        };
+        let payload = b"HelloWorld012345";
+        let ct = encrypt(&my_session, payload);
+        assert_eq!(ct.len() % 4, 0);
+        let mut msg = Message::rnew(ct.len() + 1);
+        msg.push_bytes(&ct).unwrap();
+        assert!(!msg.is_aligned_to(4));
+        let err = her_session.decrypt_msg(&mut msg).unwrap_err();
+        let addr = msg.data_ptr();
+        assert_eq!(bad_buffer(err), BufferProblem::Alignment { addr, align: 4 });
+        assert_eq!(msg.bytes(), &ct[..]);
+
+        let odd_payload = b"HelloWorld01234567";
+        let odd_ct = encrypt(&my_session, odd_payload);
+        assert_eq!(odd_ct.len() % 4, 2);
+        let mut odd_msg = Message::rnew(odd_ct.len() + 8);
+        odd_msg.push_bytes(&odd_ct).unwrap();
+        assert!(odd_msg.is_aligned_to(4));
+        let err = her_session.decrypt_msg(&mut odd_msg).unwrap_err();
+        let cap = odd_ct.len();
+        assert_eq!(
+            bad_buffer(err),
+            BufferProblem::Capacity { cap, multiple: 4 }
+        );
+
+        // Messages owned by C can't be fixed
+        her_session.set_auto_grow(true);
+        let mut c_msg = mk_msg(ct.len() + 1);
+        c_msg.push_bytes(&ct).unwrap();
+        let err = her_session.decrypt_msg(&mut c_msg).unwrap_err();
+        assert!(matches!(bad_buffer(err), BufferProblem::Alignment { .. }));
+
+        // Rust owned ones are moved to a good buffer
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert!(msg.is_aligned_to(4));
+        assert_eq!(msg.bytes(), payload);
+        her_session.decrypt_msg(&mut odd_msg).unwrap();
+        assert_eq!(odd_msg.bytes(), odd_payload);
+    }
+
+    fn established_sessions() -> (super::Session, super::Session) {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        for _ in 0..2 {
+            for (from, to) in &[(&my_session, &her_session), (&her_session, &my_session)] {
+                let mut msg = Message::rnew(512);
+                msg.push_bytes(b"HelloWorld012345").unwrap();
+                from.encrypt_msg(&mut msg).unwrap();
+                to.decrypt_msg(&mut msg).unwrap();
+            }
+        }
+        (my_session, her_session)
+    }
+
+    #[test]
+    pub fn test_encrypt_vectored() {
+        use std::io::IoSlice;
+
+        use super::{BufferProblem, DecryptError};
+
+        let (my_session, her_session) = established_sessions();
+
+        let header = [0x11_u8; 40];
+        let payload = [0x22_u8; 1000];
+        let mut msg = Message::rnew(2048);
+        msg.push_bytes(b"stale data").unwrap();
+        let bufs = [IoSlice::new(&header), IoSlice::new(&payload)];
+        my_session.encrypt_vectored(&bufs, &mut msg).unwrap();
+        assert_eq!(msg.len(), header.len() + payload.len() + 20);
+
+        // Too small for the plaintext, the packet can still be decrypted
+        let mut out = vec![0_u8; 2048];
+        let err = her_session
+            .decrypt_into(&mut msg, &mut out[..1039])
+            .unwrap_err();
+        let problem = BufferProblem::OutputTooSmall {
+            len: 1039,
+            required: 1040,
+        };
+        assert_eq!(err, DecryptError::BadBuffer(problem));
+
+        assert_eq!(
+            her_session.decrypt_into(&mut msg, &mut out[..1040]),
+            Ok(1040)
+        );
+        assert_eq!(&out[..40], &header[..]);
+        assert_eq!(&out[40..1040], &payload[..]);
+
+        // Without enough padding only auto grow helps
+        let mut small = Message::rnew(64);
+        assert!(my_session.encrypt_vectored(&bufs, &mut small).is_err());
+        my_session.set_auto_grow(true);
+        my_session.encrypt_vectored(&bufs, &mut small).unwrap();
+        assert_eq!(her_session.decrypt_into(&mut small, &mut out), Ok(1040));
+        assert_eq!(&out[40..1040], &payload[..]);
+    }
+
+    #[test]
+    pub fn test_auto_realign() {
+        use std::os::raw::c_char;
+
+        use super::{BufferProblem, DecryptError};
+
+        let (my_session, her_session) = established_sessions();
+
+        // A packet as received by a socket library, at `offset` in a 2 KiB buffer owned by C
+        let received = |ct: &[u8], offset: usize| unsafe {
+            let alloc = cffi::MallocAllocator__new(4096, "".as_ptr() as *const c_char, 0);
+            let mut msg = Message::from_c_message(cffi::Message_new(2048, 0, alloc));
+            msg.bytes_mut()[offset..offset + ct.len()].copy_from_slice(ct);
+            msg.discard_bytes(offset).unwrap();
+            (*msg.as_c_message())._length = ct.len() as i32;
+            msg
+        };
+        let encrypt = |payload: &[u8]| {
+            let mut msg = Message::rnew(256);
+            msg.push_bytes(payload).unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            msg.bytes().to_vec()
+        };
+
+        for offset in 1..4 {
+            let ct = encrypt(b"HelloWorld012345");
+            let mut msg = received(&ct, offset);
+            let err = her_session.decrypt_msg(&mut msg).unwrap_err();
+            match err.downcast_ref::<DecryptError>() {
+                Some(DecryptError::BadBuffer(BufferProblem::Alignment { align: 4, .. })) => (),
+                _ => panic!("unexpected error {}", err),
+            }
+        }
+        assert_eq!(her_session.realigned_messages(), 0);
+
+        her_session.set_auto_realign(true);
+        for offset in 1..4 {
+            let ct = encrypt(b"HelloWorld012345");
+            let mut msg = received(&ct, offset);
+            let c_msg = msg.as_c_message();
+            her_session.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.as_c_message(), c_msg);
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        }
+        assert_eq!(her_session.realigned_messages(), 3);
+
+        // Encrypting realigns too
+        my_session.set_auto_realign(true);
+        let mut msg = Message::rnew(256 + 2);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        assert!(!msg.is_aligned_to(4));
+        my_session.encrypt_msg(&mut msg).unwrap();
+        let ct = msg.bytes().to_vec();
+        let mut msg = Message::rnew(256);
+        msg.push_bytes(&ct).unwrap();
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+        assert_eq!(my_session.realigned_messages(), 1);
+    }
+
+    #[test]
+    pub fn test_encrypt_errors() {
+        use super::EncryptError;
+
+        let (my_session, _her_session) = established_sessions();
+        let encrypt_err = |mut msg: Message| {
+            let err = my_session.encrypt_msg(&mut msg).unwrap_err();
+            err.downcast_ref::<EncryptError>().cloned()
+        };
+
+        let mut msg = Message::rnew(256 + 2);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        assert!(!msg.is_aligned_to(4));
+        assert_eq!(encrypt_err(msg), Some(EncryptError::Misaligned));
+
+        assert_eq!(
+            encrypt_err(Message::rnew(256)),
+            Some(EncryptError::EmptyHandshakePayload)
+        );
+
+        let mut msg = Message::rnew(8 + 16);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        let padding = EncryptError::InsufficientPadding {
+            required: 20,
+            available: 8,
+        };
+        assert_eq!(encrypt_err(msg), Some(padding));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Message buffer overrun")]
+    pub fn test_canary_overrun() {
+        let (my_session, _her_session) = established_sessions();
+        let mut msg = Message::rnew(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        // Write one byte past the end of the data, which is also the end of the capacity
+        unsafe { *((msg.data_ptr() + msg.cap()) as *mut u8) = 0 };
+        let _ = my_session.encrypt_msg(&mut msg);
+    }
+
+    /// Run with `cargo test --release -- --ignored bench_encrypt_vectored --nocapture`
+    #[test]
+    #[ignore]
+    pub fn bench_encrypt_vectored() {
+        use std::io::IoSlice;
+        use std::time::Instant;
+
+        const ROUNDS: u32 = 100_000;
+        let (my_session, _her_session) = established_sessions();
+        let header = [0x11_u8; 40];
+        for &size in &[64_usize, 1400] {
+            let payload = vec![0x22_u8; size];
+            let mut msg = Message::rnew(2048);
+            let time = |f: &mut dyn FnMut()| {
+                let start = Instant::now();
+                for _ in 0..ROUNDS {
+                    f();
+                }
+                start.elapsed() / ROUNDS
+            };
+
+            // Only the copies, encryption takes most of the time otherwise
+            let mut gather_joined = || {
+                let mut joined = Vec::with_capacity(header.len() + size);
+                joined.extend_from_slice(&header);
+                joined.extend_from_slice(&payload);
+                msg.clear();
+                msg.push_bytes(&joined).unwrap();
+            };
+            let gather_joined = time(&mut gather_joined);
+            let mut gather_vectored = || {
+                msg.clear();
+                msg.push_bytes(&payload).unwrap();
+                msg.push_bytes(&header).unwrap();
+            };
+            let gather_vectored = time(&mut gather_vectored);
+
+            let mut joined = || {
+                let mut joined = Vec::with_capacity(header.len() + size);
+                joined.extend_from_slice(&header);
+                joined.extend_from_slice(&payload);
+                msg.clear();
+                msg.push_bytes(&joined).unwrap();
+                my_session.encrypt_msg(&mut msg).unwrap();
+            };
+            let joined = time(&mut joined);
+            let mut vectored = || {
+                let bufs = [IoSlice::new(&header), IoSlice::new(&payload)];
+                my_session.encrypt_vectored(&bufs, &mut msg).unwrap();
+            };
+            let vectored = time(&mut vectored);
+
+            println!(
+                "{} byte payload, gather: joined {:?}, vectored {:?}",
+                size, gather_joined, gather_vectored
+            );
+            println!(
+                "{} byte payload, encrypt: joined {:?}, vectored {:?}",
+                size, joined, vectored
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_new_from_hex_key() {
+        use crate::crypto::keys::{KeyParseError, PrivateKeyExt};
+
+        let my_keys = CJDNSKeysApi::new().unwrap().key_pair();
+        let key_hex = my_keys.private_key.expose_secret().to_hex();
+        let ca = super::CryptoAuth::new_from_hex_key(&key_hex, EventBase {}, Random::Fake);
+        assert_eq!(ca.unwrap().public_key, my_keys.public_key);
+
+        let zero_key = "0".repeat(64);
+        let err = super::CryptoAuth::new_from_hex_key(&zero_key, EventBase {}, Random::Fake);
+        assert_eq!(err.err(), Some(KeyParseError::ZeroKey));
+        let err = super::CryptoAuth::new_from_hex_key(&key_hex[1..], EventBase {}, Random::Fake);
+        assert_eq!(err.err(), Some(KeyParseError::BadLength(63)));
+    }
+
+    #[test]
+    pub fn test_new_from_identity() {
+        use crate::crypto::keys::{Keypair, NodeIdentity};
+
+        let keypair = Keypair::generate(&Random::new_sodium().unwrap());
+        let mut conf = Vec::new();
+        NodeIdentity::new(keypair.clone())
+            .unwrap()
+            .save(&mut conf)
+            .unwrap();
+        let identity = NodeIdentity::load(&conf[..]).unwrap();
+        let ca = super::CryptoAuth::new_from_identity(&identity, EventBase {}, Random::Fake);
+        assert_eq!(&ca.public_key, keypair.public());
+        assert_eq!(&ca.ipv6().unwrap(), keypair.ip6());
+    }
+
+    #[test]
+    pub fn test_get_auth() {
+        use crate::crypto::crypto_header::AuthType;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let ip6 = [0xfc; 16];
+        ca.add_user_ipv6(ByteString::from("pass1".to_string()), None, Some(ip6), None)
+            .unwrap();
+        ca.add_user_ipv6(
+            ByteString::from("pass2".to_string()),
+            Some(ByteString::from("alice".to_string())),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let (secret, auth) = super::hash_password(b"", b"pass1", AuthType::One);
+        let found = ca.get_auth(&auth);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].secret.as_bytes(), secret.as_bytes());
+        assert_eq!(
+            found[0].restricted_to_ip6,
+            Some(super::Ip6Restriction::Exact(ip6.into()))
+        );
+
+        let (secret, auth) = super::hash_password(b"alice", b"pass2", AuthType::Two);
+        let found = ca.get_auth(&auth);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].secret.as_bytes(), secret.as_bytes());
+        assert_eq!(found[0].restricted_to_ip6, None);
+
+        let (_, auth) = super::hash_password(b"", b"pass3", AuthType::One);
+        assert!(ca.get_auth(&auth).is_empty());
+        let (_, auth) = super::hash_password(b"bob", b"pass2", AuthType::Two);
+        assert!(ca.get_auth(&auth).is_empty());
+        let (_, mut auth) = super::hash_password(b"", b"pass1", AuthType::One);
+        auth.auth_type = AuthType::Zero;
+        assert!(ca.get_auth(&auth).is_empty());
+
+        // The remaining users are still found once others are removed
+        ca.remove_users_where(|_, ip6| ip6.is_some());
+        let (_, auth) = super::hash_password(b"", b"pass1", AuthType::One);
+        assert!(ca.get_auth(&auth).is_empty());
+        let (secret, auth) = super::hash_password(b"alice", b"pass2", AuthType::Two);
+        let found = ca.get_auth(&auth);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].secret.as_bytes(), secret.as_bytes());
+        let (secret, auth) = super::hash_password(b"", b"pass2", AuthType::One);
+        assert_eq!(ca.get_auth(&auth)[0].secret.as_bytes(), secret.as_bytes());
+    }
+
+    #[test]
+    pub fn test_users_lookup_collisions() {
+        use crate::crypto::crypto_header::{AuthType, Challenge};
+
+        fn mk_user(login: &str, password_hash: [u8; Challenge::KEYSIZE]) -> super::User {
+            super::User {
+                login: ByteString::from(login.to_string()),
+                password_hash,
+                ..super::User::default()
+            }
+        }
+
+        let lookup = [3, 4, 5, 6, 7, 8, 9];
+        let mut key = [AuthType::One as u8; Challenge::KEYSIZE];
+        key[1..].copy_from_slice(&lookup);
+        let mut users = super::Users::new();
+        users.push(mk_user("first", key));
+        // Same lookup bytes, but not the key of an AuthType One challenge
+        let mut other_key = key;
+        other_key[0] = AuthType::Two as u8;
+        users.push(mk_user("other", other_key));
+        users.push(mk_user("second", key));
+        let mut different_key = key;
+        different_key[7] ^= 1;
+        users.push(mk_user("different", different_key));
+
+        let challenge = Challenge {
+            auth_type: AuthType::One,
+            lookup,
+            ..Challenge::default()
+        };
+        let found: Vec<&[u8]> = users.find(&challenge).map(|u| &u.login[..]).collect();
+        assert_eq!(found, vec![&b"first"[..], b"second"]);
+
+        // The candidates are confirmed again after removals
+        users.retain(|u| &u.login[..] != b"first");
+        let found: Vec<&[u8]> = users.find(&challenge).map(|u| &u.login[..]).collect();
+        assert_eq!(found, vec![&b"second"[..]]);
+
+        let challenge = Challenge {
+            lookup: [0; 7],
+            ..challenge
+        };
+        assert_eq!(users.find(&challenge).count(), 0);
+    }
+
+    #[test]
+    pub fn test_ipv6() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(keys.private_key), EventBase {}, Random::Fake);
+        let expected = super::ip6_from_key(keys.public_key.raw())
+            .chunks(2)
+            .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(expected.starts_with("fc"));
+        assert_eq!(ca.ipv6().unwrap().to_string(), expected);
+
+        // A key whose address is outside of fc00::/8
+        let priv_key = (1..=255)
+            .map(|i| PrivateKey::from([i; 32]))
+            .find(|k| {
+                super::ip6_from_key(super::crypto_scalarmult_curve25519_base(k).raw())[0] != 0xfc
+            })
+            .unwrap();
+        let ca = super::CryptoAuth::new(Some(priv_key), EventBase {}, Random::Fake);
+        assert_eq!(ca.ipv6().err(), Some(super::KeyError::BadPublicKey));
+    }
+
+    #[test]
+    pub fn test_parse_packet() {
+        use super::{DecryptErr, Packet};
+
+        let mut bytes = vec![0_u8; 256];
+        assert!(matches!(
+            super::parse_packet(&bytes[..19]),
+            Err(DecryptErr::Runt)
+        ));
+        assert!(matches!(
+            super::parse_packet(&bytes[..20]),
+            Err(DecryptErr::Runt)
+        ));
+        assert!(matches!(
+            super::parse_packet(&bytes[..119]),
+            Err(DecryptErr::Runt)
+        ));
+        assert!(matches!(
+            super::parse_packet(&bytes[..120]),
+            Ok(Packet::Handshake { nonce: 0, .. })
+        ));
+        assert!(matches!(
+            super::parse_packet(&bytes),
+            Ok(Packet::Handshake { nonce: 0, .. })
+        ));
+
+        for auth_type in [3_u8, 4, 0xff].iter() {
+            bytes[4] = *auth_type;
+            assert!(matches!(
+                super::parse_packet(&bytes),
+                Err(DecryptErr::UnrecognizedAuth)
+            ));
+        }
+        bytes[4] = 2;
+        bytes[3] = 3;
+        assert!(matches!(
+            super::parse_packet(&bytes),
+            Ok(Packet::Handshake { nonce: 3, .. })
+        ));
+
+        // Traffic packets have no header, the auth type byte is ciphertext
+        bytes[3] = 4;
+        bytes[4] = 0xff;
+        assert!(matches!(
+            super::parse_packet(&bytes[..20]),
+            Ok(Packet::Traffic { nonce: 4 })
+        ));
+        bytes[0..4].copy_from_slice(&[0xff; 4]);
+        assert!(matches!(
+            super::parse_packet(&bytes[..20]),
+            Ok(Packet::Traffic { nonce: 0xffff_ffff })
+        ));
+    }
+
+    #[test]
+    pub fn test_handshake_header() {
+        use super::Packet;
+        use crate::crypto::crypto_header::{AuthType, CryptoHeader};
+        use crate::crypto::wipe::Wipe;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        my_session.set_auth(
+            Some(ByteString::from("pass".to_string())),
+            Some(ByteString::from("alice".to_string())),
+        );
+
+        let mut msg = mk_msg(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut msg).unwrap();
+        let mut header = match super::parse_packet(msg.bytes()) {
+            Ok(Packet::Handshake { nonce: 0, header }) => header,
+            other => panic!("unexpected {:?}", other),
+        };
+
+        // The fields are read from where they are in the CryptoHeader
+        let full = msg.peek::<CryptoHeader>().unwrap();
+        assert_eq!(header.auth.auth_type, AuthType::Two);
+        assert_eq!(header.auth.as_key_bytes(), full.auth.as_key_bytes());
+        assert_eq!(header.handshake_nonce, full.handshake_nonce);
+        assert_eq!(header.public_key, full.public_key);
+        assert_eq!(
+            &header.public_key,
+            my_session.inner.context.public_key.raw()
+        );
+
+        // Wiping zeroes all of it, leaving a valid auth type
+        (&mut header).wipe();
+        assert_eq!(header.auth.auth_type, AuthType::Zero);
+        assert_eq!(header.auth.as_key_bytes(), &[0; 8]);
+        assert_eq!(header.auth.require_packet_auth_and_derivation_count, 0);
+        assert_eq!(header.auth.additional, 0);
+        assert_eq!(header.handshake_nonce, [0; 24]);
+        assert_eq!(header.public_key, [0; 32]);
+    }
+
+    #[test]
+    pub fn test_fuzz_decrypt_regressions() {
+        let mut hello = vec![0_u8; 120];
+        // AuthType Three used to hit `unreachable!()` in `get_auth()`
+        hello[4] = 3;
+
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0; 19],
+            vec![0; 20],
+            vec![0; 119],
+            vec![0; 120],
+            hello,
+            vec![0; 4096],
+            [&[0, 0, 0, 2][..], &[0xaa; 4092][..]].concat(),
+            [&[0, 0, 0, 4][..], &[0xaa; 4092][..]].concat(),
+            vec![0xff; 4096],
+        ];
+        for case in cases.iter() {
+            super::fuzz_decrypt(case, 0);
+            super::fuzz_decrypt(case, u32::MAX);
+            super::fuzz_decrypt_established(case);
+        }
+    }
+
+    #[test]
+    pub fn test_decrypt_never_panics() {
+        use crate::crypto::secret::SecretBuf32;
+
+        // xorshift64, so a failing case is the same on every run
+        let mut rng = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = move || {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            rng
+        };
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let new_session = |private_key: &PrivateKey, her_key: &PublicKey| {
+            let ca = super::CryptoAuth::new(Some(private_key.clone()), EventBase {}, Random::Fake);
+            super::Session::new(Arc::new(ca), her_key.clone(), false, None).unwrap()
+        };
+
+        // A hello, a key packet and traffic, to be truncated and corrupted
+        let my_session = new_session(&my_keys.private_key, &her_keys.public_key);
+        let her_session = new_session(&her_keys.private_key, &my_keys.public_key);
+        let mut packets = Vec::new();
+        for (from, to) in &[(&my_session, &her_session), (&her_session, &my_session)].repeat(2) {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            packets.push(msg.bytes().to_vec());
+            to.decrypt_msg(&mut msg).unwrap();
+        }
+
+        // Sessions in each state which takes a different path
+        let fresh = new_session(&her_keys.private_key, &my_keys.public_key);
+        let blinding = new_session(&her_keys.private_key, &my_keys.public_key);
+        blinding.set_key_blinding(true);
+        let sent_hello = new_session(&my_keys.private_key, &her_keys.public_key);
+        let mut hello = mk_msg(256);
+        hello.push_bytes(b"HelloWorld012345").unwrap();
+        sent_hello.encrypt_msg(&mut hello).unwrap();
+        let targets = [&fresh, &blinding, &sent_hello, &her_session];
+
+        let mut cases = Vec::new();
+        for packet in packets.iter() {
+            for len in (0..=packet.len()).step_by(4) {
+                cases.push(packet[..len].to_vec());
+            }
+            for _ in 0..100 {
+                let mut corrupt = packet.clone();
+                let i = next() as usize % corrupt.len();
+                corrupt[i] ^= 1 << (next() % 8);
+                cases.push(corrupt);
+            }
+        }
+        for _ in 0..2000 {
+            let len = next() as usize % 256 & !3;
+            let mut case: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Mostly handshake nonces, random ones are taken for traffic
+            if len >= 4 && next() % 2 == 0 {
+                case[..4].copy_from_slice(&(next() as u32 % 6).to_be_bytes());
+            }
+            cases.push(case);
+        }
+
+        for case in cases.iter() {
+            for target in targets.iter() {
+                let _ = target.decrypt_untrusted(case);
+            }
+        }
+
+        // A low-order key with a password used to panic in scalarmult
+        let password_hash = SecretBuf32::from([7; 32]);
+        super::get_shared_secret(&[1; 32], [0; 32], Some(&password_hash), false);
+    }
+
+    #[test]
+    pub fn test_decrypt_untrusted() {
+        use super::DecryptErr;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(
+            Some(my_keys.private_key),
+            EventBase {},
+            Random::new_sodium().unwrap(),
+        );
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let encrypt = |from: &super::Session, data: &[u8]| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(data).unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg.bytes().to_vec()
+        };
+
+        // Truncated and misaligned input to a new session
+        assert_eq!(her_session.decrypt_untrusted(&[]), Err(DecryptErr::Runt));
+        assert_eq!(
+            her_session.decrypt_untrusted(&[0; 3]),
+            Err(DecryptErr::Runt)
+        );
+        assert_eq!(
+            her_session.decrypt_untrusted(&[0; 16]),
+            Err(DecryptErr::Runt)
+        );
+        let hello = encrypt(&my_session, b"HelloWorld012345");
+        assert_eq!(
+            her_session.decrypt_untrusted(&hello[..100]),
+            Err(DecryptErr::Runt)
+        );
+        let err = Err(DecryptErr::HandshakeDecryptFailed);
+        assert_eq!(her_session.decrypt_untrusted(&hello[..121]), err);
+
+        // The handshake goes through it as well
+        assert_eq!(
+            her_session.decrypt_untrusted(&hello),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+        let key = encrypt(&her_session, b"HelloWorld012345");
+        assert_eq!(
+            my_session.decrypt_untrusted(&key),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+        let data = encrypt(&my_session, b"HelloWorld012345");
+        assert_eq!(
+            her_session.decrypt_untrusted(&data),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+
+        // Truncated and oversized traffic
+        let data = encrypt(&my_session, b"0123");
+        assert_eq!(
+            her_session.decrypt_untrusted(&data[..16]),
+            Err(DecryptErr::Runt)
+        );
+        assert_eq!(
+            her_session.decrypt_untrusted(&data[..20]),
+            Err(DecryptErr::Decrypt)
+        );
+        let oversized = [&data[..], &[0xaa; 65536][..]].concat();
+        assert_eq!(
+            her_session.decrypt_untrusted(&oversized),
+            Err(DecryptErr::Decrypt)
+        );
+        assert_eq!(her_session.decrypt_untrusted(&data), Ok(b"0123".to_vec()));
+        assert_eq!(
+            her_session.decrypt_untrusted(&data),
+            Err(DecryptErr::Replay)
+        );
+    }
+
+    #[test]
+    pub fn test_decrypt_untrusted_odd_length() {
+        let (my_session, her_session) = established_sessions();
+
+        for payload in [&b"HelloWorld01234567"[..], b"Hello", b"HelloWorld012345678"].iter() {
+            // Padding of the plaintext length, so the plaintext starts aligned
+            let mut msg = Message::rnew(256 + payload.len());
+            msg.push_bytes(payload).unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            assert_ne!(msg.len() % 4, 0);
+            assert_eq!(
+                her_session.decrypt_untrusted(msg.bytes()),
+                Ok(payload.to_vec())
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_concurrent_final_handshake_step() {
+        use std::sync::Barrier;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        /// The session is only used for decryption, which doesn't touch the ifaces.
+        struct Shared(super::Session);
+        unsafe impl Send for Shared {}
+        unsafe impl Sync for Shared {}
+
+        const THREADS: usize = 8;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session = Arc::new(Shared(
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap(),
+        ));
+
+        let encrypt = |from: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg.bytes().to_vec()
+        };
+        let decrypt = |to: &super::Session, bytes: &[u8]| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(bytes).unwrap();
+            to.decrypt_msg(&mut msg)
+        };
+
+        decrypt(&her_session.0, &encrypt(&my_session)).unwrap();
+        decrypt(&my_session, &encrypt(&her_session.0)).unwrap();
+        assert_eq!(her_session.0.get_state(), State::SentKey);
+
+        // Every thread gets a copy of the first traffic packet
+        let packet = Arc::new(encrypt(&my_session));
+        let barrier = Arc::new(Barrier::new(THREADS));
+        let threads = (0..THREADS)
+            .map(|_| {
+                let (session, packet, barrier) = (
+                    Arc::clone(&her_session),
+                    Arc::clone(&packet),
+                    Arc::clone(&barrier),
+                );
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    decrypt(&session.0, &packet).map_err(|e| {
+                        let failure = e.downcast_ref::<super::DecryptFailure>().unwrap();
+                        // Only one thread at a time can try the final step, the others
+                        // find the session established and take the packet for traffic.
+                        assert_eq!(failure.phase(), super::DecryptPhase::Traffic);
+                        e.downcast_ref::<super::DecryptError>().cloned().unwrap()
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        for err in results.into_iter().filter_map(Result::err) {
+            assert_eq!(
+                err,
+                super::DecryptError::DecryptErr(super::DecryptErr::Replay)
+            );
+        }
+        assert_eq!(her_session.0.get_state(), State::Established);
+    }
+
+    #[test]
+    pub fn test_truncated_handshake_packets() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let mk_session = |private_key, her_pub_key| {
+            let ca = super::CryptoAuth::new(Some(private_key), EventBase {}, Random::Fake);
+            super::Session::new(Arc::new(ca), her_pub_key, false, None).unwrap()
+        };
+        let my_session = mk_session(my_keys.private_key.clone(), her_keys.public_key.clone());
+        let her_session = mk_session(her_keys.private_key.clone(), my_keys.public_key.clone());
+
+        let encrypt = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg.bytes().to_vec()
+        };
+        // Every prefix of a valid packet which still carries a full header must be rejected
+        // with an error, never a panic.
+        let check_truncated = |packet: &[u8]| {
+            for len in super::CryptoHeader::SIZE..packet.len() {
+                let fresh = mk_session(her_keys.private_key.clone(), my_keys.public_key.clone());
+                let mut msg = mk_msg(256);
+                msg.push_bytes(&packet[..len]).unwrap();
+                let err = fresh
+                    .decrypt_msg(&mut msg)
+                    .expect_err("truncated packet accepted");
+                assert!(
+                    err.downcast_ref::<super::DecryptError>().is_some(),
+                    "len {}: {}",
+                    len,
+                    err
+                );
+            }
+        };
+
+        let hello = encrypt(&my_session);
+        check_truncated(&hello);
+
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&hello).unwrap();
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let key = encrypt(&her_session);
+        check_truncated(&key);
+
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&key).unwrap();
+        my_session.decrypt_msg(&mut msg).unwrap();
+        let data = encrypt(&my_session);
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&data).unwrap();
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(her_session.get_state(), State::Established);
+
+        // A truncated hello arriving on an established session is dropped as well
+        // and leaves the session intact.
+        for len in [
+            super::CryptoHeader::SIZE,
+            super::CryptoHeader::SIZE + 16,
+            hello.len() - 1,
+        ] {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(&hello[..len]).unwrap();
+            assert!(her_session.decrypt_msg(&mut msg).is_err());
+        }
+        assert_eq!(her_session.get_state(), State::Established);
+    }
+
+    #[test]
+    pub fn test_is_abandoned() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+        use crate::util::events::ManualClock;
+
+        const MAX_SETUP_AGE: u32 = 60;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let clock = Arc::new(ManualClock::new(1_000_000));
+
+        let mk_session = |private_key, her_pub_key| {
+            let ca = super::CryptoAuth::new(Some(private_key), Arc::clone(&clock), Random::Fake);
+            super::Session::new(Arc::new(ca), her_pub_key, false, None).unwrap()
+        };
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+
+        // Half-open session which is never answered
+        let half_open = mk_session(my_keys.private_key.clone(), her_keys.public_key.clone());
+        let mut msg = mk_msg(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        half_open.encrypt_msg(&mut msg).unwrap();
+        assert_eq!(half_open.get_state(), State::SentHello);
+
+        // Established session
+        let my_session = mk_session(my_keys.private_key.clone(), her_keys.public_key.clone());
+        let her_session = mk_session(her_keys.private_key.clone(), my_keys.public_key.clone());
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        assert_eq!(her_session.get_state(), State::Established);
+
+        clock.advance(MAX_SETUP_AGE);
+        assert!(!half_open.is_abandoned(MAX_SETUP_AGE));
+
+        clock.advance(1);
+        assert!(half_open.is_abandoned(MAX_SETUP_AGE));
+        assert!(!my_session.is_abandoned(MAX_SETUP_AGE));
+        assert!(!her_session.is_abandoned(MAX_SETUP_AGE));
+
+        // The timeout logic still leaves the half-open session alone
+        half_open.reset_if_timeout();
+        assert_eq!(half_open.get_state(), State::SentHello);
+    }
+
+    #[test]
+    pub fn test_get_user_info_restrictions() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        assert!(ca.get_user_info().is_empty());
+
+        let ip6 = [0xfc; 16];
+        ca.add_user_ipv6(
+            ByteString::from("pass1".to_string()),
+            Some(ByteString::from("alice".to_string())),
+            Some(ip6),
+            None,
+        )
+        .unwrap();
+        ca.add_user_ipv6(
+            ByteString::from("pass2".to_string()),
+            Some(ByteString::from("bob".to_string())),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let users = ca.get_user_info();
+        assert_eq!(
+            users,
+            vec![
+                super::UserInfo {
+                    login: ByteString::from("alice".to_string()),
+                    restricted_to_ip6: Some(super::Ip6Restriction::Exact(super::IpV6::from(ip6))),
+                    index: 0,
+                },
+                super::UserInfo {
+                    login: ByteString::from("bob".to_string()),
+                    restricted_to_ip6: None,
+                    index: 1,
+                },
+            ]
+        );
+        let logins = users.into_iter().map(|u| u.login).collect::<Vec<_>>();
+        assert_eq!(logins, ca.get_users());
+
+        ca.remove_users(Some(ByteString::from("alice".to_string())));
+        assert_eq!(ca.get_user_info().len(), 1);
+    }
+
+    #[test]
+    pub fn test_add_user_ipv6_str() {
+        use crate::crypto::keys::KeyParseError;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+
+        let add = |login: &str, ip6: Option<&str>| {
+            ca.add_user_ipv6_str(
+                ByteString::from(format!("pass_{}", login)),
+                Some(ByteString::from(login.to_string())),
+                ip6,
+                None,
+            )
+        };
+        add("alice", Some("fc12:3456::1")).unwrap();
+        add("bob", None).unwrap();
+        let bad = add("carol", Some("fd12:3456::1"));
+        assert_eq!(
+            bad,
+            Err(super::AddUserError::BadIp6(KeyParseError::NotCjdnsIp6))
+        );
+        let bad = add("carol", Some("fc12:3456:1"));
+        assert_eq!(bad, Err(super::AddUserError::BadIp6(KeyParseError::BadIp6)));
+
+        let ip6 = [0xfc, 0x12, 0x34, 0x56, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let users = ca.get_user_info();
+        assert_eq!(users.len(), 2);
+        assert_eq!(
+            users[0].restricted_to_ip6,
+            Some(super::Ip6Restriction::Exact(ip6.into()))
+        );
+        assert_eq!(users[1].restricted_to_ip6, None);
+    }
+
+    #[test]
+    pub fn test_get_user_info() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+
+        let ip6 = [0xfc, 0x12, 0x34, 0x56, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        for (password, ip6) in [("pass1", None), ("pass2", Some(ip6)), ("pass3", None)] {
+            ca.add_user_ipv6(ByteString::from(password.to_string()), None, ip6, None)
+                .unwrap();
+        }
+        let users = ca.get_user_info();
+        assert_eq!(users.len(), 3);
+        assert_eq!(
+            users[1].restricted_to_ip6,
+            Some(super::Ip6Restriction::Exact(ip6.into()))
+        );
+        assert_eq!(users[0].restricted_to_ip6, None);
+
+        // Positions follow the remaining users
+        ca.remove_users(Some(users[0].login.clone()));
+        let users = ca.get_user_info();
+        assert_eq!(
+            users.iter().map(|u| u.index).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(
+            users[0].restricted_to_ip6,
+            Some(super::Ip6Restriction::Exact(ip6.into()))
+        );
+    }
+
+    #[test]
+    pub fn test_replay_reset_handler() {
+        use parking_lot::Mutex;
+
+        use super::ReplayReset;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        // The hello after the reset needs a new temp key
+        let my_ca = super::CryptoAuth::new(
+            Some(my_keys.private_key),
+            EventBase {},
+            Random::new_sodium().unwrap(),
+        );
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events2 = Arc::clone(&events);
+        her_ca.set_replay_reset_handler(move |reason, ip6| events2.lock().push((reason, ip6)));
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None).unwrap();
+        let my_ip6 = super::ip6_from_key(my_keys.public_key.raw());
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+
+        // Accepting the hello advances the handshake
+        send(&my_session, &her_session);
+        assert_eq!(
+            *events.lock(),
+            vec![(ReplayReset::HandshakeAdvanced, my_ip6)]
+        );
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        events.lock().clear();
+
+        // Traffic doesn't touch the replay protector
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        assert!(events.lock().is_empty());
+
+        // The peer restarts the handshake
+        my_session.reset();
+        send(&my_session, &her_session);
+        assert_eq!(
+            *events.lock(),
+            vec![
+                (ReplayReset::NewHello, my_ip6),
+                (ReplayReset::HandshakeAdvanced, my_ip6)
+            ]
+        );
+        events.lock().clear();
+
+        her_session.reset();
+        assert_eq!(*events.lock(), vec![(ReplayReset::SessionReset, my_ip6)]);
+    }
+
+    #[test]
+    pub fn test_hash_password_wire_format() {
+        use crate::crypto::crypto_header::AuthType;
+
+        let (secret, auth) = super::hash_password(b"", b"password", AuthType::One);
+        assert_eq!(
+            hex::encode(secret.as_bytes()),
+            "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+        );
+        assert_eq!(auth.auth_type, AuthType::One);
+        assert_eq!(hex::encode(auth.lookup), "641c99f7719f57");
+
+        let (secret2, auth) = super::hash_password(b"alice", b"password", AuthType::Two);
+        assert_eq!(secret2.as_bytes(), secret.as_bytes());
+        assert_eq!(auth.auth_type, AuthType::Two);
+        assert_eq!(hex::encode(auth.lookup), "d806c97f0e00af");
+    }
+
+    #[test]
+    pub fn test_nonce_never_wraps() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None).unwrap();
+
+        let encrypt = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+
+        let mut msg = encrypt(&my_session);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let mut msg = encrypt(&her_session);
+        my_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(her_session.get_state(), State::SentKey);
+
+        // A forged final handshake step with the largest nonce is dropped without overflowing
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&[0xaa; 32]).unwrap();
+        msg.push_be(u32::MAX).unwrap();
+        let err = her_session.decrypt_msg(&mut msg).unwrap_err();
+        let failure = err.downcast_ref::<super::DecryptFailure>().unwrap();
+        assert_eq!(failure.code(), super::DecryptErr::Decrypt);
+        assert_eq!(failure.phase(), super::DecryptPhase::Handshake);
+
+        let mut msg = encrypt(&my_session);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(her_session.get_state(), State::Established);
+
+        {
+            let mut session = my_session.inner.session_mut.write();
+            session.next_nonce = u32::MAX - 1;
+            assert_eq!(session.nonce_after(3), super::SessionMut::MAX_NONCE);
+            session.next_nonce = super::SessionMut::MAX_NONCE - 1;
+        }
+
+        // The last nonce is still used...
+        let sent_nonce = |msg: &Message| msg.peek_be::<u32>().unwrap();
+        let mut msg = encrypt(&my_session);
+        assert_eq!(sent_nonce(&msg), super::SessionMut::MAX_NONCE - 1);
+        assert_eq!(my_session.next_nonce(), super::SessionMut::MAX_NONCE);
+        her_session.decrypt_msg(&mut msg).unwrap();
+
+        // ...then the session starts over rather than wrapping
+        let msg = encrypt(&my_session);
+        assert_eq!(my_session.get_state(), State::SentHello);
+        assert_eq!(sent_nonce(&msg), 0);
+    }
+
+    #[test]
+    pub fn test_encrypt_her_key_unknown() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        let sess = super::Session::new_with_ip6(Arc::new(ca), her_ip6, false, None).unwrap();
+        assert!(!sess.her_key_known());
+
+        let mut msg = mk_msg(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        let err = sess.encrypt_msg(&mut msg).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::EncryptError>(),
+            Some(&super::EncryptError::HerKeyUnknown)
+        );
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+
+        // The same message can be sent once her key is known
+        sess.set_her_public_key(her_keys.public_key).unwrap();
+        sess.encrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.len(), super::CryptoHeader::SIZE + 16);
+    }
+
+    #[test]
+    pub fn test_deferred_key_handshake() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let her_ip6 = super::ip6_from_key(her_keys.public_key.raw());
+        let my_session =
+            super::Session::new_with_ip6(Arc::new(my_ca), her_ip6, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        };
+
+        // The key arrives after the session was created, then we initiate
+        my_session
+            .set_her_public_key(her_keys.public_key.clone())
+            .unwrap();
+        assert_eq!(my_session.get_her_ip6(), her_ip6);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+
+        // The established session keeps its key
+        assert_eq!(
+            my_session.set_her_public_key(other_keys.public_key),
+            Err(super::KeyError::KeyAlreadySet)
+        );
+        assert_eq!(
+            my_session.set_her_public_key(her_keys.public_key.clone()),
+            Ok(())
+        );
+        assert_eq!(my_session.get_state(), State::Established);
+        send(&my_session, &her_session);
+    }
+
+    /// A node with a new key, using `Random::Fake`.
+    fn new_ca() -> Arc<super::CryptoAuth> {
+        let keys = CJDNSKeysApi::new().unwrap().key_pair();
+        Arc::new(super::CryptoAuth::new(
+            Some(keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ))
+    }
+
+    /// Sessions of two new nodes with each other. No packet is sent.
+    fn new_sessions() -> (super::Session, super::Session) {
+        let my_ca = new_ca();
+        let her_ca = new_ca();
+        let her_key = her_ca.public_key.clone();
+        let my_key = my_ca.public_key.clone();
+        let my_session = super::Session::new(my_ca, her_key, false, None).unwrap();
+        let her_session = super::Session::new(her_ca, my_key, false, None).unwrap();
+        (my_session, her_session)
+    }
+
+    #[test]
+    pub fn test_key_blinding() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let my_ca = new_ca();
+        let her_ca = new_ca();
+        let my_key = my_ca.public_key.clone();
+        let her_key = her_ca.public_key.clone();
+        let my_session = super::Session::new(my_ca, her_key.clone(), false, None).unwrap();
+        // She only knows the ip6, the key is taken from the sealed part of the hello
+        let my_ip6 = super::ip6_from_key(my_key.raw());
+        let her_session = super::Session::new_with_ip6(her_ca, my_ip6, false, None).unwrap();
+        my_session.set_key_blinding(true);
+        her_session.set_key_blinding(true);
+
+        let contains = |bytes: &[u8], key: &PublicKey| bytes.windows(32).any(|w| w == key.raw());
+        let send = |from: &super::Session, to: &super::Session, key: &PublicKey| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            assert!(!contains(msg.bytes(), key));
+            to.decrypt_msg(&mut msg).unwrap();
+            assert_eq!(msg.bytes(), b"HelloWorld012345");
+        };
+
+        assert_eq!(
+            my_session.required_send_padding(),
+            super::CryptoHeader::SIZE + 48
+        );
+        send(&my_session, &her_session, &my_key);
+        assert_eq!(
+            her_session.required_send_padding(),
+            super::CryptoHeader::SIZE
+        );
+        send(&her_session, &my_session, &her_key);
+        send(&my_session, &her_session, &my_key);
+        send(&her_session, &my_session, &her_key);
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+    }
+
+    #[test]
+    pub fn test_key_blinding_mismatch() {
+        let try_hello = |my_blinding: bool, her_blinding: bool| {
+            let (my_session, her_session) = new_sessions();
+            my_session.set_key_blinding(my_blinding);
+            her_session.set_key_blinding(her_blinding);
+
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            her_session
+                .decrypt_msg(&mut msg)
+                .map_err(|e| super::decrypt_err_of(&e))
+        };
+
+        assert!(try_hello(false, false).is_ok());
+        assert!(try_hello(true, true).is_ok());
+        // A node which doesn't expect blinding sees the one-time key
+        assert_eq!(
+            try_hello(true, false),
+            Err(super::DecryptErr::WrongPermPubkey)
+        );
+        assert_eq!(try_hello(false, true), Err(super::DecryptErr::Blinding));
+    }
+
+    #[test]
+    pub fn test_can_decrypt() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key.clone(), false, None).unwrap();
+
+        let encrypt = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+
+        // Handshake packets can't be checked
+        let mut msg = encrypt(&my_session);
+        assert_eq!(
+            her_session.can_decrypt(&msg),
+            Err(super::DecryptErr::NoSession)
+        );
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let mut msg = encrypt(&her_session);
+        my_session.decrypt_msg(&mut msg).unwrap();
+        let mut msg = encrypt(&my_session);
+        her_session.decrypt_msg(&mut msg).unwrap();
+
+        let mut msg = encrypt(&my_session);
+        let sent = msg.bytes().to_vec();
+        let stats = her_session.stats();
+        assert_eq!(her_session.can_decrypt(&msg), Ok(()));
+        assert_eq!(her_session.can_decrypt(&msg), Ok(()));
+        assert_eq!(msg.bytes(), &sent[..]);
+        assert_eq!(her_session.stats(), stats);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(msg.bytes(), b"HelloWorld012345");
+
+        // Now it's a replay
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&sent).unwrap();
+        assert_eq!(
+            her_session.can_decrypt(&msg),
+            Err(super::DecryptErr::Replay)
+        );
+
+        // Tampered packet
+        let mut tampered = sent.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&tampered).unwrap();
+        assert_eq!(
+            her_session.can_decrypt(&msg),
+            Err(super::DecryptErr::Decrypt)
+        );
+
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&sent[..19]).unwrap();
+        assert_eq!(her_session.can_decrypt(&msg), Err(super::DecryptErr::Runt));
+    }
+
+    #[test]
+    pub fn test_decrypt_error_handler() {
+        use parking_lot::Mutex;
+
+        use super::DecryptErr;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors2 = Arc::clone(&errors);
+        her_ca.set_decrypt_error_handler(move |err, ip6| errors2.lock().push((err, ip6)));
+        let my_ip6 = super::ip6_from_key(my_keys.public_key.raw());
+
+        let encrypt = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+
+        // No password while she requires auth
+        let strict_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                .unwrap();
+        let mut msg = encrypt(&my_session);
+        assert!(strict_session.decrypt_msg(&mut msg).is_err());
+        assert_eq!(*errors.lock(), vec![(DecryptErr::AuthRequired, my_ip6)]);
+        errors.lock().clear();
+        drop(strict_session);
+
+        let her_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), false, None)
+                .unwrap();
+        let mut msg = encrypt(&my_session);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let mut msg = encrypt(&her_session);
+        my_session.decrypt_msg(&mut msg).unwrap();
+        let mut msg = encrypt(&my_session);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert!(errors.lock().is_empty());
+
+        // Replayed traffic packet
+        let msg = encrypt(&my_session);
+        let sent = msg.bytes().to_vec();
+        for _ in 0..2 {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(&sent).unwrap();
+            let _ = her_session.decrypt_msg(&mut msg);
+        }
+        assert_eq!(*errors.lock(), vec![(DecryptErr::Replay, my_ip6)]);
+    }
+
+    #[test]
+    pub fn test_decrypt_error_counts() {
+        use super::DecryptErr;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let her_keys = keys_api.key_pair();
+        let her_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        // Recording errors elsewhere doesn't change the counts
+        her_ca.set_decrypt_error_handler(|_, _| ());
+
+        let encrypt = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+        let count = |counts: [u64; DecryptErr::COUNT], err: DecryptErr| counts[err as usize];
+
+        // One drop in each of two sessions, both counted once
+        for _ in 0..2 {
+            let my_keys = keys_api.key_pair();
+            let my_ca =
+                super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+            let my_session =
+                super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            let strict_session =
+                super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+            assert!(strict_session
+                .decrypt_msg(&mut encrypt(&my_session))
+                .is_err());
+        }
+        let counts = her_ca.decrypt_error_counts();
+        assert_eq!(count(counts, DecryptErr::AuthRequired), 2);
+        assert_eq!(counts.iter().sum::<u64>(), 2);
+
+        let her_session = super::Session::new(
+            Arc::clone(&her_ca),
+            keys_api.key_pair().public_key,
+            false,
+            None,
+        )
+        .unwrap();
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&[0, 0, 0, 1]).unwrap();
+        assert!(her_session.decrypt_msg(&mut msg).is_err());
+        let counts = her_ca.decrypt_error_counts();
+        assert_eq!(count(counts, DecryptErr::AuthRequired), 2);
+        assert_eq!(count(counts, DecryptErr::Runt), 1);
+        assert_eq!(counts.iter().sum::<u64>(), 3);
+
+        her_ca.clear_decrypt_error_counts();
+        assert_eq!(her_ca.decrypt_error_counts(), [0; DecryptErr::COUNT]);
+    }
+
+    #[test]
+    pub fn test_drop_log() {
+        use super::{DecryptErr, DropLog, FixedClock};
+
+        let drop_log = DropLog::default();
+        let ip6 = super::IpV6::from([0xfc; 16]);
+        let count = || drop_log.count.load(std::sync::atomic::Ordering::Relaxed);
+
+        for _ in 0..DropLog::BURST - 1 {
+            drop_log.log(DecryptErr::Decrypt, 100, &ip6, &"test");
+        }
+        assert!(!drop_log.is_quiet());
+        drop_log.log(DecryptErr::Decrypt, 100, &ip6, &"test");
+        assert!(drop_log.is_quiet());
+
+        // Suppressed drops are still counted
+        for _ in 0..5 {
+            drop_log.log(DecryptErr::Decrypt, 100, &ip6, &"test");
+        }
+        assert_eq!(count(), DropLog::BURST + 5);
+
+        // Quiet until the second is over
+        drop_log.update(&FixedClock(100));
+        assert!(drop_log.is_quiet());
+        drop_log.update(&FixedClock(101));
+        assert!(!drop_log.is_quiet());
+
+        // Another reason starts over
+        drop_log.log(DecryptErr::Decrypt, 100, &ip6, &"test");
+        assert_eq!(count(), DropLog::BURST + 6);
+        drop_log.log(DecryptErr::Replay, 100, &ip6, &"test");
+        assert_eq!(count(), 1);
+        assert!(!drop_log.is_quiet());
+    }
+
+    #[test]
+    pub fn test_decrypt_failure() {
+        use super::{DecryptErr, DecryptError, DecryptFailure, DecryptPhase, PacketKind};
+        use crate::crypto::replay_protector::ReplayKind;
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        let my_ip6 = super::IpV6::from(super::ip6_from_key(my_keys.public_key.raw()));
+
+        let encrypt = |sess: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            sess.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+        let decrypt_bytes = |sess: &super::Session, bytes: &[u8]| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(bytes).unwrap();
+            sess.decrypt_msg(&mut msg).unwrap_err()
+        };
+
+        // No password while she requires auth
+        let strict_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                .unwrap();
+        let err = strict_session
+            .decrypt_msg(&mut encrypt(&my_session))
+            .unwrap_err();
+        let failure = err.downcast_ref::<DecryptFailure>().unwrap();
+        assert_eq!(failure.code(), DecryptErr::AuthRequired);
+        assert_eq!(failure.nonce(), Some(0));
+        assert_eq!(failure.packet_kind(), Some(PacketKind::Hello));
+        assert_eq!(failure.phase(), DecryptPhase::Handshake);
+        assert_eq!(failure.state(), State::Init);
+        assert_eq!(failure.her_ip6(), &my_ip6);
+        // The error itself is still there
+        assert_eq!(
+            err.downcast_ref::<DecryptError>(),
+            Some(&DecryptError::DecryptErr(DecryptErr::AuthRequired))
+        );
+        assert_eq!(super::decrypt_err_of(&err), DecryptErr::AuthRequired);
+
+        drop(strict_session);
+
+        let her_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), false, None)
+                .unwrap();
+        her_session.decrypt_msg(&mut encrypt(&my_session)).unwrap();
+        my_session.decrypt_msg(&mut encrypt(&her_session)).unwrap();
+        let mut msg = encrypt(&my_session);
+        let sent = msg.bytes().to_vec();
+        her_session.decrypt_msg(&mut msg).unwrap();
+
+        // Replayed traffic packet
+        let err = decrypt_bytes(&her_session, &sent);
+        let failure = err.downcast_ref::<DecryptFailure>().unwrap();
+        assert_eq!(failure.code(), DecryptErr::Replay);
+        assert_eq!(failure.nonce(), Some(4));
+        assert_eq!(failure.packet_kind(), Some(PacketKind::Traffic));
+        assert_eq!(failure.phase(), DecryptPhase::Traffic);
+        // The replay window starts after the packet which completed the handshake
+        assert_eq!(failure.replay_kind(), Some(ReplayKind::TooOld));
+        assert_eq!(failure.state(), State::Established);
+        assert_eq!(
+            failure.to_string(),
+            format!(
+                "Traffic packet with nonce [4] from [{}] in state [Established] during Traffic: {} \
+                 (nonce below the replay window)",
+                my_ip6,
+                DecryptErr::Replay,
+            )
+        );
+
+        // Too short to be classified
+        let failure = decrypt_bytes(&her_session, &[0, 0, 0, 1, 0, 0, 0, 0]);
+        let failure = failure.downcast_ref::<DecryptFailure>().unwrap();
+        assert_eq!(failure.code(), DecryptErr::Runt);
+        assert_eq!((failure.nonce(), failure.packet_kind()), (Some(1), None));
+        assert_eq!(failure.phase(), DecryptPhase::Parse);
+    }
+
+    #[test]
+    fn test_decrypt_err_numbers() {
+        use super::{DecryptErr, UnknownDecryptErr};
+        use cffi::CryptoAuth_DecryptErr as C;
+        use std::convert::TryFrom;
+
+        // Exhaustive so that a new error has to be given a place here
+        fn c_value(err: &DecryptErr) -> C {
+            match err {
+                DecryptErr::None => C::CryptoAuth_DecryptErr_NONE,
+                DecryptErr::Runt => C::CryptoAuth_DecryptErr_RUNT,
+                DecryptErr::NoSession => C::CryptoAuth_DecryptErr_NO_SESSION,
+                DecryptErr::FinalShakeFail => C::CryptoAuth_DecryptErr_FINAL_SHAKE_FAIL,
+                DecryptErr::FailedDecryptionRunMsg => {
+                    C::CryptoAuth_DecryptErr_FAILED_DECRYPT_RUN_MSG
+                }
+                DecryptErr::KeyPktEstablishedSession => {
+                    C::CryptoAuth_DecryptErr_KEY_PKT_ESTABLISHED_SESSION
+                }
+                DecryptErr::WrongPermPubkey => C::CryptoAuth_DecryptErr_WRONG_PERM_PUBKEY,
+                DecryptErr::IpRestricted => C::CryptoAuth_DecryptErr_IP_RESTRICTED,
+                DecryptErr::AuthRequired => C::CryptoAuth_DecryptErr_AUTH_REQUIRED,
+                DecryptErr::UnrecognizedAuth => C::CryptoAuth_DecryptErr_UNRECOGNIZED_AUTH,
+                DecryptErr::StrayKey => C::CryptoAuth_DecryptErr_STRAY_KEY,
+                DecryptErr::HandshakeDecryptFailed => {
+                    C::CryptoAuth_DecryptErr_HANDSHAKE_DECRYPT_FAILED
+                }
+                DecryptErr::Wiseguy => C::CryptoAuth_DecryptErr_WISEGUY,
+                DecryptErr::InvalidPacket => C::CryptoAuth_DecryptErr_INVALID_PACKET,
+                DecryptErr::Replay => C::CryptoAuth_DecryptErr_REPLAY,
+                DecryptErr::Decrypt => C::CryptoAuth_DecryptErr_DECRYPT,
+                DecryptErr::Internal => C::CryptoAuth_DecryptErr_INTERNAL,
+                DecryptErr::TooManyHandshakes => C::CryptoAuth_DecryptErr_TOO_MANY_HANDSHAKES,
+                DecryptErr::Blinding => C::CryptoAuth_DecryptErr_BLINDING,
+            }
+        }
+
+        for (i, err) in DecryptErr::ALL.iter().enumerate() {
+            let n = u32::from(err.clone());
+            assert_eq!(n as usize, i);
+            assert_eq!(DecryptErr::try_from(n), Ok(err.clone()));
+            assert_eq!(err.to_string(), err.as_str());
+            let c = c_value(err);
+            assert_eq!(n, c as u32);
+            assert_eq!(
+                format!("{:?}", c),
+                format!("CryptoAuth_DecryptErr_{}", err.as_str())
+            );
+        }
+
+        let count = DecryptErr::ALL.len() as u32;
+        assert_eq!(DecryptErr::try_from(count), Err(UnknownDecryptErr(count)));
+        assert_eq!(
+            DecryptErr::try_from(u32::MAX),
+            Err(UnknownDecryptErr(u32::MAX))
+        );
+    }
+
+    #[test]
+    pub fn test_reset_all_sessions() {
+        use std::convert::TryFrom;
+
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let other_keys = keys_api.key_pair();
+
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key),
+            EventBase {},
+            Random::Fake,
+        ));
+        let my_pub_key = my_keys.public_key;
+        let mk_peer = |private_key: PrivateKey, public_key: PublicKey| {
+            let ca = super::CryptoAuth::new(Some(private_key), EventBase {}, Random::Fake);
+            let mine = super::Session::new(Arc::clone(&my_ca), public_key, false, None).unwrap();
+            let theirs =
+                super::Session::new(Arc::new(ca), my_pub_key.clone(), false, None).unwrap();
+            (mine, theirs)
+        };
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+        let establish = |(mine, theirs): &(super::Session, super::Session)| {
+            send(mine, theirs);
+            send(theirs, mine);
+            send(mine, theirs);
+            send(theirs, mine);
+            assert_eq!(mine.get_state(), State::Established);
+        };
+
+        let her = mk_peer(her_keys.private_key, her_keys.public_key.clone());
+        let other = mk_peer(other_keys.private_key, other_keys.public_key);
+        establish(&her);
+        establish(&other);
+
+        let her_ip6 = super::IpV6::try_from(&her_keys.public_key).unwrap();
+        assert_eq!(my_ca.reset_sessions_for_ip6(&her_ip6), 1);
+        assert_eq!(her.0.get_state(), State::Init);
+        assert_eq!(other.0.get_state(), State::Established);
+
+        assert_eq!(my_ca.reset_all_sessions(), 2);
+        assert_eq!(her.0.get_state(), State::Init);
+        assert_eq!(other.0.get_state(), State::Init);
+        assert_eq!(other.0.stats().received_packets, 0);
+
+        // Dropped sessions are not counted
+        drop(other);
+        assert_eq!(my_ca.reset_all_sessions(), 1);
+    }
+
+    #[test]
+    pub fn test_auth_failure_handler() {
+        use parking_lot::Mutex;
+
+        use super::{AuthFailure, DecryptErr};
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let my_ip6 = super::ip6_from_key(my_keys.public_key.raw());
+
+        let her_ca = Arc::new(super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+        her_ca
+            .add_user_ipv6(
+                ByteString::from("pass".to_string()),
+                Some(ByteString::from("alice".to_string())),
+                None,
+                None,
+            )
+            .unwrap();
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+
+        // Returns the error she gets for a hello with the given login and password
+        let try_login = |login: &str, password: &str| {
+            let my_session =
+                super::Session::new(Arc::clone(&my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(
+                Some(ByteString::from(password.to_string())),
+                Some(ByteString::from(login.to_string())),
+            );
+            let her_session =
+                super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            match her_session.decrypt_msg(&mut msg) {
+                Ok(()) => None,
+                Err(err) => match err.downcast_ref::<super::DecryptError>() {
+                    Some(super::DecryptError::DecryptErr(err)) => Some(err.clone()),
+                    _ => panic!("unexpected error {}", err),
+                },
+            }
+        };
+
+        // The peer gets the same errors with or without the handler
+        let unknown_login = try_login("bob", "pass");
+        let wrong_password = try_login("alice", "wrong");
+        assert_eq!(unknown_login, Some(DecryptErr::AuthRequired));
+        assert_eq!(wrong_password, Some(DecryptErr::UnrecognizedAuth));
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let failures2 = Arc::clone(&failures);
+        her_ca.set_auth_failure_handler(move |failure, ip6| failures2.lock().push((failure, ip6)));
+
+        assert_eq!(try_login("bob", "pass"), unknown_login);
+        assert_eq!(try_login("alice", "wrong"), wrong_password);
+        assert_eq!(try_login("alice", "pass"), None);
+        assert_eq!(
+            *failures.lock(),
+            vec![
+                (AuthFailure::UnknownLogin, my_ip6),
+                (AuthFailure::WrongPassword, my_ip6)
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_new_from_key_str() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let ca = Arc::new(ca);
+
+        let sess = super::Session::new_from_key_str(
+            Arc::clone(&ca),
+            "lpu15wrt3tb6d8vngq9yh3lr4gmnkuv0rgcd2jwl5rp5v0mhlg30.k",
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(sess.get_her_pubkey()),
+            "b2ea50f8cd232bc3d0a6ce26ff86bcc44d1af506d72d262097e5d6b2c17cd20d"
+        );
+        assert_eq!(
+            hex::encode(sess.get_her_ip6()),
+            "fc38f1bc28ad21be2c9da543a0913087"
+        );
+
+        for bad in &[
+            "",
+            "lpu15wrt3tb6d8vngq9yh3lr4gmnkuv0rgcd2jwl5rp5v0mhlg30",
+            "not a key.k",
+        ] {
+            let err = super::Session::new_from_key_str(Arc::clone(&ca), bad, false, None).err();
+            assert_eq!(err, Some(super::SessionError::BadKeyString));
+        }
+
+        let zero_key = format!("{}.k", "0".repeat(52));
+        let err = super::Session::new_from_key_str(Arc::clone(&ca), &zero_key, false, None).err();
+        assert_eq!(
+            err,
+            Some(super::SessionError::Key(super::KeyError::ZeroPublicKey))
+        );
+    }
+
+    #[test]
+    pub fn test_stage_auth() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(
+            Some(my_keys.private_key),
+            EventBase {},
+            Random::new_sodium().unwrap(),
+        );
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        for (password, login) in &[("oldpass", "old"), ("newpass", "new")] {
+            her_ca
+                .add_user_ipv6(
+                    ByteString::from(password.to_string()),
+                    Some(ByteString::from(login.to_string())),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+        let her_ca = Arc::new(her_ca);
+        let her_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                .unwrap();
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg)
+        };
+        let handshake = || {
+            send(&my_session, &her_session)?;
+            send(&her_session, &my_session)?;
+            send(&my_session, &her_session)?;
+            send(&her_session, &my_session)
+        };
+        let creds = |password: &str, login: &str| {
+            (
+                Some(ByteString::from(password.to_string())),
+                Some(ByteString::from(login.to_string())),
+            )
+        };
+
+        let (password, login) = creds("oldpass", "old");
+        my_session.set_auth(password, login);
+        handshake().unwrap();
+        assert_eq!(my_session.get_state(), State::Established);
+
+        // Staging doesn't disturb the established session
+        let (password, login) = creds("newpass", "new");
+        my_session.stage_auth(password, login);
+        assert_eq!(my_session.get_state(), State::Established);
+        send(&my_session, &her_session).unwrap();
+        send(&her_session, &my_session).unwrap();
+
+        // The next handshake uses the new credentials, the old ones don't work anymore
+        her_ca.remove_users(Some(ByteString::from("old".to_string())));
+        my_session.reset();
+        handshake().unwrap();
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(her_session.get_state(), State::Established);
+
+        // set_auth() still resets right away
+        let (password, login) = creds("oldpass", "old");
+        my_session.set_auth(password, login);
+        assert_eq!(my_session.get_state(), State::Init);
+    }
+
+    #[test]
+    pub fn test_classify_packet() {
+        use super::PacketKind;
+
+        let classify = |nonce: u32, len: usize| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(&vec![0xaa; len - 4]).unwrap();
+            msg.push_be(nonce).unwrap();
+            let before = msg.bytes().to_vec();
+            let kind = super::classify_packet(&msg);
+            assert_eq!(msg.bytes(), &before[..]);
+            kind
+        };
+
+        let size = super::CryptoHeader::SIZE;
+        assert_eq!(classify(0, size), Some(PacketKind::Hello));
+        assert_eq!(classify(1, size), Some(PacketKind::RepeatHello));
+        assert_eq!(classify(2, size + 16), Some(PacketKind::Key));
+        assert_eq!(classify(3, size), Some(PacketKind::RepeatKey));
+        assert_eq!(classify(4, 20), Some(PacketKind::Traffic));
+        assert_eq!(classify(u32::MAX, 64), Some(PacketKind::Traffic));
+
+        // Runts
+        assert_eq!(classify(0, size - 1), None);
+        assert_eq!(classify(3, 20), None);
+        assert_eq!(classify(4, 19), None);
+        assert_eq!(classify(0, 4), None);
+        assert_eq!(super::classify_packet(&mk_msg(256)), None);
+    }
+
+    #[test]
+    pub fn test_fixed_temp_keys() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let my_priv_key = PrivateKey::from([0x11; 32]);
+        let her_priv_key = PrivateKey::from([0x22; 32]);
+        let my_pub_key = super::crypto_scalarmult_curve25519_base(&my_priv_key);
+        let her_pub_key = super::crypto_scalarmult_curve25519_base(&her_priv_key);
+        let my_temp_priv_key = [0x33; 32];
+        let her_temp_priv_key = [0x44; 32];
+
+        let handshake = || {
+            let mk_session = |priv_key: &PrivateKey, her_pub_key: &PublicKey, temp_key| {
+                let ca = super::CryptoAuth::new(
+                    Some(priv_key.clone()),
+                    EventBase {},
+                    Random::new_sodium().unwrap(),
+                );
+                let sess =
+                    super::Session::new(Arc::new(ca), her_pub_key.clone(), false, None).unwrap();
+                sess.set_fixed_temp_priv_key(temp_key);
+                sess
+            };
+            let my_session = mk_session(&my_priv_key, &her_pub_key, my_temp_priv_key);
+            let her_session = mk_session(&her_priv_key, &my_pub_key, her_temp_priv_key);
+
+            for (from, to) in &[
+                (&my_session, &her_session),
+                (&her_session, &my_session),
+                (&my_session, &her_session),
+            ] {
+                let mut msg = mk_msg(256);
+                msg.push_bytes(b"HelloWorld012345").unwrap();
+                from.encrypt_msg(&mut msg).unwrap();
+                to.decrypt_msg(&mut msg).unwrap();
+            }
+            assert_eq!(her_session.get_state(), State::Established);
+
+            let my_secret = *my_session.inner.session_mut.read().shared_secret.as_bytes();
+            let her_secret = *her_session
+                .inner
+                .session_mut
+                .read()
+                .shared_secret
+                .as_bytes();
+            assert_eq!(my_secret, her_secret);
+            (my_session, my_secret)
+        };
+
+        let (my_session, secret) = handshake();
+        let my_temp_pub_key =
+            super::crypto_scalarmult_curve25519_base(&PrivateKey::from(my_temp_priv_key));
+        let her_temp_pub_key =
+            super::crypto_scalarmult_curve25519_base(&PrivateKey::from(her_temp_priv_key));
+        assert_eq!(
+            my_session.inner.session_mut.read().our_temp_pub_key,
+            *my_temp_pub_key.raw()
+        );
+        assert_eq!(my_session.her_temp_pubkey(), Some(*her_temp_pub_key.raw()));
+        let expected =
+            super::get_shared_secret(&my_temp_priv_key, *her_temp_pub_key.raw(), None, false);
+        assert_eq!(&secret, expected.as_bytes());
+
+        // Reproducible even though the random source is not
+        let (_, secret2) = handshake();
+        assert_eq!(secret2, secret);
+    }
+
+    #[test]
+    pub fn test_auth_mode_accessors() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let session = super::Session::new(Arc::new(ca), her_keys.public_key, false, None).unwrap();
+        assert!(!session.is_authenticated_mode());
+        assert_eq!(session.login(), None);
+
+        session.set_auth(Some(ByteString::from("pass".to_string())), None);
+        assert!(session.is_authenticated_mode());
+        assert_eq!(session.login(), None);
+
+        let login = ByteString::from("alice".to_string());
+        session.set_auth(
+            Some(ByteString::from("pass2".to_string())),
+            Some(login.clone()),
+        );
+        assert!(session.is_authenticated_mode());
+        assert_eq!(session.login(), Some(login));
+
+        session.set_auth(None, None);
+        assert!(!session.is_authenticated_mode());
+        assert_eq!(session.login(), None);
+    }
+
+    #[test]
+    pub fn test_same_login_users() {
+        use super::DecryptErr;
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let her_ca = super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        );
+        let alice = || Some(ByteString::from("alice".to_string()));
+        her_ca
+            .add_user_ipv6(ByteString::from("pass1".to_string()), alice(), None, None)
+            .unwrap();
+        // Another password for the same login is a duplicate by default
+        assert_eq!(
+            her_ca.add_user_ipv6(ByteString::from("pass2".to_string()), alice(), None, None),
+            Err(super::AddUserError::Duplicate {
+                login: alice().unwrap()
+            })
+        );
+        // The same password again is not a duplicate
+        her_ca
+            .add_user_ipv6(ByteString::from("pass1".to_string()), alice(), None, None)
+            .unwrap();
+        her_ca.remove_users(alice());
+        her_ca
+            .add_user_ipv6(ByteString::from("pass1".to_string()), alice(), None, None)
+            .unwrap();
+        her_ca.set_shared_logins(true);
+        her_ca
+            .add_user_ipv6(ByteString::from("pass2".to_string()), alice(), None, None)
+            .unwrap();
+        // Only for somebody else
+        let other_ip6 = Some([0xfc; 16]);
+        let pass3 = ByteString::from("pass3".to_string());
+        her_ca
+            .add_user_ipv6(pass3, alice(), other_ip6, None)
+            .unwrap();
+        assert_eq!(her_ca.get_user_info().len(), 3);
+        let her_ca = Arc::new(her_ca);
+
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::new_sodium().unwrap(),
+        ));
+
+        let handshake = |password: &str| {
+            let my_session =
+                super::Session::new(Arc::clone(&my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(Some(ByteString::from(password.to_string())), alice());
+            let her_session =
+                super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+            let send = |from: &super::Session, to: &super::Session| {
+                let mut msg = mk_msg(256);
+                msg.push_bytes(b"HelloWorld012345").unwrap();
+                from.encrypt_msg(&mut msg).unwrap();
+                to.decrypt_msg(&mut msg)
+                    .map_err(|err| match err.downcast_ref() {
+                        Some(super::DecryptError::DecryptErr(err)) => err.clone(),
+                        _ => panic!("unexpected error {}", err),
+                    })
+            };
+            send(&my_session, &her_session)?;
+            send(&her_session, &my_session)?;
+            send(&my_session, &her_session)?;
+            assert_eq!(her_session.get_state(), State::Established);
+            Ok(())
+        };
+
+        // Either password of the login works
+        assert_eq!(handshake("pass1"), Ok(()));
+        assert_eq!(handshake("pass2"), Ok(()));
+        // A known login with a wrong password is an auth failure, not a decryption failure
+        assert_eq!(handshake("wrong"), Err(DecryptErr::UnrecognizedAuth));
+        // Passwords restricted to another ip6 are not tried
+        assert_eq!(handshake("pass3"), Err(DecryptErr::UnrecognizedAuth));
+    }
+
+    #[test]
+    pub fn test_user_stats() {
+        use super::{AuthStats, DecryptErr, UserStats};
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let login = |login: &str| ByteString::from(login.to_string());
+
+        let her_ca = super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        );
+        her_ca
+            .add_user_ipv6(login("pass1"), Some(login("alice")), None, None)
+            .unwrap();
+        her_ca
+            .add_user_ipv6(login("pass2"), Some(login("bob")), Some([0xfc; 16]), None)
+            .unwrap();
+        let her_ca = Arc::new(her_ca);
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::new_sodium().unwrap(),
+        ));
+
+        let handshake = |password: &str, user: &str| {
+            let my_session =
+                super::Session::new(Arc::clone(&my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(Some(login(password)), Some(login(user)));
+            let her_session =
+                super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), false, None)
+                    .unwrap();
+            let send = |from: &super::Session, to: &super::Session| {
+                let mut msg = mk_msg(256);
+                msg.push_bytes(b"HelloWorld012345").unwrap();
+                from.encrypt_msg(&mut msg).unwrap();
+                to.decrypt_msg(&mut msg)
+                    .map_err(|err| match err.downcast_ref() {
+                        Some(super::DecryptError::DecryptErr(err)) => err.clone(),
+                        _ => panic!("unexpected error {}", err),
+                    })
+            };
+            send(&my_session, &her_session)?;
+            send(&her_session, &my_session)?;
+            send(&my_session, &her_session)
+        };
+
+        assert_eq!(
+            her_ca.get_user_stats(&login("alice")),
+            Some(UserStats::default())
+        );
+        assert_eq!(handshake("pass1", "alice"), Ok(()));
+        assert_eq!(
+            handshake("wrong", "alice"),
+            Err(DecryptErr::UnrecognizedAuth)
+        );
+        assert_eq!(handshake("pass2", "bob"), Err(DecryptErr::IpRestricted));
+        assert_eq!(
+            handshake("pass3", "carol"),
+            Err(DecryptErr::UnrecognizedAuth)
+        );
+
+        assert_eq!(
+            her_ca.get_user_stats(&login("alice")),
+            Some(UserStats {
+                auth_successes: 1,
+                auth_failures: 1
+            })
+        );
+        assert_eq!(
+            her_ca.get_user_stats(&login("bob")),
+            Some(UserStats {
+                auth_successes: 0,
+                auth_failures: 1
+            })
+        );
+        assert_eq!(her_ca.get_user_stats(&login("carol")), None);
+        assert_eq!(
+            her_ca.get_auth_stats(),
+            AuthStats {
+                auth_successes: 1,
+                auth_failures: 2,
+                unmatched_challenges: 1
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_remove_users_by_ip6() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let ca = Arc::new(ca);
+        let old_keys = keys_api.key_pair();
+        let old_ip6 = super::ip6_from_key(old_keys.public_key.raw());
+        let other_ip6 = [0xfd; 16];
+        // Two of the users are alice
+        ca.set_shared_logins(true);
+        for (login, ip6) in &[
+            ("alice", Some(old_ip6)),
+            ("bob", None),
+            ("carol", Some(old_ip6)),
+            ("alice", None),
+            ("dave", Some(other_ip6)),
+        ] {
+            let login = ByteString::from(login.to_string());
+            let password = ByteString::from(format!("pass-{}", ca.user_count()));
+            ca.add_user_ipv6(password, Some(login), *ip6, None).unwrap();
+        }
+
+        // The old key can still log in over Noise as any of its users, and as bob
+        let old_key = || old_keys.private_key.clone();
+        let hello = |login: &str, password: &str| noise_hello(&ca, old_key(), login, password);
+        assert_eq!(hello("carol", "pass-2"), Ok(()));
+
+        assert_eq!(ca.remove_users_by_ip6(super::IpV6::from(old_ip6)), 2);
+        assert_eq!(
+            hello("carol", "pass-2"),
+            Err(super::DecryptErr::UnrecognizedAuth)
+        );
+        assert_eq!(hello("bob", "pass-1"), Ok(()));
+        let left: Vec<_> = ca
+            .get_user_info()
+            .into_iter()
+            .map(|u| (u.login, u.restricted_to_ip6))
+            .collect();
+        assert_eq!(
+            left,
+            vec![
+                (ByteString::from("bob".to_string()), None),
+                (ByteString::from("alice".to_string()), None),
+                (
+                    ByteString::from("dave".to_string()),
+                    Some(super::Ip6Restriction::Exact(super::IpV6::from(other_ip6))),
+                ),
+            ]
+        );
+        assert_eq!(ca.remove_users_by_ip6(super::IpV6::from(old_ip6)), 0);
+        assert_eq!(ca.user_count(), 3);
+    }
+
+    #[test]
+    pub fn test_remove_users_where() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let her_ca = super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        );
+        let restricted = [0xfc; 16];
+        for (login, ip6) in &[
+            ("guest-1", None),
+            ("guest-2", Some(restricted)),
+            ("admin", None),
+            ("guest", Some(restricted)),
+        ] {
+            let login = ByteString::from(login.to_string());
+            let password = ByteString::from("pass".to_string());
+            her_ca
+                .add_user_ipv6(password, Some(login), *ip6, None)
+                .unwrap();
+        }
+        let her_ca = Arc::new(her_ca);
+
+        let removed = her_ca.remove_users_where(|login, _| login.0.starts_with(b"guest-"));
+        assert_eq!(removed, 2);
+        let logins: Vec<_> = her_ca
+            .get_user_info()
+            .into_iter()
+            .map(|u| u.login)
+            .collect();
+        assert_eq!(
+            logins,
+            vec![
+                ByteString::from("admin".to_string()),
+                ByteString::from("guest".to_string())
+            ]
+        );
+        // The restriction is passed along
+        let exact = super::Ip6Restriction::Exact(super::IpV6::from(restricted));
+        let removed = her_ca.remove_users_where(|_, ip6| ip6 == Some(&exact));
+        assert_eq!(removed, 1);
+        assert_eq!(her_ca.remove_users_where(|_, _| false), 0);
+
+        // The survivor still authenticates, removed users don't
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+        let try_login = |login: &str| {
+            let my_session =
+                super::Session::new(Arc::clone(&my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(
+                Some(ByteString::from("pass".to_string())),
+                Some(ByteString::from(login.to_string())),
+            );
+            let her_session =
+                super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            her_session.decrypt_msg(&mut msg).is_ok()
+        };
+        assert!(try_login("admin"));
+        assert!(!try_login("guest-1"));
+        let refused: Result<(), _> = Err(super::DecryptErr::UnrecognizedAuth);
+        let hello = |login: &str| noise_hello(&her_ca, my_keys.private_key.clone(), login, "pass");
+        assert_eq!(hello("admin"), Ok(()));
+        assert_eq!(hello("guest-1"), refused);
+        assert_eq!(hello("guest"), refused);
+    }
+
+    #[test]
+    pub fn test_rekey_overlap() {
+        use crate::crypto::replay_protector::ReplayKind;
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+        use crate::util::events::ManualClock;
+
+        const OVERLAP: u32 = 30;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let clock = Arc::new(ManualClock::new(1_000_000));
+
+        let my_ca =
+            super::CryptoAuth::new(Some(my_keys.private_key), Arc::clone(&clock), Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        // She restarts her handshake, which needs a new temp key
+        let rand = Random::new_sodium().unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), Arc::clone(&clock), rand);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+        her_session.set_responder_rekey(true);
+        my_session.set_rekey_overlap(OVERLAP);
+
+        let encrypt = |from: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            msg
+        };
+        let decrypt = |to: &super::Session, mut msg: Message| {
+            to.decrypt_msg(&mut msg)
+                .map(|_| msg.bytes().to_vec())
+                .map_err(|err| match err.downcast_ref::<super::DecryptError>() {
+                    Some(super::DecryptError::DecryptErr(err)) => err.clone(),
+                    _ => panic!("unexpected error {}", err),
+                })
+        };
+        let send = |from: &super::Session, to: &super::Session| decrypt(to, encrypt(from));
+
+        for _ in 0..2 {
+            send(&my_session, &her_session).unwrap();
+            send(&her_session, &my_session).unwrap();
+        }
+        assert_eq!(my_session.get_state(), State::Established);
+
+        // Old key traffic still in flight when she re-handshakes
+        let late: Vec<_> = (0..3).map(|_| encrypt(&her_session)).collect();
+        let mut late = late.into_iter();
+
+        her_session.rekey().unwrap();
+        send(&her_session, &my_session).unwrap();
+        send(&my_session, &her_session).unwrap();
+
+        // Both the first packet with the new key and a late one with the old key are accepted
+        assert_eq!(
+            send(&her_session, &my_session),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(
+            decrypt(&my_session, late.next().unwrap()),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+        send(&my_session, &her_session).unwrap();
+        assert_eq!(her_session.get_state(), State::Established);
+        assert_eq!(
+            send(&her_session, &my_session),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+
+        // Replays of old key traffic are still detected
+        let replayed = late.next().unwrap();
+        let mut copy = mk_msg(256);
+        copy.push_bytes(replayed.bytes()).unwrap();
+        assert!(decrypt(&my_session, replayed).is_ok());
+        let err = my_session.decrypt_msg(&mut copy).unwrap_err();
+        let failure = err.downcast_ref::<super::DecryptFailure>().unwrap();
+        assert_eq!(failure.code(), super::DecryptErr::Replay);
+        assert_eq!(failure.replay_kind(), Some(ReplayKind::Duplicate));
+
+        // Until the overlap window is over
+        clock.advance(OVERLAP);
+        assert_eq!(
+            decrypt(&my_session, late.next().unwrap()),
+            Err(super::DecryptErr::Decrypt)
+        );
+        assert_eq!(
+            send(&her_session, &my_session),
+            Ok(b"HelloWorld012345".to_vec())
+        );
+    }
+
+    #[test]
+    pub fn test_log_secret_keys() {
+        use std::cell::RefCell;
+
+        thread_local! {
+            static LINES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        }
+
+        // Captures the lines logged on the current thread, other tests may log concurrently
+        struct CaptureLog;
+
+        impl log::Log for CaptureLog {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                LINES.with(|lines| lines.borrow_mut().push(record.args().to_string()));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE_LOG: CaptureLog = CaptureLog;
+        log::set_logger(&CAPTURE_LOG).expect("no other logger in tests");
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_ca = Arc::new(my_ca);
+        let her_ca = super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        );
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        // Logs the lines of one hello from a new session
+        let hello_log = || {
+            let my_session =
+                super::Session::new(Arc::clone(&my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            LINES.with(|lines| lines.borrow_mut().clear());
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            LINES.with(|lines| lines.borrow_mut().drain(..).collect::<Vec<_>>())
+        };
+        let has_keys = |lines: &[String]| {
+            lines
+                .iter()
+                .any(|line| line.starts_with("Generated a shared secret"))
+                && lines
+                    .iter()
+                    .any(|line| line.starts_with("Encrypting message with"))
+        };
+
+        assert!(!has_keys(&hello_log()));
+
+        LINES.with(|lines| lines.borrow_mut().clear());
+        my_ca.set_log_secret_keys(true);
+        let warning = LINES.with(|lines| lines.borrow()[0].clone());
+        assert!(warning.starts_with("Logging secret keys"));
+        assert!(has_keys(&hello_log()));
+        // Only for this CryptoAuth
+        assert!(!her_session.inner.context.log_keys());
+
+        my_ca.set_log_secret_keys(false);
+        assert!(!has_keys(&hello_log()));
+    }
+
+    #[test]
+    pub fn test_byte_stats() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let send = |from: &super::Session, to: &super::Session, len: usize| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(&vec![0x55; len]).unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg)
+        };
+
+        assert_eq!(my_session.byte_stats(), (0, 0));
+        // The handshake packets carry data too
+        for len in &[16, 20, 32, 4] {
+            send(&my_session, &her_session, *len).unwrap();
+            send(&her_session, &my_session, *len * 2).unwrap();
+        }
+        assert_eq!(my_session.byte_stats(), (72, 144));
+        assert_eq!(her_session.byte_stats(), (144, 72));
+
+        // Failed decryption isn't counted
+        let mut msg = mk_msg(256);
+        msg.push_bytes(&[0x55; 40]).unwrap();
+        my_session.encrypt_msg(&mut msg).unwrap();
+        let mut replayed = mk_msg(256);
+        replayed.push_bytes(msg.bytes()).unwrap();
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert!(her_session.decrypt_msg(&mut replayed).is_err());
+        assert_eq!(my_session.byte_stats(), (112, 144));
+        assert_eq!(her_session.byte_stats(), (144, 112));
+
+        // Cumulative across resets
+        her_session.reset();
+        assert_eq!(her_session.byte_stats(), (144, 112));
+    }
+
+    #[test]
+    #[cfg(feature = "insecure-diagnostics")]
+    pub fn test_export_shared_secret() {
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key), EventBase {}, Random::Fake);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key, false, None).unwrap();
+        let her_ca = super::CryptoAuth::new(Some(her_keys.private_key), EventBase {}, Random::Fake);
+        let her_session =
+            super::Session::new(Arc::new(her_ca), my_keys.public_key, false, None).unwrap();
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+
+        assert_eq!(my_session.export_shared_secret(), None);
+        send(&my_session, &her_session);
+        send(&her_session, &my_session);
+        send(&my_session, &her_session);
+        // Not established until she hears back
+        assert_eq!(my_session.export_shared_secret(), None);
+        send(&her_session, &my_session);
+
+        let secret = my_session.export_shared_secret().unwrap();
+        assert_eq!(her_session.export_shared_secret(), Some(secret));
+
+        her_session.reset();
+        assert_eq!(her_session.export_shared_secret(), None);
+    }
+
+    #[test]
+    pub fn test_shared_users() {
+        use parking_lot::RwLock;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+
+        // She listens on two transports with the same key and users
+        let users = Arc::new(RwLock::new(super::Users::new()));
+        let new_her_ca = || {
+            Arc::new(super::CryptoAuth::new_with_shared_users(
+                Some(her_keys.private_key.clone()),
+                EventBase {},
+                Random::Fake,
+                Arc::clone(&users),
+            ))
+        };
+        let her_ca1 = new_her_ca();
+        let her_ca2 = new_her_ca();
+        let my_ca = Arc::new(super::CryptoAuth::new(
+            Some(my_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        ));
+
+        let try_login = |her_ca: &Arc<super::CryptoAuth>| {
+            let my_session =
+                super::Session::new(Arc::clone(&my_ca), her_keys.public_key.clone(), false, None)
+                    .unwrap();
+            my_session.set_auth(
+                Some(ByteString::from("pass".to_string())),
+                Some(ByteString::from("alice".to_string())),
+            );
+            let her_session =
+                super::Session::new(Arc::clone(her_ca), my_keys.public_key.clone(), true, None)
+                    .unwrap();
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            her_session.decrypt_msg(&mut msg).is_ok()
+        };
+
+        assert!(!try_login(&her_ca2));
+        her_ca1
+            .add_user_ipv6(
+                ByteString::from("pass".to_string()),
+                Some(ByteString::from("alice".to_string())),
+                None,
+                None,
+            )
+            .unwrap();
+        assert!(try_login(&her_ca2));
+        assert!(try_login(&her_ca1));
+        assert_eq!(her_ca2.get_user_info().len(), 1);
+
+        // Removed from both as well
+        assert_eq!(her_ca2.remove_users(None), 1);
+        assert!(!try_login(&her_ca1));
+
+        // Instances created with `new()` keep their own users
+        let other_ca = super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            EventBase {},
+            Random::Fake,
+        );
+        other_ca
+            .add_user_ipv6(ByteString::from("pass".to_string()), None, None, None)
+            .unwrap();
+        assert!(users.read().is_empty());
+    }
+
+    #[test]
+    pub fn test_clock_regression() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+        use crate::util::events::ManualClock;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let clock = Arc::new(ManualClock::new(1_000_000));
+
+        let mk_session = |private_key, her_pub_key, rand| {
+            let ca = super::CryptoAuth::new(Some(private_key), Arc::clone(&clock), rand);
+            super::Session::new(Arc::new(ca), her_pub_key, false, None).unwrap()
+        };
+        // Handshakes again after the reset, which needs a new temp key
+        let rand = Random::new_sodium().unwrap();
+        let my_session = mk_session(
+            my_keys.private_key.clone(),
+            her_keys.public_key.clone(),
+            rand,
+        );
+        let her_session = mk_session(
+            her_keys.private_key.clone(),
+            my_keys.public_key.clone(),
+            Random::Fake,
+        );
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg).unwrap();
+        };
+        for _ in 0..2 {
+            send(&my_session, &her_session);
+            send(&her_session, &my_session);
+        }
+        assert_eq!(my_session.get_state(), State::Established);
+
+        // Jumping back doesn't reset the session
+        clock.rewind(3600);
+        my_session.reset_if_timeout();
+        assert_eq!(my_session.get_state(), State::Established);
+        assert_eq!(my_session.clock_regressions(), 1);
+        // and is only counted once
+        my_session.reset_if_timeout();
+        send(&her_session, &my_session);
+        assert_eq!(my_session.clock_regressions(), 1);
+        // She saw it when sending
+        assert_eq!(her_session.clock_regressions(), 1);
+        assert_eq!(
+            my_session.inner.context.sessions.snapshot()[0].clock_regressions,
+            1
+        );
+
+        // The timeout still works from the new time, the session isn't stuck
+        clock.advance(super::Session::DEFAULT_RESET_AFTER_INACTIVITY_SECONDS - 1);
+        my_session.reset_if_timeout();
+        assert_eq!(my_session.get_state(), State::Established);
+        clock.advance(1);
+        my_session.reset_if_timeout();
+        assert_eq!(my_session.get_state(), State::Init);
+
+        // Also seen when a packet is received
+        for _ in 0..2 {
+            send(&my_session, &her_session);
+            send(&her_session, &my_session);
+        }
+        let mut msg = mk_msg(256);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut msg).unwrap();
+        clock.rewind(10);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        assert_eq!(her_session.clock_regressions(), 2);
+    }
+
+    #[test]
+    pub fn test_user_expiry() {
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+        use crate::util::events::ManualClock;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let my_keys = keys_api.key_pair();
+        let her_keys = keys_api.key_pair();
+        let clock = Arc::new(ManualClock::new(1_000_000));
+
+        let her_ca = super::CryptoAuth::new(
+            Some(her_keys.private_key.clone()),
+            Arc::clone(&clock),
+            Random::Fake,
+        );
+        let alice = || Some(ByteString::from("alice".to_string()));
+        let password = || Some(ByteString::from("pass".to_string()));
+        her_ca
+            .add_user_ipv6(password().unwrap(), alice(), None, Some(1_000_030))
+            .unwrap();
+        let her_ca = Arc::new(her_ca);
+        let her_session =
+            super::Session::new(Arc::clone(&her_ca), my_keys.public_key.clone(), false, None)
+                .unwrap();
+
+        // Handshakes again after the rekey, which needs a new temp key
+        let rand = Random::new_sodium().unwrap();
+        let my_ca = super::CryptoAuth::new(Some(my_keys.private_key.clone()), clock.clone(), rand);
+        let my_session =
+            super::Session::new(Arc::new(my_ca), her_keys.public_key.clone(), false, None).unwrap();
+        my_session.set_auth(password(), alice());
+
+        let send = |from: &super::Session, to: &super::Session| {
+            let mut msg = mk_msg(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt_msg(&mut msg).unwrap();
+            to.decrypt_msg(&mut msg)
+                .map_err(|err| match err.downcast_ref() {
+                    Some(super::DecryptError::DecryptErr(err)) => err.clone(),
+                    _ => panic!("unexpected error {}", err),
+                })
+        };
+        for _ in 0..2 {
+            send(&my_session, &her_session).unwrap();
+            send(&her_session, &my_session).unwrap();
+        }
+        assert_eq!(her_session.get_state(), State::Established);
+        assert_eq!(her_ca.get_users(), vec![alice().unwrap()]);
+        let hello = || noise_hello(&her_ca, my_keys.private_key.clone(), "alice", "pass");
+        assert_eq!(hello(), Ok(()));
+        assert!(her_ca.get_auth_stats().auth_successes > 0);
+
+        // Still valid until the last second
+        clock.advance(29);
+        assert!(her_ca.has_user(&alice().unwrap()));
+        clock.advance(1);
+        assert!(her_ca.get_users().is_empty());
+        assert!(her_ca.get_user_info().is_empty());
+        assert!(!her_ca.has_user(&alice().unwrap()));
+        assert_eq!(her_ca.user_count(), 0);
+        assert_eq!(her_ca.get_auth_stats().auth_successes, 0);
+        assert_eq!(hello(), Err(super::DecryptErr::UnrecognizedAuth));
+
+        // The established session keeps running, but a new handshake is refused
+        send(&my_session, &her_session).unwrap();
+        my_session.rekey().unwrap();
+        assert_eq!(
+            send(&my_session, &her_session),
+            Err(super::DecryptErr::UnrecognizedAuth)
+        );
+
+        // Only skipped until the user list is written
+        assert_eq!(her_ca.users.read().len(), 1);
+        let other = ByteString::from("other".to_string());
+        her_ca
+            .add_user_ipv6(other.clone(), Some(other), None, None)
+            .unwrap();
+        assert_eq!(her_ca.users.read().len(), 1);
+    }
+
+    #[test]
+    pub fn test_busiest_sessions() {
+        let (my_session, her_session) = established_sessions();
+        let sessions = &her_session.inner.context.sessions;
+        let snapshot = sessions.snapshot();
+
+        let (busiest, omitted) = sessions.busiest(1);
+        assert_eq!(omitted, 0);
+        assert_eq!(busiest[0].stats, snapshot[0].stats);
+        assert_eq!(busiest[0].bytes_decrypted, 32);
+
+        // A session which is decrypting is not waited for, the last stats are given
+        let mut msg = Message::rnew(512);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut msg).unwrap();
+        let replay_protector = her_session.inner.replay_protector.lock();
+        let (busiest, _) = sessions.busiest(1);
+        assert_eq!(busiest[0].stats, snapshot[0].stats);
+        drop(replay_protector);
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let (busiest, _) = sessions.busiest(1);
+        assert_eq!(
+            busiest[0].stats.received_packets,
+            snapshot[0].stats.received_packets + 1
+        );
+
+        let (busiest, omitted) = sessions.busiest(0);
+        assert!(busiest.is_empty());
+        assert_eq!(omitted, 1);
+    }
+
+    #[test]
+    pub fn test_session_dump() {
+        use std::net::Ipv6Addr;
+
+        use super::DecryptErr;
+        use crate::crypto::keys::PublicKeyExt;
+        use crate::rtypes::RTypes_CryptoAuth_State_t as State;
+
+        let (my_session, her_session) = established_sessions();
+        her_session.set_name(Some("alice".to_string()));
+
+        let dump = her_session.dump();
+        assert_eq!(dump.state, State::Established);
+        assert_eq!(dump.display_name.as_deref(), Some("alice"));
+        let my_key = &my_session.inner.context.public_key;
+        let my_ip6 = Ipv6Addr::from(super::ip6_from_key(my_key.raw()));
+        assert_eq!(dump.her_ip6, my_ip6.to_string());
+        assert_eq!(dump.her_public_key, Some(my_key.to_base32()));
+        assert_eq!(dump.bytes_encrypted, 32);
+        assert_eq!(dump.bytes_decrypted, 32);
+        assert_eq!(dump.duplicate_packets, 0);
+        let received = dump.received_packets;
+        assert!(!dump.stale_stats);
+        assert_eq!(dump.last_error, None);
+
+        // A replayed packet
+        let mut msg = Message::rnew(512);
+        msg.push_bytes(b"HelloWorld012345").unwrap();
+        my_session.encrypt_msg(&mut msg).unwrap();
+        let sent = msg.bytes().to_vec();
+        her_session.decrypt_msg(&mut msg).unwrap();
+        let mut msg = Message::rnew(512);
+        msg.push_bytes(&sent).unwrap();
+        her_session.decrypt_msg(&mut msg).unwrap_err();
+
+        let dump = her_session.dump();
+        assert_eq!(dump.last_error, Some(DecryptErr::Replay));
+        assert_eq!(dump.received_packets, received + 1);
+        assert_eq!(dump.duplicate_packets, 1);
+        assert!(!dump.stale_stats);
+
+        // The replay protector is in use, the counters are those of the last dump
+        let guard = her_session.inner.replay_protector.lock();
+        let stale = her_session.dump();
+        drop(guard);
+        assert!(stale.stale_stats);
+        assert_eq!(stale.received_packets, received + 1);
+        assert_eq!(stale.duplicate_packets, 1);
+        assert_eq!(stale.last_error, Some(DecryptErr::Replay));
+    }
+
+    #[test]
+    pub fn test_replay_kinds() {
+        use super::{DecryptErr, DecryptFailure};
+        use crate::crypto::replay_protector::ReplayKind;
+
+        let (my_session, her_session) = established_sessions();
+        let her_ca = &her_session.inner.context;
+
+        let send = || {
+            let mut msg = Message::rnew(512);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            my_session.encrypt_msg(&mut msg).unwrap();
+            let sent = msg.bytes().to_vec();
+            her_session.decrypt_msg(&mut msg).unwrap();
+            sent
+        };
+        let replay_kind = |sent: &[u8]| {
+            let mut msg = Message::rnew(512);
+            msg.push_bytes(sent).unwrap();
+            let err = her_session.decrypt_msg(&mut msg).unwrap_err();
+            let failure = err.downcast_ref::<DecryptFailure>().unwrap();
+            assert_eq!(failure.code(), DecryptErr::Replay);
+            failure.replay_kind()
+        };
+        let sent = send();
+        let nonce = u32::from_be_bytes([sent[0], sent[1], sent[2], sent[3]]);
+
+        // Copied by the network
+        assert_eq!(replay_kind(&sent), Some(ReplayKind::Duplicate));
+        assert_eq!(her_ca.replay_counts(), [1, 0]);
+
+        // Replayed once the window has moved past it
+        for _ in 0..70 {
+            send();
+        }
+        let replay_protector = her_session.inner.replay_protector.lock();
+        assert_eq!(
+            replay_protector.replay_kind(nonce),
+            Some(ReplayKind::TooOld)
+        );
+        assert_eq!(
+            replay_protector.replay_kind(nonce + 70),
+            Some(ReplayKind::Duplicate)
+        );
+        assert_eq!(replay_protector.replay_kind(nonce + 71), None);
+        drop(replay_protector);
+        assert_eq!(replay_kind(&sent), Some(ReplayKind::TooOld));
+        assert_eq!(her_ca.replay_counts(), [1, 1]);
+
+        // Both are counted as replays
+        let counts = her_ca.decrypt_error_counts();
+        assert_eq!(counts[DecryptErr::Replay as usize], 2);
+        her_ca.clear_decrypt_error_counts();
+        assert_eq!(her_ca.replay_counts(), [0, 0]);
+    }
 }
@@ -1,5 +1,13 @@
 //! CryptoHeader
 
+use std::convert::TryInto;
+
+use thiserror::Error;
+
+use super::keys::{KeyCtEq, PublicKey};
+use super::secret::SecretBuf32;
+use super::utils::crypto_hash_sha256;
+
 /// The AuthType specifies how the secret should be used to connect.
 #[repr(u8)]
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
@@ -78,7 +86,7 @@ impl Default for AuthType {
 /// to see if the given password is known. It can be thought of as the "username" although it is
 /// a derivative of the password.
 #[repr(C)]
-#[derive(Default, Clone, Debug)]
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub struct Challenge {
     pub auth_type: AuthType,
     pub lookup: [u8; 7],
@@ -95,13 +103,80 @@ impl Challenge {
     /// The number of bytes from the beginning which identify the auth for looking up the secret.
     pub const KEYSIZE: usize = 8;
 
+    /// The challenge sent by a peer authenticating with `password`, and `login` for
+    /// AuthType Two, e.g. to precompute the lookups of authorized passwords.
+    ///
+    /// Panics with AuthTypes which are not derived from a password.
+    pub fn for_password(login: &[u8], password: &[u8], auth_type: AuthType) -> Challenge {
+        Self::derive(login, password, auth_type).1
+    }
+
+    /// Derive the secret of a password together with its challenge, see `for_password()`.
+    pub(super) fn derive(
+        login: &[u8],
+        password: &[u8],
+        auth_type: AuthType,
+    ) -> (SecretBuf32, Self) {
+        let secret = SecretBuf32::from(crypto_hash_sha256(password));
+
+        let hash = match auth_type {
+            AuthType::One => crypto_hash_sha256(secret.as_bytes()),
+            AuthType::Two => crypto_hash_sha256(login),
+            _ => panic!("Unsupported auth type [{}]", auth_type as u8),
+        };
+
+        let mut challenge = Challenge {
+            auth_type,
+            ..Challenge::default()
+        };
+        challenge.lookup.copy_from_slice(&hash[1..8]);
+
+        (secret, challenge)
+    }
+
+    pub fn auth_type(&self) -> AuthType {
+        self.auth_type
+    }
+
     pub fn as_key_bytes(&self) -> &[u8] {
         unsafe {
             let self_bytes = self as *const Self as *const u8;
             std::slice::from_raw_parts(self_bytes, Self::KEYSIZE)
         }
     }
+
+    /// Read a challenge as it is on the wire, `None` if the AuthType is unknown.
+    ///
+    /// The deprecated 16 bit fields are kept in wire byte order, like the C code does.
+    pub fn parse(bytes: &[u8; Self::SIZE]) -> Option<Challenge> {
+        let auth_type = match bytes[0] {
+            0 => AuthType::Zero,
+            1 => AuthType::One,
+            2 => AuthType::Two,
+            3 => AuthType::Three,
+            _ => return None,
+        };
+        Some(Challenge {
+            auth_type,
+            lookup: bytes[1..8].try_into().expect("7 bytes"),
+            require_packet_auth_and_derivation_count: u16::from_ne_bytes([bytes[8], bytes[9]]),
+            additional: u16::from_ne_bytes([bytes[10], bytes[11]]),
+        })
+    }
+
+    /// The challenge as it is on the wire, see `parse()`.
+    pub fn serialize(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.auth_type as u8;
+        bytes[1..8].copy_from_slice(&self.lookup);
+        bytes[8..10].copy_from_slice(&self.require_packet_auth_and_derivation_count.to_ne_bytes());
+        bytes[10..12].copy_from_slice(&self.additional.to_ne_bytes());
+        bytes
+    }
 }
+// The wire format is the C struct, see `test_layout_matches_c()` for the offsets.
+const _: () = assert!(std::mem::size_of::<Challenge>() == Challenge::SIZE);
+
 impl Into<Challenge2> for Challenge {
     fn into(self) -> Challenge2 {
         Challenge2 { auth_type: self.auth_type, lookup: self.lookup, }
@@ -240,15 +315,237 @@ impl CryptoHeader {
     /// This value in the `nonce` field indicates Noise Protocol usage.
     pub const NOISE_PROTOCOL_NONCE: u32 = 0xFFFF_FFFE;
 
+    /// The highest `nonce` of a handshake packet, the one of a repeated key packet.
+    const LAST_HANDSHAKE_NONCE: u32 = 3;
+
     pub fn as_mut_bytes(&mut self) -> &mut [u8] {
         unsafe {
             let self_bytes = self as *mut Self as *mut u8;
             std::slice::from_raw_parts_mut(self_bytes, Self::SIZE)
         }
     }
+
+    /// Structural checks of a CryptoAuth handshake header, which need no crypto and
+    /// no session state.
+    ///
+    /// `expected_key` is the permanent public key of the peer, if it is known.
+    /// The encrypted temporary key can only be checked once it is decrypted.
+    pub fn validate(&self, expected_key: Option<&PublicKey>) -> Result<(), HeaderError> {
+        if u32::from_be(self.nonce) > Self::LAST_HANDSHAKE_NONCE {
+            return Err(HeaderError::NotHandshake);
+        }
+        // AuthType Three is only used by the Noise protocol.
+        if self.auth.auth_type() == AuthType::Three {
+            return Err(HeaderError::UnknownAuthType);
+        }
+        if self.public_key == [0; 32] {
+            return Err(HeaderError::ZeroPublicKey);
+        }
+        if let Some(expected_key) = expected_key {
+            if !expected_key.ct_eq(&PublicKey::from(self.public_key)) {
+                return Err(HeaderError::WrongPublicKey);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A CryptoAuth handshake header which fails `CryptoHeader::validate()`.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    #[error("nonce is not the one of a handshake packet")]
+    NotHandshake,
+
+    #[error("auth type is not used by CryptoAuth")]
+    UnknownAuthType,
+
+    #[error("permanent public key is zero")]
+    ZeroPublicKey,
+
+    #[error("permanent public key is not the expected one")]
+    WrongPublicKey,
+}
+
+const _: () = assert!(std::mem::size_of::<CryptoHeader>() == CryptoHeader::SIZE);
+
+/// Offset and size of a field of a struct, like `offset_of!` in C.
+#[cfg(test)]
+macro_rules! field_layout {
+    ($ty:ty, $field:ident) => {{
+        fn size_of_pointee<T>(_: *const T) -> usize {
+            std::mem::size_of::<T>()
+        }
+        let uninit = std::mem::MaybeUninit::<$ty>::uninit();
+        let base = uninit.as_ptr();
+        // No reference to the uninitialized field is made
+        let field = unsafe { std::ptr::addr_of!((*base).$field) };
+        (field as usize - base as usize, size_of_pointee(field))
+    }};
 }
 
 #[test]
 fn test_crypto_header() {
     assert_eq!(std::mem::size_of::<CryptoHeader>(), CryptoHeader::SIZE);
 }
+
+#[test]
+fn test_layout_matches_c() {
+    use crate::cffi;
+
+    macro_rules! assert_same_field {
+        ($ty:ty, $field:ident, $c_ty:ty, $c_field:ident) => {
+            assert_eq!(
+                field_layout!($ty, $field),
+                field_layout!($c_ty, $c_field),
+                "(offset, size) of {}::{} differs from C {}",
+                stringify!($ty),
+                stringify!($field),
+                stringify!($c_field),
+            );
+        };
+    }
+
+    let c_size = std::mem::size_of::<cffi::CryptoHeader_Challenge>();
+    assert_eq!(std::mem::size_of::<Challenge>(), c_size);
+    assert_same_field!(Challenge, auth_type, cffi::CryptoHeader_Challenge, type_);
+    assert_same_field!(Challenge, lookup, cffi::CryptoHeader_Challenge, lookup);
+    assert_same_field!(
+        Challenge,
+        require_packet_auth_and_derivation_count,
+        cffi::CryptoHeader_Challenge,
+        requirePacketAuthAndDerivationCount
+    );
+    assert_same_field!(
+        Challenge,
+        additional,
+        cffi::CryptoHeader_Challenge,
+        additional
+    );
+
+    assert_eq!(
+        std::mem::size_of::<CryptoHeader>(),
+        std::mem::size_of::<cffi::CryptoHeader>()
+    );
+    assert_same_field!(CryptoHeader, nonce, cffi::CryptoHeader, nonce);
+    assert_same_field!(CryptoHeader, auth, cffi::CryptoHeader, auth);
+    assert_same_field!(
+        CryptoHeader,
+        handshake_nonce,
+        cffi::CryptoHeader,
+        handshakeNonce
+    );
+    assert_same_field!(CryptoHeader, public_key, cffi::CryptoHeader, publicKey);
+    assert_same_field!(
+        CryptoHeader,
+        authenticator,
+        cffi::CryptoHeader,
+        authenticator
+    );
+    assert_same_field!(
+        CryptoHeader,
+        encrypted_temp_key,
+        cffi::CryptoHeader,
+        encryptedTempKey
+    );
+
+    // Headers are read in place from messages, with the alignment the C code expects
+    assert_eq!(
+        std::mem::align_of::<CryptoHeader>(),
+        std::mem::align_of::<cffi::CryptoHeader>()
+    );
+}
+
+#[test]
+fn test_challenge_for_password() {
+    // The lookups of `test_hash_password_wire_format()`
+    let challenge = Challenge::for_password(b"", b"password", AuthType::One);
+    assert_eq!(challenge.auth_type(), AuthType::One);
+    assert_eq!(
+        challenge.as_key_bytes(),
+        &hex::decode("01641c99f7719f57").unwrap()[..]
+    );
+    let challenge = Challenge::for_password(b"alice", b"password", AuthType::Two);
+    assert_eq!(challenge.auth_type(), AuthType::Two);
+    assert_eq!(
+        challenge.as_key_bytes(),
+        &hex::decode("02d806c97f0e00af").unwrap()[..]
+    );
+    assert_eq!(challenge.require_packet_auth_and_derivation_count, 0);
+    assert_eq!(challenge.additional, 0);
+
+    // The login only matters to AuthType Two
+    let challenge = Challenge::for_password(b"alice", b"password", AuthType::One);
+    assert_eq!(
+        challenge,
+        Challenge::for_password(b"bob", b"password", AuthType::One)
+    );
+}
+
+#[test]
+fn test_challenge_wire_format() {
+    let bytes = *b"\x02lookup\x80\x00\x01\xab\xcd";
+    let challenge = Challenge::parse(&bytes).unwrap();
+    assert_eq!(challenge.auth_type(), AuthType::Two);
+    assert_eq!(&challenge.lookup, b"lookup\x80");
+    assert_eq!(challenge.serialize(), bytes);
+    assert_eq!(challenge.as_key_bytes(), &bytes[..Challenge::KEYSIZE]);
+
+    // The same bytes as the C struct in memory
+    let memory = unsafe {
+        std::slice::from_raw_parts(&challenge as *const Challenge as *const u8, Challenge::SIZE)
+    };
+    assert_eq!(memory, &bytes[..]);
+
+    for auth_type in 0..=3 {
+        let mut bytes = bytes;
+        bytes[0] = auth_type;
+        assert_eq!(
+            Challenge::parse(&bytes).unwrap().auth_type() as u8,
+            auth_type
+        );
+    }
+    for &auth_type in &[4, 0x80, 0xff] {
+        let mut bytes = bytes;
+        bytes[0] = auth_type;
+        assert_eq!(Challenge::parse(&bytes), None);
+    }
+}
+
+#[test]
+fn test_crypto_header_validate() {
+    let key = PublicKey::from([7; 32]);
+    let other_key = PublicKey::from([8; 32]);
+    let mut header = CryptoHeader {
+        public_key: [7; 32],
+        ..CryptoHeader::default()
+    };
+    for nonce in 0..=3_u32 {
+        header.nonce = nonce.to_be();
+        assert_eq!(header.validate(None), Ok(()));
+        assert_eq!(header.validate(Some(&key)), Ok(()));
+        assert_eq!(
+            header.validate(Some(&other_key)),
+            Err(HeaderError::WrongPublicKey)
+        );
+    }
+    for &nonce in &[4, 0x100, CryptoHeader::NOISE_PROTOCOL_NONCE, u32::MAX] {
+        header.nonce = nonce.to_be();
+        assert_eq!(header.validate(None), Err(HeaderError::NotHandshake));
+    }
+    header.nonce = 0;
+
+    for &auth_type in &[AuthType::One, AuthType::Two] {
+        header.auth.auth_type = auth_type;
+        assert_eq!(header.validate(Some(&key)), Ok(()));
+    }
+    header.auth.auth_type = AuthType::Three;
+    assert_eq!(
+        header.validate(Some(&key)),
+        Err(HeaderError::UnknownAuthType)
+    );
+    header.auth.auth_type = AuthType::Zero;
+
+    header.public_key = [0; 32];
+    assert_eq!(header.validate(None), Err(HeaderError::ZeroPublicKey));
+    assert_eq!(header.validate(Some(&key)), Err(HeaderError::ZeroPublicKey));
+}
@@ -14,14 +14,15 @@ use parking_lot::{Mutex,RwLock};
 
 use crate::bytestring::ByteString;
 use crate::crypto::crypto_header::{AuthType, Challenge2};
-use crate::crypto::keys::{PrivateKey, PublicKey};
-use crate::crypto::utils::crypto_scalarmult_curve25519_base;
+use crate::crypto::keys::{Ip6Restriction, IpV6, PrivateKey, PublicKey};
+use crate::crypto::utils::{crypto_scalarmult_curve25519_base, ct_eq};
 use crate::crypto::cnoise;
 use crate::external::interface::iface::{self, IfRecv, Iface, IfacePvt};
 use crate::interface::wire::message::Message;
 use crate::crypto::crypto_auth::{ip6_from_key, DecryptError, DecryptErr};
 use crate::crypto::session::SessionTrait;
 use crate::external::memory::allocator::Allocator;
+use crate::util::events::Clock;
 
 use self::types::*;
 
@@ -93,7 +94,16 @@ thread_local!(static THREAD_CTX: RefCell<ThreadCtx> = Default::default());
 pub struct User {
     secret: [u8; 32],
     login: ByteString,
-    restricted_to_ip6: Option<[u8; 16]>,
+    restricted_to_ip6: Option<Ip6Restriction>,
+    /// Clock time in seconds from which this user is no longer accepted.
+    expires_at: Option<u64>,
+}
+
+impl User {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| now >= expires_at)
+    }
 }
 
 pub struct CryptoNoise {
@@ -109,10 +119,13 @@ pub struct CryptoNoise {
     sessions: RwLock<HashMap<u32, Arc<SessionInner>>>,
 
     next_sess_index: AtomicUsize,
+
+    /// The clock of the CryptoAuth, for the expiration of users.
+    clock: Arc<dyn Clock + Send + Sync>,
 }
 
 impl CryptoNoise {
-    pub fn new(private_key: &PrivateKey) -> Arc<CryptoNoise> {
+    pub fn new(private_key: &PrivateKey, clock: Arc<dyn Clock + Send + Sync>) -> Arc<CryptoNoise> {
         // Unfortunately, Boringtun private key cannot be constructed from raw bytes.
         // As a workaround, we convert the key to a HEX string
         // and then parse it into Boringtun secret key.
@@ -137,29 +150,54 @@ impl CryptoNoise {
             noise_handshaker,
             sessions: RwLock::new(HashMap::new()),
             next_sess_index: AtomicUsize::new(1),
+            clock,
         })
     }
+    /// Add a user, `name` is its login or the one it was given if it has none,
+    /// the same as in the CryptoAuth so that both can be removed together.
     pub fn add_user_ipv6(
         &self,
         password: ByteString,
         login: Option<ByteString>,
-        ipv6: Option<[u8; 16]>,
+        name: ByteString,
+        restriction: Option<Ip6Restriction>,
+        expires_at: Option<u64>,
     ) {
-        let mut users = self.users.write();
-        let mut user = User::default();
-        if let Some(login) = login.clone() {
-            user.login = login;
-        } else {
-            user.login = ByteString::from(format!("Anon #{}", users.len()));
-        }
-        let mut user = user.clone();
         let (secret, challenge) = compute_auth(Some(password), login);
-        user.secret = secret.unwrap(); // we know this will exist because there is a passwd
-        user.restricted_to_ip6 = ipv6;
-        users.insert(challenge.unwrap(), user);
+        let user = User {
+            secret: secret.unwrap(), // we know this will exist because there is a passwd
+            login: name,
+            restricted_to_ip6: restriction,
+            expires_at,
+        };
+        self.users.write().insert(challenge.unwrap(), user);
+    }
+    /// Remove the users for which `f` returns true, given each user's login and the ip6
+    /// restriction, if any. Returns the number of users removed.
+    pub fn remove_users_where<F>(&self, f: F) -> usize
+    where
+        F: Fn(&ByteString, Option<&Ip6Restriction>) -> bool,
+    {
+        let mut users = self.users.write();
+        let before = users.len();
+        users.retain(|_, u| !f(&u.login, u.restricted_to_ip6.as_ref()));
+        before - users.len()
+    }
+    /// Drop the users which have expired, they are only skipped until then.
+    pub fn purge_expired_users(&self) {
+        let now = self.now_seconds();
+        self.users.write().retain(|_, u| !u.is_expired(now));
     }
     fn get_auth(&self, ch: &Challenge2) -> Option<User> {
-        self.users.read().get(ch).map(|u|u.clone())
+        let now = self.now_seconds();
+        self.users
+            .read()
+            .get(ch)
+            .filter(|u| !u.is_expired(now))
+            .map(|u| u.clone())
+    }
+    fn now_seconds(&self) -> u64 {
+        self.clock.now_seconds() as u64
     }
 }
 
@@ -781,7 +819,7 @@ fn handle_init_msg(
     let sess = if let Some(psi) = prev_sess_id {
         if let Some(sess) = ca.sessions.read().get(&psi) {
             log::debug!("Continuing session with ID {:#x}", psi);
-            if sess.her_pubkey != &valid_handshake.peer_static_public[..] {
+            if !ct_eq(&sess.her_pubkey, &valid_handshake.peer_static_public[..]) {
                 log::debug!("DROP Handshake with key change from {:?}", peer_id);
                 return Err(DecryptError::DecryptErr(DecryptErr::WrongPermPubkey).into());
             }
@@ -807,8 +845,8 @@ fn handle_init_msg(
     };
 
     if let Some(user) = &user_opt {
-        if let Some(ip6) = user.restricted_to_ip6 {
-            if ip6 != sess.her_ip6 {
+        if let Some(restriction) = &user.restricted_to_ip6 {
+            if !restriction.allows(&IpV6::from(sess.her_ip6)) {
                 return Err(DecryptError::DecryptErr(DecryptErr::IpRestricted).into());
             }
         }
@@ -1,3 +1,1355 @@
 //! Public and private keys
 
+use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use thiserror::Error;
+
 pub use cjdns_keys::{IpV6, PrivateKey, PublicKey};
+
+use super::crypto_auth::ip6_from_key;
+use super::random::Random;
+use super::secret::SecretBuf32;
+use super::utils::{crypto_scalarmult_curve25519_base, ct_eq};
+use super::wipe::Wipe;
+use super::zero::IsZero;
+
+/// The cjdns base32 alphabet, as in `util/Base32.h`.
+const BASE32_CHARS: &[u8; 32] = b"0123456789bcdfghjklmnpqrstuvwxyz";
+
+/// Length of a public key in the base32 form, without the ".k" suffix.
+const BASE32_KEY_LEN: usize = 52;
+
+/// Error parsing a key, for public keys in the base32 ".k" form these are the same
+/// errors as `Key_parse()` reports.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum KeyParseError {
+    #[error("key must be 52 characters long")]
+    TooShort,
+
+    #[error("key must end in .k")]
+    Malformed,
+
+    #[error("failed to base-32 decode key")]
+    DecodeFailed,
+
+    #[error("not a valid cjdns public key")]
+    Invalid,
+
+    #[error("private key must be 64 hex characters, got {0}")]
+    BadLength(usize),
+
+    #[error("private key is not valid hex")]
+    NotHex,
+
+    #[error("private key is all zeroes")]
+    ZeroKey,
+
+    #[error("not a valid IPv6 address")]
+    BadIp6,
+
+    #[error("IPv6 address is not in fc00::/8")]
+    NotCjdnsIp6,
+}
+
+/// Public keys in the base32 ".k" form found in config files and admin tools.
+///
+/// `PublicKey` belongs to the `cjdns_keys` crate, so these are provided by a trait
+/// and `Base32Key` stands in for a `Display` impl.
+pub trait PublicKeyExt: Sized {
+    /// Encode the key like `Key_stringify()`, e.g. `lpu15wrt3tb6d8vng...5v0mhlg30.k`.
+    fn to_base32(&self) -> String;
+
+    /// Parse a key like `Key_parse()` does, also rejecting strings which are not
+    /// exactly what `to_base32()` gives for the decoded key (e.g. upper case).
+    fn from_base32(key: &str) -> Result<Self, KeyParseError>;
+
+    /// Compare the raw bytes of two keys, first byte first, like `memcmp()` in the C code.
+    ///
+    /// When hellos cross on the wire, the node whose key is higher per this comparison
+    /// yields, so both nodes must order keys the same way whatever `Ord` `PublicKey` has.
+    fn wire_cmp(&self, other: &Self) -> cmp::Ordering;
+}
+
+impl PublicKeyExt for PublicKey {
+    fn to_base32(&self) -> String {
+        Base32Key(self).to_string()
+    }
+
+    fn from_base32(key: &str) -> Result<Self, KeyParseError> {
+        let key = key.as_bytes();
+        if key.len() < BASE32_KEY_LEN {
+            return Err(KeyParseError::TooShort);
+        }
+        if &key[BASE32_KEY_LEN..] != b".k" {
+            return Err(KeyParseError::Malformed);
+        }
+
+        let mut bytes = [0_u8; 32];
+        let (mut work, mut bits, mut len) = (0_u32, 0, 0);
+        for c in &key[..BASE32_KEY_LEN] {
+            let n = BASE32_CHARS
+                .iter()
+                .position(|b| b == c)
+                .ok_or(KeyParseError::DecodeFailed)?;
+            work |= (n as u32) << bits;
+            bits += 5;
+            if bits >= 8 {
+                // 52 characters are 260 bits, the last 4 must be zero
+                *bytes.get_mut(len).ok_or(KeyParseError::DecodeFailed)? = work as u8;
+                len += 1;
+                bits -= 8;
+                work >>= 8;
+            }
+        }
+        if work != 0 || len != bytes.len() {
+            return Err(KeyParseError::DecodeFailed);
+        }
+
+        let public_key = PublicKey::from(bytes);
+        if IpV6::try_from(&public_key).is_err() {
+            return Err(KeyParseError::Invalid);
+        }
+        Ok(public_key)
+    }
+
+    fn wire_cmp(&self, other: &Self) -> cmp::Ordering {
+        self.raw()[..].cmp(&other.raw()[..])
+    }
+}
+
+/// Displays a public key in the base32 ".k" form, see `PublicKeyExt::to_base32()`.
+pub struct Base32Key<'a>(pub &'a PublicKey);
+
+impl fmt::Display for Base32Key<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = [0_u8; BASE32_KEY_LEN + 2];
+        let (mut work, mut bits, mut len) = (0_u32, 0, 0);
+        for &b in self.0.raw() {
+            work |= (b as u32) << bits;
+            bits += 8;
+            while bits >= 5 {
+                out[len] = BASE32_CHARS[(work & 31) as usize];
+                len += 1;
+                bits -= 5;
+                work >>= 5;
+            }
+        }
+        if bits > 0 {
+            out[len] = BASE32_CHARS[(work & 31) as usize];
+            len += 1;
+        }
+        debug_assert_eq!(len, BASE32_KEY_LEN);
+        out[len..].copy_from_slice(b".k");
+        f.write_str(std::str::from_utf8(&out).expect("base32 is ascii"))
+    }
+}
+
+/// Private keys in the hex form used by `cjdroute.conf`.
+///
+/// There is deliberately no `Display` for private keys, the hex form is only available
+/// through `expose_secret()` so it doesn't end up in a log line by accident.
+pub trait PrivateKeyExt: Sized {
+    /// Parse 64 hex characters, rejecting the all-zero key.
+    fn from_hex(key: &str) -> Result<Self, KeyParseError>;
+
+    /// Explicit access to the secret key material, e.g. to write a config file.
+    fn expose_secret(&self) -> ExposedPrivateKey<'_>;
+}
+
+impl PrivateKeyExt for PrivateKey {
+    fn from_hex(key: &str) -> Result<Self, KeyParseError> {
+        if key.len() != 64 {
+            return Err(KeyParseError::BadLength(key.len()));
+        }
+        let mut bytes = [0_u8; 32];
+        let res = match hex::decode_to_slice(key, &mut bytes) {
+            Err(_) => Err(KeyParseError::NotHex),
+            Ok(()) if bytes.is_zero() => Err(KeyParseError::ZeroKey),
+            Ok(()) => Ok(PrivateKey::from(bytes)),
+        };
+        (&mut bytes).wipe(); // Wipe sensitive data on the stack
+        res
+    }
+
+    fn expose_secret(&self) -> ExposedPrivateKey<'_> {
+        ExposedPrivateKey(self)
+    }
+}
+
+/// A private key which the caller asked to see, see `PrivateKeyExt::expose_secret()`.
+pub struct ExposedPrivateKey<'a>(&'a PrivateKey);
+
+impl ExposedPrivateKey<'_> {
+    /// The key as 64 lower case hex characters, what `PrivateKeyExt::from_hex()` parses.
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0.raw())
+    }
+}
+
+/// cjdns addresses in text form, e.g. the `ipv6` of a user in `cjdroute.conf`.
+///
+/// `IpV6` belongs to the `cjdns_keys` crate, so these are provided by a trait
+/// and `CompressedIp6` stands in for a `Display` impl.
+pub trait IpV6Ext: Sized {
+    /// Parse an address in the full or the compressed form, e.g. `fc00::1`,
+    /// which must be in fc00::/8.
+    fn parse(ip6: &str) -> Result<Self, KeyParseError>;
+
+    /// Whether the address is in fc00::/8, like those of all valid keys.
+    fn is_cjdns(&self) -> bool;
+
+    fn to_ipv6_addr(&self) -> Ipv6Addr;
+
+    /// Any address is converted, see `is_cjdns()`.
+    fn from_ipv6_addr(addr: Ipv6Addr) -> Self;
+
+    /// Whether the first `bits` bits of this address are those of `other`.
+    ///
+    /// Panics if `bits` is more than 128.
+    fn matches_prefix(&self, other: &IpV6, bits: u8) -> bool;
+}
+
+impl IpV6Ext for IpV6 {
+    fn parse(ip6: &str) -> Result<Self, KeyParseError> {
+        let addr = ip6.parse::<Ipv6Addr>().map_err(|_| KeyParseError::BadIp6)?;
+        let ip6 = Self::from_ipv6_addr(addr);
+        if !ip6.is_cjdns() {
+            return Err(KeyParseError::NotCjdnsIp6);
+        }
+        Ok(ip6)
+    }
+
+    fn is_cjdns(&self) -> bool {
+        self.raw()[0] == 0xfc
+    }
+
+    fn to_ipv6_addr(&self) -> Ipv6Addr {
+        Ipv6Addr::from(*self.raw())
+    }
+
+    fn from_ipv6_addr(addr: Ipv6Addr) -> Self {
+        IpV6::from(addr.octets())
+    }
+
+    fn matches_prefix(&self, other: &IpV6, bits: u8) -> bool {
+        ip6_has_prefix(self, other.raw(), bits as usize)
+    }
+}
+
+/// The addresses a user may authenticate from, see `CryptoAuth::add_user_restricted()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ip6Restriction {
+    /// Only this address.
+    Exact(IpV6),
+    /// Any address with the first `bits` bits of `addr`, at most 128.
+    Prefix { addr: IpV6, bits: u8 },
+}
+
+impl Ip6Restriction {
+    /// Whether a peer with the address `ip6` may authenticate.
+    pub fn allows(&self, ip6: &IpV6) -> bool {
+        match self {
+            Ip6Restriction::Exact(addr) => addr.raw() == ip6.raw(),
+            Ip6Restriction::Prefix { addr, bits } => ip6.matches_prefix(addr, *bits),
+        }
+    }
+
+    /// The address of an `Exact` restriction.
+    pub fn exact(&self) -> Option<&IpV6> {
+        match self {
+            Ip6Restriction::Exact(addr) => Some(addr),
+            Ip6Restriction::Prefix { .. } => None,
+        }
+    }
+}
+
+/// Displays an address in the compressed form, e.g. `fc00::1`, see `IpV6Ext::parse()`.
+pub struct CompressedIp6<'a>(pub &'a IpV6);
+
+impl fmt::Display for CompressedIp6<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0.to_ipv6_addr(), f)
+    }
+}
+
+/// Constant-time equality for keys.
+///
+/// The `PartialEq` of the `cjdns_keys` types stops at the first byte which differs, so its
+/// timing tells where two keys differ. These types belong to `cjdns_keys`, so the impls can't
+/// be replaced: secrets and keys checked against untrusted input should use `ct_eq()`.
+///
+/// `PublicKey` keeps its ordinary `Ord`, which only decides the hello collision tie-break;
+/// both keys compared there are public, so that comparison needn't be constant-time.
+pub trait KeyCtEq {
+    fn ct_eq(&self, other: &Self) -> bool;
+}
+
+impl KeyCtEq for PublicKey {
+    fn ct_eq(&self, other: &Self) -> bool {
+        ct_eq(self.raw(), other.raw())
+    }
+}
+
+impl KeyCtEq for PrivateKey {
+    fn ct_eq(&self, other: &Self) -> bool {
+        ct_eq(self.raw(), other.raw())
+    }
+}
+
+/// A private key with its public key and ip6, which are derived once when it is created.
+#[derive(Clone)]
+pub struct Keypair {
+    private: PrivateKey,
+    public: PublicKey,
+    ip6: IpV6,
+}
+
+impl Keypair {
+    /// Generate a new keypair like `Key_gen()`, drawing keys until the ip6 is in fc00::/8.
+    ///
+    /// With `Random::Fake` this only returns if its fixed key happens to be valid.
+    pub fn generate(rand: &Random) -> Self {
+        loop {
+            let keypair = Self::from_private(PrivateKey::new_random(rand));
+            if keypair.valid_ip6() {
+                return keypair;
+            }
+        }
+    }
+
+    /// Derive the public key and ip6 of `private`, the ip6 is not checked, see `valid_ip6()`.
+    pub fn from_private(private: PrivateKey) -> Self {
+        let public = crypto_scalarmult_curve25519_base(&private);
+        let ip6 = IpV6::from(ip6_from_key(public.raw()));
+        Keypair {
+            private,
+            public,
+            ip6,
+        }
+    }
+
+    pub fn private(&self) -> &PrivateKey {
+        &self.private
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    /// The ip6 of the public key, which is only a cjdns address if `valid_ip6()`.
+    pub fn ip6(&self) -> &IpV6 {
+        &self.ip6
+    }
+
+    /// Whether the ip6 is in fc00::/8, the keys of a node must be.
+    pub fn valid_ip6(&self) -> bool {
+        self.ip6.is_cjdns()
+    }
+
+    /// Generate keypairs until one with a valid ip6 satisfies `predicate`, e.g. for a vanity
+    /// address. `None` if there is none in `max_attempts` keys.
+    ///
+    /// See `KeySearch` to search on several threads.
+    pub fn generate_matching(
+        rand: &Random,
+        predicate: impl Fn(&IpV6, &PublicKey) -> bool,
+        max_attempts: u64,
+    ) -> Option<Self> {
+        search_keypair(
+            rand,
+            &predicate,
+            max_attempts,
+            &SearchState::default(),
+            None,
+        )
+    }
+
+    /// Generate a keypair whose ip6 starts with the first `bits` bits of `prefix`,
+    /// see `ip6_has_prefix()`.
+    ///
+    /// All valid ip6 start with 0xfc, so other prefixes are never found.
+    pub fn generate_with_ip6_prefix(
+        rand: &Random,
+        prefix: &[u8],
+        bits: usize,
+        max_attempts: u64,
+    ) -> Option<Self> {
+        Self::generate_matching(
+            rand,
+            |ip6, _| ip6_has_prefix(ip6, prefix, bits),
+            max_attempts,
+        )
+    }
+}
+
+/// Whether the first `bits` bits of `ip6` are those of `prefix`.
+///
+/// Panics if `prefix` is shorter than `bits`, or if `bits` is more than 128.
+pub fn ip6_has_prefix(ip6: &IpV6, prefix: &[u8], bits: usize) -> bool {
+    assert!(
+        bits <= 128 && bits <= prefix.len() * 8,
+        "prefix of {} bits is too long",
+        bits
+    );
+    let ip6 = ip6.raw();
+    let whole = bits / 8;
+    if ip6[..whole] != prefix[..whole] {
+        return false;
+    }
+    let rest = bits % 8;
+    let mask = !(0xff_u8 >> rest);
+    rest == 0 || (ip6[whole] ^ prefix[whole]) & mask == 0
+}
+
+/// Parallel search for a keypair, see `Keypair::generate_matching()`.
+///
+/// Every thread draws keys from its own `Random::new_sodium()`.
+pub struct KeySearch {
+    threads: usize,
+    max_attempts: u64,
+    progress_interval: u64,
+    on_progress: Option<Arc<dyn Fn(u64) + Send + Sync>>,
+}
+
+impl KeySearch {
+    /// A search on one thread, giving up after `max_attempts` keys in total.
+    pub fn new(max_attempts: u64) -> Self {
+        KeySearch {
+            threads: 1,
+            max_attempts,
+            progress_interval: 0,
+            on_progress: None,
+        }
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Call `on_progress` with the number of keys tried so far every `interval` keys,
+    /// e.g. for a CLI to show the rate. It is called from the searching threads.
+    pub fn on_progress<F>(mut self, interval: u64, on_progress: F) -> Self
+    where
+        F: Fn(u64) + Send + Sync + 'static,
+    {
+        self.progress_interval = interval;
+        self.on_progress = Some(Arc::new(on_progress));
+        self
+    }
+
+    /// Search until a thread finds a keypair satisfying `predicate`, or all of the attempts
+    /// are used up. `None` too if the random number generator can't be initialized.
+    pub fn run<P>(&self, predicate: P) -> Option<Keypair>
+    where
+        P: Fn(&IpV6, &PublicKey) -> bool + Send + Sync + 'static,
+    {
+        let predicate = Arc::new(predicate);
+        let state = Arc::new(SearchState::default());
+        let workers: Vec<_> = (0..self.threads)
+            .map(|_| {
+                let predicate = Arc::clone(&predicate);
+                let state = Arc::clone(&state);
+                let max_attempts = self.max_attempts;
+                let progress = self
+                    .on_progress
+                    .clone()
+                    .map(|f| (self.progress_interval, f));
+                std::thread::spawn(move || {
+                    let rand = Random::new_sodium().ok()?;
+                    let progress = progress.as_ref().map(|(interval, f)| (*interval, &**f));
+                    search_keypair(&rand, &*predicate, max_attempts, &state, progress)
+                })
+            })
+            .collect();
+        let mut found = None;
+        for worker in workers {
+            if let Ok(Some(keypair)) = worker.join() {
+                found.get_or_insert(keypair);
+            }
+        }
+        found
+    }
+}
+
+/// Error loading a `NodeIdentity`.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum IdentityError {
+    #[error("failed to read the identity: {0:?}")]
+    Io(io::ErrorKind),
+
+    #[error("malformed config at line {0}")]
+    Syntax(usize),
+
+    #[error("no privateKey in the config")]
+    MissingPrivateKey,
+
+    #[error("bad privateKey: {0}")]
+    BadPrivateKey(KeyParseError),
+
+    #[error("bad publicKey: {0}")]
+    BadPublicKey(KeyParseError),
+
+    #[error("bad ipv6: {0}")]
+    BadIp6(KeyParseError),
+
+    #[error("the ipv6 of the private key is not in fc00::/8")]
+    InvalidIp6,
+
+    #[error("publicKey does not belong to privateKey")]
+    PublicKeyMismatch,
+
+    #[error("ipv6 does not belong to privateKey")]
+    Ip6Mismatch,
+}
+
+/// The keys of a node as they are in `cjdroute.conf`: `privateKey` in hex, `publicKey` in
+/// base32 and `ipv6`. The last two are only there for people to read, they are checked
+/// against the private key when they are present.
+pub struct NodeIdentity {
+    keypair: Keypair,
+}
+
+impl NodeIdentity {
+    /// An identity with the keys of `keypair`, which must have a valid ip6.
+    pub fn new(keypair: Keypair) -> Result<Self, IdentityError> {
+        if !keypair.valid_ip6() {
+            return Err(IdentityError::InvalidIp6);
+        }
+        Ok(NodeIdentity { keypair })
+    }
+
+    pub fn keypair(&self) -> &Keypair {
+        &self.keypair
+    }
+
+    /// Read the keys from a `cjdroute.conf`, or from what `save()` writes.
+    ///
+    /// The config is JSON with comments and trailing commas like the C parser accepts,
+    /// only the string members of the top level object are looked at.
+    pub fn load(mut reader: impl Read) -> Result<Self, IdentityError> {
+        let mut conf = String::new();
+        let res = match reader.read_to_string(&mut conf) {
+            Ok(_) => Self::parse_conf(&conf),
+            Err(e) => Err(IdentityError::Io(e.kind())),
+        };
+        // Wipe the copies of the private key
+        conf.into_bytes().wipe();
+        res
+    }
+
+    fn parse_conf(conf: &str) -> Result<Self, IdentityError> {
+        let mut members = conf_top_level_strings(conf)?;
+        // The last one counts, like with the C parser
+        let mut take = |name: &str| {
+            members
+                .iter_mut()
+                .rev()
+                .find(|m| m.0 == name)
+                .map(|m| std::mem::take(&mut m.1))
+        };
+        let private_hex = take("privateKey");
+        let public_str = take("publicKey");
+        let ip6_str = take("ipv6");
+        for (_, value) in members {
+            value.into_bytes().wipe();
+        }
+
+        let private_hex = private_hex.ok_or(IdentityError::MissingPrivateKey)?;
+        let private = PrivateKey::from_hex(&private_hex);
+        private_hex.into_bytes().wipe();
+        let keypair = Keypair::from_private(private.map_err(IdentityError::BadPrivateKey)?);
+
+        if let Some(public_str) = public_str {
+            let public =
+                PublicKey::from_base32(&public_str).map_err(IdentityError::BadPublicKey)?;
+            if !public.ct_eq(keypair.public()) {
+                return Err(IdentityError::PublicKeyMismatch);
+            }
+        }
+        if let Some(ip6_str) = ip6_str {
+            let ip6 = IpV6::parse(&ip6_str).map_err(IdentityError::BadIp6)?;
+            if ip6.raw() != keypair.ip6().raw() {
+                return Err(IdentityError::Ip6Mismatch);
+            }
+        }
+        Self::new(keypair)
+    }
+
+    /// Write the keys in the form `cjdroute --genconf` does, which `load()` reads back.
+    /// The private key is written in the clear, the file must be kept secret.
+    pub fn save(&self, mut writer: impl Write) -> io::Result<()> {
+        let ip6 = self.keypair.ip6().raw();
+        let ip6_full = (0..8)
+            .map(|i| format!("{:02x}{:02x}", ip6[2 * i], ip6[2 * i + 1]))
+            .collect::<Vec<_>>()
+            .join(":");
+        let private_hex = self.keypair.private().expose_secret().to_hex();
+        let res = write!(
+            writer,
+            concat!(
+                "{{\n",
+                "    // Private key:\n",
+                "    // Your confidentiality and data integrity depend on this key, ",
+                "keep it secret!\n",
+                "    \"privateKey\": \"{}\",\n",
+                "\n",
+                "    // This key corresponds to the public key and ipv6 address:\n",
+                "    \"publicKey\": \"{}\",\n",
+                "    \"ipv6\": \"{}\"\n",
+                "}}\n",
+            ),
+            private_hex,
+            self.keypair.public().to_base32(),
+            ip6_full,
+        );
+        private_hex.into_bytes().wipe();
+        res
+    }
+}
+
+/// The members of the top level object of a `cjdroute.conf` whose values are strings,
+/// in order. Comments and trailing commas are accepted, other values are skipped.
+fn conf_top_level_strings(conf: &str) -> Result<Vec<(String, String)>, IdentityError> {
+    #[derive(PartialEq)]
+    enum Token {
+        Str(String),
+        Punct(u8),
+        Other,
+    }
+
+    // Numbers, true, false and null
+    fn is_scalar_byte(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || b"-+.".contains(&c)
+    }
+
+    let bytes = conf.as_bytes();
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let token_line = line;
+        match c {
+            b'\n' => line += 1,
+            b' ' | b'\t' | b'\r' => (),
+            b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let end = conf[i + 2..]
+                    .find("*/")
+                    .ok_or(IdentityError::Syntax(token_line))?;
+                line += conf[i..i + 2 + end].matches('\n').count();
+                i += end + 4;
+                continue;
+            }
+            b'{' | b'}' | b'[' | b']' | b':' | b',' => tokens.push((Token::Punct(c), line)),
+            b'"' => {
+                let mut value = String::new();
+                let mut chars = conf[i + 1..].char_indices();
+                loop {
+                    let (j, ch) = chars.next().ok_or(IdentityError::Syntax(token_line))?;
+                    match ch {
+                        '"' => {
+                            i += j + 1;
+                            break;
+                        }
+                        '\n' => return Err(IdentityError::Syntax(token_line)),
+                        '\\' => {
+                            let (_, esc) = chars.next().ok_or(IdentityError::Syntax(line))?;
+                            value.push(match esc {
+                                'n' => '\n',
+                                't' => '\t',
+                                'r' => '\r',
+                                'b' => '\u{8}',
+                                'f' => '\u{c}',
+                                'u' => {
+                                    let hex: String =
+                                        (0..4).filter_map(|_| chars.next()).map(|c| c.1).collect();
+                                    u32::from_str_radix(&hex, 16)
+                                        .ok()
+                                        .and_then(std::char::from_u32)
+                                        .ok_or(IdentityError::Syntax(line))?
+                                }
+                                other => other,
+                            });
+                        }
+                        other => value.push(other),
+                    }
+                }
+                tokens.push((Token::Str(value), token_line));
+            }
+            c if is_scalar_byte(c) => {
+                while i + 1 < bytes.len() && is_scalar_byte(bytes[i + 1]) {
+                    i += 1;
+                }
+                tokens.push((Token::Other, line));
+            }
+            _ => return Err(IdentityError::Syntax(line)),
+        }
+        i += 1;
+    }
+
+    let mut members = Vec::new();
+    let mut depth = 0;
+    let mut tokens = tokens.into_iter().peekable();
+    if tokens.peek().map(|t| &t.0) != Some(&Token::Punct(b'{')) {
+        return Err(IdentityError::Syntax(tokens.peek().map_or(line, |t| t.1)));
+    }
+    while let Some((token, token_line)) = tokens.next() {
+        match token {
+            Token::Punct(b'{') | Token::Punct(b'[') => depth += 1,
+            Token::Punct(b'}') | Token::Punct(b']') => {
+                depth -= 1;
+                if depth == 0 && tokens.peek().is_some() {
+                    return Err(IdentityError::Syntax(token_line));
+                }
+            }
+            Token::Str(name) if depth == 1 => {
+                if tokens.peek().map(|t| &t.0) != Some(&Token::Punct(b':')) {
+                    return Err(IdentityError::Syntax(token_line));
+                }
+                tokens.next();
+                if let Some((Token::Str(_), _)) = tokens.peek() {
+                    if let Some((Token::Str(value), _)) = tokens.next() {
+                        members.push((name, value));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if depth != 0 {
+        return Err(IdentityError::Syntax(line));
+    }
+    Ok(members)
+}
+
+/// Shared by the threads of a `KeySearch`.
+#[derive(Default)]
+struct SearchState {
+    attempts: AtomicU64,
+    found: AtomicBool,
+}
+
+fn search_keypair(
+    rand: &Random,
+    predicate: &dyn Fn(&IpV6, &PublicKey) -> bool,
+    max_attempts: u64,
+    state: &SearchState,
+    progress: Option<(u64, &(dyn Fn(u64) + Send + Sync))>,
+) -> Option<Keypair> {
+    // Every key is drawn into the same buffer, which is wiped when the search ends
+    let mut private = SecretBuf32::default();
+    while !state.found.load(Ordering::Relaxed) {
+        let attempt = state.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        if attempt > max_attempts {
+            return None;
+        }
+        if let Some((interval, on_progress)) = progress {
+            if interval != 0 && attempt % interval == 0 {
+                on_progress(attempt);
+            }
+        }
+        rand.random_bytes(private.as_mut_bytes());
+        let keypair = Keypair::from_private(PrivateKey::from(*private.as_bytes()));
+        if keypair.valid_ip6() && predicate(keypair.ip6(), keypair.public()) {
+            state.found.store(true, Ordering::Relaxed);
+            return Some(keypair);
+        }
+    }
+    None
+}
+
+impl Drop for Keypair {
+    fn drop(&mut self) {
+        std::mem::replace(&mut self.private, PrivateKey::from([0; 32])).wipe();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use std::net::Ipv6Addr;
+
+    use super::{ip6_has_prefix, IdentityError, Ip6Restriction, KeySearch, NodeIdentity};
+    use super::{Base32Key, CompressedIp6, IpV6, IpV6Ext, KeyCtEq, KeyParseError, Keypair};
+    use super::{PrivateKey, PrivateKeyExt, PublicKey, PublicKeyExt};
+    use crate::crypto::random::Random;
+
+    /// Keys from the C code and tests with their hex form.
+    const VECTORS: &[(&str, &str)] = &[
+        (
+            "lpu15wrt3tb6d8vngq9yh3lr4gmnkuv0rgcd2jwl5rp5v0mhlg30.k",
+            "b2ea50f8cd232bc3d0a6ce26ff86bcc44d1af506d72d262097e5d6b2c17cd20d",
+        ),
+        (
+            "h9xgk0418x538kg2h5yw4n32rkl4wk4wplkzsmltpg20q78bu7q0.k",
+            "2f75170109a89781a213af784ee810374ac223e155c68fa7ccd509600f52fa58",
+        ),
+        (
+            "usclqxtgkksmgwv10h8h3pltm3zy27bddb20mpsbrvjlcw4d9gl0.k",
+            "1a2f697b7631e2e9f80ee0a137aacc737c2f8e624c09302b567743b93861c949",
+        ),
+        (
+            "rjndc8rvg194ddf2j5v679cfjcpmsmhv8p022q3lvpym21cqwyh0.k",
+            "1752b6d0dd2e24c25813b06c73d26a70d589e7dba80221ec90bbfa29c2b2dc3f",
+        ),
+    ];
+
+    #[test]
+    fn test_base32_vectors() {
+        for &(key_str, key_hex) in VECTORS {
+            let key = PublicKey::from_base32(key_str).unwrap();
+            assert_eq!(hex::encode(key.raw()), key_hex);
+            assert_eq!(key.to_base32(), key_str);
+            assert_eq!(format!("{}", Base32Key(&key)), key_str);
+        }
+    }
+
+    #[test]
+    fn test_base32_errors() {
+        let key = VECTORS[0].0;
+        let parse = |s: &str| PublicKey::from_base32(s).err();
+        assert_eq!(parse(""), Some(KeyParseError::TooShort));
+        assert_eq!(parse(&key[..51]), Some(KeyParseError::TooShort));
+        assert_eq!(parse(&key[..52]), Some(KeyParseError::Malformed));
+        assert_eq!(parse(&format!("{}x", key)), Some(KeyParseError::Malformed));
+        assert_eq!(
+            parse(&key.replace('.', "_")),
+            Some(KeyParseError::Malformed)
+        );
+
+        // Not in the alphabet, upper case is not the canonical form
+        assert_eq!(
+            parse(&key.replacen('l', "a", 1)),
+            Some(KeyParseError::DecodeFailed)
+        );
+        let upper = key.to_uppercase().replace(".K", ".k");
+        assert_eq!(parse(&upper), Some(KeyParseError::DecodeFailed));
+
+        // Bits past the 32 bytes would be lost
+        assert_eq!(
+            parse(&key.replace("30.k", "32.k")),
+            Some(KeyParseError::DecodeFailed)
+        );
+
+        // Decodes but doesn't hash to an fc address
+        let zero_key = format!("{}.k", "0".repeat(52));
+        assert_eq!(parse(&zero_key), Some(KeyParseError::Invalid));
+        assert_eq!(PublicKey::from([0; 32]).to_base32(), zero_key);
+    }
+
+    #[test]
+    fn test_private_key_hex() {
+        let key_hex = "751d3db85b848b6d34a2ba1d7fb9b6bd47ed5fd4cf37a08a8e8a9aedb9ea1b1e";
+        let key = PrivateKey::from_hex(key_hex).unwrap();
+        assert_eq!(hex::encode(key.raw()), key_hex);
+        assert_eq!(key.expose_secret().to_hex(), key_hex);
+
+        // Upper case is accepted, the output is always lower case
+        let key = PrivateKey::from_hex(&key_hex.to_uppercase()).unwrap();
+        assert_eq!(key.expose_secret().to_hex(), key_hex);
+    }
+
+    #[test]
+    fn test_private_key_hex_errors() {
+        let key_hex = "751d3db85b848b6d34a2ba1d7fb9b6bd47ed5fd4cf37a08a8e8a9aedb9ea1b1e";
+        let parse = |s: &str| PrivateKey::from_hex(s).err();
+        assert_eq!(parse(""), Some(KeyParseError::BadLength(0)));
+        assert_eq!(parse(&key_hex[..63]), Some(KeyParseError::BadLength(63)));
+        assert_eq!(parse(&key_hex[..62]), Some(KeyParseError::BadLength(62)));
+        assert_eq!(
+            parse(&format!("{}00", key_hex)),
+            Some(KeyParseError::BadLength(66))
+        );
+        assert_eq!(
+            parse(&format!(" {}", &key_hex[1..])),
+            Some(KeyParseError::NotHex)
+        );
+        assert_eq!(
+            parse(&key_hex.replacen('d', "g", 1)),
+            Some(KeyParseError::NotHex)
+        );
+        assert_eq!(
+            parse(&format!("0x{}", &key_hex[2..])),
+            Some(KeyParseError::NotHex)
+        );
+
+        // Multi-byte characters can't be 64 hex digits either
+        let wide = format!("{}é", &key_hex[..62]);
+        assert_eq!(wide.len(), 64);
+        assert_eq!(parse(&wide), Some(KeyParseError::NotHex));
+
+        assert_eq!(parse(&"0".repeat(64)), Some(KeyParseError::ZeroKey));
+    }
+
+    /// Keys from `test/CryptoAddress_test.c` and `test/BeaconFramework.c`.
+    const KEYPAIR_VECTORS: &[(&str, &str, &str)] = &[
+        (
+            "751d3db85b848deaf221e0ed2b6cc17f587b29057d74cdd4dc0bd18b7157288e",
+            "r6jzx210usqbgnm3pdtm1z6btd14pvdtkn5j8qnpgqzknpggkuw0.k",
+            "fc68cb2c60dbcb9619ac34a8fd3403fc",
+        ),
+        (
+            "ad7ea326aa01940a25bc9e012622db694fd9b4177cf3f89116f3cfe85c80e14a",
+            "kmzm4w0kj9bswd5qmx74nu7kusv5pj40vcsmp781j6xxgpd59z00.k",
+            "fc4194b509257ba9395911aba006367a",
+        ),
+        (
+            "d8543e70b9ae7c41bc18a49a9ceeca9cdc4501966bbd7e76cf3a9fbc12ed8bb4",
+            "vz21tg07061s8v9mckrvgtfds7j2u5lst8cwl6nqhp81njrh5wg0.k",
+            "fc1f5b96e1c5625dafde2523a7fa383a",
+        ),
+    ];
+
+    #[test]
+    fn test_keypair_vectors() {
+        for &(private_hex, public_str, ip6_hex) in KEYPAIR_VECTORS {
+            let keypair = Keypair::from_private(PrivateKey::from_hex(private_hex).unwrap());
+            assert_eq!(keypair.private().expose_secret().to_hex(), private_hex);
+            assert_eq!(keypair.public().to_base32(), public_str);
+            assert_eq!(hex::encode(keypair.ip6().raw()), ip6_hex);
+            assert!(keypair.valid_ip6());
+        }
+    }
+
+    #[test]
+    fn test_keypair_generate() {
+        let rand = Random::new_sodium().unwrap();
+        for _ in 0..4 {
+            let keypair = Keypair::generate(&rand);
+            assert!(keypair.valid_ip6());
+            let derived = Keypair::from_private(keypair.private().clone());
+            assert_eq!(derived.public(), keypair.public());
+            assert_eq!(derived.ip6(), keypair.ip6());
+            assert_eq!(&IpV6::try_from(keypair.public()).unwrap(), keypair.ip6());
+        }
+
+        // Most keys are not valid, but they still have an ip6
+        let keypair = (1..=255)
+            .map(|i| Keypair::from_private(PrivateKey::from([i; 32])))
+            .find(|keypair| !keypair.valid_ip6())
+            .unwrap();
+        assert_ne!(keypair.ip6().raw()[0], 0xfc);
+        assert!(IpV6::try_from(keypair.public()).is_err());
+    }
+
+    #[test]
+    fn test_identity_fixtures() {
+        let (private_hex, public_str, ip6_hex) = KEYPAIR_VECTORS[0];
+        for &conf in &[
+            &include_bytes!("test_data/identity.conf")[..],
+            include_bytes!("test_data/cjdroute.conf"),
+        ] {
+            let identity = NodeIdentity::load(conf).unwrap();
+            let keypair = identity.keypair();
+            assert_eq!(keypair.private().expose_secret().to_hex(), private_hex);
+            assert_eq!(keypair.public().to_base32(), public_str);
+            assert_eq!(hex::encode(keypair.ip6().raw()), ip6_hex);
+        }
+
+        // The form `cjdroute --genconf` writes
+        let identity = NodeIdentity::load(&include_bytes!("test_data/identity.conf")[..]).unwrap();
+        let mut saved = Vec::new();
+        identity.save(&mut saved).unwrap();
+        assert_eq!(saved, &include_bytes!("test_data/identity.conf")[..]);
+
+        let conf = &include_bytes!("test_data/wrong_public_key.conf")[..];
+        assert_eq!(
+            NodeIdentity::load(conf).err(),
+            Some(IdentityError::PublicKeyMismatch)
+        );
+    }
+
+    #[test]
+    fn test_identity_save_load() {
+        let rand = Random::new_sodium().unwrap();
+        let keypair = Keypair::generate(&rand);
+        let identity = NodeIdentity::new(keypair.clone()).unwrap();
+        let mut saved = Vec::new();
+        identity.save(&mut saved).unwrap();
+        let loaded = NodeIdentity::load(&saved[..]).unwrap();
+        assert!(loaded.keypair().private().ct_eq(keypair.private()));
+        assert_eq!(loaded.keypair().public(), keypair.public());
+        assert_eq!(loaded.keypair().ip6(), keypair.ip6());
+
+        // The public key and ip6 are optional
+        let private_hex = keypair.private().expose_secret().to_hex();
+        let conf = format!("{{ /* keys */ \"privateKey\": \"{}\", }}", private_hex);
+        let loaded = NodeIdentity::load(conf.as_bytes()).unwrap();
+        assert_eq!(loaded.keypair().public(), keypair.public());
+
+        // An ip6 in any form, if it's the right one
+        let ip6 = CompressedIp6(keypair.ip6()).to_string();
+        let conf = format!(
+            "{{\"ipv6\":\"{}\",\"privateKey\":\"{}\"}}",
+            ip6, private_hex
+        );
+        assert!(NodeIdentity::load(conf.as_bytes()).is_ok());
+        let other = Keypair::generate(&rand);
+        let other_ip6 = CompressedIp6(other.ip6()).to_string();
+        let conf = format!(
+            "{{\"ipv6\":\"{}\",\"privateKey\":\"{}\"}}",
+            other_ip6, private_hex
+        );
+        assert_eq!(
+            NodeIdentity::load(conf.as_bytes()).err(),
+            Some(IdentityError::Ip6Mismatch)
+        );
+        let conf = format!(
+            "{{\"publicKey\":\"{}\",\"privateKey\":\"{}\"}}",
+            other.public().to_base32(),
+            private_hex
+        );
+        let err = NodeIdentity::load(conf.as_bytes()).err();
+        assert_eq!(err, Some(IdentityError::PublicKeyMismatch));
+
+        // Keys which are not at the top level are not the node's
+        let conf = format!("{{ \"admin\": {{ \"privateKey\": \"{}\" }} }}", private_hex);
+        let err = NodeIdentity::load(conf.as_bytes()).err();
+        assert_eq!(err, Some(IdentityError::MissingPrivateKey));
+
+        // A key without a valid ip6 is not a node's
+        let invalid = (1..=255)
+            .map(|i| Keypair::from_private(PrivateKey::from([i; 32])))
+            .find(|keypair| !keypair.valid_ip6())
+            .unwrap();
+        assert_eq!(
+            NodeIdentity::new(invalid.clone()).err(),
+            Some(IdentityError::InvalidIp6)
+        );
+        let conf = format!(
+            "{{\"privateKey\": \"{}\"}}",
+            invalid.private().expose_secret().to_hex()
+        );
+        assert_eq!(
+            NodeIdentity::load(conf.as_bytes()).err(),
+            Some(IdentityError::InvalidIp6)
+        );
+    }
+
+    #[test]
+    fn test_identity_load_errors() {
+        let load = |conf: &str| NodeIdentity::load(conf.as_bytes()).err();
+        let key = "751d3db85b848deaf221e0ed2b6cc17f587b29057d74cdd4dc0bd18b7157288e";
+
+        assert_eq!(load(""), Some(IdentityError::Syntax(1)));
+        assert_eq!(load("{}"), Some(IdentityError::MissingPrivateKey));
+        assert_eq!(load("[]"), Some(IdentityError::Syntax(1)));
+        assert_eq!(
+            load("{ \"privateKey\": 1 }"),
+            Some(IdentityError::MissingPrivateKey)
+        );
+        assert_eq!(
+            load("{\n\"privateKey\": \"abc\n}"),
+            Some(IdentityError::Syntax(2))
+        );
+        assert_eq!(
+            load("{\n\n\"privateKey\" \"x\" }"),
+            Some(IdentityError::Syntax(3))
+        );
+        assert_eq!(load("{ \"a\": 1 } {"), Some(IdentityError::Syntax(1)));
+        assert_eq!(load("{ \"a\": [1, 2 }"), Some(IdentityError::Syntax(1)));
+        assert_eq!(
+            load("{ /* \n\n */ \"a\": @ }"),
+            Some(IdentityError::Syntax(3))
+        );
+        assert_eq!(load("{ /* \"a\": 1 }"), Some(IdentityError::Syntax(1)));
+
+        let conf = format!("{{ \"privateKey\": \"{}\" }}", &key[1..]);
+        assert_eq!(
+            load(&conf),
+            Some(IdentityError::BadPrivateKey(KeyParseError::BadLength(63)))
+        );
+        let conf = format!("{{ \"privateKey\": \"{}\" }}", "0".repeat(64));
+        assert_eq!(
+            load(&conf),
+            Some(IdentityError::BadPrivateKey(KeyParseError::ZeroKey))
+        );
+        let conf = format!("{{ \"privateKey\": \"{}\", \"publicKey\": \"x.k\" }}", key);
+        assert_eq!(
+            load(&conf),
+            Some(IdentityError::BadPublicKey(KeyParseError::TooShort))
+        );
+        let conf = format!("{{ \"privateKey\": \"{}\", \"ipv6\": \"fc::1::1\" }}", key);
+        assert_eq!(
+            load(&conf),
+            Some(IdentityError::BadIp6(KeyParseError::BadIp6))
+        );
+
+        // Escapes in strings, and the last privateKey is the one which counts
+        let conf = format!(
+            "{{ \"privateKey\": \"{}\", \"priv\\u0061teKey\": \"\\\"\\n\" }}",
+            key
+        );
+        assert_eq!(
+            load(&conf),
+            Some(IdentityError::BadPrivateKey(KeyParseError::BadLength(2)))
+        );
+    }
+
+    #[test]
+    fn test_ip6_has_prefix() {
+        let ip6 = IpV6::from([0xfc, 0x5a, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(ip6_has_prefix(&ip6, &[], 0));
+        assert!(ip6_has_prefix(&ip6, &[0xfc], 8));
+        assert!(ip6_has_prefix(&ip6, &[0xfc, 0x50], 12));
+        assert!(!ip6_has_prefix(&ip6, &[0xfc, 0x50], 13));
+        assert!(ip6_has_prefix(&ip6, &[0xfc, 0x5b], 15));
+        assert!(!ip6_has_prefix(&ip6, &[0xfc, 0x5b], 16));
+        assert!(!ip6_has_prefix(&ip6, &[0xfd], 8));
+        assert!(ip6_has_prefix(&ip6, ip6.raw(), 128));
+    }
+
+    #[test]
+    fn test_ip6_matches_prefix() {
+        let ip6 = IpV6::from([0xfc, 0x5a, 0, 0, 0, 0, 0, 0x64, 0x11, 0, 0, 0, 0, 0, 0, 1]);
+        let same_64 = IpV6::from([0xfc, 0x5a, 0, 0, 0, 0, 0, 0x64, 0x22, 0, 0, 0, 0, 0, 0, 2]);
+        let other_fc = IpV6::from([0xfc, 0x5a, 0, 0, 0, 0, 0, 0x65, 0x11, 0, 0, 0, 0, 0, 0, 1]);
+        let not_fc = IpV6::from([0xfd, 0x5a, 0, 0, 0, 0, 0, 0x64, 0x11, 0, 0, 0, 0, 0, 0, 1]);
+
+        for other in &[&ip6, &same_64, &other_fc, &not_fc] {
+            assert!(ip6.matches_prefix(other, 0));
+        }
+
+        assert!(ip6.matches_prefix(&same_64, 8));
+        assert!(ip6.matches_prefix(&other_fc, 8));
+        assert!(!ip6.matches_prefix(&not_fc, 8));
+
+        assert!(ip6.matches_prefix(&same_64, 64));
+        assert!(!ip6.matches_prefix(&other_fc, 64));
+        assert!(!ip6.matches_prefix(&not_fc, 64));
+
+        assert!(ip6.matches_prefix(&ip6, 128));
+        assert!(!ip6.matches_prefix(&same_64, 128));
+        let last_bit = IpV6::from([0xfc, 0x5a, 0, 0, 0, 0, 0, 0x64, 0x11, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(!ip6.matches_prefix(&last_bit, 128));
+        assert!(ip6.matches_prefix(&last_bit, 127));
+    }
+
+    #[test]
+    fn test_ip6_restriction() {
+        let ip6 = IpV6::from([0xfc, 0x5a, 0, 0, 0, 0, 0, 0x64, 0x11, 0, 0, 0, 0, 0, 0, 1]);
+        let same_64 = IpV6::from([0xfc, 0x5a, 0, 0, 0, 0, 0, 0x64, 0x22, 0, 0, 0, 0, 0, 0, 2]);
+
+        let exact = Ip6Restriction::Exact(ip6.clone());
+        assert!(exact.allows(&ip6));
+        assert!(!exact.allows(&same_64));
+        assert_eq!(exact.exact(), Some(&ip6));
+
+        let prefix = |bits| Ip6Restriction::Prefix {
+            addr: ip6.clone(),
+            bits,
+        };
+        assert!(prefix(64).allows(&ip6));
+        assert!(prefix(64).allows(&same_64));
+        assert!(!prefix(72).allows(&same_64));
+        assert_eq!(prefix(64).exact(), None);
+        // A /128 allows the same addresses as `Exact`.
+        assert!(prefix(128).allows(&ip6));
+        assert!(!prefix(128).allows(&same_64));
+    }
+
+    #[test]
+    fn test_generate_with_ip6_prefix() {
+        let rand = Random::new_sodium().unwrap();
+        // 4 bits after fc, about 4096 keys
+        let keypair = Keypair::generate_with_ip6_prefix(&rand, &[0xfc, 0xa0], 12, 1 << 20).unwrap();
+        assert!(keypair.valid_ip6());
+        assert_eq!(keypair.ip6().raw()[1] >> 4, 0xa);
+        assert_eq!(&IpV6::try_from(keypair.public()).unwrap(), keypair.ip6());
+        assert_eq!(
+            Keypair::from_private(keypair.private().clone()).ip6(),
+            keypair.ip6()
+        );
+
+        // The predicate also sees the public key
+        let keypair =
+            Keypair::generate_matching(&rand, |_, public| public.raw()[0] & 0xf == 3, 1 << 20)
+                .unwrap();
+        assert!(keypair.valid_ip6());
+        assert_eq!(keypair.public().raw()[0] & 0xf, 3);
+
+        // The search gives up
+        assert!(Keypair::generate_with_ip6_prefix(&rand, &[0xfd], 8, 1000).is_none());
+        assert!(Keypair::generate_matching(&rand, |_, _| true, 0).is_none());
+    }
+
+    #[test]
+    fn test_key_search_threads() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        let progress = Arc::new(AtomicU64::new(0));
+        let reported = Arc::clone(&progress);
+        let keypair = KeySearch::new(1 << 20)
+            .threads(4)
+            .on_progress(1, move |attempts| {
+                reported.fetch_max(attempts, Ordering::Relaxed);
+            })
+            .run(|ip6, _| ip6_has_prefix(ip6, &[0xfc, 0x30], 12))
+            .unwrap();
+        assert!(keypair.valid_ip6());
+        assert_eq!(keypair.ip6().raw()[1] >> 4, 0x3);
+        assert!(progress.load(Ordering::Relaxed) > 0);
+
+        // Every thread stops once all of the attempts are used
+        let progress = Arc::new(AtomicU64::new(0));
+        let reported = Arc::clone(&progress);
+        let keypair = KeySearch::new(2000)
+            .threads(3)
+            .on_progress(100, move |attempts| {
+                assert_eq!(attempts % 100, 0);
+                reported.fetch_max(attempts, Ordering::Relaxed);
+            })
+            .run(|_, _| false);
+        assert!(keypair.is_none());
+        assert_eq!(progress.load(Ordering::Relaxed), 2000);
+    }
+
+    #[test]
+    fn test_ip6_text() {
+        for &(_, _, ip6_hex) in KEYPAIR_VECTORS {
+            let mut bytes = [0_u8; 16];
+            bytes.copy_from_slice(&hex::decode(ip6_hex).unwrap());
+            let ip6 = IpV6::from(bytes);
+            assert!(ip6.is_cjdns());
+
+            // The full form as written by `AddrTools_printIp()`
+            let full = ip6_hex
+                .as_bytes()
+                .chunks(4)
+                .map(|group| std::str::from_utf8(group).unwrap())
+                .collect::<Vec<_>>()
+                .join(":");
+            assert_eq!(IpV6::parse(&full).unwrap(), ip6);
+
+            let compressed = CompressedIp6(&ip6).to_string();
+            assert_eq!(compressed, ip6.to_ipv6_addr().to_string());
+            assert_eq!(IpV6::parse(&compressed).unwrap(), ip6);
+            assert_eq!(IpV6::from_ipv6_addr(ip6.to_ipv6_addr()), ip6);
+        }
+
+        let ip6 = IpV6::parse("fc00::1").unwrap();
+        assert_eq!(ip6.raw()[..2], [0xfc, 0]);
+        assert_eq!(ip6.raw()[15], 1);
+        assert_eq!(CompressedIp6(&ip6).to_string(), "fc00::1");
+        let ip6 = IpV6::parse("FC68:CB2C:0:0:0:34A8:0:3FC").unwrap();
+        assert_eq!(CompressedIp6(&ip6).to_string(), "fc68:cb2c::34a8:0:3fc");
+        let ip6 = IpV6::parse("fc00::").unwrap();
+        assert_eq!(format!("{:>10}", CompressedIp6(&ip6)), "    fc00::");
+    }
+
+    #[test]
+    fn test_ip6_text_errors() {
+        let parse = |s: &str| IpV6::parse(s).err();
+        for &bad in &[
+            "",
+            "fc00",
+            "fc00:::1",
+            "fc00::1::2",
+            "fc68:cb2c:60db:cb96:19ac:34a8:fd34:03fc:1",
+            "fc68:cb2c:60db:cb96:19ac:34a8:fd34",
+            "fc68:cb2c:60db:cb96:19ac:34a8:fd34:3fcg",
+            "fc68:cb2c:60db:cb96:19ac:34a8:fd34:103fc",
+            " fc00::1",
+            "[fc00::1]",
+            "fc00::1%eth0",
+            "10.0.0.1",
+        ] {
+            assert_eq!(parse(bad), Some(KeyParseError::BadIp6), "{:?}", bad);
+        }
+        for &not_cjdns in &[
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "2001:db8::1",
+            "::ffff:10.0.0.1",
+        ] {
+            assert_eq!(
+                parse(not_cjdns),
+                Some(KeyParseError::NotCjdnsIp6),
+                "{:?}",
+                not_cjdns
+            );
+        }
+
+        // Conversions don't check the prefix
+        let ip6 = IpV6::from_ipv6_addr(Ipv6Addr::LOCALHOST);
+        assert!(!ip6.is_cjdns());
+        assert_eq!(CompressedIp6(&ip6).to_string(), "::1");
+    }
+
+    #[test]
+    fn test_public_key_wire_cmp() {
+        use std::cmp::Ordering;
+
+        let key = |first: u8, last: u8| {
+            let mut bytes = [0x55; 32];
+            bytes[0] = first;
+            bytes[31] = last;
+            PublicKey::from(bytes)
+        };
+        assert_eq!(key(1, 1).wire_cmp(&key(1, 1)), Ordering::Equal);
+        assert_eq!(key(1, 1).wire_cmp(&key(1, 2)), Ordering::Less);
+        assert_eq!(key(1, 2).wire_cmp(&key(1, 1)), Ordering::Greater);
+        // The first byte is the most significant, bytes are unsigned
+        assert_eq!(key(1, 0xff).wire_cmp(&key(2, 0)), Ordering::Less);
+        assert_eq!(key(0x80, 0).wire_cmp(&key(0x7f, 0xff)), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_key_ct_eq() {
+        let key = hex::decode(KEYPAIR_VECTORS[0].0).unwrap();
+        let mut keys = vec![[0_u8; 32], [0xff; 32]];
+        for &i in &[0, 1, 15, 30, 31] {
+            for &bit in &[0x01, 0x80] {
+                let mut other = [0_u8; 32];
+                other.copy_from_slice(&key);
+                other[i] ^= bit;
+                keys.push(other);
+            }
+        }
+        let mut key_bytes = [0_u8; 32];
+        key_bytes.copy_from_slice(&key);
+        keys.push(key_bytes);
+
+        // The same answers as the derived `PartialEq`
+        for a in &keys {
+            for b in &keys {
+                let (pub_a, pub_b) = (PublicKey::from(*a), PublicKey::from(*b));
+                assert_eq!(pub_a.ct_eq(&pub_b), pub_a == pub_b);
+                let (priv_a, priv_b) = (PrivateKey::from(*a), PrivateKey::from(*b));
+                assert_eq!(priv_a.ct_eq(&priv_b), priv_a == priv_b);
+                assert_eq!(pub_a.ct_eq(&pub_b), a == b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_keypair_wiped_on_drop() {
+        use std::mem::MaybeUninit;
+        use std::ptr;
+
+        let private = PrivateKey::from([0x55; 32]);
+        let mut keypair = MaybeUninit::new(Keypair::from_private(private));
+        unsafe {
+            ptr::drop_in_place(keypair.as_mut_ptr());
+            // Still allocated, only dropped
+            assert_eq!((*keypair.as_ptr()).private().raw(), &[0; 32]);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! Metrics in the Prometheus text exposition format
+
+use std::fmt::Write;
+
+use crate::crypto::crypto_auth::{CryptoAuth, DecryptErr, SessionSummary};
+
+const PREFIX: &str = "cjdns_cryptoauth";
+
+impl CryptoAuth {
+    /// Append the counters of this CryptoAuth and of its sessions to `out`,
+    /// in the text format scraped by Prometheus.
+    ///
+    /// Only the `metrics_max_sessions()` sessions with the most traffic are listed,
+    /// the number of the others is given by `cjdns_cryptoauth_sessions_omitted`.
+    /// No write lock is taken and no session waits for it, so this can run while the
+    /// sessions are in use. The replay counters of a session which is decrypting at the
+    /// time are those of the previous scrape.
+    pub fn render_metrics(&self, out: &mut String) {
+        let counts = self.decrypt_error_counts();
+        header(
+            out,
+            "decrypt_errors_total",
+            "counter",
+            "Packets dropped, by reason.",
+        );
+        for err in DecryptErr::ALL
+            .iter()
+            .filter(|err| **err != DecryptErr::None)
+        {
+            let code = err.clone() as usize;
+            sample(
+                out,
+                "decrypt_errors_total",
+                &[("reason", err.as_str())],
+                counts[code],
+            );
+        }
+
+        header(
+            out,
+            "users",
+            "gauge",
+            "Users allowed to authenticate with a password.",
+        );
+        sample(out, "users", &[], self.user_count() as u64);
+
+        header(
+            out,
+            "pending_sessions",
+            "gauge",
+            "Sessions in the middle of a handshake.",
+        );
+        sample(
+            out,
+            "pending_sessions",
+            &[],
+            self.sessions.handshakes_in_progress() as u64,
+        );
+
+        let (sessions, omitted) = self.sessions.busiest(self.metrics_max_sessions());
+
+        header(
+            out,
+            "sessions_omitted",
+            "gauge",
+            "Sessions left out of the session metrics.",
+        );
+        sample(out, "sessions_omitted", &[], omitted as u64);
+
+        type Counter = fn(&SessionSummary) -> u64;
+        let session_counters: [(&str, &str, Counter); 6] = [
+            (
+                "session_encrypted_bytes_total",
+                "Plaintext bytes encrypted.",
+                |s| s.bytes_encrypted,
+            ),
+            (
+                "session_decrypted_bytes_total",
+                "Plaintext bytes decrypted.",
+                |s| s.bytes_decrypted,
+            ),
+            (
+                "session_received_packets_total",
+                "Packets accepted by the replay protector.",
+                |s| s.stats.received_packets,
+            ),
+            (
+                "session_duplicate_packets_total",
+                "Replayed packets.",
+                |s| s.stats.duplicate_packets,
+            ),
+            (
+                "session_lost_packets_total",
+                "Packets which never arrived.",
+                |s| s.stats.lost_packets,
+            ),
+            (
+                "session_unexpected_packets_total",
+                "Packets which arrived out of order.",
+                |s| s.stats.received_unexpected,
+            ),
+        ];
+        for (name, help, counter) in session_counters.iter() {
+            header(out, name, "counter", help);
+            for session in sessions.iter() {
+                let ip6 = session.her_ip6.to_string();
+                let labels = [
+                    ("ip6", ip6.as_str()),
+                    ("name", session.display_name.as_deref().unwrap_or("")),
+                ];
+                sample(out, name, &labels, counter(session));
+            }
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    let _ = write!(out, "{}_{}", PREFIX, name);
+    for (i, (label, value)) in labels.iter().enumerate() {
+        out.push(if i == 0 { '{' } else { ',' });
+        let _ = write!(out, "{}=\"", label);
+        escape_label(out, value);
+        out.push('"');
+    }
+    if !labels.is_empty() {
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+/// Label values are quoted, with backslash, double quote and line feed escaped.
+fn escape_label(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_sample() {
+        let mut out = String::new();
+        super::sample(&mut out, "users", &[], 3);
+        let labels = [("ip6", "fc00::1"), ("name", "a \"b\"\\c\nd")];
+        super::sample(&mut out, "session_lost_packets_total", &labels, 0);
+        assert_eq!(
+            out,
+            concat!(
+                "cjdns_cryptoauth_users 3\n",
+                "cjdns_cryptoauth_session_lost_packets_total",
+                "{ip6=\"fc00::1\",name=\"a \\\"b\\\"\\\\c\\nd\"} 0\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        use std::net::Ipv6Addr;
+        use std::sync::Arc;
+
+        use cjdns_keys::CJDNSKeysApi;
+
+        use crate::bytestring::ByteString;
+        use crate::crypto::crypto_auth::{ip6_from_key, CryptoAuth, Session};
+        use crate::crypto::keys::PublicKeyExt;
+        use crate::crypto::random::Random;
+        use crate::crypto::session::CryptoSession;
+        use crate::interface::wire::message::Message;
+        use crate::util::events::EventBase;
+
+        let keys_api = CJDNSKeysApi::new().unwrap();
+        let new_ca = || {
+            let keys = keys_api.key_pair();
+            Arc::new(CryptoAuth::new(
+                Some(keys.private_key),
+                EventBase {},
+                Random::Fake,
+            ))
+        };
+        let her_ca = new_ca();
+        let login = ByteString::from("login".to_string());
+        her_ca
+            .add_user_ipv6(login.clone(), Some(login), None, None)
+            .unwrap();
+
+        let send = |from: &Session, to: &Session| {
+            let mut msg = Message::rnew(256);
+            msg.push_bytes(b"HelloWorld012345").unwrap();
+            from.encrypt(&mut msg).unwrap();
+            let sent = msg.bytes().to_vec();
+            to.decrypt(&mut msg).unwrap();
+            sent
+        };
+        let new_peer = |her_name: &str| {
+            let ca = new_ca();
+            let her_key = her_ca.public_key.to_base32();
+            let session = Session::new_from_key_str(Arc::clone(&ca), &her_key, false, None);
+            let name = Some(her_name.to_string());
+            let key = ca.public_key.to_base32();
+            let her_session = Session::new_from_key_str(Arc::clone(&her_ca), &key, false, name);
+            let ip6 = Ipv6Addr::from(ip6_from_key(ca.public_key.raw()));
+            (session.unwrap(), her_session.unwrap(), ip6)
+        };
+
+        // Established, with a replayed packet
+        let (alice, her_alice, alice_ip6) = new_peer("alice");
+        send(&alice, &her_alice);
+        send(&her_alice, &alice);
+        send(&alice, &her_alice);
+        let sent = send(&alice, &her_alice);
+        let mut msg = Message::rnew(256);
+        msg.push_bytes(&sent).unwrap();
+        her_alice.decrypt(&mut msg).unwrap_err();
+
+        // Only a hello received
+        let (bob, her_bob, bob_ip6) = new_peer("bob \"the\\builder\"");
+        send(&bob, &her_bob);
+
+        // Only the session with the most traffic
+        her_ca.set_metrics_max_sessions(1);
+        let mut out = String::new();
+        her_ca.render_metrics(&mut out);
+        let expected = r#"# HELP cjdns_cryptoauth_decrypt_errors_total Packets dropped, by reason.
+# TYPE cjdns_cryptoauth_decrypt_errors_total counter
+cjdns_cryptoauth_decrypt_errors_total{reason="RUNT"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="NO_SESSION"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="FINAL_SHAKE_FAIL"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="FAILED_DECRYPT_RUN_MSG"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="KEY_PKT_ESTABLISHED_SESSION"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="WRONG_PERM_PUBKEY"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="IP_RESTRICTED"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="AUTH_REQUIRED"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="UNRECOGNIZED_AUTH"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="STRAY_KEY"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="HANDSHAKE_DECRYPT_FAILED"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="WISEGUY"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="INVALID_PACKET"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="REPLAY"} 1
+cjdns_cryptoauth_decrypt_errors_total{reason="DECRYPT"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="INTERNAL"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="TOO_MANY_HANDSHAKES"} 0
+cjdns_cryptoauth_decrypt_errors_total{reason="BLINDING"} 0
+# HELP cjdns_cryptoauth_users Users allowed to authenticate with a password.
+# TYPE cjdns_cryptoauth_users gauge
+cjdns_cryptoauth_users 1
+# HELP cjdns_cryptoauth_pending_sessions Sessions in the middle of a handshake.
+# TYPE cjdns_cryptoauth_pending_sessions gauge
+cjdns_cryptoauth_pending_sessions 1
+# HELP cjdns_cryptoauth_sessions_omitted Sessions left out of the session metrics.
+# TYPE cjdns_cryptoauth_sessions_omitted gauge
+cjdns_cryptoauth_sessions_omitted 1
+# HELP cjdns_cryptoauth_session_encrypted_bytes_total Plaintext bytes encrypted.
+# TYPE cjdns_cryptoauth_session_encrypted_bytes_total counter
+cjdns_cryptoauth_session_encrypted_bytes_total{ip6="{ip6}",name="alice"} 16
+# HELP cjdns_cryptoauth_session_decrypted_bytes_total Plaintext bytes decrypted.
+# TYPE cjdns_cryptoauth_session_decrypted_bytes_total counter
+cjdns_cryptoauth_session_decrypted_bytes_total{ip6="{ip6}",name="alice"} 48
+# HELP cjdns_cryptoauth_session_received_packets_total Packets accepted by the replay protector.
+# TYPE cjdns_cryptoauth_session_received_packets_total counter
+cjdns_cryptoauth_session_received_packets_total{ip6="{ip6}",name="alice"} 6
+# HELP cjdns_cryptoauth_session_duplicate_packets_total Replayed packets.
+# TYPE cjdns_cryptoauth_session_duplicate_packets_total counter
+cjdns_cryptoauth_session_duplicate_packets_total{ip6="{ip6}",name="alice"} 1
+# HELP cjdns_cryptoauth_session_lost_packets_total Packets which never arrived.
+# TYPE cjdns_cryptoauth_session_lost_packets_total counter
+cjdns_cryptoauth_session_lost_packets_total{ip6="{ip6}",name="alice"} 0
+# HELP cjdns_cryptoauth_session_unexpected_packets_total Packets which arrived out of order.
+# TYPE cjdns_cryptoauth_session_unexpected_packets_total counter
+cjdns_cryptoauth_session_unexpected_packets_total{ip6="{ip6}",name="alice"} 0
+"#;
+        assert_eq!(out, expected.replace("{ip6}", &alice_ip6.to_string()));
+
+        her_ca.set_metrics_max_sessions(100);
+        let mut out = String::new();
+        her_ca.render_metrics(&mut out);
+        assert!(out.contains("cjdns_cryptoauth_sessions_omitted 0\n"));
+        let bob_line = format!(
+            "cjdns_cryptoauth_session_decrypted_bytes_total{{ip6=\"{}\",name={}}} 16\n",
+            bob_ip6, r#""bob \"the\\builder\"""#
+        );
+        assert!(out.contains(&bob_line));
+    }
+}
@@ -9,7 +9,7 @@ use crate::cffi::Random_bytes;
 pub enum Random {
     Sodium(SodiumRandom),
     Legacy(*mut CRandom),
-    #[cfg(test)]
+    #[cfg(any(test, feature = "fuzz"))]
     Fake,
 }
 
@@ -29,7 +29,7 @@ impl Random {
         match self {
             Random::Sodium(r) => r.random_bytes(dest),
             Random::Legacy(r) => c_random_bytes(*r, dest),
-            #[cfg(test)]
+            #[cfg(any(test, feature = "fuzz"))]
             Random::Fake => (0..dest.len()).for_each(|i| dest[i] = i as u8),
         }
     }
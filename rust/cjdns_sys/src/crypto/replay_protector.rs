@@ -1,6 +1,8 @@
 //! Replay attack protector
 
-#[derive(Default)]
+use std::fmt;
+
+#[derive(Clone, Default)]
 pub struct ReplayProtector {
     /// Internal bitfield.
     bitfield: u64,
@@ -28,6 +30,32 @@ pub struct ReplayProtectorStats {
     pub duplicate_packets: u32,
 }
 
+/// Why a nonce was refused by `ReplayProtector::check_nonce()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayKind {
+    /// The nonce is within the window and was already seen, the packet is a copy.
+    ///
+    /// Networks may duplicate packets, so a few of these are nothing to worry about.
+    Duplicate,
+    /// The nonce is below the window, so it can't be told whether it was seen.
+    ///
+    /// Either the packet was delayed by a lot or someone is replaying old traffic.
+    TooOld,
+}
+
+impl ReplayKind {
+    pub const COUNT: usize = 2;
+}
+
+impl fmt::Display for ReplayKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayKind::Duplicate => write!(f, "duplicate nonce"),
+            ReplayKind::TooOld => write!(f, "nonce below the replay window"),
+        }
+    }
+}
+
 impl ReplayProtector {
     pub fn new() -> Self {
         Self::default()
@@ -92,6 +120,31 @@ impl ReplayProtector {
         return true;
     }
 
+    /// Same as `check_nonce()` but without filing the nonce as seen or updating the counters,
+    /// so the packet is still accepted when it's checked for real.
+    pub fn would_accept(&self, nonce: u32) -> bool {
+        if nonce < self.base_offset {
+            return false;
+        }
+        let offset = nonce - self.base_offset;
+        // Nonces past the window are accepted, the window moves forward to cover them.
+        offset > 63 || self.bitfield & (1_u64 << offset) == 0
+    }
+
+    /// Why `check_nonce()` refuses `nonce`, `None` if it would accept it.
+    ///
+    /// A refused nonce leaves the window as it was, so this gives the reason
+    /// for a nonce which was just refused, as long as the protector was not used in between.
+    pub fn replay_kind(&self, nonce: u32) -> Option<ReplayKind> {
+        if nonce < self.base_offset {
+            Some(ReplayKind::TooOld)
+        } else if self.would_accept(nonce) {
+            None
+        } else {
+            Some(ReplayKind::Duplicate)
+        }
+    }
+
     #[inline]
     fn do_shift(&mut self, offset: u32, bits: u32) -> u32 {
         self.base_offset += bits;
@@ -35,4 +35,135 @@ pub trait SessionTrait {
     fn tick(&self, alloc: &mut Allocator) -> Result<Option<Message>>;
 
     fn cjdns_ver(&self) -> u32;
-}
\ No newline at end of file
+}
+
+/// In-place encryption and decryption of messages exchanged with the peer.
+///
+/// Consumers which only need to encrypt and decrypt can accept `&dyn CryptoSession`,
+/// which allows testing them against a mock session.
+pub trait CryptoSession: SessionTrait {
+    /// Encrypts the message inplace. The new content of `msg` should be sent to the peer.
+    fn encrypt(&self, msg: &mut Message) -> Result<()>;
+
+    /// Decrypt a packet from the peer inplace. If the msg is non-empty, it is the
+    /// decrypted plaintext.
+    fn decrypt(&self, msg: &mut Message) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::Result;
+
+    use crate::bytestring::ByteString;
+    use crate::external::interface::iface::Iface;
+    use crate::external::memory::allocator::Allocator;
+    use crate::interface::wire::message::Message;
+
+    use super::types::*;
+    use super::{CryptoSession, SessionTrait};
+
+    #[derive(Default)]
+    struct MockSession {
+        encrypted: Cell<usize>,
+        decrypted: Cell<usize>,
+        resets: Cell<usize>,
+        fail_decrypt: bool,
+    }
+
+    impl SessionTrait for MockSession {
+        fn set_auth(&self, _: Option<ByteString>, _: Option<ByteString>) {}
+
+        fn get_state(&self) -> State {
+            State::Established
+        }
+
+        fn get_her_pubkey(&self) -> [u8; 32] {
+            [1; 32]
+        }
+
+        fn get_her_ip6(&self) -> [u8; 16] {
+            [0xfc; 16]
+        }
+
+        fn get_name(&self) -> Option<String> {
+            None
+        }
+
+        fn stats(&self) -> CryptoStats {
+            CryptoStats {
+                lost_packets: 0,
+                received_unexpected: 0,
+                received_packets: self.decrypted.get() as u64,
+                duplicate_packets: 0,
+                noise_proto: false,
+            }
+        }
+
+        fn reset_if_timeout(&self) {}
+
+        fn reset(&self) {
+            self.resets.set(self.resets.get() + 1);
+        }
+
+        fn her_key_known(&self) -> bool {
+            true
+        }
+
+        fn ifaces(&self) -> Option<(Iface, Iface)> {
+            None
+        }
+
+        fn tick(&self, _: &mut Allocator) -> Result<Option<Message>> {
+            Ok(None)
+        }
+
+        fn cjdns_ver(&self) -> u32 {
+            0
+        }
+    }
+
+    impl CryptoSession for MockSession {
+        fn encrypt(&self, _: &mut Message) -> Result<()> {
+            self.encrypted.set(self.encrypted.get() + 1);
+            Ok(())
+        }
+
+        fn decrypt(&self, _: &mut Message) -> Result<()> {
+            self.decrypted.set(self.decrypted.get() + 1);
+            if self.fail_decrypt {
+                anyhow::bail!("decrypt failed");
+            }
+            Ok(())
+        }
+    }
+
+    /// Consumer which resets the session when a packet fails to decrypt.
+    fn receive(sess: &dyn CryptoSession, msg: &mut Message) -> bool {
+        if sess.get_state() != State::Established || sess.decrypt(msg).is_err() {
+            sess.reset();
+            return false;
+        }
+        true
+    }
+
+    #[test]
+    fn test_consumer_with_mock_session() {
+        let mut msg = Message::rnew(64);
+
+        let sess = MockSession::default();
+        sess.encrypt(&mut msg).unwrap();
+        assert!(receive(&sess, &mut msg));
+        assert!(receive(&sess, &mut msg));
+        assert_eq!(sess.encrypted.get(), 1);
+        assert_eq!(sess.decrypted.get(), 2);
+        assert_eq!(sess.stats().received_packets, 2);
+        assert_eq!(sess.resets.get(), 0);
+
+        let sess = MockSession { fail_decrypt: true, ..Default::default() };
+        assert!(!receive(&sess, &mut msg));
+        assert_eq!(sess.decrypted.get(), 1);
+        assert_eq!(sess.resets.get(), 1);
+    }
+}
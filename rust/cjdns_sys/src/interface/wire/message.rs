@@ -1,5 +1,6 @@
 //! Message type.
 
+use std::fmt;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
 use std::i32;
 
@@ -16,6 +17,7 @@ use crate::external::memory::allocator::Allocator;
 pub struct Message {
     msg: *mut cffi::Message,
     alloc: Option<Allocator>,
+    sensitive: bool,
 }
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
@@ -34,18 +36,99 @@ pub enum MessageError {
 
     #[error("Buffer misaligned: item size {0}, required alignment {1}")]
     InvalidAlign(usize, usize),
+
+    #[error("Can't move the data to get {0} bytes of padding, the buffer is not owned by Rust")]
+    NotOwned(usize),
 }
 
 pub type Result<T> = std::result::Result<T, MessageError>;
 
+/// Guard bytes following the capacity of the buffers owned by Rust in debug builds,
+/// see `Message::check_canary()`.
+#[cfg(debug_assertions)]
+const CANARY: [u8; 8] = [0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe, 0xba, 0xbe];
+#[cfg(not(debug_assertions))]
+const CANARY: [u8; 0] = [];
+
+/// Integer types which can be pushed to or popped from a message in a given byte order,
+/// see `Message::push_be()` and friends.
+pub trait Endian: Copy + Default {
+    fn to_be(self) -> Self;
+    fn from_be(value: Self) -> Self;
+    fn to_le(self) -> Self;
+    fn from_le(value: Self) -> Self;
+}
+
+macro_rules! impl_endian {
+    ($($t:ty),*) => {
+        $(
+            impl Endian for $t {
+                #[inline(always)]
+                fn to_be(self) -> Self { <$t>::to_be(self) }
+                #[inline(always)]
+                fn from_be(value: Self) -> Self { <$t>::from_be(value) }
+                #[inline(always)]
+                fn to_le(self) -> Self { <$t>::to_le(self) }
+                #[inline(always)]
+                fn from_le(value: Self) -> Self { <$t>::from_le(value) }
+            }
+        )*
+    };
+}
+
+impl_endian!(u8, u16, u32, u64, i8, i16, i32, i64);
+
 impl Message {
     pub fn anew(padding: usize, alloc: &mut Allocator) -> Self {
-        unsafe { Message { msg: cffi::Message_new(0, padding as u32, alloc.native), alloc: None } }
+        let msg = unsafe { cffi::Message_new(0, padding as u32, alloc.native) };
+        Message {
+            msg,
+            alloc: None,
+            sensitive: false,
+        }
     }
 
     pub fn rnew(padding: usize) -> Self {
-        let alloc = Allocator::new(padding + 1024);
-        unsafe { Message { msg: cffi::Message_new(0, padding as u32, alloc.native), alloc: Some(alloc) } }
+        let (msg, alloc) = Self::new_owned(0, padding);
+        Message {
+            msg,
+            alloc: Some(alloc),
+            sensitive: false,
+        }
+    }
+
+    /// Allocate an empty C `Message` with `cap` bytes of capacity after `padding` bytes,
+    /// followed by the canary.
+    fn new_owned(cap: usize, padding: usize) -> (*mut cffi::Message, Allocator) {
+        let alloc = Allocator::new(padding + cap + CANARY.len() + 1024);
+        let size = (cap + CANARY.len()) as u32;
+        let msg = unsafe { &mut *cffi::Message_new(size, padding as u32, alloc.native) };
+        unsafe {
+            let canary = msg.msgbytes.add(cap);
+            std::ptr::copy_nonoverlapping(CANARY.as_ptr(), canary, CANARY.len());
+        }
+        msg._length = 0;
+        msg._capacity = cap as i32;
+        (msg, alloc)
+    }
+
+    /// Panic if the guard bytes following the capacity of a buffer owned by Rust were
+    /// overwritten. These only exist in debug builds, in release builds this does nothing.
+    #[inline]
+    pub fn check_canary(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if self.alloc.is_some() {
+                let end = (self.data_ptr() + self.cap()) as *const u8;
+                let canary = unsafe { from_raw_parts(end, CANARY.len()) };
+                assert!(
+                    canary == CANARY,
+                    "Message buffer overrun: the canary after {} bytes of capacity is {}",
+                    self.cap(),
+                    hex::encode(canary)
+                );
+            }
+        }
     }
 
     /// Create empty new message with the given amount of free space,
@@ -54,7 +137,12 @@ impl Message {
     /// Note: this function does *NOT* clone original message,
     /// the resulting message will be empty.
     pub fn new(&self, padding: usize) -> Self {
-        unsafe { Message { msg: cffi::Message_new(0, padding as u32, (*self.msg)._alloc), alloc: None } }
+        let msg = unsafe { cffi::Message_new(0, padding as u32, (*self.msg)._alloc) };
+        Message {
+            msg,
+            alloc: None,
+            sensitive: false,
+        }
     }
 
     /// Construct a Rust `Message` by wrapping a pointer to C `Message`.
@@ -62,7 +150,11 @@ impl Message {
     /// *Unsafe:* The original pointer *must* remain valid until this instance is dropped.
     #[inline]
     pub fn from_c_message(c_msg: *mut cffi::Message) -> Self {
-        Message { msg: c_msg, alloc: None }
+        Message {
+            msg: c_msg,
+            alloc: None,
+            sensitive: false,
+        }
     }
 
     /// Return original C `Message` pointer from this Rust `Message`.
@@ -96,6 +188,18 @@ impl Message {
         msg._padding as usize
     }
 
+    /// Get the number of bytes which can be pushed in front of the data, same as `pad()`.
+    #[inline]
+    pub fn headroom(&self) -> usize {
+        self.pad()
+    }
+
+    /// Get the number of bytes the data can grow by at its end, without moving it.
+    #[inline]
+    pub fn tailroom(&self) -> usize {
+        self.cap() - self.len()
+    }
+
     /// Check the message data alignment.
     #[inline]
     pub fn is_aligned_to(&self, align: usize) -> bool {
@@ -109,6 +213,30 @@ impl Message {
         msg.msgbytes as usize
     }
 
+    /// Flag the message as containing plaintext key material (or not),
+    /// so that `hexdump()` and `Debug` don't print its content.
+    #[inline]
+    pub fn set_sensitive(&mut self, sensitive: bool) {
+        self.sensitive = sensitive;
+    }
+
+    /// Check whether the message is flagged as containing plaintext key material.
+    #[inline]
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
+    }
+
+    /// Get a displayable summary of the message: its length, capacity, padding, alignment
+    /// and the first and last `Hexdump::DEFAULT_LIMIT` bytes of data.
+    #[inline]
+    pub fn hexdump(&self) -> Hexdump<'_> {
+        Hexdump {
+            msg: self,
+            limit: Hexdump::DEFAULT_LIMIT,
+            redact: self.sensitive,
+        }
+    }
+
     /// Get the read-only view into the message data as byte slice.
     #[inline]
     pub fn bytes(&self) -> &[u8] {
@@ -131,6 +259,84 @@ impl Message {
         unsafe { from_raw_parts_mut(ptr, len) }
     }
 
+    /// Make sure at least `bytes` of padding are available, moving the message data into
+    /// a bigger buffer if needed.
+    ///
+    /// Only messages created with `rnew()` own their buffer and can be moved,
+    /// for the others `MessageError::NotOwned` is returned if the padding is too small.
+    pub fn ensure_padding(&mut self, bytes: usize) -> Result<()> {
+        debug_assert!(bytes < i32::MAX as usize);
+        if self.pad() >= bytes {
+            return Ok(());
+        }
+        self.restage(bytes)
+    }
+
+    /// Move the message data into a new buffer with at least `padding` bytes of padding,
+    /// where the data is 8-byte aligned and the capacity is a multiple of 8.
+    ///
+    /// Only messages created with `rnew()` own their buffer and can be moved,
+    /// for the others `MessageError::NotOwned` is returned.
+    pub fn restage(&mut self, padding: usize) -> Result<()> {
+        debug_assert!(padding < i32::MAX as usize);
+        let old = unsafe { &*self.msg };
+        if self.alloc.is_none() || old._adLen != 0 {
+            return Err(MessageError::NotOwned(padding));
+        }
+
+        self.check_canary();
+
+        // Keep the data 8-byte aligned, as in a fresh message, and the space after it.
+        let padding = (padding + 7) & !7;
+        let (len, cap) = (self.len(), (self.cap() + 7) & !7);
+        let (msg, alloc) = Self::new_owned(cap, padding);
+        let msg = unsafe { &mut *msg };
+        msg._length = len as i32;
+        msg._associatedFd = old._associatedFd;
+        msg.currentIface = old.currentIface;
+        unsafe { std::ptr::copy_nonoverlapping(old.msgbytes, msg.msgbytes, len) };
+
+        // Dropping the old allocator frees the old buffer.
+        self.msg = msg;
+        self.alloc = Some(alloc);
+        Ok(())
+    }
+
+    /// Move the data within the buffer so that it is aligned to `align` bytes, a power of 2,
+    /// using the padding in front of it or the free space after it.
+    ///
+    /// If there is not enough of either, messages created with `rnew()` are moved to a new
+    /// buffer (for `align` up to 8), for the others `MessageError::InvalidAlign` is returned.
+    pub fn realign(&mut self, align: usize) -> Result<()> {
+        debug_assert!(align.is_power_of_two());
+        let offset = self.data_ptr() % align;
+        if offset == 0 {
+            return Ok(());
+        }
+        let (len, pad, cap) = (self.len(), self.pad(), self.cap());
+        let shift = if pad >= offset {
+            -(offset as isize)
+        } else if cap - len >= align - offset {
+            (align - offset) as isize
+        } else if align <= 8 {
+            return self
+                .restage(pad)
+                .map_err(|_| MessageError::InvalidAlign(len, align));
+        } else {
+            return Err(MessageError::InvalidAlign(len, align));
+        };
+
+        let msg = unsafe { &mut *self.msg };
+        unsafe {
+            let dst = msg.msgbytes.offset(shift);
+            std::ptr::copy(msg.msgbytes, dst, len);
+            msg.msgbytes = dst;
+        }
+        msg._padding += shift as i32;
+        msg._capacity -= shift as i32;
+        Ok(())
+    }
+
     /// Push additional data `bytes` *before* the message's existing data.
     /// The available padding must be enough to accommodate additional data,
     /// otherwise error is returned.
@@ -252,6 +458,34 @@ impl Message {
         Ok(res)
     }
 
+    /// Push the integer `value` *before* the message's existing data, in big-endian
+    /// (network) byte order.
+    pub fn push_be<T: Endian>(&mut self, value: T) -> Result<()> {
+        self.push(value.to_be())
+    }
+
+    /// Push the integer `value` *before* the message's existing data, in little-endian
+    /// byte order.
+    pub fn push_le<T: Endian>(&mut self, value: T) -> Result<()> {
+        self.push(value.to_le())
+    }
+
+    /// Pop a big-endian (network byte order) integer from the beginning of the message.
+    pub fn pop_be<T: Endian>(&mut self) -> Result<T> {
+        self.pop::<T>().map(T::from_be)
+    }
+
+    /// Pop a little-endian integer from the beginning of the message.
+    pub fn pop_le<T: Endian>(&mut self) -> Result<T> {
+        self.pop::<T>().map(T::from_le)
+    }
+
+    /// Peek a big-endian (network byte order) integer at the beginning of the message.
+    /// Error is returned if the message is too short or buffer pointer is misaligned.
+    pub fn peek_be<T: Endian>(&self) -> Result<T> {
+        self.peek::<T>().map(|value| T::from_be(*value))
+    }
+
     /// Discard data item of type `T` from the beginning of the message.
     /// The message must be big enough, otherwise error is returned.
     pub fn discard<T: Default>(&mut self) -> Result<()> {
@@ -349,11 +583,13 @@ impl Message {
     }
 
     /// Clear the message: discard all data and set size to 0.
+    /// The message is not flagged as sensitive anymore.
     pub fn clear(&mut self) {
         unsafe {
             let mut msg = &mut (*self.msg);
             msg._length = msg._capacity;
         }
+        self.sensitive = false;
         let size = self.len() as i32;
         if size > 0 {
             self.shift(-size).expect("clear");
@@ -361,11 +597,74 @@ impl Message {
     }
 }
 
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Message {{ {} }}", self.hexdump())
+    }
+}
+
+/// Displayable summary of a message, see `Message::hexdump()`.
+pub struct Hexdump<'a> {
+    msg: &'a Message,
+    limit: usize,
+    redact: bool,
+}
+
+impl Hexdump<'_> {
+    /// Default number of bytes shown at the start and at the end of the message data.
+    pub const DEFAULT_LIMIT: usize = 64;
+
+    /// Show at most `limit` bytes at the start and `limit` bytes at the end of the data.
+    pub fn limit(self, limit: usize) -> Self {
+        Hexdump { limit, ..self }
+    }
+
+    /// Don't show the data at all.
+    pub fn redacted(self) -> Self {
+        Hexdump {
+            redact: true,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = self.msg;
+        // Largest power of 2 dividing the data pointer, capped for readability.
+        let align = 1_usize << msg.data_ptr().trailing_zeros().min(6);
+        write!(
+            f,
+            "len {}, cap {}, pad {}, align {}: ",
+            msg.len(),
+            msg.cap(),
+            msg.pad(),
+            align
+        )?;
+        let bytes = msg.bytes();
+        if self.redact {
+            write!(f, "<redacted>")
+        } else if bytes.len() <= 2 * self.limit {
+            write!(f, "{}", hex::encode(bytes))
+        } else {
+            let elided = bytes.len() - 2 * self.limit;
+            let (head, tail) = (&bytes[..self.limit], &bytes[bytes.len() - self.limit..]);
+            write!(
+                f,
+                "{}..<{} bytes>..{}",
+                hex::encode(head),
+                elided,
+                hex::encode(tail)
+            )
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::cffi;
 
-    use super::Message;
+    use super::{Message, MessageError};
 
     mod alloc {
         use crate::cffi::Allocator;
@@ -416,6 +715,31 @@ mod tests {
         assert_eq!(msg.len(), 64);
     }
 
+    #[test]
+    fn test_message_push_pop_endian() {
+        let alloc = alloc::new_allocator(1024);
+        let c_msg = unsafe { cffi::Message_new(0, 64, alloc) };
+        let mut msg = Message::from_c_message(c_msg);
+        assert_eq!(msg.push_be(0x12345678_u32), Ok(()));
+        assert_eq!(msg.bytes(), &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(msg.peek_be(), Ok(0x12345678_u32));
+        assert_eq!(msg.push_le(0x1234_u16), Ok(()));
+        assert_eq!(msg.bytes(), &[0x34, 0x12, 0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(msg.pop_le(), Ok(0x1234_u16));
+        assert_eq!(msg.pop_le(), Ok(0x78563412_u32));
+
+        assert_eq!(msg.push_be(-2_i64), Ok(()));
+        assert_eq!(
+            msg.bytes(),
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]
+        );
+        assert_eq!(msg.pop_be(), Ok(-2_i64));
+        assert_eq!(
+            msg.pop_be::<u8>(),
+            Err(super::MessageError::InsufficientLength(1, 0))
+        );
+    }
+
     #[test]
     fn test_message_push_pop_unaligned() {
         let alloc = alloc::new_allocator(1024);
@@ -439,4 +763,163 @@ mod tests {
         // Pop 4 bytes unaligned
         assert_eq!(msg.pop(), Ok(0x345678EE_u32));
     }
+
+    #[test]
+    fn test_message_ensure_padding() {
+        // Exact fit, nothing to do
+        let mut msg = Message::rnew(16);
+        msg.push_bytes(&[1, 2, 3, 4]).unwrap();
+        let data_ptr = msg.data_ptr();
+        assert_eq!(msg.ensure_padding(12), Ok(()));
+        assert_eq!(msg.data_ptr(), data_ptr);
+        assert_eq!(msg.pad(), 12);
+
+        // Grow
+        assert_eq!(msg.ensure_padding(13), Ok(()));
+        assert!(msg.pad() >= 13);
+        assert!(msg.is_aligned_to(8));
+        assert_eq!(msg.bytes(), &[1, 2, 3, 4]);
+        msg.push_bytes(&[0; 13]).unwrap();
+        assert_eq!(msg.len(), 17);
+        assert_eq!(msg.pop_bytes(13), Ok(vec![0; 13]));
+        assert_eq!(msg.bytes(), &[1, 2, 3, 4]);
+
+        // Growing rounded the capacity up to 8
+        assert_eq!((msg.headroom(), msg.tailroom()), (msg.pad(), 4));
+
+        // Restaging keeps the padding and rounds up the capacity
+        let mut msg = Message::rnew(5);
+        msg.push_bytes(&[1, 2, 3]).unwrap();
+        assert_eq!((msg.pad(), msg.cap()), (2, 3));
+        assert_eq!(msg.restage(msg.pad()), Ok(()));
+        assert_eq!((msg.pad(), msg.cap()), (8, 8));
+        assert_eq!((msg.headroom(), msg.tailroom()), (8, 5));
+        assert!(msg.is_aligned_to(8));
+        assert_eq!(msg.bytes(), &[1, 2, 3]);
+
+        // Buffers owned by C can't be moved
+        let alloc = alloc::new_allocator(1024);
+        let c_msg = unsafe { cffi::Message_new(4, 8, alloc) };
+        let mut msg = Message::from_c_message(c_msg);
+        assert_eq!(msg.ensure_padding(8), Ok(()));
+        assert_eq!(msg.ensure_padding(9), Err(MessageError::NotOwned(9)));
+        assert_eq!(msg.as_c_message(), c_msg);
+        assert_eq!(msg.pad(), 8);
+    }
+
+    #[test]
+    fn test_message_realign() {
+        for offset in 1..4 {
+            // Moved back into the padding
+            let mut msg = Message::rnew(16 + offset);
+            msg.push_bytes(&[1, 2, 3, 4, 5]).unwrap();
+            msg.discard_bytes(1).unwrap();
+            assert!(!msg.is_aligned_to(4));
+            assert_eq!(msg.realign(4), Ok(()));
+            assert!(msg.is_aligned_to(4));
+            assert_eq!(msg.bytes(), &[2, 3, 4, 5]);
+            assert_eq!(msg.pad() + msg.cap(), 16 + offset);
+
+            // Moved forward into the free space, in a buffer owned by C
+            let alloc = alloc::new_allocator(1024);
+            let c_msg = unsafe { cffi::Message_new(8, 16, alloc) };
+            let mut msg = Message::from_c_message(c_msg);
+            msg.bytes_mut().copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            msg.discard_bytes(offset).unwrap();
+            let data = msg.bytes()[..4 - offset].to_vec();
+            // As handed over by a library, without padding and with a short payload
+            unsafe {
+                (*c_msg)._padding = 0;
+                (*c_msg)._length = (4 - offset) as i32;
+            }
+            assert_eq!(msg.realign(4), Ok(()));
+            assert!(msg.is_aligned_to(4));
+            assert_eq!(msg.bytes(), &data[..]);
+            assert_eq!((msg.pad(), msg.cap()), (4 - offset, 4));
+        }
+
+        // No room anywhere: moved to a new buffer if owned by Rust
+        let mut msg = Message::rnew(4);
+        msg.push_bytes(&[0, 1]).unwrap();
+        msg.discard_bytes(1).unwrap();
+        unsafe { (*msg.as_c_message())._padding = 0 };
+        assert!(!msg.is_aligned_to(4));
+        assert_eq!(msg.realign(4), Ok(()));
+        assert!(msg.is_aligned_to(4));
+        assert_eq!(msg.bytes(), &[1]);
+
+        let alloc = alloc::new_allocator(1024);
+        let c_msg = unsafe { cffi::Message_new(4, 1, alloc) };
+        let mut msg = Message::from_c_message(c_msg);
+        msg.discard_bytes(1).unwrap();
+        let data_ptr = msg.data_ptr();
+        unsafe { (*c_msg)._padding = 0 };
+        assert_eq!(msg.realign(4), Err(MessageError::InvalidAlign(3, 4)));
+        assert_eq!(msg.data_ptr(), data_ptr);
+    }
+
+    #[test]
+    fn test_message_canary() {
+        let mut msg = Message::rnew(16);
+        msg.push_bytes(&[1; 16]).unwrap();
+        msg.check_canary();
+        msg.ensure_padding(32).unwrap();
+        msg.check_canary();
+        msg.clear();
+        msg.check_canary();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Message buffer overrun: the canary after 4 bytes of capacity")]
+    fn test_message_canary_overrun() {
+        let mut msg = Message::rnew(16);
+        msg.push_bytes(&[1, 2, 3, 4]).unwrap();
+        let data = msg.data_ptr() as *mut u8;
+        unsafe { std::ptr::write_bytes(data, 0xff, msg.len() + 1) };
+        msg.check_canary();
+    }
+
+    #[test]
+    fn test_message_hexdump() {
+        let mut msg = Message::rnew(200);
+        assert!(msg.is_aligned_to(8));
+        let dump = msg.hexdump().to_string();
+        assert!(dump.starts_with("len 0, cap 0, pad 200, align "));
+        assert!(dump.ends_with(": "));
+
+        // Short messages are shown in full
+        msg.push_bytes(&[0xab; 8]).unwrap();
+        assert!(msg
+            .hexdump()
+            .to_string()
+            .starts_with("len 8, cap 8, pad 192, align "));
+        assert!(msg.hexdump().to_string().ends_with(": abababababababab"));
+        assert!(format!("{:?}", msg).ends_with(": abababababababab }"));
+        assert!(msg
+            .hexdump()
+            .limit(2)
+            .to_string()
+            .ends_with(": abab..<4 bytes>..abab"));
+
+        // Long ones are cut in the middle
+        msg.push_bytes(&[0x01; 150]).unwrap();
+        let dump = msg.hexdump().to_string();
+        let tail = "01".repeat(56) + &"ab".repeat(8);
+        let expected = format!("{}..<30 bytes>..{}", "01".repeat(64), tail);
+        assert!(dump.starts_with("len 158, cap 158, pad 42, align 2:"));
+        assert!(dump.ends_with(&expected));
+
+        // Key material is never shown
+        assert!(msg
+            .hexdump()
+            .redacted()
+            .to_string()
+            .ends_with(": <redacted>"));
+        msg.set_sensitive(true);
+        assert!(msg.hexdump().to_string().ends_with(": <redacted>"));
+        assert!(!format!("{:?}", msg).contains("0101"));
+        msg.clear();
+        assert!(!msg.is_sensitive());
+    }
 }
@@ -0,0 +1,161 @@
+//! Pool of reusable messages.
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+
+use crate::interface::wire::message::Message;
+
+/// A pool of messages which are reused instead of allocating a new one for every packet.
+///
+/// Every message has room for `msg_size` bytes of data plus `padding` bytes of headroom
+/// for the headers pushed in front of it, e.g. by `Session::encrypt_msg()`.
+/// Messages are created on demand, at most `capacity` idle messages are kept.
+pub struct MessagePool {
+    idle: RefCell<Vec<Message>>,
+    capacity: usize,
+    msg_size: usize,
+    padding: usize,
+}
+
+/// A message taken from a `MessagePool`, it goes back to the pool when dropped.
+pub struct PooledMessage<'a> {
+    msg: Option<Message>,
+    pool: &'a MessagePool,
+}
+
+impl MessagePool {
+    /// Room for an ethernet frame.
+    pub const DEFAULT_MSG_SIZE: usize = 1500;
+
+    /// Room for the headers of the switch, the route and both CryptoAuth sessions.
+    pub const DEFAULT_PADDING: usize = 512;
+
+    pub fn new(capacity: usize, msg_size: usize, padding: usize) -> Self {
+        MessagePool {
+            idle: RefCell::new(Vec::with_capacity(capacity)),
+            capacity,
+            msg_size,
+            padding,
+        }
+    }
+
+    /// Take an empty message from the pool, or allocate a new one if none is idle.
+    pub fn get(&self) -> PooledMessage<'_> {
+        let msg = self.idle.borrow_mut().pop();
+        let msg = msg.unwrap_or_else(|| Message::rnew(self.msg_size + self.padding));
+        PooledMessage { msg: Some(msg), pool: self }
+    }
+
+    /// Number of messages waiting in the pool to be reused.
+    pub fn idle_count(&self) -> usize {
+        self.idle.borrow().len()
+    }
+
+    fn put(&self, mut msg: Message) {
+        // All of the buffer becomes padding again, so the data can be pushed from the end.
+        msg.clear();
+        let mut idle = self.idle.borrow_mut();
+        if idle.len() < self.capacity && msg.pad() >= self.msg_size + self.padding {
+            idle.push(msg);
+        }
+    }
+}
+
+impl Default for MessagePool {
+    fn default() -> Self {
+        Self::new(64, Self::DEFAULT_MSG_SIZE, Self::DEFAULT_PADDING)
+    }
+}
+
+impl Deref for PooledMessage<'_> {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        self.msg.as_ref().expect("pooled message")
+    }
+}
+
+impl DerefMut for PooledMessage<'_> {
+    fn deref_mut(&mut self) -> &mut Message {
+        self.msg.as_mut().expect("pooled message")
+    }
+}
+
+impl Drop for PooledMessage<'_> {
+    fn drop(&mut self) {
+        if let Some(msg) = self.msg.take() {
+            self.pool.put(msg);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::MessagePool;
+    use crate::interface::wire::message::Message;
+
+    #[test]
+    fn test_message_pool() {
+        let pool = MessagePool::new(2, 100, 20);
+        assert_eq!(pool.idle_count(), 0);
+
+        let mut msg = pool.get();
+        assert_eq!(msg.len(), 0);
+        assert_eq!(msg.pad(), 120);
+        let data_ptr = msg.data_ptr();
+        msg.push_bytes(&[1; 100]).unwrap();
+        msg.push_bytes(&[2; 4]).unwrap();
+        drop(msg);
+        assert_eq!(pool.idle_count(), 1);
+
+        // The same buffer comes back empty
+        let msg = pool.get();
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(msg.data_ptr(), data_ptr);
+        assert_eq!(msg.len(), 0);
+        assert_eq!(msg.pad(), 120);
+
+        // At most `capacity` messages are kept
+        let more = (0..3).map(|_| pool.get()).collect::<Vec<_>>();
+        drop(msg);
+        drop(more);
+        assert_eq!(pool.idle_count(), 2);
+    }
+
+    /// Run with `cargo test --release -- --ignored bench_message_pool --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_message_pool() {
+        const ROUNDS: u32 = 100_000;
+        let pool = MessagePool::default();
+        for &size in &[64_usize, 1400] {
+            let payload = vec![0xaa_u8; size];
+            let run = |get: &dyn Fn()| {
+                let start = Instant::now();
+                for _ in 0..ROUNDS {
+                    get();
+                }
+                let elapsed = start.elapsed();
+                let mbps = (size as f64 * ROUNDS as f64) / elapsed.as_secs_f64() / 1e6;
+                (elapsed / ROUNDS, mbps)
+            };
+            let fresh = run(&|| {
+                let padding = MessagePool::DEFAULT_MSG_SIZE + MessagePool::DEFAULT_PADDING;
+                let mut msg = Message::rnew(padding);
+                msg.push_bytes(&payload).unwrap();
+            });
+            let pooled = run(&|| {
+                let mut msg = pool.get();
+                msg.push_bytes(&payload).unwrap();
+            });
+            for (name, (per_msg, mbps)) in &[("fresh", fresh), ("pooled", pooled)] {
+                println!(
+                    "{} byte payload, {}: {:?} per message, {:.1} MB/s",
+                    size, name, per_msg, mbps
+                );
+            }
+        }
+    }
+}
@@ -51,6 +51,7 @@ mod interface {
         pub mod ethernet;
         pub mod headers;
         pub mod message;
+        pub mod message_pool;
     }
 
     pub mod tuntap {
@@ -67,3 +68,9 @@ mod crypto;
 mod rffi;
 mod rtypes;
 mod util;
+
+/// Entry points for the fuzz targets.
+#[cfg(feature = "fuzz")]
+pub mod fuzz {
+    pub use crate::crypto::crypto_auth::{fuzz_decrypt, fuzz_decrypt_established};
+}
@@ -8,7 +8,6 @@
 use crate::bytestring::ByteString;
 use crate::cffi::{self, Allocator_t, Random_t, String_t};
 use crate::crypto::crypto_auth;
-use crate::crypto::crypto_auth::DecryptError;
 use crate::crypto::keys::{PrivateKey, PublicKey};
 use crate::crypto::session;
 use crate::external::interface::cif;
@@ -87,12 +86,22 @@ pub unsafe extern "C" fn Rffi_CryptoAuth2_addUser_ipv6(
     };
     match (*ca)
         .0
-        .add_user_ipv6(cstr(password).expect("password"), cstr(login), ip6)
+        .add_user_ipv6(cstr(password).expect("password"), cstr(login), ip6, None)
     {
         Ok(_) => 0,
         Err(crypto_auth::AddUserError::Duplicate { .. }) => {
             cffi::CryptoAuth_addUser_Res::CryptoAuth_addUser_DUPLICATE as i32
         }
+        Err(crypto_auth::AddUserError::EmptyPassword)
+        | Err(crypto_auth::AddUserError::WeakPassword { .. }) => {
+            cffi::CryptoAuth_addUser_Res::CryptoAuth_addUser_WEAK_PASSWORD as i32
+        }
+        Err(crypto_auth::AddUserError::TooManyUsers { .. }) => {
+            cffi::CryptoAuth_addUser_Res::CryptoAuth_addUser_TOO_MANY_USERS as i32
+        }
+        // Only for addresses in text form and prefixes, which C doesn't pass
+        Err(crypto_auth::AddUserError::BadIp6(_))
+        | Err(crypto_auth::AddUserError::BadPrefixLength(_)) => -1,
     }
 }
 
@@ -171,15 +180,7 @@ pub unsafe extern "C" fn Rffi_CryptoAuth2_tryHandshake(
     let mut msg = Message::from_c_message(c_msg);
     match crypto_auth::try_handshake(&(*ca).0, &mut msg, requireAuth) {
         Err(e) => {
-            let ee = match e.downcast_ref::<DecryptError>() {
-                Some(ee) => match ee {
-                    DecryptError::DecryptErr(ee) => ee,
-                    DecryptError::Internal(_) => &crate::crypto::crypto_auth::DecryptErr::Internal,
-                },
-                None => &crate::crypto::crypto_auth::DecryptErr::Internal,
-            }
-            .clone() as u32;
-            (*ret).err = ee;
+            (*ret).err = u32::from(crypto_auth::decrypt_err_of(&e));
             (*ret).code = RTypes_CryptoAuth2_TryHandshake_Code_t::Error;
         }
         Ok((code, sess)) => {
@@ -1,8 +1,17 @@
 //! Utils
 
 pub mod events {
+    #[cfg(test)]
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    /// Source of the current time.
+    pub trait Clock {
+        /// Current time in seconds.
+        fn now_seconds(&self) -> u32;
+    }
+
     pub struct EventBase;
 
     impl EventBase {
@@ -13,4 +22,42 @@ pub mod events {
                 .as_secs() as u32
         }
     }
+
+    impl Clock for EventBase {
+        fn now_seconds(&self) -> u32 {
+            self.current_time_seconds()
+        }
+    }
+
+    impl<C: Clock + ?Sized> Clock for Arc<C> {
+        fn now_seconds(&self) -> u32 {
+            (**self).now_seconds()
+        }
+    }
+
+    /// Clock which only moves when told to.
+    #[cfg(test)]
+    pub struct ManualClock(AtomicU32);
+
+    #[cfg(test)]
+    impl ManualClock {
+        pub fn new(now_seconds: u32) -> Self {
+            ManualClock(AtomicU32::new(now_seconds))
+        }
+
+        pub fn advance(&self, seconds: u32) {
+            self.0.fetch_add(seconds, Ordering::SeqCst);
+        }
+
+        pub fn rewind(&self, seconds: u32) {
+            self.0.fetch_sub(seconds, Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(test)]
+    impl Clock for ManualClock {
+        fn now_seconds(&self) -> u32 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
 }